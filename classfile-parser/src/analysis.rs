@@ -0,0 +1,326 @@
+use crate::attributes::CodeAttribute;
+use crate::bytecode::Instruction;
+use crate::constant_pool::{types, ConstantPool};
+use crate::descriptor::{DescriptorError, FieldDescriptor, MethodDescriptor};
+use crate::ClassParseError;
+use alloc::borrow::ToOwned;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum AnalysisError {
+    #[error("whilst parsing the code")]
+    ParseError(#[from] ClassParseError),
+    #[error("byte {0} is not the start of an instruction")]
+    InvalidTarget(i64),
+    #[error("instruction at byte {0} pops more values than there are on the stack")]
+    StackUnderflow(usize),
+    #[error("instruction at byte {0} pushes more values than the stack can hold")]
+    StackOverflow(usize),
+    #[error("byte {0} can be reached with different stack heights")]
+    StackHeightMismatch(usize),
+    #[error("execution can run past the end of the code")]
+    FallsOffEnd,
+    #[error("invalid constant pool reference: {0}")]
+    InvalidConstantPoolIndex(u16),
+    #[error("invalid descriptor: {0}")]
+    InvalidDescriptor(String, #[source] DescriptorError),
+}
+
+/// The size of the operand stack and local variable array a method needs.
+/// Both are measured in slots, where longs and doubles take up two slots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameLimits {
+    pub max_stack: u16,
+    pub max_locals: u16,
+}
+
+impl CodeAttribute {
+    /// Recalculates `max_stack` and `max_locals`, see [`compute_limits`]
+    pub fn update_limits(&mut self, descriptor: &str, is_static: bool, pool: &impl ConstantPool) -> Result<(), AnalysisError> {
+        let limits = compute_limits(self, descriptor, is_static, pool)?;
+        self.max_stack = limits.max_stack;
+        self.max_locals = limits.max_locals;
+        Ok(())
+    }
+}
+
+/// Calculates the frame limits of a method by following every path through the code,
+/// including the ones into exception handlers. The values stored in `code` are ignored.
+///
+/// `descriptor` and `is_static` describe the method the code belongs to, they're needed to know
+/// which local variables are taken up by the arguments.
+pub fn compute_limits(code: &CodeAttribute, descriptor: &str, is_static: bool, pool: &impl ConstantPool) -> Result<FrameLimits, AnalysisError> {
+    let instructions = code.code.decode()?;
+    let indices: BTreeMap<usize, usize> = instructions.iter().enumerate()
+        .map(|(i, (address, _))| (*address, i))
+        .collect();
+    let index_of = |target: i64| {
+        usize::try_from(target).ok()
+            .and_then(|target| indices.get(&target).copied())
+            .ok_or(AnalysisError::InvalidTarget(target))
+    };
+
+    let (argument_slots, _) = method_slots(descriptor)?;
+    let mut max_locals = argument_slots + if is_static { 0 } else { 1 };
+    for (_, instruction) in &instructions {
+        max_locals = max_locals.max(locals_used(instruction));
+    }
+
+    // The stack height before each instruction
+    let mut heights: Vec<Option<u16>> = vec![None; instructions.len()];
+    let mut queue = Vec::new();
+    let enqueue = |heights: &mut Vec<Option<u16>>, queue: &mut Vec<usize>, index: usize, height: u16| {
+        match heights[index] {
+            Some(existing) if existing != height => Err(AnalysisError::StackHeightMismatch(instructions[index].0)),
+            Some(_) => Ok(()),
+            None => {
+                heights[index] = Some(height);
+                queue.push(index);
+                Ok(())
+            }
+        }
+    };
+
+    if !instructions.is_empty() {
+        enqueue(&mut heights, &mut queue, 0, 0)?;
+    }
+    for handler in &code.exception_table {
+        // Handlers start with only the exception on the stack
+        enqueue(&mut heights, &mut queue, index_of(handler.handler_pc as i64)?, 1)?;
+    }
+
+    let mut max_stack = 0;
+    while let Some(index) = queue.pop() {
+        let (address, instruction) = &instructions[index];
+        let height = heights[index].unwrap();
+        let (pop, push) = stack_effect(instruction, pool)?;
+        let after = height.checked_sub(pop).ok_or(AnalysisError::StackUnderflow(*address))?
+            .checked_add(push).ok_or(AnalysisError::StackOverflow(*address))?;
+        max_stack = max_stack.max(height).max(after);
+
+        for offset in instruction.branch_offsets() {
+            enqueue(&mut heights, &mut queue, index_of(*address as i64 + offset as i64)?, after)?;
+        }
+        if instruction.can_fall_through() {
+            if index + 1 >= instructions.len() {
+                return Err(AnalysisError::FallsOffEnd);
+            }
+            // A subroutine returns to the instruction after the jsr, with the return address already popped
+            let next_height = match instruction {
+                Instruction::JSr(_) | Instruction::JSr_w(_) => height,
+                _ => after,
+            };
+            enqueue(&mut heights, &mut queue, index + 1, next_height)?;
+        }
+    }
+
+    Ok(FrameLimits { max_stack, max_locals })
+}
+
+/// The highest local variable slot an instruction touches, plus one
+fn locals_used(instruction: &Instruction) -> u16 {
+    match instruction {
+        Instruction::ALoad(i) |
+        Instruction::AStore(i) |
+        Instruction::FLoad(i) |
+        Instruction::FStore(i) |
+        Instruction::ILoad(i) |
+        Instruction::IStore(i) |
+        Instruction::IInc(i, _) |
+        Instruction::Ret(i) => *i as u16 + 1,
+        Instruction::DLoad(i) |
+        Instruction::DStore(i) |
+        Instruction::LLoad(i) |
+        Instruction::LStore(i) => *i as u16 + 2,
+        _ => 0,
+    }
+}
+
+/// The amount of slots an instruction pops off the stack, and the amount it pushes back on
+fn stack_effect(instruction: &Instruction, pool: &impl ConstantPool) -> Result<(u16, u16), AnalysisError> {
+    use Instruction::*;
+    Ok(match instruction {
+        Nop | Breakpoint | Goto(_) | Goto_w(_) | IInc(..) | Ret(_) | Return => (0, 0),
+        AConstNull | ALoad(_) | BIPush(_) | SIPush(_) | FConst(_) | FLoad(_) | IConst(_) | ILoad(_) |
+        LdC(_) | LdC_w(_) | New(_) | JSr(_) | JSr_w(_) => (0, 1),
+        DConst(_) | DLoad(_) | LConst(_) | LLoad(_) | LdC2_w(_) => (0, 2),
+        AStore(_) | AReturn | AThrow | FReturn | FStore(_) | IReturn | IStore(_) | MonitorEnter | MonitorExit | Pop |
+        IfEq(_) | IfGe(_) | IfGt(_) | IfLe(_) | IfLt(_) | IfNe(_) | IfNonNull(_) | IfNull(_) |
+        TableSwitch(_) | LookupSwitch(_) => (1, 0),
+        ANewArray(_) | ArrayLength | Checkcast(_) | FSI | FNeg | I2B | I2C | I2F | I2S | INeg | InstanceOf(_) | NewArray(_) => (1, 1),
+        F2D | F2L | I2D | I2L => (1, 2),
+        Dup => (1, 2),
+        DReturn | DStore(_) | LReturn | LStore(_) | Pop2 |
+        IfACmpEq(_) | IfACmpNe(_) | IfICmpEq(_) | IfICmpGe(_) | IfICmpGt(_) | IfICmpLe(_) | IfICmpLt(_) | IfICmpNe(_) => (2, 0),
+        AALoad | BALoad | CALoad | FAload | IALoad | SALoad | D2F | D2I | L2F | L2I |
+        FAdd | FCmpG | FCmpL | FDiv | FMul | FRem | FSub |
+        IAdd | IAnd | IDiv | IMul | IOr | IRem | IShL | IShR | ISub | IUShR | IXor => (2, 1),
+        D2L | L2D | DNeg | LNeg | DALoad | LALoad | Swap => (2, 2),
+        Dup_x1 => (2, 3),
+        Dup2 => (2, 4),
+        AAStore | BAStore | CAStore | FAstore | IAstore | SAStore => (3, 0),
+        LShL | LShR | LUShR => (3, 2),
+        Dup_x2 => (3, 4),
+        Dup2_x1 => (3, 5),
+        DAStore | LAStore => (4, 0),
+        DCmpG | DCmpL | LCmp => (4, 1),
+        DAdd | DDiv | DMul | DRem | DSub | LAdd | LanD | LDiv | LMul | LOr | LRem | LSub | LXor => (4, 2),
+        Dup2_x2 => (4, 6),
+        MultiANewArray(_, dimensions) => (*dimensions as u16, 1),
+        GetStatic(index) => (0, field_ref_slots(*index, pool)?),
+        PutStatic(index) => (field_ref_slots(*index, pool)?, 0),
+        GetField(index) => (1, field_ref_slots(*index, pool)?),
+        Putfield(index) => (1 + field_ref_slots(*index, pool)?, 0),
+        InvokeStatic(index) => method_ref_slots(*index, pool)?,
+        InvokeVirtual(index) | InvokeSpecial(index) | InvokeInterface(index, _) => {
+            let (arguments, result) = method_ref_slots(*index, pool)?;
+            (arguments + 1, result)
+        }
+        InvokeDynamic(index, _) => {
+            let dynamic = pool.get_as::<types::InvokeDynamicInfo>(*index).ok_or(AnalysisError::InvalidConstantPoolIndex(*index))?;
+            method_slots(name_and_type_descriptor(dynamic.name_and_type_index, pool)?)?
+        }
+    })
+}
+
+fn name_and_type_descriptor(index: u16, pool: &impl ConstantPool) -> Result<&str, AnalysisError> {
+    let name_and_type = pool.get_as::<types::NameAndTypeInfo>(index).ok_or(AnalysisError::InvalidConstantPoolIndex(index))?;
+    pool.get_as::<types::Utf8Info>(name_and_type.descriptor_index)
+        .map(|descriptor| descriptor.inner.as_str())
+        .ok_or(AnalysisError::InvalidConstantPoolIndex(name_and_type.descriptor_index))
+}
+
+fn field_ref_slots(index: u16, pool: &impl ConstantPool) -> Result<u16, AnalysisError> {
+    let field = pool.get_as::<types::FieldRef>(index).ok_or(AnalysisError::InvalidConstantPoolIndex(index))?;
+    let descriptor = name_and_type_descriptor(field.name_and_type_index, pool)?;
+    field_slots(descriptor)
+}
+
+fn method_ref_slots(index: u16, pool: &impl ConstantPool) -> Result<(u16, u16), AnalysisError> {
+    let method = pool.get_as::<types::MethodRef>(index)
+        .or_else(|| pool.get_as::<types::InterfaceMethodRef>(index))
+        .ok_or(AnalysisError::InvalidConstantPoolIndex(index))?;
+    let descriptor = name_and_type_descriptor(method.name_and_type_index, pool)?;
+    method_slots(descriptor)
+}
+
+/// Amount of slots a value of the type takes up
+fn field_slots(descriptor: &str) -> Result<u16, AnalysisError> {
+    let field: FieldDescriptor = descriptor.parse()
+        .map_err(|e| AnalysisError::InvalidDescriptor(descriptor.to_owned(), e))?;
+    Ok(field.slots())
+}
+
+/// Amount of slots the arguments of a method take up, and the amount its return value takes up
+fn method_slots(descriptor: &str) -> Result<(u16, u16), AnalysisError> {
+    let method: MethodDescriptor = descriptor.parse()
+        .map_err(|e| AnalysisError::InvalidDescriptor(descriptor.to_owned(), e))?;
+    Ok((method.parameter_slots(), method.return_slots()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analysis::{compute_limits, AnalysisError, FrameLimits};
+    use crate::attributes::{CodeAttribute, ExceptionTableEntry};
+    use crate::bytecode::Code;
+    use crate::constant_pool::{ConstantPoolEntry, NameAndTypeInfo, TypeRefInfo, Utf8Info};
+    use alloc::borrow::ToOwned;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use assert_matches::assert_matches;
+
+    fn code_attribute(code: Vec<u8>) -> CodeAttribute {
+        CodeAttribute {
+            max_stack: 0,
+            max_locals: 0,
+            code: Code::from_vec(code),
+            exception_table: vec![],
+            attributes: vec![],
+        }
+    }
+
+    #[test]
+    fn category_2_values() {
+        // static long f(int a, long b) { return a + b; }
+        let code = code_attribute(vec![
+            0x1a, // iload_0
+            0x85, // i2l
+            0x1f, // lload_1
+            0x61, // ladd
+            0xad, // lreturn
+        ]);
+        let limits = compute_limits(&code, "(IJ)J", true, &vec![]).unwrap();
+        assert_eq!(limits, FrameLimits { max_stack: 4, max_locals: 3 });
+
+        let limits = compute_limits(&code, "(IJ)J", false, &vec![]).unwrap();
+        assert_eq!(limits.max_locals, 4);
+    }
+
+    #[test]
+    fn exception_handler() {
+        let mut code = code_attribute(vec![
+            0x03, // 0: iconst_0
+            0xac, // 1: ireturn
+            0x59, // 2: dup
+            0x59, // 3: dup
+            0x57, // 4: pop
+            0x57, // 5: pop
+            0xbf, // 6: athrow
+        ]);
+        code.exception_table.push(ExceptionTableEntry { start_pc: 0, end_pc: 2, handler_pc: 2, catch_type: 0 });
+
+        let limits = compute_limits(&code, "()I", true, &vec![]).unwrap();
+        assert_eq!(limits, FrameLimits { max_stack: 3, max_locals: 0 });
+    }
+
+    #[test]
+    fn invoke() {
+        let pool = vec![
+            ConstantPoolEntry::MethodRef(TypeRefInfo { class_index: 0, name_and_type_index: 2 }),
+            ConstantPoolEntry::NameAndTypeInfo(NameAndTypeInfo { name_index: 3, descriptor_index: 4 }),
+            ConstantPoolEntry::Utf8Info(Utf8Info::new("f".to_owned())),
+            ConstantPoolEntry::Utf8Info(Utf8Info::new("([[Ljava/lang/String;DI)D".to_owned())),
+        ];
+        let code = code_attribute(vec![
+            0x01, // aconst_null
+            0x0e, // dconst_0
+            0x04, // iconst_1
+            0xb8, 0x00, 0x01, // invokestatic #1
+            0xaf, // dreturn
+        ]);
+
+        let limits = compute_limits(&code, "()D", true, &pool).unwrap();
+        assert_eq!(limits.max_stack, 4);
+    }
+
+    #[test]
+    fn loop_with_mismatched_height() {
+        let code = code_attribute(vec![
+            0x03, // 0: iconst_0
+            0xa7, 0xff, 0xff, // 1: goto 0
+        ]);
+
+        assert_matches!(compute_limits(&code, "()V", true, &vec![]), Err(AnalysisError::StackHeightMismatch(0)));
+    }
+
+    #[test]
+    fn underflow() {
+        let code = code_attribute(vec![0x60, 0xac]); // iadd, ireturn
+        assert_matches!(compute_limits(&code, "()I", true, &vec![]), Err(AnalysisError::StackUnderflow(0)));
+    }
+
+    #[test]
+    fn overflow() {
+        // iconst_0, iconst_0, then dup2 until the height doesn't fit in a u16
+        let mut bytes = vec![0x03, 0x03];
+        bytes.resize(2 + 32_767, 0x5c);
+        bytes.push(0xb1); // return
+        let code = code_attribute(bytes);
+        assert_matches!(compute_limits(&code, "()V", true, &vec![]), Err(AnalysisError::StackOverflow(32_768)));
+    }
+}
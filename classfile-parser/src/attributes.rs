@@ -1,8 +1,8 @@
-use crate::constant_pool::{types, ConstantPool};
-use std::io::Read;
-use std::ops::Range;
+use crate::constant_pool::{types, ConstantPool, ConstantPoolEntry};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use crate::ClassParseError;
-use crate::byte_util::{ByteParseable, BigEndianReadExt, read_to_vec};
+use crate::byte_util::{ByteParseable, BigEndianReadExt, ByteRead, read_to_vec, parse_multiple};
 use crate::gen_parseable;
 use crate::bytecode::{Code, Instruction};
 
@@ -23,7 +23,7 @@ macro_rules! gen_attribute_parser {
         }
 
         impl $Name {
-            pub fn parse(bytes: &mut impl Read, pool: &impl ConstantPool) -> Result<Option<Self>, ClassParseError> {
+            pub fn parse(bytes: &mut impl ByteRead, pool: &impl ConstantPool) -> Result<Option<Self>, ClassParseError> {
                 let name_index = bytes.read_u16()?;
                 let attribute_size = bytes.read_u32()?;
 
@@ -60,10 +60,14 @@ gen_attribute_parser!(
     pub enum AttributeEntry {
         ConstantValue(ConstantValueAttribute) = "ConstantValue",
         Code(CodeAttribute) = "Code",
+        LineNumberTable(LineNumberTableAttribute) = "LineNumberTable",
+        NestHost(NestHostAttribute) = "NestHost",
+        NestMembers(NestMembersAttribute) = "NestMembers",
+        BootstrapMethods(BootstrapMethodsAttribute) = "BootstrapMethods",
     }
 );
 
-pub fn parse_attribute_array(bytes: &mut impl Read, pool: &impl ConstantPool) -> Result<Vec<AttributeEntry>, ClassParseError> {
+pub fn parse_attribute_array(bytes: &mut impl ByteRead, pool: &impl ConstantPool) -> Result<Vec<AttributeEntry>, ClassParseError> {
     let amount = bytes.read_u16()?;
 
     let mut result = Vec::with_capacity(amount as usize);
@@ -82,7 +86,37 @@ pub fn parse_attribute_array(bytes: &mut impl Read, pool: &impl ConstantPool) ->
 gen_parseable! {
     #[derive(Debug, Clone)]
     pub struct ConstantValueAttribute {
-        value_index: u16,
+        pub value_index: u16,
+    }
+}
+
+/// A compile-time constant, as stored in a `ConstantValue` attribute
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConstantValue {
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    /// The UTF-16 units of the string
+    String(Vec<u16>),
+}
+
+impl ConstantValueAttribute {
+    /// Looks up the value this attribute points to
+    pub fn resolve(&self, pool: &impl ConstantPool) -> Result<ConstantValue, ClassParseError> {
+        let invalid = || ClassParseError::InvalidConstantPoolIndex(self.value_index);
+        return match pool.get_entry(self.value_index).ok_or_else(invalid)? {
+            ConstantPoolEntry::IntegerInfo(value) => Ok(ConstantValue::Int(value.inner as i32)),
+            ConstantPoolEntry::LongInfo(value) => Ok(ConstantValue::Long(value.inner as i64)),
+            ConstantPoolEntry::FloatInfo(value) => Ok(ConstantValue::Float(value.inner)),
+            ConstantPoolEntry::DoubleInfo(value) => Ok(ConstantValue::Double(value.inner)),
+            ConstantPoolEntry::StringInfo(value) => {
+                let string = pool.get_as::<types::Utf8Info>(value.string_index)
+                    .ok_or(ClassParseError::InvalidConstantPoolIndex(value.string_index))?;
+                Ok(ConstantValue::String(string.to_utf16()))
+            }
+            _ => Err(invalid()),
+        };
     }
 }
 
@@ -91,33 +125,126 @@ pub struct CodeAttribute {
     pub max_stack: u16,
     pub max_locals: u16,
     pub code: Code,
-    pub exception_table: Vec<u8>,
+    pub exception_table: Vec<ExceptionTableEntry>,
     pub attributes: Vec<AttributeEntry>,
 }
 
+gen_parseable! {
+    /// A handler for exceptions thrown between `start_pc` (inclusive) and `end_pc` (exclusive).
+    /// A `catch_type` of 0 catches everything.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct ExceptionTableEntry {
+        pub start_pc: u16,
+        pub end_pc: u16,
+        pub handler_pc: u16,
+        pub catch_type: u16,
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct LineNumberEntry {
+        pub start_pc: u16,
+        pub line_number: u16,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LineNumberTableAttribute {
+    pub entries: Vec<LineNumberEntry>,
+}
+
+impl ByteParseable for LineNumberTableAttribute {
+    fn parse(bytes: &mut impl ByteRead) -> Result<Self, ClassParseError> where Self: Sized {
+        let amount = bytes.read_u16()?;
+        Ok(LineNumberTableAttribute {
+            entries: parse_multiple(bytes, amount as usize)?
+        })
+    }
+}
+
+gen_parseable! {
+    /// The class that is the host of the nest this class belongs to.
+    /// See: https://docs.oracle.com/javase/specs/jvms/se16/html/jvms-4.html#jvms-4.7.28
+    #[derive(Debug, Clone)]
+    pub struct NestHostAttribute {
+        pub host_class_index: u16,
+    }
+}
+
+/// The classes that are allowed to claim membership of the nest hosted by this class
+#[derive(Debug, Clone)]
+pub struct NestMembersAttribute {
+    /// Indices of `Class` constants
+    pub classes: Vec<u16>,
+}
+
+impl ByteParseable for NestMembersAttribute {
+    fn parse(bytes: &mut impl ByteRead) -> Result<Self, ClassParseError> where Self: Sized {
+        let amount = bytes.read_u16()?;
+        Ok(NestMembersAttribute {
+            classes: parse_multiple(bytes, amount as usize)?
+        })
+    }
+}
+
+/// The bootstrap methods that `invokedynamic` instructions refer to.
+/// See: https://docs.oracle.com/javase/specs/jvms/se16/html/jvms-4.html#jvms-4.7.23
+#[derive(Debug, Clone)]
+pub struct BootstrapMethodsAttribute {
+    pub methods: Vec<BootstrapMethod>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BootstrapMethod {
+    /// Index of a `MethodHandle` constant
+    pub method_ref: u16,
+    /// Indices of the constants passed to the bootstrap method
+    pub arguments: Vec<u16>,
+}
+
+impl ByteParseable for BootstrapMethod {
+    fn parse(bytes: &mut impl ByteRead) -> Result<Self, ClassParseError> where Self: Sized {
+        let method_ref = bytes.read_u16()?;
+        let amount = bytes.read_u16()?;
+        Ok(BootstrapMethod {
+            method_ref,
+            arguments: parse_multiple(bytes, amount as usize)?
+        })
+    }
+}
+
+impl ByteParseable for BootstrapMethodsAttribute {
+    fn parse(bytes: &mut impl ByteRead) -> Result<Self, ClassParseError> where Self: Sized {
+        let amount = bytes.read_u16()?;
+        Ok(BootstrapMethodsAttribute {
+            methods: parse_multiple(bytes, amount as usize)?
+        })
+    }
+}
+
 trait Attribute {
-    fn parse(bytes: &mut impl Read, expected_size: u32, pool: &impl ConstantPool) -> Result<Self, ClassParseError> where Self: Sized;
+    fn parse(bytes: &mut impl ByteRead, expected_size: u32, pool: &impl ConstantPool) -> Result<Self, ClassParseError> where Self: Sized;
 }
 
 impl<T: ByteParseable> Attribute for T {
-    fn parse(bytes: &mut impl Read, _expected_size: u32, _pool: &impl ConstantPool) -> Result<Self, ClassParseError> {
+    fn parse(bytes: &mut impl ByteRead, _expected_size: u32, _pool: &impl ConstantPool) -> Result<Self, ClassParseError> {
         Self::parse(bytes)
     }
 }
 
 impl Attribute for CodeAttribute {
-    fn parse(bytes: &mut impl Read, _expected_size: u32, pool: &impl ConstantPool) -> Result<Self, ClassParseError> where Self: Sized {
+    fn parse(bytes: &mut impl ByteRead, expected_size: u32, pool: &impl ConstantPool) -> Result<Self, ClassParseError> where Self: Sized {
         let max_stack = bytes.read_u16()?;
         let max_locals = bytes.read_u16()?;
 
         let bytecode_size = bytes.read_u32()?;
-        let mut bytecode_bytes = bytes.take(bytecode_size as u64);
-
-        let mut bytecode = Vec::new();
-        bytecode_bytes.read_to_end(&mut bytecode)?;
+        // The bytecode has to fit in the attribute
+        if bytecode_size > expected_size.saturating_sub(8) {
+            return Err(ClassParseError::UnexpectedEof);
+        }
+        let bytecode = read_to_vec(bytes, bytecode_size as usize)?;
 
         let exception_table_size = bytes.read_u16()?;
-        let exception_table = read_to_vec(bytes, (exception_table_size * 8) as usize)?;
+        let exception_table = parse_multiple(bytes, exception_table_size as usize)?;
 
         let attributes = parse_attribute_array(bytes, pool)?;
 
@@ -133,16 +260,17 @@ impl Attribute for CodeAttribute {
 
 #[cfg(test)]
 mod tests {
-    use crate::constant_pool::{ConstantPoolEntry, Utf8Info};
-    use crate::attributes::AttributeEntry;
-    use std::io::Cursor;
+    use crate::constant_pool::{ConstantPoolEntry, Long, StringInfo, Utf8Info};
+    use crate::attributes::{AttributeEntry, BootstrapMethod, ConstantValue, ConstantValueAttribute, LineNumberEntry};
+    use alloc::borrow::ToOwned;
+    use alloc::vec;
     use crate::ClassParseError;
     use assert_matches::assert_matches;
 
     #[test]
     fn parse_constant_value() {
         let pool = vec![
-            ConstantPoolEntry::Utf8Info(Utf8Info { inner: "ConstantValue".to_owned() })
+            ConstantPoolEntry::Utf8Info(Utf8Info::new("ConstantValue".to_owned()))
         ];
 
         let bytes = vec![
@@ -151,16 +279,36 @@ mod tests {
             0xFE, 0xFEu8 // content
         ];
 
-        let parsed = AttributeEntry::parse(&mut Cursor::new(bytes), &pool).unwrap().unwrap();
+        let parsed = AttributeEntry::parse(&mut &bytes[..], &pool).unwrap().unwrap();
         assert_matches!(parsed, AttributeEntry::ConstantValue(inner) => {
             assert_eq!(inner.value_index, 0xFEFE);
         });
     }
 
+    #[test]
+    fn resolve_constant_value() {
+        let pool = vec![
+            ConstantPoolEntry::LongInfo(Long::new(-2i64 as u64)),
+            ConstantPoolEntry::Unusable,
+            ConstantPoolEntry::StringInfo(StringInfo { string_index: 4 }),
+            ConstantPoolEntry::Utf8Info(Utf8Info::new("hello".to_owned())),
+        ];
+
+        let long = ConstantValueAttribute { value_index: 1 };
+        assert_eq!(long.resolve(&pool).unwrap(), ConstantValue::Long(-2));
+        let string = ConstantValueAttribute { value_index: 3 };
+        assert_eq!(string.resolve(&pool).unwrap(), ConstantValue::String("hello".encode_utf16().collect()));
+
+        let unusable = ConstantValueAttribute { value_index: 2 };
+        assert_matches!(unusable.resolve(&pool), Err(ClassParseError::InvalidConstantPoolIndex(2)));
+        let zero = ConstantValueAttribute { value_index: 0 };
+        assert_matches!(zero.resolve(&pool), Err(ClassParseError::InvalidConstantPoolIndex(0)));
+    }
+
     #[test]
     fn parse_unknown() {
         let pool = vec![
-            ConstantPoolEntry::Utf8Info(Utf8Info { inner: "Unknown Value".to_owned() })
+            ConstantPoolEntry::Utf8Info(Utf8Info::new("Unknown Value".to_owned()))
         ];
 
         let bytes = vec![
@@ -169,14 +317,101 @@ mod tests {
             5u8 // content
         ];
 
-        let parsed = AttributeEntry::parse(&mut Cursor::new(bytes), &pool).unwrap();
+        let parsed = AttributeEntry::parse(&mut &bytes[..], &pool).unwrap();
         assert_matches!(parsed, None);
     }
 
+    #[test]
+    fn parse_line_numbers() {
+        let pool = vec![
+            ConstantPoolEntry::Utf8Info(Utf8Info::new("LineNumberTable".to_owned()))
+        ];
+
+        let bytes = vec![
+            0, 1, //name index
+            0, 0, 0, 10, // length
+            0, 2, // amount of entries
+            0, 0, 0, 3,
+            0, 5, 0, 4,
+        ];
+
+        let parsed = AttributeEntry::parse(&mut &bytes[..], &pool).unwrap().unwrap();
+        assert_matches!(parsed, AttributeEntry::LineNumberTable(inner) => {
+            assert_eq!(inner.entries, vec![
+                LineNumberEntry { start_pc: 0, line_number: 3 },
+                LineNumberEntry { start_pc: 5, line_number: 4 },
+            ]);
+        });
+    }
+
+    #[test]
+    fn parse_nest_members() {
+        let pool = vec![
+            ConstantPoolEntry::Utf8Info(Utf8Info::new("NestMembers".to_owned()))
+        ];
+
+        let bytes = vec![
+            0, 1, //name index
+            0, 0, 0, 6, // length
+            0, 2, // amount of classes
+            0, 4,
+            0, 7,
+        ];
+
+        let parsed = AttributeEntry::parse(&mut &bytes[..], &pool).unwrap().unwrap();
+        assert_matches!(parsed, AttributeEntry::NestMembers(inner) => {
+            assert_eq!(inner.classes, vec![4, 7]);
+        });
+    }
+
+    #[test]
+    fn parse_bootstrap_methods() {
+        let pool = vec![
+            ConstantPoolEntry::Utf8Info(Utf8Info::new("BootstrapMethods".to_owned()))
+        ];
+
+        let bytes = vec![
+            0, 1, //name index
+            0, 0, 0, 12, // length
+            0, 2, // amount of methods
+            0, 3, 0, 1, 0, 9, // handle 3 with argument 9
+            0, 5, 0, 0, // handle 5 without arguments
+        ];
+
+        let parsed = AttributeEntry::parse(&mut &bytes[..], &pool).unwrap().unwrap();
+        assert_matches!(parsed, AttributeEntry::BootstrapMethods(inner) => {
+            assert_eq!(inner.methods, vec![
+                BootstrapMethod { method_ref: 3, arguments: vec![9] },
+                BootstrapMethod { method_ref: 5, arguments: vec![] },
+            ]);
+        });
+    }
+
+    #[test]
+    fn parse_oversized_code() {
+        let pool = vec![
+            ConstantPoolEntry::Utf8Info(Utf8Info::new("Code".to_owned()))
+        ];
+
+        let bytes = vec![
+            0, 1, //name index
+            0, 0, 0, 12, // length
+            0, 1, // max stack
+            0, 1, // max locals
+            0xFF, 0xFF, 0xFF, 0xFF, // code length
+            0xb1, // return
+        ];
+
+        let parsed = AttributeEntry::parse(&mut &bytes[..], &pool);
+        assert_matches!(parsed, Err(ClassParseError::AttributingError(_, error)) => {
+            assert_matches!(*error, ClassParseError::UnexpectedEof);
+        });
+    }
+
     #[test]
     fn parse_invalid_index() {
         let pool = vec![
-            ConstantPoolEntry::Utf8Info(Utf8Info { inner: "Unknown Value".to_owned() })
+            ConstantPoolEntry::Utf8Info(Utf8Info::new("Unknown Value".to_owned()))
         ];
 
         let bytes = vec![
@@ -185,7 +420,7 @@ mod tests {
             5u8
         ];
 
-        let parsed = AttributeEntry::parse(&mut Cursor::new(bytes), &pool);
+        let parsed = AttributeEntry::parse(&mut &bytes[..], &pool);
         assert_matches!(parsed, Err(error) => {
             assert_matches!(error, ClassParseError::InvalidConstantPoolIndex(233));
         });
@@ -0,0 +1,304 @@
+use crate::analysis::AnalysisError;
+use crate::attributes::{AttributeEntry, BootstrapMethod, BootstrapMethodsAttribute, CodeAttribute, ConstantValue, ConstantValueAttribute, ExceptionTableEntry, NestHostAttribute, NestMembersAttribute};
+use crate::bytecode::{Code, Instruction, UnencodableInstruction};
+use crate::class_file::{ClassAccessFlags, ClassFile, FieldAccessFlags, FieldInfo, MethodAccessFlags, MethodInfo};
+use crate::constant_pool::{ConstantPoolEntry, Double, DynamicInfo, Float, Integer, Long, MethodHandleInfo, NameAndTypeInfo, NameInfo, StringInfo, TypeRefInfo, Utf8Info};
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum BuildError {
+    #[error(transparent)]
+    Unencodable(#[from] UnencodableInstruction),
+    #[error("whilst computing the frame limits")]
+    AnalysisError(#[from] AnalysisError),
+}
+
+struct PendingMethod {
+    info: MethodInfo,
+    descriptor: String,
+    code: Option<(Vec<Instruction>, Vec<ExceptionTableEntry>)>,
+}
+
+/// Creates a [`ClassFile`] without going through bytes. Constant pool entries are deduplicated.
+///
+/// Branch offsets inside of method code are in bytes, like in a real class file.
+/// `max_stack` and `max_locals` are computed when the class is built.
+pub struct ClassBuilder {
+    constant_pool: Vec<ConstantPoolEntry>,
+    access_flags: ClassAccessFlags,
+    this_class: u16,
+    super_class: u16,
+    interfaces: Vec<u16>,
+    fields: Vec<FieldInfo>,
+    methods: Vec<PendingMethod>,
+    attributes: Vec<AttributeEntry>,
+}
+
+impl ClassBuilder {
+    /// Starts a public class. `super_class` should only be `None` for `java/lang/Object`.
+    pub fn new(name: &str, super_class: Option<&str>) -> Self {
+        let mut builder = ClassBuilder {
+            constant_pool: Vec::new(),
+            access_flags: ClassAccessFlags::PUBLIC | ClassAccessFlags::SUPER,
+            this_class: 0,
+            super_class: 0,
+            interfaces: Vec::new(),
+            fields: Vec::new(),
+            methods: Vec::new(),
+            attributes: Vec::new(),
+        };
+        builder.this_class = builder.class(name);
+        if let Some(super_class) = super_class {
+            builder.super_class = builder.class(super_class);
+        }
+        builder
+    }
+
+    pub fn access_flags(&mut self, access_flags: ClassAccessFlags) -> &mut Self {
+        self.access_flags = access_flags;
+        self
+    }
+
+    pub fn interface(&mut self, name: &str) -> &mut Self {
+        let index = self.class(name);
+        self.interfaces.push(index);
+        self
+    }
+
+    /// Makes this class a member of the nest hosted by `host`
+    pub fn nest_host(&mut self, host: &str) -> &mut Self {
+        let host_class_index = self.class(host);
+        self.attributes.push(AttributeEntry::NestHost(NestHostAttribute { host_class_index }));
+        self
+    }
+
+    /// Lets `member` claim membership of the nest hosted by this class
+    pub fn nest_member(&mut self, member: &str) -> &mut Self {
+        let index = self.class(member);
+        for attribute in &mut self.attributes {
+            if let AttributeEntry::NestMembers(members) = attribute {
+                members.classes.push(index);
+                return self;
+            }
+        }
+        self.attributes.push(AttributeEntry::NestMembers(NestMembersAttribute { classes: vec![index] }));
+        self
+    }
+
+    /// Adds a bootstrap method that calls the static method `class.name`, and returns its index
+    pub fn bootstrap_method(&mut self, class: &str, name: &str, descriptor: &str, arguments: Vec<u16>) -> u16 {
+        let reference_index = self.method_ref(class, name, descriptor);
+        // REF_invokeStatic
+        let method_ref = self.entry(ConstantPoolEntry::MethodHandleInfo(MethodHandleInfo { reference_kind: 6, reference_index }));
+        let method = BootstrapMethod { method_ref, arguments };
+        for attribute in &mut self.attributes {
+            if let AttributeEntry::BootstrapMethods(bootstrap) = attribute {
+                bootstrap.methods.push(method);
+                return bootstrap.methods.len() as u16 - 1;
+            }
+        }
+        self.attributes.push(AttributeEntry::BootstrapMethods(BootstrapMethodsAttribute { methods: vec![method] }));
+        0
+    }
+
+    pub fn field(&mut self, access_flags: FieldAccessFlags, name: &str, descriptor: &str) -> &mut Self {
+        let name_index = self.utf8(name);
+        let descriptor = self.utf8(descriptor);
+        self.fields.push(FieldInfo { access_flags, name_index, descriptor, attributes: vec![] });
+        self
+    }
+
+    /// Adds a field with a `ConstantValue` attribute
+    pub fn constant_field(&mut self, access_flags: FieldAccessFlags, name: &str, descriptor: &str, value: ConstantValue) -> &mut Self {
+        let value_index = match value {
+            ConstantValue::Int(value) => self.integer(value),
+            ConstantValue::Long(value) => self.long(value),
+            ConstantValue::Float(value) => self.float(value),
+            ConstantValue::Double(value) => self.double(value),
+            ConstantValue::String(value) => {
+                let string_index = self.entry(ConstantPoolEntry::Utf8Info(Utf8Info::from_utf16(&value)));
+                self.entry(ConstantPoolEntry::StringInfo(StringInfo { string_index }))
+            }
+        };
+        self.field(access_flags, name, descriptor);
+        self.fields.last_mut().unwrap().attributes.push(AttributeEntry::ConstantValue(ConstantValueAttribute { value_index }));
+        self
+    }
+
+    /// Adds a method with code
+    pub fn method(&mut self, access_flags: MethodAccessFlags, name: &str, descriptor: &str, code: Vec<Instruction>) -> &mut Self {
+        self.method_with_handlers(access_flags, name, descriptor, code, vec![])
+    }
+
+    /// Adds a method with code and an exception table
+    pub fn method_with_handlers(&mut self, access_flags: MethodAccessFlags, name: &str, descriptor: &str, code: Vec<Instruction>, handlers: Vec<ExceptionTableEntry>) -> &mut Self {
+        self.add_method(access_flags, name, descriptor, Some((code, handlers)))
+    }
+
+    /// Adds a method without code, for abstract and native methods
+    pub fn method_without_code(&mut self, access_flags: MethodAccessFlags, name: &str, descriptor: &str) -> &mut Self {
+        self.add_method(access_flags, name, descriptor, None)
+    }
+
+    fn add_method(&mut self, access_flags: MethodAccessFlags, name: &str, descriptor: &str, code: Option<(Vec<Instruction>, Vec<ExceptionTableEntry>)>) -> &mut Self {
+        let name_index = self.utf8(name);
+        let descriptor_index = self.utf8(descriptor);
+        self.methods.push(PendingMethod {
+            info: MethodInfo { access_flags, name_index, descriptor: descriptor_index, attributes: vec![] },
+            descriptor: descriptor.to_owned(),
+            code,
+        });
+        self
+    }
+
+    /// Returns the index of an entry, adding it if the pool doesn't have it yet
+    pub fn entry(&mut self, entry: ConstantPoolEntry) -> u16 {
+        if let Some(i) = self.constant_pool.iter().position(|existing| *existing == entry) {
+            return i as u16 + 1;
+        }
+        let two_slots = matches!(entry, ConstantPoolEntry::LongInfo(_) | ConstantPoolEntry::DoubleInfo(_));
+        self.constant_pool.push(entry);
+        let index = self.constant_pool.len() as u16;
+        if two_slots {
+            self.constant_pool.push(ConstantPoolEntry::Unusable);
+        }
+        index
+    }
+
+    pub fn utf8(&mut self, value: &str) -> u16 {
+        self.entry(ConstantPoolEntry::Utf8Info(Utf8Info::new(value.to_owned())))
+    }
+
+    pub fn class(&mut self, name: &str) -> u16 {
+        let name_index = self.utf8(name);
+        self.entry(ConstantPoolEntry::Class(NameInfo { name_index }))
+    }
+
+    pub fn string(&mut self, value: &str) -> u16 {
+        let string_index = self.utf8(value);
+        self.entry(ConstantPoolEntry::StringInfo(StringInfo { string_index }))
+    }
+
+    pub fn integer(&mut self, value: i32) -> u16 {
+        self.entry(ConstantPoolEntry::IntegerInfo(Integer::new(value as u32)))
+    }
+
+    pub fn long(&mut self, value: i64) -> u16 {
+        self.entry(ConstantPoolEntry::LongInfo(Long::new(value as u64)))
+    }
+
+    pub fn float(&mut self, value: f32) -> u16 {
+        self.entry(ConstantPoolEntry::FloatInfo(Float::new(value)))
+    }
+
+    pub fn double(&mut self, value: f64) -> u16 {
+        self.entry(ConstantPoolEntry::DoubleInfo(Double::new(value)))
+    }
+
+    pub fn name_and_type(&mut self, name: &str, descriptor: &str) -> u16 {
+        let name_index = self.utf8(name);
+        let descriptor_index = self.utf8(descriptor);
+        self.entry(ConstantPoolEntry::NameAndTypeInfo(NameAndTypeInfo { name_index, descriptor_index }))
+    }
+
+    pub fn field_ref(&mut self, class: &str, name: &str, descriptor: &str) -> u16 {
+        let reference = self.type_ref(class, name, descriptor);
+        self.entry(ConstantPoolEntry::FieldRef(reference))
+    }
+
+    pub fn method_ref(&mut self, class: &str, name: &str, descriptor: &str) -> u16 {
+        let reference = self.type_ref(class, name, descriptor);
+        self.entry(ConstantPoolEntry::MethodRef(reference))
+    }
+
+    pub fn interface_method_ref(&mut self, class: &str, name: &str, descriptor: &str) -> u16 {
+        let reference = self.type_ref(class, name, descriptor);
+        self.entry(ConstantPoolEntry::InterfaceMethodRef(reference))
+    }
+
+    /// A call site for `invokedynamic`, linked by the bootstrap method at `bootstrap_method`
+    pub fn invoke_dynamic(&mut self, bootstrap_method: u16, name: &str, descriptor: &str) -> u16 {
+        let name_and_type_index = self.name_and_type(name, descriptor);
+        self.entry(ConstantPoolEntry::InvokeDynamicInfo(DynamicInfo { bootstrap_method_attr_index: bootstrap_method, name_and_type_index }))
+    }
+
+    fn type_ref(&mut self, class: &str, name: &str, descriptor: &str) -> TypeRefInfo {
+        TypeRefInfo {
+            class_index: self.class(class),
+            name_and_type_index: self.name_and_type(name, descriptor),
+        }
+    }
+
+    pub fn build(self) -> Result<ClassFile, BuildError> {
+        let mut methods = Vec::with_capacity(self.methods.len());
+        for mut method in self.methods {
+            if let Some((instructions, exception_table)) = method.code {
+                let mut bytes = Vec::new();
+                for instruction in &instructions {
+                    instruction.write(bytes.len(), &mut bytes)?;
+                }
+                let mut code = CodeAttribute {
+                    max_stack: 0,
+                    max_locals: 0,
+                    code: Code::from_vec(bytes),
+                    exception_table,
+                    attributes: vec![],
+                };
+                let is_static = method.info.access_flags.contains(MethodAccessFlags::STATIC);
+                code.update_limits(&method.descriptor, is_static, &self.constant_pool)?;
+                method.info.attributes.push(AttributeEntry::Code(code));
+            }
+            methods.push(method.info);
+        }
+
+        Ok(ClassFile {
+            minor_version: 0,
+            major_version: 52,
+            constant_pool: self.constant_pool,
+            access_flags: self.access_flags,
+            this_class: self.this_class,
+            super_class: self.super_class,
+            interfaces: self.interfaces,
+            fields: self.fields,
+            methods,
+            attributes: self.attributes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use crate::attributes::{AttributeEntry, ConstantValue};
+    use crate::builder::ClassBuilder;
+    use crate::bytecode::Instruction;
+    use crate::class_file::{FieldAccessFlags, MethodAccessFlags};
+    use crate::constant_pool::{types, ConstantPool};
+
+    #[test]
+    fn build_class() {
+        let mut builder = ClassBuilder::new("foo/Bar", Some("java/lang/Object"));
+        builder.interface("foo/Baz");
+        let long = builder.long(5);
+        let after = builder.integer(1);
+        assert_eq!(after, long + 2);
+        assert_eq!(builder.class("foo/Bar"), 2);
+
+        builder.constant_field(FieldAccessFlags::STATIC | FieldAccessFlags::FINAL, "X", "J", ConstantValue::Long(7));
+        builder.method(MethodAccessFlags::STATIC, "run", "(I)J", vec![
+            Instruction::LdC2_w(long),
+            Instruction::LReturn,
+        ]);
+        let class = builder.build().unwrap();
+
+        let this_class = class.constant_pool.get_as::<types::Class>(class.this_class).unwrap();
+        assert_eq!(class.constant_pool.get_as::<types::Utf8Info>(this_class.name_index).unwrap().inner, "foo/Bar");
+        assert_eq!(class.interfaces.len(), 1);
+        assert_eq!(class.fields[0].constant_value(&class.constant_pool).unwrap(), Some(ConstantValue::Long(7)));
+        assert!(matches!(&class.methods[0].attributes[0], AttributeEntry::Code(code) if code.max_stack == 2 && code.max_locals == 1));
+    }
+}
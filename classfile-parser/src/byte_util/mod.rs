@@ -1,22 +1,32 @@
 mod parseable;
 mod read_ext;
+mod writeable;
 
 pub use parseable::ByteParseable;
-pub use read_ext::BigEndianReadExt;
-use std::io;
-use std::io::Read;
+pub use read_ext::{BigEndianReadExt, ByteRead};
+pub use writeable::ByteWriteable;
+use alloc::vec::Vec;
 use crate::ClassParseError;
 
+/// The most that's allocated up front for an amount read from the input. Amounts come from untrusted
+/// class files, so anything above this is only allocated as the input turns out to actually contain it.
+const MAX_PREALLOCATION: usize = 4096;
+
 /// Reads an amount of bytes to a vector.
-pub fn read_to_vec(buffer: &mut impl Read, amount: usize) -> io::Result<Vec<u8>> {
-    let mut vec = vec![0u8; amount];
-    buffer.read_exact(&mut vec)?;
+pub fn read_to_vec(buffer: &mut impl ByteRead, amount: usize) -> Result<Vec<u8>, ClassParseError> {
+    let mut vec = Vec::new();
+    while vec.len() < amount {
+        // Never more than doubles what was read already
+        let start = vec.len();
+        vec.resize(start + (amount - start).min(start.max(MAX_PREALLOCATION)), 0);
+        buffer.read_exact(&mut vec[start..])?;
+    }
 
     Ok(vec)
 }
 
-pub fn parse_multiple<T: ByteParseable>(bytes: &mut impl Read, amount: usize) -> Result<Vec<T>, ClassParseError> {
-    let mut result = Vec::with_capacity(amount);
+pub fn parse_multiple<T: ByteParseable>(bytes: &mut impl ByteRead, amount: usize) -> Result<Vec<T>, ClassParseError> {
+    let mut result = Vec::with_capacity(amount.min(MAX_PREALLOCATION));
     for _ in 0..amount {
         result.push(T::parse(bytes)?);
     }
@@ -25,16 +35,22 @@ pub fn parse_multiple<T: ByteParseable>(bytes: &mut impl Read, amount: usize) ->
 
 #[cfg(test)]
 mod tests {
+    use alloc::vec;
+    use alloc::vec::Vec;
     use crate::byte_util::{read_to_vec, BigEndianReadExt, parse_multiple};
-    use std::io::Cursor;
 
     #[test]
     fn test_read_to_vec() {
-        let mut bytes = Cursor::new(&[0u8, 1, 2, 3, 4, 5, 6, 7]);
+        let mut bytes: &[u8] = &[0u8, 1, 2, 3, 4, 5, 6, 7];
         let vec = read_to_vec(&mut bytes, 4).unwrap();
 
         assert_eq!(vec, vec![0, 1, 2, 3]);
         assert_eq!(bytes.read_u8().unwrap(), 4);
+
+        let mut bytes: &[u8] = &[0u8; 10000];
+        assert_eq!(read_to_vec(&mut bytes, 9000).unwrap().len(), 9000);
+        assert_eq!(bytes.len(), 1000);
+        assert!(read_to_vec(&mut bytes, usize::MAX).is_err());
     }
 
     #[test]
@@ -43,7 +59,7 @@ mod tests {
         let expected = vec![0x0102, 0x0304];
 
         // Parses the bytes as u16's
-        let parsed: Vec<u16> = parse_multiple(&mut Cursor::new(bytes), expected.len()).unwrap();
+        let parsed: Vec<u16> = parse_multiple(&mut &bytes[..], expected.len()).unwrap();
         assert_eq!(parsed, expected)
     }
 }
@@ -1,13 +1,13 @@
-use std::io::{Cursor, Read};
-use crate::byte_util::BigEndianReadExt;
+use crate::byte_util::{BigEndianReadExt, ByteRead};
 use crate::ClassParseError;
 
 pub trait ByteParseable {
     fn parse_bytes(bytes: &[u8]) -> Result<Self, ClassParseError> where Self: Sized {
-        return Self::parse(&mut Cursor::new(bytes));
+        let mut bytes = bytes;
+        return Self::parse(&mut bytes);
     }
 
-    fn parse(bytes: &mut impl Read) -> Result<Self, ClassParseError> where Self: Sized;
+    fn parse(bytes: &mut impl ByteRead) -> Result<Self, ClassParseError> where Self: Sized;
 }
 
 macro_rules! gen_primitive_impl {
@@ -16,8 +16,8 @@ macro_rules! gen_primitive_impl {
     ) => {
         $(
             impl ByteParseable for $Type {
-                fn parse(bytes: &mut impl Read) -> Result<Self, ClassParseError> where Self: Sized {
-                    return Ok($Function(bytes)?);
+                fn parse(bytes: &mut impl ByteRead) -> Result<Self, ClassParseError> where Self: Sized {
+                    return $Function(bytes);
                 }
             }
         )+
@@ -59,7 +59,7 @@ macro_rules! gen_parseable {
             }
 
             impl ByteParseable for $Name {
-                fn parse(bytes: &mut impl Read) -> Result<Self, $crate::ClassParseError> {
+                fn parse(bytes: &mut impl $crate::byte_util::ByteRead) -> Result<Self, $crate::ClassParseError> {
                     Ok(
                         Self {
                             $(
@@ -75,7 +75,7 @@ macro_rules! gen_parseable {
 
 #[cfg(test)]
 mod tests {
-    use std::io::Read;
+    use alloc::vec;
     use crate::byte_util::parseable::ByteParseable;
     use crate::gen_parseable;
 
@@ -1,182 +1,128 @@
-use std::io;
-use std::io::Result;
+use crate::ClassParseError;
 
-use byteorder::BigEndian;
-use byteorder::ReadBytesExt;
+/// A source of bytes that can be parsed from.
+///
+/// With the `std` feature this is implemented for everything that implements [`std::io::Read`].
+/// Without it, it's implemented for byte slices, which advance past the bytes that were read
+/// (just like `std`'s implementation of `Read` for `&[u8]`).
+pub trait ByteRead {
+    /// Reads exactly enough bytes to fill `buf`.
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), ClassParseError>;
+}
+
+#[cfg(feature = "std")]
+impl<R: std::io::Read + ?Sized> ByteRead for R {
+    #[inline]
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), ClassParseError> {
+        std::io::Read::read_exact(self, buf).map_err(ClassParseError::from)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl ByteRead for &[u8] {
+    #[inline]
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), ClassParseError> {
+        if buf.len() > self.len() {
+            return Err(ClassParseError::UnexpectedEof);
+        }
+        let (read, rest) = self.split_at(buf.len());
+        buf.copy_from_slice(read);
+        *self = rest;
+        Ok(())
+    }
+}
+
+macro_rules! gen_read_methods {
+    (
+        $($Name:ident => $Type:ty),+
+    ) => {
+        $(
+            #[doc = concat!("Reads a big endian `", stringify!($Type), "` from the underlying reader.")]
+            #[inline]
+            fn $Name(&mut self) -> Result<$Type, ClassParseError> {
+                let mut buf = [0; core::mem::size_of::<$Type>()];
+                self.read_exact(&mut buf)?;
+                Ok(<$Type>::from_be_bytes(buf))
+            }
+        )+
+    }
+}
 
-/// Extends [`Read`] with methods for reading numbers. (For `std::io`.)
+/// Extends [`ByteRead`] with methods for reading numbers.
 ///
 /// All of these methods are explicitly big endian
 ///
 /// # Examples
 ///
-/// Read unsigned 16 bit big-endian integers from a [`Read`]:
+/// Read unsigned 16 bit big-endian integers from a slice:
 ///
 /// ```ignore
-/// let mut rdr = Cursor::new(vec![2, 5, 3, 0]);
+/// let mut rdr: &[u8] = &[2, 5, 3, 0];
 /// assert_eq!(517, rdr.read_u16().unwrap());
 /// assert_eq!(768, rdr.read_u16().unwrap());
 /// ```
-///
-/// [`BigEndian`]: enum.BigEndian.html
-/// [`Read`]: https://doc.rust-lang.org/std/io/trait.Read.html
-pub trait BigEndianReadExt: io::Read {
-    /// Reads an unsigned 8 bit integer from the underlying reader.
-    ///
-    /// # Errors
-    ///
-    /// This method returns the same errors as [`Read::read_exact`].
-    ///
-    /// [`Read::read_exact`]: https://doc.rust-lang.org/std/io/trait.Read.html#method.read_exact
-    #[inline]
-    fn read_u8(&mut self) -> Result<u8> {
-        let mut buf = [0; 1];
-        self.read_exact(&mut buf)?;
-        Ok(buf[0])
-    }
-
-    /// Reads a signed 8 bit integer from the underlying reader.
-    ///
-    /// # Errors
-    ///
-    /// This method returns the same errors as [`Read::read_exact`].
-    ///
-    /// [`Read::read_exact`]: https://doc.rust-lang.org/std/io/trait.Read.html#method.read_exact
-    #[inline]
-    fn read_i8(&mut self) -> Result<i8> {
-        self::ReadBytesExt::read_i8(self)
-    }
-
-    /// Reads an unsigned 16 bit integer from the underlying reader.
-    ///
-    /// # Errors
-    ///
-    /// This method returns the same errors as [`Read::read_exact`].
-    ///
-    /// [`Read::read_exact`]: https://doc.rust-lang.org/std/io/trait.Read.html#method.read_exact
-    #[inline]
-    fn read_u16(&mut self) -> Result<u16> {
-        self::ReadBytesExt::read_u16::<BigEndian>(self)
-    }
-
-    /// Reads a signed 16 bit integer from the underlying reader.
-    ///
-    /// # Errors
-    ///
-    /// This method returns the same errors as [`Read::read_exact`].
-    ///
-    /// [`Read::read_exact`]: https://doc.rust-lang.org/std/io/trait.Read.html#method.read_exact
-    #[inline]
-    fn read_i16(&mut self) -> Result<i16> {
-        self::ReadBytesExt::read_i16::<BigEndian>(self)
-    }
-
-    /// Reads an unsigned 32 bit integer from the underlying reader.
-    ///
-    /// # Errors
-    ///
-    /// This method returns the same errors as [`Read::read_exact`].
-    ///
-    /// [`Read::read_exact`]: https://doc.rust-lang.org/std/io/trait.Read.html#method.read_exact
-    #[inline]
-    fn read_u32(&mut self) -> Result<u32> {
-        self::ReadBytesExt::read_u32::<BigEndian>(self)
-    }
-
-    /// Reads a signed 32 bit integer from the underlying reader.
-    ///
-    /// # Errors
-    ///
-    /// This method returns the same errors as [`Read::read_exact`].
-    ///
-    /// [`Read::read_exact`]: https://doc.rust-lang.org/std/io/trait.Read.html#method.read_exact
-    #[inline]
-    fn read_i32(&mut self) -> Result<i32> {
-        self::ReadBytesExt::read_i32::<BigEndian>(self)
-    }
-
-    /// Reads an unsigned 64 bit integer from the underlying reader.
-    ///
-    /// # Errors
-    ///
-    /// This method returns the same errors as [`Read::read_exact`].
-    ///
-    /// [`Read::read_exact`]: https://doc.rust-lang.org/std/io/trait.Read.html#method.read_exact
-    #[inline]
-    fn read_u64(&mut self) -> Result<u64> {
-        self::ReadBytesExt::read_u64::<BigEndian>(self)
-    }
-
-    /// Reads a signed 64 bit integer from the underlying reader.
-    ///
-    /// # Errors
-    ///
-    /// This method returns the same errors as [`Read::read_exact`].
-    ///
-    /// [`Read::read_exact`]: https://doc.rust-lang.org/std/io/trait.Read.html#method.read_exact
-    #[inline]
-    fn read_i64(&mut self) -> Result<i64> {
-        self::ReadBytesExt::read_i64::<BigEndian>(self)
-    }
-
-    /// Reads a 32 bit float from the underlying reader.
-    ///
-    /// # Errors
-    ///
-    /// This method returns the same errors as [`Read::read_exact`].
-    ///
-    /// [`Read::read_exact`]: https://doc.rust-lang.org/std/io/trait.Read.html#method.read_exact
-    #[inline]
-    fn read_f32(&mut self) -> Result<f32> {
-        self::ReadBytesExt::read_f32::<BigEndian>(self)
-    }
-
-    /// Reads a 64 bit float from the underlying reader.
-    ///
-    /// # Errors
-    ///
-    /// This method returns the same errors as [`Read::read_exact`].
-    ///
-    /// [`Read::read_exact`]: https://doc.rust-lang.org/std/io/trait.Read.html#method.read_exact
-    #[inline]
-    fn read_f64(&mut self) -> Result<f64> {
-        self::ReadBytesExt::read_f64::<BigEndian>(self)
+pub trait BigEndianReadExt: ByteRead {
+    gen_read_methods! {
+        read_u8 => u8,
+        read_i8 => i8,
+        read_u16 => u16,
+        read_i16 => i16,
+        read_u32 => u32,
+        read_i32 => i32,
+        read_u64 => u64,
+        read_i64 => i64,
+        read_f32 => f32,
+        read_f64 => f64
     }
 }
 
-impl<R: io::Read + ?Sized> BigEndianReadExt for R {}
+impl<R: ByteRead + ?Sized> BigEndianReadExt for R {}
 
 #[cfg(test)]
 mod tests {
-    use std::io::{Cursor};
     use super::BigEndianReadExt;
+    use crate::ClassParseError;
+    use assert_matches::assert_matches;
 
     #[test]
     fn read_0() {
-        assert_eq!(Cursor::new(&[0x00]).read_u8().unwrap(), 0x00)
+        assert_eq!((&[0x00u8][..]).read_u8().unwrap(), 0x00)
     }
 
     #[test]
     fn read_u8() {
-        assert_eq!(testing_cursor().read_u8().unwrap(), 0x01)
+        assert_eq!(testing_bytes().read_u8().unwrap(), 0x01)
     }
 
     #[test]
     fn read_u16() {
-        assert_eq!(testing_cursor().read_u16().unwrap(), 0x0102)
+        assert_eq!(testing_bytes().read_u16().unwrap(), 0x0102)
     }
 
     #[test]
     fn read_u32() {
-        assert_eq!(testing_cursor().read_u32().unwrap(), 0x01020304)
+        assert_eq!(testing_bytes().read_u32().unwrap(), 0x01020304)
     }
 
     #[test]
     fn read_u64() {
-        assert_eq!(testing_cursor().read_u64().unwrap(), 0x0102030405060708)
+        assert_eq!(testing_bytes().read_u64().unwrap(), 0x0102030405060708)
+    }
+
+    #[test]
+    fn read_advances() {
+        let mut bytes = testing_bytes();
+        bytes.read_u16().unwrap();
+        assert_eq!(bytes, &[0x03, 0x04, 0x05, 0x06, 0x07, 0x08]);
+    }
+
+    #[test]
+    fn read_past_end() {
+        let mut bytes: &[u8] = &[0x01];
+        assert_matches!(bytes.read_u16(), Err(ClassParseError::UnexpectedEof));
     }
 
-    fn testing_cursor() -> Cursor<&'static [u8]> {
-        Cursor::new(&[0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08])
+    fn testing_bytes() -> &'static [u8] {
+        &[0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]
     }
 }
@@ -0,0 +1,37 @@
+use alloc::vec::Vec;
+
+pub trait ByteWriteable {
+    /// Appends the big endian representation of this value to `out`
+    fn write(&self, out: &mut Vec<u8>);
+}
+
+macro_rules! gen_primitive_impl {
+    (
+        $($Type:ty),+
+    ) => {
+        $(
+            impl ByteWriteable for $Type {
+                fn write(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_be_bytes());
+                }
+            }
+        )+
+    }
+}
+
+gen_primitive_impl!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use super::ByteWriteable;
+
+    #[test]
+    fn write_big_endian() {
+        let mut out = Vec::new();
+        0x0102u16.write(&mut out);
+        (-2i32).write(&mut out);
+        assert_eq!(out, vec![0x01, 0x02, 0xFF, 0xFF, 0xFF, 0xFE]);
+    }
+}
@@ -1,13 +1,31 @@
-use crate::byte_util::{ByteParseable, BigEndianReadExt};
+use crate::byte_util::{ByteParseable, BigEndianReadExt, ByteRead, ByteWriteable, parse_multiple, read_to_vec};
 use crate::ClassParseError;
-use std::io::{Cursor, Read, Seek, SeekFrom, Take};
-use std::ops::{Range, RangeFrom, RangeFull, RangeTo};
-use std::slice::SliceIndex;
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::ops::{Range, RangeFrom, RangeFull, RangeTo};
+use thiserror::Error;
 
 macro_rules! ignore {
     ($a:ident) => {};
 }
 
+macro_rules! write_operands {
+    ($out:ident, $value:expr, $Name:ident, $Instr:ident) => {};
+    ($out:ident, $value:expr, $Name:ident, $Instr:ident, $a:ident) => {
+        if let $Name::$Instr(a) = $value {
+            a.write($out);
+        }
+    };
+    ($out:ident, $value:expr, $Name:ident, $Instr:ident, $a:ident, $b:ident) => {
+        if let $Name::$Instr(a, b) = $value {
+            a.write($out);
+            b.write($out);
+        }
+    };
+}
+
 macro_rules! gen_bytecode_enum {
     (
         $(#[$Meta:meta])*
@@ -29,30 +47,58 @@ macro_rules! gen_bytecode_enum {
             )*
         }
 
-        impl ByteParseable for $Name {
-            fn parse(bytes: &mut impl Read) -> Result<Self, ClassParseError> {
-                let code = bytes.read_u8()?;
+        impl $Name {
+            /// Parses the operands of an instruction whose opcode was already read.
+            /// Returns `None` if the opcode isn't one with a fixed layout.
+            fn parse_fixed(code: u8, bytes: &mut impl ByteRead) -> Result<Option<Self>, ClassParseError> {
                 match code {
                     $(
-                        $($InstrHex => Ok($Name::$Instr$(($($innerType::parse(bytes)?),*))?),)?
-                        $($PHInstrHex => Ok($Name::$Result$(($($Value),*))?),)?
+                        $($InstrHex => Ok(Some($Name::$Instr$(($($innerType::parse(bytes)?),*))?)),)?
+                        $($PHInstrHex => Ok(Some($Name::$Result$(($($Value),*))?)),)?
                     )*
-                    _ => Err(ClassParseError::InvalidBytecode(code))
+                    _ => Ok(None)
                 }
             }
-        }
 
-        impl $Name {
-            /// Length of this instruction in bytes
-            pub fn byte_size(&self) -> usize {
+            /// Writes this instruction using the shortest fixed layout that represents it.
+            /// Returns false if no fixed layout exists, in which case nothing is written.
+            fn write_fixed(&self, out: &mut Vec<u8>) -> bool {
+                match self {
+                    $(
+                        $($Name::$Result$(($($Value),*))? => {
+                            out.push($PHInstrHex);
+                            return true;
+                        })?
+                    )*
+                    _ => {}
+                }
+                match self {
+                    $(
+                        $($Name::$Instr$((..) if { $(ignore!($innerType);)* true })? => {
+                            out.push($InstrHex);
+                            write_operands!(out, self, $Name, $Instr $(, $($innerType),*)?);
+                            true
+                        })?
+                    )*
+                    _ => false
+                }
+            }
+
+            /// Size of the layout [`write_fixed`] would use
+            fn fixed_size(&self) -> Option<usize> {
+                match self {
+                    $(
+                        $($Name::$Result$(($($Value),*))? => return Some(1),)?
+                    )*
+                    _ => {}
+                }
                 match self {
                     $(
                         // SAFETY: core::mem::size_of needs to return the same amount as what ByteParseable parses
                         // TODO: might be better to add a `size` field to ByteParseable instead of querying core::mem::size_of
-                        $($Name::$Instr$((..) if { $(ignore!($innerType);)* true })? => [1,$($(core::mem::size_of::<$innerType>()),*)?].iter().sum(),)?
-                        $($Name::$NameInternal(..) => [1,$($(core::mem::size_of::<$ValueInternal>()),*)?].iter().sum(),)?
+                        $($Name::$Instr$((..) if { $(ignore!($innerType);)* true })? => Some([1,$($(core::mem::size_of::<$innerType>()),*)?].iter().sum()),)?
                     )*
-                    _ => unreachable!()
+                    _ => None
                 }
             }
         }
@@ -61,7 +107,7 @@ macro_rules! gen_bytecode_enum {
 
 gen_bytecode_enum! {
     /// https://docs.oracle.com/javase/specs/jvms/se21/html/jvms-6.html#jvms-6.5
-    #[derive(Debug, Clone)]
+    #[derive(Debug, Clone, PartialEq)]
     #[allow(non_camel_case_types)]
     pub enum Instruction {
         /// Load onto the stack a reference from an array
@@ -85,7 +131,7 @@ gen_bytecode_enum! {
         AThrow = 0xbf,
         BALoad = 0x33,
         BAStore = 0x54,
-        BIPush(u8) = 0x10,
+        BIPush(i8) = 0x10,
         Breakpoint = 0xca,
         CALoad = 0x34,
         CAStore = 0x55,
@@ -154,7 +200,7 @@ gen_bytecode_enum! {
         GetField(u16) = 0xb4,
         GetStatic(u16) = 0xb2,
         Goto(i16) = 0xa7,
-        Goto_w(u32) = 0xc8,
+        Goto_w(i32) = 0xc8,
         I2B = 0x91,
         I2C = 0x92,
         I2D = 0x87,
@@ -174,22 +220,22 @@ gen_bytecode_enum! {
         |IConst(4) = 0x07,
         |IConst(5) = 0x08,
         IDiv = 0x6c,
-        IfACmpEq(u16) = 0xa5,
-        IfACmpNe(u16) = 0xa6,
-        IfICmpEq(u16) = 0x9f,
-        IfICmpGe(u16) = 0xa2,
-        IfICmpGt(u16) = 0xa3,
-        IfICmpLe(u16) = 0xa4,
-        IfICmpLt(u16) = 0xa1,
-        IfICmpNe(u16) = 0xa0,
-        IfEq(u16) = 0x99,
-        IfGe(u16) = 0x9c,
-        IfGt(u16) = 0x9d,
-        IfLe(u16) = 0x9e,
-        IfLt(u16) = 0x9b,
-        IfNe(u16) = 0x9a,
-        IfNonNull(u16) = 0xc7,
-        IfNull(u16) = 0xc6,
+        IfACmpEq(i16) = 0xa5,
+        IfACmpNe(i16) = 0xa6,
+        IfICmpEq(i16) = 0x9f,
+        IfICmpGe(i16) = 0xa2,
+        IfICmpGt(i16) = 0xa3,
+        IfICmpLe(i16) = 0xa4,
+        IfICmpLt(i16) = 0xa1,
+        IfICmpNe(i16) = 0xa0,
+        IfEq(i16) = 0x99,
+        IfGe(i16) = 0x9c,
+        IfGt(i16) = 0x9d,
+        IfLe(i16) = 0x9e,
+        IfLt(i16) = 0x9b,
+        IfNe(i16) = 0x9a,
+        IfNonNull(i16) = 0xc7,
+        IfNull(i16) = 0xc6,
         IInc(u8, i8) = 0x84,
         ILoad(u8) = 0x15,
         |ILoad(0) = 0x1a,
@@ -219,8 +265,8 @@ gen_bytecode_enum! {
         ISub = 0x64,
         IUShR = 0x7c,
         IXor = 0x82,
-        JSr(u16) = 0xa8,
-        JSr_w(u32) = 0xc9,
+        JSr(i16) = 0xa8,
+        JSr_w(i32) = 0xc9,
         L2D = 0x8a,
         L2F = 0x89,
         L2I = 0x88,
@@ -243,7 +289,7 @@ gen_bytecode_enum! {
         |LLoad(3) = 0x21,
         LMul = 0x69,
         LNeg = 0x75,
-        //TODO LookupSwitch = 0xab,
+        &LookupSwitch(LookupSwitch),
         LOr = 0x81,
         LRem = 0x71,
         LReturn = 0xad,
@@ -272,17 +318,248 @@ gen_bytecode_enum! {
         Return = 0xb1,
         SALoad = 0x35,
         SAStore = 0x56,
-        SIPush(u16) = 0x11,
+        SIPush(i16) = 0x11,
         Swap = 0x5f,
-        //TODO TableSwitch = 0xaa,
+        &TableSwitch(TableSwitch),
         //TODO Wide = 0xc4,
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableSwitch {
+    pub default: i32,
+    pub low: i32,
+    pub high: i32,
+    /// Jump offsets for the values `low..=high`
+    pub offsets: Vec<i32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LookupSwitch {
+    pub default: i32,
+    /// Pairs of values and their jump offsets, sorted by value
+    pub pairs: Vec<(i32, i32)>,
+}
+
+/// The operands of the switch instructions are aligned to 4 bytes, relative to the start of the method
+fn switch_padding(address: usize) -> usize {
+    (4 - (address + 1) % 4) % 4
+}
+
+impl TableSwitch {
+    fn parse(bytes: &mut impl ByteRead, address: usize) -> Result<Self, ClassParseError> {
+        read_to_vec(bytes, switch_padding(address))?;
+        let default = bytes.read_i32()?;
+        let low = bytes.read_i32()?;
+        let high = bytes.read_i32()?;
+        // Only allocates as much as the input really contains, the range can be far larger than that
+        let offsets = parse_multiple(bytes, (high as i64 - low as i64 + 1).max(0) as usize)?;
+        Ok(TableSwitch { default, low, high, offsets })
+    }
+}
+
+impl LookupSwitch {
+    fn parse(bytes: &mut impl ByteRead, address: usize) -> Result<Self, ClassParseError> {
+        read_to_vec(bytes, switch_padding(address))?;
+        let default = bytes.read_i32()?;
+        let amount = bytes.read_i32()?;
+        // The amount comes from the input, so the pairs only take up memory once they're actually read
+        let mut pairs = Vec::new();
+        for _ in 0..amount {
+            pairs.push((bytes.read_i32()?, bytes.read_i32()?));
+        }
+        Ok(LookupSwitch { default, pairs })
+    }
+}
+
+impl ByteParseable for Instruction {
+    /// Parses an instruction, assuming it's located at the start of the method.
+    /// Use [`Instruction::parse_at`] if the instruction might be a switch.
+    fn parse(bytes: &mut impl ByteRead) -> Result<Self, ClassParseError> {
+        Self::parse_at(bytes, 0)
+    }
+}
+
+/// Returned when an instruction can't be written without adding entries to the constant pool
+#[derive(Error, Debug)]
+#[error("{0:?} can't be encoded without the constant pool")]
+pub struct UnencodableInstruction(pub Instruction);
+
+impl Instruction {
+    /// Parses an instruction located at `address` bytes from the start of the method
+    pub fn parse_at(bytes: &mut impl ByteRead, address: usize) -> Result<Self, ClassParseError> {
+        let code = bytes.read_u8()?;
+        match code {
+            0xaa => Ok(Instruction::TableSwitch(TableSwitch::parse(bytes, address)?)),
+            0xab => Ok(Instruction::LookupSwitch(LookupSwitch::parse(bytes, address)?)),
+            _ => Self::parse_fixed(code, bytes)?.ok_or(ClassParseError::InvalidBytecode(code)),
+        }
+    }
+
+    /// Writes this instruction as if it's located `address` bytes from the start of the method.
+    /// The shortest possible encoding is used, so `IConst(100)` will be written as a `bipush`.
+    pub fn write(&self, address: usize, out: &mut Vec<u8>) -> Result<(), UnencodableInstruction> {
+        if self.write_fixed(out) {
+            return Ok(());
+        }
+        match self {
+            Instruction::IConst(x) => {
+                if let Ok(byte) = i8::try_from(*x) {
+                    Instruction::BIPush(byte).write_fixed(out);
+                } else if let Ok(short) = i16::try_from(*x) {
+                    Instruction::SIPush(short).write_fixed(out);
+                } else {
+                    return Err(UnencodableInstruction(self.clone()));
+                }
+            }
+            Instruction::TableSwitch(switch) => {
+                out.push(0xaa);
+                out.resize(out.len() + switch_padding(address), 0);
+                switch.default.write(out);
+                switch.low.write(out);
+                switch.high.write(out);
+                for offset in &switch.offsets {
+                    offset.write(out);
+                }
+            }
+            Instruction::LookupSwitch(switch) => {
+                out.push(0xab);
+                out.resize(out.len() + switch_padding(address), 0);
+                switch.default.write(out);
+                (switch.pairs.len() as i32).write(out);
+                for (value, offset) in &switch.pairs {
+                    value.write(out);
+                    offset.write(out);
+                }
+            }
+            _ => return Err(UnencodableInstruction(self.clone())),
+        }
+        Ok(())
+    }
+
+    /// Length of this instruction in bytes.
+    /// The size of the switch instructions depends on their location, see [`Instruction::byte_size_at`]
+    pub fn byte_size(&self) -> usize {
+        self.byte_size_at(0)
+    }
+
+    /// Length of this instruction in bytes, when located `address` bytes from the start of the method
+    pub fn byte_size_at(&self, address: usize) -> usize {
+        if let Some(size) = self.fixed_size() {
+            return size;
+        }
+        match self {
+            Instruction::IConst(x) if i8::try_from(*x).is_ok() => 2,
+            Instruction::IConst(x) if i16::try_from(*x).is_ok() => 3,
+            Instruction::TableSwitch(switch) => 1 + switch_padding(address) + 12 + 4 * switch.offsets.len(),
+            Instruction::LookupSwitch(switch) => 1 + switch_padding(address) + 8 + 8 * switch.pairs.len(),
+            _ => panic!("{:?} has no encoding", self),
+        }
+    }
+
+    /// The offsets, relative to this instruction, of all locations this instruction may jump to.
+    /// For the switches, the default offset comes first.
+    pub fn branch_offsets(&self) -> Vec<i32> {
+        match self {
+            Instruction::IfACmpEq(offset) |
+            Instruction::IfACmpNe(offset) |
+            Instruction::IfICmpEq(offset) |
+            Instruction::IfICmpGe(offset) |
+            Instruction::IfICmpGt(offset) |
+            Instruction::IfICmpLe(offset) |
+            Instruction::IfICmpLt(offset) |
+            Instruction::IfICmpNe(offset) |
+            Instruction::IfEq(offset) |
+            Instruction::IfGe(offset) |
+            Instruction::IfGt(offset) |
+            Instruction::IfLe(offset) |
+            Instruction::IfLt(offset) |
+            Instruction::IfNe(offset) |
+            Instruction::IfNonNull(offset) |
+            Instruction::IfNull(offset) |
+            Instruction::Goto(offset) |
+            Instruction::JSr(offset) => vec![*offset as i32],
+            Instruction::Goto_w(offset) |
+            Instruction::JSr_w(offset) => vec![*offset],
+            Instruction::TableSwitch(switch) => {
+                let mut offsets = vec![switch.default];
+                offsets.extend_from_slice(&switch.offsets);
+                offsets
+            }
+            Instruction::LookupSwitch(switch) => {
+                let mut offsets = vec![switch.default];
+                offsets.extend(switch.pairs.iter().map(|(_, offset)| *offset));
+                offsets
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Returns a copy of this instruction which jumps to `offsets` instead.
+    /// The offsets are in the same order as [`Instruction::branch_offsets`] returns them.
+    /// Returns `None` if an offset doesn't fit inside of the instruction.
+    pub fn with_branch_offsets(&self, offsets: &[i32]) -> Option<Self> {
+        assert_eq!(offsets.len(), self.branch_offsets().len(), "wrong amount of offsets for {:?}", self);
+        let short = || i16::try_from(offsets[0]).ok();
+        Some(match self {
+            Instruction::IfACmpEq(_) => Instruction::IfACmpEq(short()?),
+            Instruction::IfACmpNe(_) => Instruction::IfACmpNe(short()?),
+            Instruction::IfICmpEq(_) => Instruction::IfICmpEq(short()?),
+            Instruction::IfICmpGe(_) => Instruction::IfICmpGe(short()?),
+            Instruction::IfICmpGt(_) => Instruction::IfICmpGt(short()?),
+            Instruction::IfICmpLe(_) => Instruction::IfICmpLe(short()?),
+            Instruction::IfICmpLt(_) => Instruction::IfICmpLt(short()?),
+            Instruction::IfICmpNe(_) => Instruction::IfICmpNe(short()?),
+            Instruction::IfEq(_) => Instruction::IfEq(short()?),
+            Instruction::IfGe(_) => Instruction::IfGe(short()?),
+            Instruction::IfGt(_) => Instruction::IfGt(short()?),
+            Instruction::IfLe(_) => Instruction::IfLe(short()?),
+            Instruction::IfLt(_) => Instruction::IfLt(short()?),
+            Instruction::IfNe(_) => Instruction::IfNe(short()?),
+            Instruction::IfNonNull(_) => Instruction::IfNonNull(short()?),
+            Instruction::IfNull(_) => Instruction::IfNull(short()?),
+            Instruction::Goto(_) => Instruction::Goto(short()?),
+            Instruction::JSr(_) => Instruction::JSr(short()?),
+            Instruction::Goto_w(_) => Instruction::Goto_w(offsets[0]),
+            Instruction::JSr_w(_) => Instruction::JSr_w(offsets[0]),
+            Instruction::TableSwitch(switch) => Instruction::TableSwitch(TableSwitch {
+                default: offsets[0],
+                offsets: offsets[1..].to_vec(),
+                ..switch.clone()
+            }),
+            Instruction::LookupSwitch(switch) => Instruction::LookupSwitch(LookupSwitch {
+                default: offsets[0],
+                pairs: switch.pairs.iter().zip(&offsets[1..]).map(|((value, _), offset)| (*value, *offset)).collect(),
+            }),
+            _ => self.clone(),
+        })
+    }
+
+    /// Whether execution may continue with the instruction directly after this one
+    pub fn can_fall_through(&self) -> bool {
+        !matches!(self,
+            Instruction::Goto(_) |
+            Instruction::Goto_w(_) |
+            Instruction::TableSwitch(_) |
+            Instruction::LookupSwitch(_) |
+            Instruction::AThrow |
+            Instruction::Ret(_) |
+            Instruction::Return |
+            Instruction::AReturn |
+            Instruction::DReturn |
+            Instruction::FReturn |
+            Instruction::IReturn |
+            Instruction::LReturn
+        )
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::bytecode::Instruction;
+    use crate::bytecode::{Code, Instruction, LookupSwitch, TableSwitch};
     use crate::byte_util::ByteParseable;
+    use alloc::vec;
+    use alloc::vec::Vec;
     use crate::ClassParseError;
     use assert_matches::assert_matches;
 
@@ -315,6 +592,84 @@ mod tests {
 
         assert_matches!(result, Err(ClassParseError::InvalidBytecode(0xfd)));
     }
+
+    #[test]
+    fn parse_tableswitch() {
+        let bytes = vec![
+            0xaa, // located at address 2, so one byte of padding follows
+            0,
+            0, 0, 0, 20, // default
+            0, 0, 0, 1, // low
+            0, 0, 0, 2, // high
+            0, 0, 0, 10,
+            0xFF, 0xFF, 0xFF, 0xF0,
+        ];
+        let result = Instruction::parse_at(&mut &bytes[..], 2).unwrap();
+
+        assert_eq!(result, Instruction::TableSwitch(TableSwitch { default: 20, low: 1, high: 2, offsets: vec![10, -16] }));
+        assert_eq!(result.byte_size_at(2), bytes.len());
+    }
+
+    #[test]
+    fn parse_truncated_switches() {
+        let table = vec![
+            0xaa, 0, 0, 0,
+            0, 0, 0, 20, // default
+            0x80, 0, 0, 0, // low
+            0x7F, 0xFF, 0xFF, 0xFF, // high
+            0, 0, 0, 10,
+        ];
+        assert_matches!(Instruction::parse_bytes(&table), Err(ClassParseError::UnexpectedEof));
+
+        let lookup = vec![
+            0xab, 0, 0, 0,
+            0, 0, 0, 20, // default
+            0x7F, 0xFF, 0xFF, 0xFF, // amount of pairs
+            0, 0, 0, 1,
+            0, 0, 0, 10,
+        ];
+        assert_matches!(Instruction::parse_bytes(&lookup), Err(ClassParseError::UnexpectedEof));
+    }
+
+    #[test]
+    fn write_roundtrip() {
+        let instructions = vec![
+            Instruction::ALoad(0),
+            Instruction::ALoad(7),
+            Instruction::IConst(-1),
+            Instruction::IfNe(-3),
+            Instruction::IInc(1, -1),
+            Instruction::LookupSwitch(LookupSwitch { default: 8, pairs: vec![(-5, 12), (3, 16)] }),
+            Instruction::Goto_w(-70000),
+        ];
+
+        let mut bytes = Vec::new();
+        for instruction in &instructions {
+            let address = bytes.len();
+            instruction.write(address, &mut bytes).unwrap();
+            assert_eq!(bytes.len() - address, instruction.byte_size_at(address), "size of {:?}", instruction);
+        }
+
+        let parsed: Vec<_> = Code::from_vec(bytes).iter(..).map(|(_, instruction)| instruction).collect();
+        assert_eq!(parsed, instructions);
+    }
+
+    #[test]
+    fn write_uses_shortest_encoding() {
+        let mut bytes = Vec::new();
+        Instruction::IConst(100).write(0, &mut bytes).unwrap();
+        Instruction::IConst(1000).write(0, &mut bytes).unwrap();
+        assert_eq!(bytes, vec![0x10, 100, 0x11, 0x03, 0xe8]);
+
+        assert!(Instruction::DConst(0.5).write(0, &mut bytes).is_err());
+    }
+
+    #[test]
+    fn retarget_branch() {
+        assert_eq!(Instruction::Goto(5).with_branch_offsets(&[-20]), Some(Instruction::Goto(-20)));
+        assert_eq!(Instruction::IfEq(5).with_branch_offsets(&[40000]), None);
+        assert_eq!(Instruction::IAdd.branch_offsets(), vec![]);
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -361,9 +716,18 @@ impl Code {
     pub fn iter<'code, I: IndexingRange<usize>>(&'code self, range: I) -> CodeIterator<'code> {
         let range = range.get_or(0..self.inner.len());
 
-        let cursor = Cursor::new(&self.inner[range.clone()]);
+        CodeIterator { end: range.end, data: &self.inner[range] }
+    }
 
-        CodeIterator { start: range.start, data: cursor }
+    /// Parses all instructions along with their addresses. Unlike [`Code::iter`], this doesn't panic on invalid bytecode.
+    pub fn decode(&self) -> Result<Vec<(usize, Instruction)>, ClassParseError> {
+        let mut data = &self.inner[..];
+        let mut instructions = Vec::new();
+        while !data.is_empty() {
+            let address = self.inner.len() - data.len();
+            instructions.push((address, Instruction::parse_at(&mut data, address)?));
+        }
+        return Ok(instructions);
     }
 
     pub fn byte_len(&self) -> usize {
@@ -372,8 +736,9 @@ impl Code {
 }
 
 pub struct CodeIterator<'code> {
-    start: usize,
-    data: Cursor<&'code [u8]>,
+    /// The address right after the last byte of `data`
+    end: usize,
+    data: &'code [u8],
 }
 
 impl<'code> Iterator for CodeIterator<'code> {
@@ -383,7 +748,8 @@ impl<'code> Iterator for CodeIterator<'code> {
         if self.data.is_empty() {
             None
         } else {
-            Some((self.data.position() as usize + self.start, ByteParseable::parse(&mut self.data).unwrap()))
+            let address = self.end - self.data.len();
+            Some((address, Instruction::parse_at(&mut self.data, address).unwrap()))
         }
     }
 }
\ No newline at end of file
@@ -1,8 +1,9 @@
-use crate::constant_pool::{ConstantPoolEntry, ParseableWithCP, ConstantPool, parse_multiple_with_cp};
-use crate::byte_util::{ByteParseable, BigEndianReadExt, parse_multiple};
-use std::io::Read;
+use crate::constant_pool::{ConstantPoolEntry, ParseableWithCP, ConstantPool, parse_multiple_with_cp, parse_constant_pool};
+use crate::byte_util::{ByteParseable, BigEndianReadExt, ByteRead, parse_multiple};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use crate::ClassParseError;
-use crate::attributes::{AttributeEntry, parse_attribute_array};
+use crate::attributes::{AttributeEntry, ConstantValue, parse_attribute_array};
 
 bitflags! {
     pub struct ClassAccessFlags: u16 {
@@ -85,7 +86,7 @@ pub struct MethodInfo {
 }
 
 impl ByteParseable for ClassFile {
-    fn parse(bytes: &mut impl Read) -> Result<Self, ClassParseError> where Self: Sized {
+    fn parse(bytes: &mut impl ByteRead) -> Result<Self, ClassParseError> where Self: Sized {
         let magic = bytes.read_u32()?;
         if magic != 0xCAFEBABE {
             return Err(ClassParseError::WrongMagic(magic));
@@ -95,7 +96,7 @@ impl ByteParseable for ClassFile {
         let major_version = ByteParseable::parse(bytes)?;
 
         let constant_pool_size = bytes.read_u16()?;
-        let constant_pool = parse_multiple(bytes, (constant_pool_size - 1) as usize)?;
+        let constant_pool = parse_constant_pool(bytes, constant_pool_size)?;
 
         let access_flags = ClassAccessFlags::from_bits_truncate(bytes.read_u16()?);
 
@@ -130,7 +131,7 @@ impl ByteParseable for ClassFile {
 }
 
 impl ParseableWithCP for FieldInfo {
-    fn parse(bytes: &mut impl Read, pool: &impl ConstantPool) -> Result<Self, ClassParseError> {
+    fn parse(bytes: &mut impl ByteRead, pool: &impl ConstantPool) -> Result<Self, ClassParseError> {
         Ok(FieldInfo {
             access_flags: FieldAccessFlags::from_bits_truncate(bytes.read_u16()?),
             name_index: ByteParseable::parse(bytes)?,
@@ -140,8 +141,21 @@ impl ParseableWithCP for FieldInfo {
     }
 }
 
+impl FieldInfo {
+    /// Resolves the value of this field's `ConstantValue` attribute, if it has one.
+    /// These are the values static fields are initialized to before `<clinit>` runs.
+    pub fn constant_value(&self, pool: &impl ConstantPool) -> Result<Option<ConstantValue>, ClassParseError> {
+        for attribute in &self.attributes {
+            if let AttributeEntry::ConstantValue(value) = attribute {
+                return value.resolve(pool).map(Some);
+            }
+        }
+        return Ok(None);
+    }
+}
+
 impl ParseableWithCP for MethodInfo {
-    fn parse(bytes: &mut impl Read, pool: &impl ConstantPool) -> Result<Self, ClassParseError> {
+    fn parse(bytes: &mut impl ByteRead, pool: &impl ConstantPool) -> Result<Self, ClassParseError> {
         Ok(MethodInfo {
             access_flags: MethodAccessFlags::from_bits_truncate(bytes.read_u16()?),
             name_index: ByteParseable::parse(bytes)?,
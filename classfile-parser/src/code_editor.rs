@@ -0,0 +1,538 @@
+use crate::attributes::{AttributeEntry, CodeAttribute, ExceptionTableEntry, LineNumberEntry};
+use crate::bytecode::{Code, Instruction, UnencodableInstruction};
+use crate::ClassParseError;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::mem;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum EditError {
+    #[error("whilst parsing the original code")]
+    ParseError(#[from] ClassParseError),
+    #[error("byte {0} is not the start of an instruction")]
+    InvalidTarget(usize),
+    #[error("instruction {0} jumps past the end of the code")]
+    JumpPastEnd(usize),
+    #[error("instruction {0} jumps further than its offset can hold")]
+    JumpOutOfRange(usize),
+    #[error("an exception handler starts past the end of the code")]
+    HandlerPastEnd,
+    #[error("code is {0} bytes long, while the maximum is 65535")]
+    CodeTooLarge(usize),
+    #[error(transparent)]
+    Unencodable(#[from] UnencodableInstruction),
+    /// [`Instruction`] has no `wide` forms yet, so methods that use them can't be decoded
+    #[error("wide instructions aren't supported")]
+    Wide,
+}
+
+/// Refers to an instruction inside of a [`CodeEditor`].
+/// Labels keep referring to the same instruction when other instructions are inserted or removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Label(usize);
+
+/// The opcode of `wide`
+const WIDE: u8 = 0xc4;
+
+impl Label {
+    /// The location directly after the last instruction.
+    /// Exception handlers which cover the last instruction end here.
+    const END: Label = Label(usize::MAX);
+}
+
+struct Entry {
+    label: Label,
+    instruction: Instruction,
+    /// Where the instruction jumps to, in the order of [`Instruction::branch_offsets`]
+    targets: Vec<Label>,
+}
+
+struct Handler {
+    start: Label,
+    end: Label,
+    handler: Label,
+    catch_type: u16,
+}
+
+struct LineNumber {
+    /// Index of the `LineNumberTable` attribute this entry came from
+    attribute: usize,
+    start: Label,
+    line_number: u16,
+}
+
+/// A mutable view over the instructions of a [`CodeAttribute`].
+///
+/// Jumps, switch tables, exception handlers and line numbers refer to instructions via [`Label`]s,
+/// so they stay correct as instructions are inserted and removed. The new bytecode is only written
+/// back once [`CodeEditor::commit`] is called, at which point all offsets are recalculated and
+/// `goto`s which no longer fit are widened into `goto_w`s.
+///
+/// The editor doesn't touch `max_stack` and `max_locals`, and can't edit methods that use `wide`
+/// instructions, see [`EditError::Wide`].
+pub struct CodeEditor<'attr> {
+    attribute: &'attr mut CodeAttribute,
+    entries: Vec<Entry>,
+    handlers: Vec<Handler>,
+    line_numbers: Vec<LineNumber>,
+    next_label: usize,
+}
+
+impl CodeAttribute {
+    pub fn edit(&mut self) -> Result<CodeEditor<'_>, EditError> {
+        CodeEditor::new(self)
+    }
+}
+
+impl<'attr> CodeEditor<'attr> {
+    pub fn new(attribute: &'attr mut CodeAttribute) -> Result<Self, EditError> {
+        let bytes = &attribute.code.inner;
+        let decoded = attribute.code.decode().map_err(|e| match e {
+            ClassParseError::InvalidBytecode(WIDE) => EditError::Wide,
+            e => e.into(),
+        })?;
+
+        let labels: BTreeMap<usize, Label> = decoded.iter().enumerate()
+            .map(|(i, (address, _))| (*address, Label(i)))
+            .collect();
+        let find = |address: usize| labels.get(&address).copied().ok_or(EditError::InvalidTarget(address));
+        let find_or_end = |address: usize| if address == bytes.len() { Ok(Label::END) } else { find(address) };
+
+        let mut entries = Vec::with_capacity(decoded.len());
+        for (i, (address, instruction)) in decoded.into_iter().enumerate() {
+            let targets = instruction.branch_offsets().into_iter()
+                .map(|offset| {
+                    let target = usize::try_from(address as i64 + offset as i64).map_err(|_| EditError::InvalidTarget(address))?;
+                    find(target)
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            entries.push(Entry { label: Label(i), instruction, targets });
+        }
+
+        let handlers = attribute.exception_table.iter()
+            .map(|entry| Ok(Handler {
+                start: find(entry.start_pc as usize)?,
+                end: find_or_end(entry.end_pc as usize)?,
+                handler: find(entry.handler_pc as usize)?,
+                catch_type: entry.catch_type,
+            }))
+            .collect::<Result<Vec<_>, EditError>>()?;
+
+        let mut line_numbers = Vec::new();
+        for (i, attribute) in attribute.attributes.iter().enumerate() {
+            if let AttributeEntry::LineNumberTable(table) = attribute {
+                for entry in &table.entries {
+                    line_numbers.push(LineNumber {
+                        attribute: i,
+                        start: find(entry.start_pc as usize)?,
+                        line_number: entry.line_number,
+                    });
+                }
+            }
+        }
+
+        let next_label = entries.len();
+        Ok(CodeEditor { attribute, entries, handlers, line_numbers, next_label })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn instruction(&self, index: usize) -> &Instruction {
+        &self.entries[index].instruction
+    }
+
+    pub fn instructions(&self) -> impl Iterator<Item = &Instruction> {
+        self.entries.iter().map(|entry| &entry.instruction)
+    }
+
+    pub fn label(&self, index: usize) -> Label {
+        self.entries[index].label
+    }
+
+    /// Returns the current index of the instruction the label refers to
+    pub fn index_of(&self, label: Label) -> Option<usize> {
+        self.entries.iter().position(|entry| entry.label == label)
+    }
+
+    /// Where the instruction at `index` jumps to, in the order of [`Instruction::branch_offsets`]
+    pub fn targets(&self, index: usize) -> &[Label] {
+        &self.entries[index].targets
+    }
+
+    /// Inserts an instruction before the one at `index`.
+    /// Jumps, exception handlers and line numbers keep referring to the instruction that was at `index`,
+    /// so code which jumps there skips the new instruction.
+    ///
+    /// # Panics
+    /// When the instruction jumps, use [`CodeEditor::insert_branch`] for those.
+    pub fn insert(&mut self, index: usize, instruction: Instruction) -> Label {
+        self.insert_entry(index, instruction, &[], false)
+    }
+
+    /// Inserts an instruction which takes the place of the one at `index`.
+    /// Jumps, exception handlers and line numbers which referred to the old instruction will refer to the new one,
+    /// so the new instruction runs whenever the old one would've been reached. This is what probes and hooks usually want.
+    ///
+    /// # Panics
+    /// When the instruction jumps, use [`CodeEditor::insert_branch`] for those.
+    pub fn insert_before(&mut self, index: usize, instruction: Instruction) -> Label {
+        self.insert_entry(index, instruction, &[], true)
+    }
+
+    /// Inserts an instruction which jumps to `targets`, see [`CodeEditor::insert`].
+    /// The offsets inside of the instruction are ignored.
+    pub fn insert_branch(&mut self, index: usize, instruction: Instruction, targets: &[Label]) -> Label {
+        self.insert_entry(index, instruction, targets, false)
+    }
+
+    fn insert_entry(&mut self, index: usize, instruction: Instruction, targets: &[Label], take_place: bool) -> Label {
+        check_targets(&instruction, targets);
+        let label = Label(self.next_label);
+        self.next_label += 1;
+
+        if take_place {
+            if let Some(old) = self.entries.get(index) {
+                let old = old.label;
+                self.redirect(old, label);
+            }
+        }
+        self.entries.insert(index, Entry { label, instruction, targets: targets.to_vec() });
+        label
+    }
+
+    /// Removes the instruction at `index`.
+    /// Anything which referred to it will refer to the instruction after it instead.
+    pub fn remove(&mut self, index: usize) -> Instruction {
+        let removed = self.entries.remove(index);
+        let next = self.entries.get(index).map_or(Label::END, |entry| entry.label);
+        self.redirect(removed.label, next);
+
+        self.handlers.retain(|handler| handler.start != handler.end);
+        self.line_numbers.retain(|line| line.start != Label::END);
+        removed.instruction
+    }
+
+    /// Replaces the instruction at `index`, anything which referred to the old instruction will refer to the new one.
+    ///
+    /// # Panics
+    /// When the instruction jumps, use [`CodeEditor::replace_branch`] for those.
+    pub fn replace(&mut self, index: usize, instruction: Instruction) -> Instruction {
+        self.replace_branch(index, instruction, &[])
+    }
+
+    /// Replaces the instruction at `index` with one that jumps to `targets`, see [`CodeEditor::replace`].
+    /// The offsets inside of the instruction are ignored.
+    pub fn replace_branch(&mut self, index: usize, instruction: Instruction, targets: &[Label]) -> Instruction {
+        check_targets(&instruction, targets);
+        let entry = &mut self.entries[index];
+        entry.targets = targets.to_vec();
+        mem::replace(&mut entry.instruction, instruction)
+    }
+
+    fn redirect(&mut self, from: Label, to: Label) {
+        let mut redirect = |label: &mut Label| {
+            if *label == from {
+                *label = to;
+            }
+        };
+
+        for entry in &mut self.entries {
+            entry.targets.iter_mut().for_each(&mut redirect);
+        }
+        for handler in &mut self.handlers {
+            redirect(&mut handler.start);
+            redirect(&mut handler.end);
+            redirect(&mut handler.handler);
+        }
+        for line in &mut self.line_numbers {
+            redirect(&mut line.start);
+        }
+    }
+
+    /// Writes the edited code back into the attribute
+    pub fn commit(self) -> Result<(), EditError> {
+        // Bail out early on instructions which can't be written, so the layout can rely on their sizes
+        for entry in &self.entries {
+            entry.instruction.write(0, &mut Vec::new())?;
+        }
+
+        let indices: BTreeMap<Label, usize> = self.entries.iter().enumerate()
+            .map(|(i, entry)| (entry.label, i))
+            .collect();
+
+        // Widening a goto moves everything after it, which might push other jumps out of range.
+        // Keep going until nothing new needs to be widened.
+        let mut wide = vec![false; self.entries.len()];
+        let (addresses, end) = loop {
+            let (addresses, end) = self.layout(&wide);
+            let mut changed = false;
+            for (i, entry) in self.entries.iter().enumerate() {
+                if entry.targets.is_empty() || wide[i] {
+                    continue;
+                }
+                let offsets = self.offsets(i, &indices, &addresses, end)?;
+                if entry.instruction.with_branch_offsets(&offsets).is_none() {
+                    match entry.instruction {
+                        Instruction::Goto(_) | Instruction::JSr(_) => {
+                            wide[i] = true;
+                            changed = true;
+                        }
+                        _ => return Err(EditError::JumpOutOfRange(i)),
+                    }
+                }
+            }
+            if !changed {
+                break (addresses, end);
+            }
+        };
+
+        if end > u16::MAX as usize {
+            return Err(EditError::CodeTooLarge(end));
+        }
+
+        let mut code = Vec::with_capacity(end);
+        for (i, entry) in self.entries.iter().enumerate() {
+            let instruction = if entry.targets.is_empty() {
+                entry.instruction.clone()
+            } else {
+                let offsets = self.offsets(i, &indices, &addresses, end)?;
+                match (&entry.instruction, wide[i]) {
+                    (Instruction::Goto(_), true) => Instruction::Goto_w(offsets[0]),
+                    (Instruction::JSr(_), true) => Instruction::JSr_w(offsets[0]),
+                    (instruction, _) => instruction.with_branch_offsets(&offsets).unwrap(),
+                }
+            };
+            instruction.write(addresses[i], &mut code)?;
+        }
+        debug_assert_eq!(code.len(), end);
+
+        let address_of = |label: Label| {
+            if label == Label::END { end } else { addresses[indices[&label]] }
+        };
+
+        let mut exception_table = Vec::with_capacity(self.handlers.len());
+        for handler in &self.handlers {
+            if handler.handler == Label::END {
+                return Err(EditError::HandlerPastEnd);
+            }
+            exception_table.push(ExceptionTableEntry {
+                start_pc: address_of(handler.start) as u16,
+                end_pc: address_of(handler.end) as u16,
+                handler_pc: address_of(handler.handler) as u16,
+                catch_type: handler.catch_type,
+            });
+        }
+
+        for attribute in &mut self.attribute.attributes {
+            if let AttributeEntry::LineNumberTable(table) = attribute {
+                table.entries.clear();
+            }
+        }
+        for line in &self.line_numbers {
+            if let AttributeEntry::LineNumberTable(table) = &mut self.attribute.attributes[line.attribute] {
+                table.entries.push(LineNumberEntry {
+                    start_pc: address_of(line.start) as u16,
+                    line_number: line.line_number,
+                });
+            }
+        }
+
+        self.attribute.code = Code::from_vec(code);
+        self.attribute.exception_table = exception_table;
+        Ok(())
+    }
+
+    /// Calculates the address of every instruction, as well as the total length
+    fn layout(&self, wide: &[bool]) -> (Vec<usize>, usize) {
+        let mut addresses = Vec::with_capacity(self.entries.len());
+        let mut address = 0;
+        for (entry, wide) in self.entries.iter().zip(wide) {
+            addresses.push(address);
+            address += if *wide { 5 } else { entry.instruction.byte_size_at(address) };
+        }
+        (addresses, address)
+    }
+
+    fn offsets(&self, index: usize, indices: &BTreeMap<Label, usize>, addresses: &[usize], end: usize) -> Result<Vec<i32>, EditError> {
+        self.entries[index].targets.iter()
+            .map(|target| {
+                let target = indices.get(target).ok_or(EditError::JumpPastEnd(index))?;
+                debug_assert!(end < i32::MAX as usize);
+                Ok(addresses[*target] as i32 - addresses[index] as i32)
+            })
+            .collect()
+    }
+}
+
+fn check_targets(instruction: &Instruction, targets: &[Label]) {
+    assert_eq!(instruction.branch_offsets().len(), targets.len(), "{:?} needs a target for each of its jumps", instruction);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::attributes::{AttributeEntry, CodeAttribute, ExceptionTableEntry, LineNumberEntry, LineNumberTableAttribute};
+    use crate::bytecode::{Code, Instruction, TableSwitch};
+    use crate::code_editor::EditError;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use assert_matches::assert_matches;
+
+    fn code_attribute(code: Vec<u8>) -> CodeAttribute {
+        CodeAttribute {
+            max_stack: 1,
+            max_locals: 1,
+            code: Code::from_vec(code),
+            exception_table: vec![],
+            attributes: vec![],
+        }
+    }
+
+    /// `return x == 0 ? 0 : 1`
+    fn if_else() -> CodeAttribute {
+        code_attribute(vec![
+            0x1a, // 0: iload_0
+            0x99, 0x00, 0x05, // 1: ifeq 6
+            0x04, // 4: iconst_1
+            0xac, // 5: ireturn
+            0x03, // 6: iconst_0
+            0xac, // 7: ireturn
+        ])
+    }
+
+    #[test]
+    fn wide_unsupported() {
+        let mut attribute = code_attribute(vec![
+            0xc4, 0x15, 0x01, 0x00, // 0: wide iload 256
+            0xac, // 4: ireturn
+        ]);
+        assert_matches!(attribute.edit().err(), Some(EditError::Wide));
+    }
+
+    #[test]
+    fn insert_moves_jumps() {
+        let mut attribute = if_else();
+        let mut editor = attribute.edit().unwrap();
+        editor.insert(2, Instruction::Nop);
+        editor.commit().unwrap();
+
+        assert_eq!(&*attribute.code.inner, &[0x1a, 0x99, 0x00, 0x06, 0x00, 0x04, 0xac, 0x03, 0xac]);
+    }
+
+    #[test]
+    fn insert_before_jump_target() {
+        let mut attribute = if_else();
+        let mut editor = attribute.edit().unwrap();
+        editor.insert_before(4, Instruction::Nop);
+        editor.commit().unwrap();
+
+        // The jump lands on the nop
+        assert_eq!(&*attribute.code.inner, &[0x1a, 0x99, 0x00, 0x05, 0x04, 0xac, 0x00, 0x03, 0xac]);
+    }
+
+    #[test]
+    fn remove_jump_target() {
+        let mut attribute = if_else();
+        let mut editor = attribute.edit().unwrap();
+        assert_eq!(editor.remove(4), Instruction::IConst(0));
+        editor.replace(2, Instruction::IConst(5));
+        editor.commit().unwrap();
+
+        assert_eq!(&*attribute.code.inner, &[0x1a, 0x99, 0x00, 0x05, 0x08, 0xac, 0xac]);
+    }
+
+    #[test]
+    fn widen_goto() {
+        let mut attribute = code_attribute(vec![
+            0xa7, 0x00, 0x03, // 0: goto 3
+            0xb1, // 3: return
+        ]);
+        let mut editor = attribute.edit().unwrap();
+        for _ in 0..40000 {
+            editor.insert(editor.len() - 1, Instruction::Nop);
+        }
+        editor.commit().unwrap();
+
+        let instructions: Vec<_> = attribute.code.iter(..).map(|(_, instruction)| instruction).collect();
+        assert_eq!(instructions[0], Instruction::Goto_w(40005));
+        assert_eq!(instructions.len(), 40002);
+    }
+
+    #[test]
+    fn conditional_out_of_range() {
+        let mut attribute = if_else();
+        let mut editor = attribute.edit().unwrap();
+        for _ in 0..40000 {
+            editor.insert(4, Instruction::Nop);
+        }
+
+        assert_matches!(editor.commit(), Err(EditError::JumpOutOfRange(1)));
+    }
+
+    #[test]
+    fn insert_branch() {
+        let mut attribute = if_else();
+        let mut editor = attribute.edit().unwrap();
+        let target = editor.label(4);
+        editor.insert_branch(2, Instruction::Goto(0), &[target]);
+        editor.commit().unwrap();
+
+        assert_eq!(&*attribute.code.inner, &[0x1a, 0x99, 0x00, 0x08, 0xa7, 0x00, 0x05, 0x04, 0xac, 0x03, 0xac]);
+    }
+
+    #[test]
+    fn switch_padding() {
+        let mut attribute = code_attribute(vec![
+            0x1a, // 0: iload_0
+            0xaa, 0x00, 0x00, // 1: tableswitch
+            0x00, 0x00, 0x00, 0x13, // default: 20
+            0x00, 0x00, 0x00, 0x00, // low: 0
+            0x00, 0x00, 0x00, 0x00, // high: 0
+            0x00, 0x00, 0x00, 0x14, // 0: 21
+            0x03, // 20: iconst_0
+            0xac, // 21: ireturn
+        ]);
+        let mut editor = attribute.edit().unwrap();
+        editor.insert(0, Instruction::Nop);
+        editor.commit().unwrap();
+
+        let instructions: Vec<_> = attribute.code.iter(..).collect();
+        assert_eq!(instructions[2], (2, Instruction::TableSwitch(TableSwitch { default: 18, low: 0, high: 0, offsets: vec![19] })));
+        assert_eq!(instructions[3], (20, Instruction::IConst(0)));
+    }
+
+    #[test]
+    fn handlers_and_line_numbers() {
+        let mut attribute = if_else();
+        attribute.exception_table.push(ExceptionTableEntry { start_pc: 0, end_pc: 8, handler_pc: 6, catch_type: 0 });
+        attribute.exception_table.push(ExceptionTableEntry { start_pc: 4, end_pc: 5, handler_pc: 6, catch_type: 0 });
+        attribute.attributes.push(AttributeEntry::LineNumberTable(LineNumberTableAttribute {
+            entries: vec![
+                LineNumberEntry { start_pc: 0, line_number: 1 },
+                LineNumberEntry { start_pc: 4, line_number: 2 },
+            ]
+        }));
+
+        let mut editor = attribute.edit().unwrap();
+        editor.insert_before(0, Instruction::Nop);
+        editor.remove(3); // iconst_1
+        editor.commit().unwrap();
+
+        // The second handler covered nothing but the removed instruction
+        assert_eq!(attribute.exception_table, vec![ExceptionTableEntry { start_pc: 0, end_pc: 8, handler_pc: 6, catch_type: 0 }]);
+        assert_matches!(&attribute.attributes[0], AttributeEntry::LineNumberTable(table) => {
+            assert_eq!(table.entries, vec![
+                LineNumberEntry { start_pc: 0, line_number: 1 },
+                LineNumberEntry { start_pc: 5, line_number: 2 },
+            ]);
+        });
+    }
+}
@@ -1,22 +1,24 @@
-use crate::byte_util::{read_to_vec, BigEndianReadExt, ByteParseable};
+use crate::byte_util::{read_to_vec, BigEndianReadExt, ByteParseable, ByteRead};
 use crate::{gen_parseable, ClassParseError};
-use std::io::{Read, Cursor};
+use alloc::string::String;
+use alloc::vec::Vec;
 
 pub trait ParseableWithCP {
     fn parse_bytes(bytes: &[u8], pool: &impl ConstantPool) -> Result<Self, ClassParseError> where Self: Sized {
-        return Self::parse(&mut Cursor::new(bytes), pool);
+        let mut bytes = bytes;
+        return Self::parse(&mut bytes, pool);
     }
 
-    fn parse(bytes: &mut impl Read, pool: &impl ConstantPool) -> Result<Self, ClassParseError> where Self: Sized;
+    fn parse(bytes: &mut impl ByteRead, pool: &impl ConstantPool) -> Result<Self, ClassParseError> where Self: Sized;
 }
 
 impl<T: ByteParseable> ParseableWithCP for T {
-    fn parse(bytes: &mut impl Read, _: &impl ConstantPool) -> Result<Self, ClassParseError> {
+    fn parse(bytes: &mut impl ByteRead, _: &impl ConstantPool) -> Result<Self, ClassParseError> {
         Self::parse(bytes)
     }
 }
 
-pub fn parse_multiple_with_cp<T: ParseableWithCP>(bytes: &mut impl Read, pool: &impl ConstantPool, amount: usize) -> Result<Vec<T>, ClassParseError> {
+pub fn parse_multiple_with_cp<T: ParseableWithCP>(bytes: &mut impl ByteRead, pool: &impl ConstantPool, amount: usize) -> Result<Vec<T>, ClassParseError> {
     let mut result = Vec::with_capacity(amount);
     for _ in 0..amount {
         result.push(T::parse(bytes, pool)?);
@@ -24,6 +26,22 @@ pub fn parse_multiple_with_cp<T: ParseableWithCP>(bytes: &mut impl Read, pool: &
     return Ok(result);
 }
 
+/// Parses a constant pool containing `count - 1` slots.
+/// Long and double entries are followed by an [`ConstantPoolEntry::Unusable`] entry, so indices stay correct.
+pub fn parse_constant_pool(bytes: &mut impl ByteRead, count: u16) -> Result<Vec<ConstantPoolEntry>, ClassParseError> {
+    let slots = count.saturating_sub(1) as usize;
+    let mut result = Vec::with_capacity(slots);
+    while result.len() < slots {
+        let entry: ConstantPoolEntry = ByteParseable::parse(bytes)?;
+        let two_slots = matches!(entry, ConstantPoolEntry::LongInfo(_) | ConstantPoolEntry::DoubleInfo(_));
+        result.push(entry);
+        if two_slots {
+            result.push(ConstantPoolEntry::Unusable);
+        }
+    }
+    return Ok(result);
+}
+
 macro_rules! gen_constant_pool {
     (
         $(#[$Meta:meta])*
@@ -38,10 +56,12 @@ macro_rules! gen_constant_pool {
             $(
                 $Type($DataContainer),
             )+
+            /// Occupies the index after a long or double, which take up two entries.
+            Unusable,
         }
 
         impl ByteParseable for ConstantPoolEntry {
-            fn parse(bytes: &mut impl Read) -> Result<Self, ClassParseError> {
+            fn parse(bytes: &mut impl ByteRead) -> Result<Self, ClassParseError> {
                 let tag = bytes.read_u8()?;
                 match tag {
                     $(
@@ -175,18 +195,65 @@ impl Double {
     }
 }
 
+/// The text of a constant pool entry. Java strings are UTF-16 and can have unpaired surrogates, which a [String]
+/// can't hold, so those strings also keep their UTF-16 units.
 #[derive(Debug, PartialEq, Clone)]
-pub struct Utf8Info{pub inner: String,}
+pub struct Utf8Info {
+    /// The text, with unpaired surrogates replaced by U+FFFD
+    pub inner: String,
+    /// The exact UTF-16 units, only for text that `inner` can't represent
+    pub utf16: Option<Vec<u16>>,
+}
+
+impl Utf8Info {
+    pub fn new(inner: String) -> Self {
+        Utf8Info { inner, utf16: None }
+    }
+
+    pub fn from_utf16(units: &[u16]) -> Self {
+        match String::from_utf16(units) {
+            Ok(inner) => Utf8Info::new(inner),
+            Err(_) => Utf8Info { inner: String::from_utf16_lossy(units), utf16: Some(units.to_vec()) },
+        }
+    }
+
+    /// The UTF-16 units of the text, which is what java strings are made of
+    pub fn to_utf16(&self) -> Vec<u16> {
+        match &self.utf16 {
+            Some(units) => units.clone(),
+            None => self.inner.encode_utf16().collect(),
+        }
+    }
+}
 
 impl ByteParseable for Utf8Info {
-    fn parse(bytes: &mut impl Read) -> Result<Self, ClassParseError> where Self: Sized {
+    fn parse(bytes: &mut impl ByteRead) -> Result<Self, ClassParseError> where Self: Sized {
         let len = bytes.read_u16()?;
         let vec = read_to_vec(bytes, len as usize)?;
-        // TODO doesn't respect custom string format
-        Ok(Self {
-            inner: String::from_utf8(vec)?
-        })
+        match String::from_utf8(vec) {
+            Ok(inner) => Ok(Utf8Info::new(inner)),
+            Err(e) => Ok(Utf8Info::from_utf16(&decode_modified_utf8(e.as_bytes()).ok_or(e)?)),
+        }
+    }
+}
+
+/// Decodes the modified UTF-8 that class files use into UTF-16 units. It encodes null as two bytes, and characters
+/// outside of the basic multilingual plane as two encoded surrogates, which plain UTF-8 doesn't allow.
+/// See: https://docs.oracle.com/javase/specs/jvms/se16/html/jvms-4.html#jvms-4.4.7
+fn decode_modified_utf8(bytes: &[u8]) -> Option<Vec<u16>> {
+    let mut units = Vec::with_capacity(bytes.len());
+    let mut bytes = bytes.iter().copied();
+    let continuation = |bytes: &mut dyn Iterator<Item = u8>| bytes.next().filter(|byte| byte & 0xC0 == 0x80).map(|byte| (byte & 0x3F) as u16);
+    while let Some(byte) = bytes.next() {
+        let unit = match byte {
+            0x00..=0x7F => byte as u16,
+            0xC0..=0xDF => ((byte & 0x1F) as u16) << 6 | continuation(&mut bytes)?,
+            0xE0..=0xEF => ((byte & 0x0F) as u16) << 12 | continuation(&mut bytes)? << 6 | continuation(&mut bytes)?,
+            _ => return None,
+        };
+        units.push(unit);
     }
+    Some(units)
 }
 
 // Implemented on empty enums in the types crate
@@ -225,7 +292,7 @@ pub trait ConstantPool {
     /// ```
     #[inline]
     fn get_entry(&self, index: u16) -> Option<&ConstantPoolEntry> {
-        return self.get_entry_0(index-1);
+        return self.get_entry_0(index.checked_sub(1)?);
     }
 
     /// Returns the value at [`index`] and unwraps it into the specified type.
@@ -273,7 +340,7 @@ pub trait ConstantPool {
     /// ```
     #[inline]
     fn get_as<T: ConstantPoolType>(&self, index: u16) -> Option<&T::Inner> {
-        return self.get_as_0::<T>(index-1);
+        return self.get_as_0::<T>(index.checked_sub(1)?);
     }
 
     /// Gets the total size of this pool
@@ -300,4 +367,40 @@ impl ConstantPool for Vec<ConstantPoolEntry> {
     fn size(&self) -> u16 {
         return self.len() as u16;
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::byte_util::ByteParseable;
+    use crate::constant_pool::{parse_constant_pool, ConstantPool, ConstantPoolEntry, Integer, Long, Utf8Info};
+
+    #[test]
+    fn long_takes_two_slots() {
+        let bytes: &[u8] = &[
+            5, 0, 0, 0, 0, 0, 0, 0, 7, // long
+            3, 0, 0, 0, 9, // integer
+        ];
+        let pool = parse_constant_pool(&mut &bytes[..], 4).unwrap();
+
+        assert_eq!(pool.size(), 3);
+        assert_eq!(pool.get_entry(1), Some(&ConstantPoolEntry::LongInfo(Long::new(7))));
+        assert_eq!(pool.get_entry(2), Some(&ConstantPoolEntry::Unusable));
+        assert_eq!(pool.get_entry(3), Some(&ConstantPoolEntry::IntegerInfo(Integer::new(9))));
+    }
+
+    #[test]
+    fn modified_utf8() {
+        let parse = |bytes: &[u8]| Utf8Info::parse(&mut &bytes[..]).map(|info| info.inner);
+        assert_eq!(parse(&[0, 3, b'a', 0xC3, 0xA9]).unwrap(), "aé");
+        assert_eq!(parse(&[0, 3, b'a', 0xC0, 0x80]).unwrap(), "a\0");
+        // U+1F600 as a surrogate pair
+        assert_eq!(parse(&[0, 6, 0xED, 0xA0, 0xBD, 0xED, 0xB8, 0x80]).unwrap(), "\u{1F600}");
+        // An unpaired surrogate is kept in the UTF-16 units
+        let unpaired = Utf8Info::parse(&mut &[0, 4, 0xED, 0xA0, 0xBD, b'a'][..]).unwrap();
+        assert_eq!(unpaired.inner, "\u{FFFD}a");
+        assert_eq!(unpaired.to_utf16(), [0xD83D, b'a' as u16]);
+        assert_eq!(Utf8Info::parse(&mut &[0, 3, b'a', 0xC0, 0x80][..]).unwrap().utf16, None);
+        assert!(parse(&[0, 4, 0xF0, 0x9F, 0x98, 0x80]).is_ok());
+        assert!(parse(&[0, 2, 0xC3, b'a']).is_err());
+    }
+}
@@ -0,0 +1,275 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+use thiserror::Error;
+
+/// Arrays can't have more dimensions than this
+pub const MAX_ARRAY_DIMENSIONS: usize = 255;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum DescriptorError {
+    #[error("descriptor ended unexpectedly")]
+    UnexpectedEnd,
+    #[error("unexpected character {0:?} at {1}")]
+    UnexpectedCharacter(char, usize),
+    #[error("descriptor continues after {0}")]
+    TrailingCharacters(usize),
+    #[error("array has more than 255 dimensions")]
+    TooManyDimensions,
+}
+
+/// The type of a field, parameter or local variable.
+/// See: https://docs.oracle.com/javase/specs/jvms/se16/html/jvms-4.html#jvms-4.3.2
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum FieldDescriptor {
+    Byte,
+    Char,
+    Double,
+    Float,
+    Int,
+    Long,
+    Short,
+    Boolean,
+    /// Contains the binary name of the class, like `java/lang/Object`
+    Object(String),
+    Array(Box<FieldDescriptor>),
+}
+
+/// The parameters and return type of a method. A `return_type` of `None` means the method returns void.
+/// See: https://docs.oracle.com/javase/specs/jvms/se16/html/jvms-4.html#jvms-4.3.3
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MethodDescriptor {
+    pub parameters: Vec<FieldDescriptor>,
+    pub return_type: Option<FieldDescriptor>,
+}
+
+impl FieldDescriptor {
+    /// Amount of local variable or operand stack slots a value of this type takes up
+    pub fn slots(&self) -> u16 {
+        match self {
+            FieldDescriptor::Long | FieldDescriptor::Double => 2,
+            _ => 1,
+        }
+    }
+
+    pub fn is_reference(&self) -> bool {
+        matches!(self, FieldDescriptor::Object(_) | FieldDescriptor::Array(_))
+    }
+
+    /// Wraps this type into an array with `dimensions` extra dimensions
+    pub fn array_of(self, dimensions: usize) -> Self {
+        let mut result = self;
+        for _ in 0..dimensions {
+            result = FieldDescriptor::Array(Box::new(result));
+        }
+        result
+    }
+
+    /// The amount of dimensions if this is an array type, zero otherwise
+    pub fn array_dimensions(&self) -> usize {
+        let mut dimensions = 0;
+        let mut current = self;
+        while let FieldDescriptor::Array(component) = current {
+            dimensions += 1;
+            current = component;
+        }
+        dimensions
+    }
+
+    /// The innermost element type of an array, or the type itself if it isn't an array.
+    /// For `[[I` this returns `I`.
+    pub fn element_type(&self) -> &FieldDescriptor {
+        let mut current = self;
+        while let FieldDescriptor::Array(component) = current {
+            current = component;
+        }
+        current
+    }
+
+    fn parse_from(descriptor: &str, position: &mut usize) -> Result<Self, DescriptorError> {
+        let mut dimensions = 0;
+        while descriptor[*position..].starts_with('[') {
+            dimensions += 1;
+            *position += 1;
+        }
+        if dimensions > MAX_ARRAY_DIMENSIONS {
+            return Err(DescriptorError::TooManyDimensions);
+        }
+
+        let start = *position;
+        let c = descriptor[start..].chars().next().ok_or(DescriptorError::UnexpectedEnd)?;
+        *position += c.len_utf8();
+        let base = match c {
+            'B' => FieldDescriptor::Byte,
+            'C' => FieldDescriptor::Char,
+            'D' => FieldDescriptor::Double,
+            'F' => FieldDescriptor::Float,
+            'I' => FieldDescriptor::Int,
+            'J' => FieldDescriptor::Long,
+            'S' => FieldDescriptor::Short,
+            'Z' => FieldDescriptor::Boolean,
+            'L' => {
+                let name_length = descriptor[*position..].find(';').ok_or(DescriptorError::UnexpectedEnd)?;
+                let name = &descriptor[*position..*position + name_length];
+                if let Some((i, c)) = name.char_indices().find(|(_, c)| matches!(c, '.' | '[' | '(' | ')')) {
+                    return Err(DescriptorError::UnexpectedCharacter(c, *position + i));
+                }
+                if name.is_empty() {
+                    return Err(DescriptorError::UnexpectedCharacter(';', *position));
+                }
+                *position += name_length + 1;
+                FieldDescriptor::Object(name.into())
+            }
+            other => return Err(DescriptorError::UnexpectedCharacter(other, start)),
+        };
+        Ok(base.array_of(dimensions))
+    }
+}
+
+impl MethodDescriptor {
+    /// Amount of local variable slots the parameters take up. This doesn't include `this`.
+    pub fn parameter_slots(&self) -> u16 {
+        self.parameters.iter().map(FieldDescriptor::slots).sum()
+    }
+
+    /// Amount of operand stack slots the return value takes up
+    pub fn return_slots(&self) -> u16 {
+        self.return_type.as_ref().map_or(0, FieldDescriptor::slots)
+    }
+}
+
+impl FromStr for FieldDescriptor {
+    type Err = DescriptorError;
+
+    fn from_str(descriptor: &str) -> Result<Self, Self::Err> {
+        let mut position = 0;
+        let result = FieldDescriptor::parse_from(descriptor, &mut position)?;
+        if position != descriptor.len() {
+            return Err(DescriptorError::TrailingCharacters(position));
+        }
+        Ok(result)
+    }
+}
+
+impl FromStr for MethodDescriptor {
+    type Err = DescriptorError;
+
+    fn from_str(descriptor: &str) -> Result<Self, Self::Err> {
+        match descriptor.chars().next() {
+            Some('(') => {}
+            Some(other) => return Err(DescriptorError::UnexpectedCharacter(other, 0)),
+            None => return Err(DescriptorError::UnexpectedEnd),
+        }
+
+        let mut position = 1;
+        let mut parameters = Vec::new();
+        while !descriptor[position..].starts_with(')') {
+            parameters.push(FieldDescriptor::parse_from(descriptor, &mut position)?);
+        }
+        position += 1;
+
+        let return_type = if descriptor[position..].starts_with('V') {
+            position += 1;
+            None
+        } else {
+            Some(FieldDescriptor::parse_from(descriptor, &mut position)?)
+        };
+        if position != descriptor.len() {
+            return Err(DescriptorError::TrailingCharacters(position));
+        }
+
+        Ok(MethodDescriptor { parameters, return_type })
+    }
+}
+
+impl fmt::Display for FieldDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldDescriptor::Byte => f.write_str("B"),
+            FieldDescriptor::Char => f.write_str("C"),
+            FieldDescriptor::Double => f.write_str("D"),
+            FieldDescriptor::Float => f.write_str("F"),
+            FieldDescriptor::Int => f.write_str("I"),
+            FieldDescriptor::Long => f.write_str("J"),
+            FieldDescriptor::Short => f.write_str("S"),
+            FieldDescriptor::Boolean => f.write_str("Z"),
+            FieldDescriptor::Object(name) => write!(f, "L{};", name),
+            FieldDescriptor::Array(component) => write!(f, "[{}", component),
+        }
+    }
+}
+
+impl fmt::Display for MethodDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("(")?;
+        for parameter in &self.parameters {
+            write!(f, "{}", parameter)?;
+        }
+        f.write_str(")")?;
+        match &self.return_type {
+            Some(return_type) => write!(f, "{}", return_type),
+            None => f.write_str("V"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+    use alloc::vec;
+    use crate::descriptor::{DescriptorError, FieldDescriptor, MethodDescriptor};
+    use assert_matches::assert_matches;
+
+    #[test]
+    fn parse_method() {
+        let descriptor: MethodDescriptor = "([Ljava/lang/String;DSZ)V".parse().unwrap();
+        assert_eq!(descriptor, MethodDescriptor {
+            parameters: vec![
+                FieldDescriptor::Object("java/lang/String".into()).array_of(1),
+                FieldDescriptor::Double,
+                FieldDescriptor::Short,
+                FieldDescriptor::Boolean,
+            ],
+            return_type: None,
+        });
+        assert_eq!(descriptor.parameter_slots(), 5);
+        assert_eq!(descriptor.return_slots(), 0);
+    }
+
+    #[test]
+    fn roundtrip() {
+        for descriptor in ["()V", "(IJ)D", "([[I[Ljava/lang/Object;)[[[J", "(Lfoo/Bar;)Lfoo/Bar;"] {
+            assert_eq!(descriptor.parse::<MethodDescriptor>().unwrap().to_string(), descriptor);
+        }
+        for descriptor in ["Z", "[[[B", "Ljava/lang/String;"] {
+            assert_eq!(descriptor.parse::<FieldDescriptor>().unwrap().to_string(), descriptor);
+        }
+    }
+
+    #[test]
+    fn array_dimensions() {
+        let descriptor: FieldDescriptor = "[[Ljava/lang/String;".parse().unwrap();
+        assert_eq!(descriptor.array_dimensions(), 2);
+        assert_eq!(descriptor.element_type(), &FieldDescriptor::Object("java/lang/String".into()));
+        assert!(descriptor.is_reference());
+        assert_eq!(descriptor.slots(), 1);
+
+        assert_matches!(("[".repeat(256) + "I").parse::<FieldDescriptor>(), Err(DescriptorError::TooManyDimensions));
+        assert!(("[".repeat(255) + "I").parse::<FieldDescriptor>().is_ok());
+    }
+
+    #[test]
+    fn invalid() {
+        assert_matches!("V".parse::<FieldDescriptor>(), Err(DescriptorError::UnexpectedCharacter('V', 0)));
+        assert_matches!("(V)V".parse::<MethodDescriptor>(), Err(DescriptorError::UnexpectedCharacter('V', 1)));
+        assert_matches!("(I".parse::<MethodDescriptor>(), Err(DescriptorError::UnexpectedEnd));
+        assert_matches!("()".parse::<MethodDescriptor>(), Err(DescriptorError::UnexpectedEnd));
+        assert_matches!("II".parse::<FieldDescriptor>(), Err(DescriptorError::TrailingCharacters(1)));
+        assert_matches!("()VV".parse::<MethodDescriptor>(), Err(DescriptorError::TrailingCharacters(3)));
+        assert_matches!("Ljava/lang/String".parse::<FieldDescriptor>(), Err(DescriptorError::UnexpectedEnd));
+        assert_matches!("L;".parse::<FieldDescriptor>(), Err(DescriptorError::UnexpectedCharacter(';', 1)));
+        assert_matches!("Ljava.lang.String;".parse::<FieldDescriptor>(), Err(DescriptorError::UnexpectedCharacter('.', 5)));
+    }
+}
@@ -1,16 +1,24 @@
-#![feature(cursor_remaining)]
+#![cfg_attr(not(feature = "std"), no_std)]
 
-use std::string::FromUtf8Error;
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::string::{FromUtf8Error, String};
 use thiserror::Error;
 use crate::class_file::ClassFile;
-use std::io::Read;
 use crate::byte_util::ByteParseable;
 
+pub use crate::byte_util::ByteRead;
+
 mod byte_util;
 pub mod class_file;
 pub mod constant_pool;
 pub mod attributes;
 pub mod bytecode;
+pub mod descriptor;
+pub mod code_editor;
+pub mod analysis;
+pub mod builder;
 
 #[macro_use]
 extern crate bitflags;
@@ -35,13 +43,20 @@ pub enum ClassParseError {
 
     #[error("whilst parsing utf-8")]
     Utf8Error(#[from] FromUtf8Error),
+    #[error("unexpected end of input")]
+    UnexpectedEof,
+    #[cfg(feature = "std")]
     #[error("io error ({0})")]
     IoError(std::io::Error),
 }
 
+#[cfg(feature = "std")]
 impl From<std::io::Error> for ClassParseError {
     fn from(err: std::io::Error) -> Self {
-        ClassParseError::IoError(err)
+        match err.kind() {
+            std::io::ErrorKind::UnexpectedEof => ClassParseError::UnexpectedEof,
+            _ => ClassParseError::IoError(err)
+        }
     }
 }
 
@@ -51,6 +66,13 @@ impl ClassParseError {
     }
 }
 
-pub fn parse(bytes: &mut impl Read) -> Result<ClassFile, ClassParseError> {
+/// Parses a class file from a reader. Without the `std` feature only byte slices can be read from,
+/// see [`parse_bytes`].
+pub fn parse(bytes: &mut impl ByteRead) -> Result<ClassFile, ClassParseError> {
     ClassFile::parse(bytes)
+}
+
+/// Parses a class file that's fully in memory.
+pub fn parse_bytes(bytes: &[u8]) -> Result<ClassFile, ClassParseError> {
+    ClassFile::parse_bytes(bytes)
 }
\ No newline at end of file
@@ -11,7 +11,7 @@ use classfile_parser::attributes::AttributeEntry;
 use std::error::Error;
 use std::fmt::Display;
 use vm_core::VirtualMachine;
-use vm_core::class_loaders::SimpleClassLoader;
+use vm_core::class_loaders::{BootstrapClassLoader, ParentFirstClassLoader, SimpleClassLoader};
 
 fn main() {
     let cli = RaveCliFormat::parse();
@@ -68,7 +68,7 @@ fn main() {
             let res = classfile_parser::parse(&mut reader);
             match res {
                 Ok(class) => {
-                    let loader = SimpleClassLoader::new(class);
+                    let loader = ParentFirstClassLoader::new(BootstrapClassLoader::new(), SimpleClassLoader::new(class));
                     let mut vm = VirtualMachine::new(loader, LlvmJitCompiler::default());
                     // vm.start("nl.theepicblock.Addition").unwrap();
                 }
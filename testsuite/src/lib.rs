@@ -1,6 +1,6 @@
 use std::io::Cursor;
 
-use vm_core::{ClassLoader, class_loaders::SimpleClassLoader};
+use vm_core::{ClassLoader, class_loaders::{BootstrapClassLoader, ParentFirstClassLoader, SimpleClassLoader}};
 
 // In non-test mode, none of this code will be used because everything was made for test mode
 #[cfg_attr(not(test), allow(dead_code))]
@@ -13,7 +13,7 @@ mod basic;
 
 fn setup_classloader(bytes: &[u8]) -> impl ClassLoader {
     let classfile = classfile_parser::parse(&mut Cursor::new(bytes)).unwrap();
-    SimpleClassLoader::new(classfile)
+    ParentFirstClassLoader::new(BootstrapClassLoader::new(), SimpleClassLoader::new(classfile))
 }
 
 macro_rules! include_class {
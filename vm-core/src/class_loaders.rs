@@ -1,20 +1,430 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+
 use classfile_parser::class_file::ClassFile;
-use crate::ClassLoader;
+use classfile_parser::ClassParseError;
+use classfile_parser::descriptor::DescriptorError;
+use thiserror::Error;
+use zip::ZipArchive;
+use zip::result::ZipError;
+use crate::{runtime, ClassLoader, JitError, LoaderId};
+use crate::classfile_util::ConstantPoolExtensions;
+
+/// The most that's allocated up front for a class in an archive. The archive claims the size of the class,
+/// so anything above this is only allocated as the class turns out to actually be that large.
+const MAX_PREALLOCATION: usize = 64 * 1024;
 
+/// Only knows about a single class
 pub struct SimpleClassLoader {
+    id: LoaderId,
     base: ClassFile
 }
 
 impl ClassLoader for SimpleClassLoader {
-    fn load(&self, _: &str) -> ClassFile {
-        self.base.clone()
+    fn id(&self) -> LoaderId {
+        self.id
+    }
+
+    fn load_defined(&self, class: &str) -> Result<(LoaderId, ClassFile), LoadError> {
+        if class_name(&self.base) != Some(class) {
+            return Err(LoadError::NotFound(class.to_owned()));
+        }
+        Ok((self.id, self.base.clone()))
     }
 }
 
 impl SimpleClassLoader {
     pub fn new(base: ClassFile) -> Self {
         SimpleClassLoader {
+            id: LoaderId::new(),
             base
         }
     }
-}
\ No newline at end of file
+}
+
+#[derive(Error, Debug)]
+pub enum LoadError {
+    #[error("class not found: {0}")]
+    NotFound(String),
+    #[error("{path} was expected to contain {expected} but contains {found}")]
+    WrongName { path: String, expected: String, found: String },
+    #[error("whilst parsing {0}")]
+    ParseError(String, #[source] ClassParseError),
+    #[error("whilst reading {0}")]
+    IoError(String, #[source] io::Error),
+    #[error("whilst reading archive {0}")]
+    ZipError(String, #[source] ZipError),
+    #[error("the jit compiler couldn't load {0}")]
+    JitError(String, #[source] JitError),
+    #[error("invalid descriptor: {0}")]
+    InvalidDescriptor(String, #[source] DescriptorError),
+    #[error("{0} is its own superclass or superinterface")]
+    ClassCircularityError(String),
+    #[error("{0}")]
+    IncompatibleClassChangeError(String),
+    #[error("{0}")]
+    VerifyError(String),
+    #[error("{0}")]
+    ClassFormatError(String),
+}
+
+impl LoadError {
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, LoadError::NotFound(_))
+    }
+}
+
+enum ClassPathEntry {
+    Directory(PathBuf),
+    Archive(PathBuf, RefCell<ZipArchive<BufReader<File>>>),
+}
+
+/// Loads classes from a list of directories and jar (or zip) files, like java's `-classpath`.
+/// Entries are searched in order. Parsed classes are cached, so every class is only read once.
+pub struct ClassPathLoader {
+    id: LoaderId,
+    entries: Vec<ClassPathEntry>,
+    cache: RefCell<HashMap<String, ClassFile>>,
+}
+
+impl ClassPathLoader {
+    /// Creates a loader for the given directories and archives. Archives are opened immediately.
+    pub fn new<P: AsRef<Path>>(paths: impl IntoIterator<Item = P>) -> Result<Self, LoadError> {
+        let mut entries = Vec::new();
+        for path in paths {
+            let path = path.as_ref();
+            if path.is_dir() {
+                entries.push(ClassPathEntry::Directory(path.to_owned()));
+            } else {
+                let file = File::open(path).map_err(|e| LoadError::IoError(path.display().to_string(), e))?;
+                let archive = ZipArchive::new(BufReader::new(file)).map_err(|e| LoadError::ZipError(path.display().to_string(), e))?;
+                entries.push(ClassPathEntry::Archive(path.to_owned(), RefCell::new(archive)));
+            }
+        }
+        Ok(ClassPathLoader { id: LoaderId::new(), entries, cache: Default::default() })
+    }
+
+    /// Creates a loader from a classpath string, where entries are separated like in the `PATH` environment variable
+    pub fn from_classpath(classpath: &str) -> Result<Self, LoadError> {
+        Self::new(std::env::split_paths(classpath))
+    }
+
+    /// Finds and parses the class with the given binary name, like `java/lang/Object`
+    pub fn find(&self, name: &str) -> Result<ClassFile, LoadError> {
+        if let Some(class) = self.cache.borrow().get(name) {
+            return Ok(class.clone());
+        }
+
+        // Don't let names escape the classpath entries
+        if name.is_empty() || name.starts_with('/') || name.split('/').any(|part| part.is_empty() || part == "." || part == "..") {
+            return Err(LoadError::NotFound(name.to_owned()));
+        }
+        let file_name = format!("{}.class", name);
+
+        for entry in &self.entries {
+            let (path, bytes) = match entry {
+                ClassPathEntry::Directory(directory) => {
+                    let path = directory.join(&file_name);
+                    match std::fs::read(&path) {
+                        Ok(bytes) => (path.display().to_string(), bytes),
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                        Err(e) => return Err(LoadError::IoError(path.display().to_string(), e)),
+                    }
+                }
+                ClassPathEntry::Archive(archive_path, archive) => {
+                    let mut archive = archive.borrow_mut();
+                    let path = format!("{}!/{}", archive_path.display(), file_name);
+                    let mut file = match archive.by_name(&file_name) {
+                        Ok(file) => file,
+                        Err(ZipError::FileNotFound) => continue,
+                        Err(e) => return Err(LoadError::ZipError(path, e)),
+                    };
+                    let mut bytes = Vec::with_capacity((file.size() as usize).min(MAX_PREALLOCATION));
+                    file.read_to_end(&mut bytes).map_err(|e| LoadError::IoError(path.clone(), e))?;
+                    (path, bytes)
+                }
+            };
+
+            let class = parse_named(path, &bytes, name)?;
+            self.cache.borrow_mut().insert(name.to_owned(), class.clone());
+            return Ok(class);
+        }
+
+        Err(LoadError::NotFound(name.to_owned()))
+    }
+}
+
+impl ClassLoader for ClassPathLoader {
+    fn id(&self) -> LoaderId {
+        self.id
+    }
+
+    fn load_defined(&self, class: &str) -> Result<(LoaderId, ClassFile), LoadError> {
+        Ok((self.id, self.find(class)?))
+    }
+}
+
+/// Loads classes from class files that are already in memory, keyed by their binary name
+pub struct MemoryClassLoader {
+    id: LoaderId,
+    classes: HashMap<String, Vec<u8>>,
+}
+
+impl MemoryClassLoader {
+    pub fn new(classes: HashMap<String, Vec<u8>>) -> Self {
+        MemoryClassLoader { id: LoaderId::new(), classes }
+    }
+
+    pub fn insert(&mut self, name: impl Into<String>, bytes: Vec<u8>) {
+        self.classes.insert(name.into(), bytes);
+    }
+}
+
+impl ClassLoader for MemoryClassLoader {
+    fn id(&self) -> LoaderId {
+        self.id
+    }
+
+    fn load_defined(&self, class: &str) -> Result<(LoaderId, ClassFile), LoadError> {
+        let bytes = self.classes.get(class).ok_or_else(|| LoadError::NotFound(class.to_owned()))?;
+        Ok((self.id, parse_named(format!("<memory>/{}", class), bytes, class)?))
+    }
+}
+
+/// Asks `parent` first, and only loads the class itself if the parent can't find it.
+/// This is how java's application class loader works, it can't replace classes from the platform.
+///
+/// Classes defined by `child` keep the child's identity, this loader has the same id as `child`.
+pub struct ParentFirstClassLoader<P: ClassLoader, C: ClassLoader> {
+    parent: P,
+    child: C,
+}
+
+impl<P: ClassLoader, C: ClassLoader> ParentFirstClassLoader<P, C> {
+    pub fn new(parent: P, child: C) -> Self {
+        ParentFirstClassLoader { parent, child }
+    }
+}
+
+impl<P: ClassLoader, C: ClassLoader> ClassLoader for ParentFirstClassLoader<P, C> {
+    fn id(&self) -> LoaderId {
+        self.child.id()
+    }
+
+    fn load_defined(&self, class: &str) -> Result<(LoaderId, ClassFile), LoadError> {
+        match self.parent.load_defined(class) {
+            Err(e) if e.is_not_found() => self.child.load_defined(class),
+            result => result,
+        }
+    }
+
+    fn contains(&self, loader: LoaderId) -> bool {
+        self.parent.contains(loader) || self.child.contains(loader)
+    }
+
+    /// Classes of the child ask the parent first as well, classes of the parent never see the child
+    fn load_for(&self, loader: LoaderId, class: &str) -> Option<Result<(LoaderId, ClassFile), LoadError>> {
+        if self.child.contains(loader) {
+            return Some(self.load_defined(class));
+        }
+        self.parent.load_for(loader, class)
+    }
+}
+
+/// Tries a list of loaders in order, the first one to find the class defines it
+pub struct ChainedClassLoader {
+    id: LoaderId,
+    loaders: Vec<Box<dyn ClassLoader>>,
+}
+
+impl ChainedClassLoader {
+    pub fn new(loaders: Vec<Box<dyn ClassLoader>>) -> Self {
+        ChainedClassLoader { id: LoaderId::new(), loaders }
+    }
+}
+
+impl ClassLoader for ChainedClassLoader {
+    fn id(&self) -> LoaderId {
+        self.id
+    }
+
+    fn load_defined(&self, class: &str) -> Result<(LoaderId, ClassFile), LoadError> {
+        for loader in &self.loaders {
+            match loader.load_defined(class) {
+                Err(e) if e.is_not_found() => continue,
+                result => return result,
+            }
+        }
+        Err(LoadError::NotFound(class.to_owned()))
+    }
+
+    /// Classes of every loader in the chain can see the classes of the others
+    fn contains(&self, loader: LoaderId) -> bool {
+        loader == self.id || self.loaders.iter().any(|chained| chained.contains(loader))
+    }
+}
+
+/// Provides the small class library that comes with the vm, see [runtime]. Usually used as the parent of a [`ParentFirstClassLoader`].
+pub struct BootstrapClassLoader {
+    id: LoaderId,
+}
+
+impl BootstrapClassLoader {
+    pub fn new() -> Self {
+        BootstrapClassLoader { id: LoaderId::new() }
+    }
+}
+
+impl Default for BootstrapClassLoader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClassLoader for BootstrapClassLoader {
+    fn id(&self) -> LoaderId {
+        self.id
+    }
+
+    fn load_defined(&self, class: &str) -> Result<(LoaderId, ClassFile), LoadError> {
+        runtime::build_class(class).map(|built| (self.id, built)).ok_or_else(|| LoadError::NotFound(class.to_owned()))
+    }
+}
+
+/// Parses a class file and checks that it actually contains `name`
+fn parse_named(path: String, bytes: &[u8], name: &str) -> Result<ClassFile, LoadError> {
+    let class = classfile_parser::parse_bytes(bytes).map_err(|e| LoadError::ParseError(path.clone(), e))?;
+    let found = class_name(&class).unwrap_or_default();
+    if found != name {
+        return Err(LoadError::WrongName { path, expected: name.to_owned(), found: found.to_owned() });
+    }
+    Ok(class)
+}
+
+fn class_name(class: &ClassFile) -> Option<&str> {
+    class.constant_pool.get_class_name(class.this_class)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use zip::ZipWriter;
+    use zip::write::FileOptions;
+    use crate::ClassLoader;
+    use crate::test_util::class_bytes;
+    use crate::classfile_util::ConstantPoolExtensions;
+    use crate::class_loaders::{LoadError, BootstrapClassLoader, ClassPathLoader, ChainedClassLoader, MemoryClassLoader, ParentFirstClassLoader, SimpleClassLoader, class_name};
+
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rave-classpath-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn load_from_directory() {
+        let dir = temp_dir("directory");
+        fs::create_dir_all(dir.join("foo")).unwrap();
+        fs::write(dir.join("foo/Bar.class"), class_bytes("foo/Bar")).unwrap();
+        fs::write(dir.join("foo/Wrong.class"), class_bytes("foo/Bar")).unwrap();
+
+        let loader = ClassPathLoader::new([&dir]).unwrap();
+        assert_eq!(class_name(&loader.find("foo/Bar").unwrap()), Some("foo/Bar"));
+        // Served from the cache
+        fs::remove_file(dir.join("foo/Bar.class")).unwrap();
+        assert!(loader.find("foo/Bar").is_ok());
+
+        assert!(matches!(loader.find("foo/Baz"), Err(LoadError::NotFound(_))));
+        assert!(matches!(loader.find("../foo/Bar"), Err(LoadError::NotFound(_))));
+        assert!(matches!(loader.find("foo/Wrong"), Err(LoadError::WrongName { .. })));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn load_from_jar() {
+        let dir = temp_dir("jar");
+        let jar = dir.join("test.jar");
+        let mut writer = ZipWriter::new(File::create(&jar).unwrap());
+        writer.start_file("foo/Bar.class", FileOptions::default()).unwrap();
+        writer.write_all(&class_bytes("foo/Bar")).unwrap();
+        writer.finish().unwrap();
+
+        // Directories that don't contain the class are skipped
+        let loader = ClassPathLoader::new([&dir, &jar]).unwrap();
+        assert_eq!(class_name(&loader.find("foo/Bar").unwrap()), Some("foo/Bar"));
+        assert!(matches!(loader.find("foo/Baz"), Err(LoadError::NotFound(_))));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn memory_loader(names: &[&str]) -> MemoryClassLoader {
+        MemoryClassLoader::new(names.iter().map(|name| (name.to_string(), class_bytes(name))).collect())
+    }
+
+    #[test]
+    fn simple_checks_name() {
+        let loader = SimpleClassLoader::new(classfile_parser::parse_bytes(&class_bytes("Foo")).unwrap());
+        assert!(loader.load("Foo").is_ok());
+        assert!(matches!(loader.load("Bar"), Err(LoadError::NotFound(_))));
+    }
+
+    #[test]
+    fn memory_checks_name() {
+        let mut loader = memory_loader(&["foo/Bar"]);
+        loader.insert("foo/Wrong", class_bytes("foo/Bar"));
+        assert_eq!(class_name(&loader.load("foo/Bar").unwrap()), Some("foo/Bar"));
+        assert!(matches!(loader.load("foo/Wrong"), Err(LoadError::WrongName { .. })));
+        assert!(matches!(loader.load("foo/Baz"), Err(LoadError::NotFound(_))));
+    }
+
+    #[test]
+    fn parent_first() {
+        let parent = memory_loader(&["java/lang/Object"]);
+        let child = memory_loader(&["java/lang/Object", "app/Main"]);
+        let (parent_id, child_id) = (parent.id(), child.id());
+        let loader = ParentFirstClassLoader::new(parent, child);
+
+        assert_eq!(loader.id(), child_id);
+        assert_eq!(loader.load_defined("java/lang/Object").unwrap().0, parent_id);
+        assert_eq!(loader.load_defined("app/Main").unwrap().0, child_id);
+        assert!(matches!(loader.load("app/Other"), Err(LoadError::NotFound(_))));
+    }
+
+    #[test]
+    fn chained() {
+        let first = memory_loader(&["a/A"]);
+        let second = memory_loader(&["a/A", "b/B"]);
+        let (first_id, second_id) = (first.id(), second.id());
+        let loader = ChainedClassLoader::new(vec![Box::new(first), Box::new(second)]);
+
+        assert_ne!(loader.id(), first_id);
+        assert_eq!(loader.load_defined("a/A").unwrap().0, first_id);
+        assert_eq!(loader.load_defined("b/B").unwrap().0, second_id);
+        assert!(matches!(loader.load("c/C"), Err(LoadError::NotFound(_))));
+    }
+
+    #[test]
+    fn errors_stop_delegation() {
+        let mut parent = memory_loader(&[]);
+        parent.insert("a/A", vec![0, 1, 2]);
+        let loader = ParentFirstClassLoader::new(parent, memory_loader(&["a/A"]));
+        assert!(matches!(loader.load("a/A"), Err(LoadError::ParseError(..))));
+    }
+
+    #[test]
+    fn bootstrap() {
+        let loader = BootstrapClassLoader::new();
+        let object = loader.load("java/lang/Object").unwrap();
+        assert_eq!(class_name(&object), Some("java/lang/Object"));
+        assert_eq!(object.super_class, 0);
+        let exception = loader.load("java/lang/ArrayIndexOutOfBoundsException").unwrap();
+        assert_eq!(exception.constant_pool.get_class_name(exception.super_class), Some("java/lang/IndexOutOfBoundsException"));
+        assert!(matches!(loader.load("java/lang/Foo"), Err(LoadError::NotFound(_))));
+    }
+}
@@ -1,77 +1,277 @@
-use std::str::Chars;
-use classfile_parser::class_file::{ClassFile, MethodAccessFlags, MethodInfo};
-use classfile_parser::constant_pool::types::{self, MethodRef};
+use std::cell::Cell;
+use std::collections::HashMap;
+
+use classfile_parser::class_file::{ClassAccessFlags, ClassFile, FieldAccessFlags, FieldInfo, MethodAccessFlags, MethodInfo};
 use classfile_parser::constant_pool::ConstantPool;
 use bitflags::bitflags;
 use classfile_parser::attributes::CodeAttribute;
-use crate::class_store::DescriptorEntry::{Array, Boolean, Byte, Char, Double, Float, Int, Long, Short, Void};
-use crate::classfile_util::ConstantPoolExtensions;
-use crate::JitCompiler;
+use classfile_parser::descriptor::{DescriptorError, MethodDescriptor};
+use crate::classfile_util::{package_of, ConstantPoolExtensions};
+use crate::dispatch::DispatchTables;
+use crate::exceptions::{PendingException, RuntimeExceptionClasses};
+use crate::natives::{register_builtins, NativeMethods};
+use crate::heap::Heap;
+use crate::initialization::InitState;
+use crate::object::InstanceLayout;
+use crate::statics::StaticStorage;
+use crate::strings::StringTable;
+use crate::{JitCompiler, LoaderId};
 
 pub struct ClassStore<J: JitCompiler> {
     class_store: Vec<ClassData<J>>,
+    /// Classes by the loader that defined them and their name
+    defined: HashMap<LoaderId, HashMap<String, LoadedClassRef>>,
+    /// Classes by the loader that was asked to load them and their name.
+    /// This differs from `defined` when a loader delegates.
+    initiated: HashMap<LoaderId, HashMap<String, LoadedClassRef>>,
+    pub(crate) heap: Heap,
+    pub(crate) strings: StringTable,
+    pub(crate) exception: PendingException,
+    pub(crate) runtime_exceptions: RuntimeExceptionClasses,
+    pub(crate) natives: NativeMethods,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[repr(transparent)]
 pub struct LoadedClassRef(usize);
 
-#[derive(Clone, Copy)]
+impl LoadedClassRef {
+    /// The index of the class, for compiled code that needs to refer to it
+    pub fn as_raw(self) -> usize {
+        self.0
+    }
+
+    pub(crate) fn from_raw(index: usize) -> Self {
+        LoadedClassRef(index)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub struct LoadedMethodRef{
     pub class_ref: LoadedClassRef,
     method_index: usize,
 }
 
+impl LoadedMethodRef {
+    pub(crate) fn new(class_ref: LoadedClassRef, method_index: usize) -> Self {
+        LoadedMethodRef { class_ref, method_index }
+    }
+}
+
 impl<J: JitCompiler> Default for ClassStore<J> {
     fn default() -> Self {
-        Self { class_store: Default::default() }
+        Self::with_heap(Default::default())
     }
 }
 
 pub trait ClassStoreIsh<J: JitCompiler> {
     fn retrieve(&self, class: LoadedClassRef) -> &ClassData<J>;
 
+    /// Finds a class that `loader` has loaded before, either by defining it itself or by delegating
+    fn lookup(&self, loader: LoaderId, name: &str) -> Option<LoadedClassRef>;
+
+    /// Amount of classes that are loaded. Their references are numbered from zero.
+    fn class_count(&self) -> usize;
+
     fn retrieve_method_ref(&self, class: LoadedClassRef, method_name: &str, method_desc: &str) -> Option<LoadedMethodRef> {
-        let loaded_class = self.retrieve(class);
-        loaded_class.java_class.methods.iter().enumerate().find(|(_i, method)| {
-            let name = loaded_class.java_class.constant_pool.get_as_string(method.name_index).unwrap();
-            let desc = loaded_class.java_class.constant_pool.get_as_string(method.descriptor).unwrap();
-            return method_name == name && method_desc == desc;
-        }).map(|(i, _method)| {
-            LoadedMethodRef {
-                class_ref: class,
-                method_index: i
-            }
+        let method_index = *self.retrieve(class).methods.get(method_name)?.get(method_desc)?;
+        Some(LoadedMethodRef {
+            class_ref: class,
+            method_index
         })
     }
+
+    /// Finds a field in `class`, then in its superinterfaces and then in its superclass, and returns the class that declares it.
+    /// See: https://docs.oracle.com/javase/specs/jvms/se16/html/jvms-5.html#jvms-5.4.3.2
+    fn find_field<'a>(&'a self, class: LoadedClassRef, name: &str, descriptor: &str) -> Option<(LoadedClassRef, &'a FieldInfo)> where J: 'a {
+        let data = self.retrieve(class);
+        let pool = &data.java_class.constant_pool;
+        let declared = data.java_class.fields.iter().find(|field| {
+            pool.get_as_string(field.name_index) == Some(name) && pool.get_as_string(field.descriptor) == Some(descriptor)
+        });
+        if let Some(field) = declared {
+            return Some((class, field));
+        }
+        data.interfaces.iter().find_map(|&interface| self.find_field(interface, name, descriptor))
+            .or_else(|| self.find_field(data.super_class?, name, descriptor))
+    }
+
+    /// Checks if `class` is `other` or extends it, directly or indirectly
+    fn is_subclass_of(&self, class: LoadedClassRef, other: LoadedClassRef) -> bool {
+        let mut current = Some(class);
+        while let Some(class) = current {
+            if class == other {
+                return true;
+            }
+            current = self.retrieve(class).super_class;
+        }
+        false
+    }
+
+    /// `java/lang/Object`, the superclass that every hierarchy ends in, found from `class`
+    fn root_class(&self, class: LoadedClassRef) -> LoadedClassRef {
+        let mut current = class;
+        while let Some(super_class) = self.retrieve(current).super_class {
+            current = super_class;
+        }
+        current
+    }
+
+    /// Checks if `class` implements `interface`, either itself, through a superclass or through a superinterface.
+    /// An interface implements itself.
+    fn implements(&self, class: LoadedClassRef, interface: LoadedClassRef) -> bool {
+        if class == interface {
+            return true;
+        }
+        let data = self.retrieve(class);
+        data.interfaces.iter().any(|&superinterface| self.implements(superinterface, interface))
+            || data.super_class.is_some_and(|super_class| self.implements(super_class, interface))
+    }
+
+    /// Checks if instances of `class` are also instances of `target`, like `instanceof` and `checkcast` do
+    fn is_instance_of(&self, class: LoadedClassRef, target: LoadedClassRef) -> bool {
+        if self.retrieve(target).is_interface() {
+            self.implements(class, target)
+        } else {
+            self.is_subclass_of(class, target)
+        }
+    }
 }
 
 impl<J: JitCompiler> ClassStoreIsh<J> for ClassStore<J> {
     fn retrieve(&self, class: LoadedClassRef) -> &ClassData<J> {
         &self.class_store[class.0]
     }
+
+    fn lookup(&self, loader: LoaderId, name: &str) -> Option<LoadedClassRef> {
+        self.initiated.get(&loader)?.get(name).copied()
+    }
+
+    fn class_count(&self) -> usize {
+        self.class_store.len()
+    }
 }
 
 impl<J: JitCompiler> ClassStore<J> {
-    pub fn store(&mut self, class: ClassData<J>) -> LoadedClassRef {
-        let i = self.class_store.len();
-        self.class_store.push(class);
-        return LoadedClassRef(i);
+    /// A store with the natives that the vm implements itself already registered
+    pub fn with_heap(heap: Heap) -> Self {
+        let mut natives = NativeMethods::default();
+        register_builtins(&mut natives);
+        Self {
+            class_store: Default::default(),
+            defined: Default::default(),
+            initiated: Default::default(),
+            heap,
+            strings: Default::default(),
+            exception: Default::default(),
+            runtime_exceptions: Default::default(),
+            natives,
+        }
+    }
+
+    /// Stores a newly defined class, `initiating` is the loader that was asked to load it.
+    /// If the defining loader already defined a class with the same name, that one is returned instead.
+    pub fn store(&mut self, initiating: LoaderId, class: ClassData<J>) -> LoadedClassRef {
+        let name = class.name().to_owned();
+        let defining = class.loader;
+        let class_ref = match self.lookup_defined(defining, &name) {
+            Some(existing) => existing,
+            None => {
+                let class_ref = LoadedClassRef(self.class_store.len());
+                self.class_store.push(class);
+                self.defined.entry(defining).or_default().insert(name.clone(), class_ref);
+                class_ref
+            }
+        };
+        self.record_initiated(defining, &name, class_ref);
+        self.record_initiated(initiating, &name, class_ref);
+        class_ref
+    }
+
+    /// Finds a class by the loader that defined it
+    pub fn lookup_defined(&self, loader: LoaderId, name: &str) -> Option<LoadedClassRef> {
+        self.defined.get(&loader)?.get(name).copied()
+    }
+
+    /// Records that `loader` resolves `name` to an already stored class
+    pub fn record_initiated(&mut self, loader: LoaderId, name: &str, class: LoadedClassRef) {
+        self.initiated.entry(loader).or_default().insert(name.to_owned(), class);
+    }
+
+    /// The reference that the next newly defined class is stored under
+    pub fn next_ref(&self) -> LoadedClassRef {
+        LoadedClassRef(self.class_store.len())
+    }
+
+    pub fn len(&self) -> usize {
+        self.class_store.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.class_store.is_empty()
     }
 }
 
 pub struct ClassData<J: JitCompiler> {
     pub java_class: ClassFile,
+    /// The loader that defined this class
+    pub loader: LoaderId,
+    /// `None` for `java/lang/Object`
+    pub super_class: Option<LoadedClassRef>,
+    /// The direct superinterfaces
+    pub interfaces: Vec<LoadedClassRef>,
+    pub statics: StaticStorage,
+    pub layout: InstanceLayout,
+    pub dispatch: DispatchTables,
+    init_state: Cell<InitState>,
     pub jit_data: J::ClassData,
+    /// Method indices by name and then descriptor
+    methods: HashMap<String, HashMap<String, usize>>,
 }
 
 impl<J: JitCompiler> ClassData<J> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(java_class: ClassFile, loader: LoaderId, super_class: Option<LoadedClassRef>, interfaces: Vec<LoadedClassRef>, statics: StaticStorage, layout: InstanceLayout, dispatch: DispatchTables, jit_data: J::ClassData) -> Self {
+        let mut methods: HashMap<String, HashMap<String, usize>> = HashMap::new();
+        for (i, method) in java_class.methods.iter().enumerate() {
+            let name = java_class.constant_pool.get_as_string(method.name_index);
+            let descriptor = java_class.constant_pool.get_as_string(method.descriptor);
+            if let (Some(name), Some(descriptor)) = (name, descriptor) {
+                methods.entry(name.to_owned()).or_default().insert(descriptor.to_owned(), i);
+            }
+        }
+        ClassData { java_class, loader, super_class, interfaces, statics, layout, dispatch, init_state: Cell::new(InitState::Linked), jit_data, methods }
+    }
+
+    pub fn method_info(&self, method: LoadedMethodRef) -> &MethodInfo {
+        &self.java_class.methods[method.method_index]
+    }
+
     pub fn retrieve_method(&self, method: LoadedMethodRef) -> MethodData {
         // TODO assert that the ref is for the correct class
         return MethodData::from_info(&self.java_class.methods[method.method_index], &self.java_class.constant_pool).unwrap();
     }
 
     pub fn name(&self) -> &str {
-        return self.java_class.constant_pool.get_as_string(self.java_class.constant_pool.get_as::<types::Class>(self.java_class.this_class).unwrap().name_index).unwrap();
+        return self.java_class.constant_pool.get_class_name(self.java_class.this_class).unwrap();
+    }
+
+    pub fn init_state(&self) -> InitState {
+        self.init_state.get()
+    }
+
+    pub(crate) fn set_init_state(&self, state: InitState) {
+        self.init_state.set(state);
+    }
+
+    pub fn is_interface(&self) -> bool {
+        self.java_class.access_flags.contains(ClassAccessFlags::INTERFACE)
+    }
+
+    /// Classes can only access package-private members of classes in the same runtime package,
+    /// which is the package name together with the defining loader
+    pub fn runtime_package(&self) -> (LoaderId, &str) {
+        (self.loader, package_of(self.name()))
     }
 }
 
@@ -80,10 +280,10 @@ bitflags! {
         const STATIC = 0b00000001;
         const FINAL  = 0b00000010;
         const SYNCHRONISED = 0b00001000;
+        const NATIVE = 0b00010000;
+        const ABSTRACT = 0b00100000;
         // const BRIDGE = 0x0040;
         // const VARARGS = 0x0080;
-        // const NATIVE = 0x0100;
-        // const ABSTRACT = 0x0400;
         // const STRICT = 0x0800;
         // const SYNTHETIC = 0x1000;
     }
@@ -101,13 +301,22 @@ impl MethodFlags {
         if access_flags.contains(MethodAccessFlags::SYNCHRONISED) {
             base |= MethodFlags::SYNCHRONISED;
         }
+        if access_flags.contains(MethodAccessFlags::NATIVE) {
+            base |= MethodFlags::NATIVE;
+        }
+        if access_flags.contains(MethodAccessFlags::ABSTRACT) {
+            base |= MethodFlags::ABSTRACT;
+        }
         return base;
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Visibility {
     Public,
     Protected,
+    /// Only visible inside of the same runtime package, used when no other visibility is set
+    Package,
     Private,
 }
 
@@ -120,7 +329,11 @@ impl Visibility {
         } else if flags.contains(MethodAccessFlags::PRIVATE) {
             return Self::Private;
         }
-        Self::Private // Default to private
+        Self::Package
+    }
+
+    pub fn from_field_flags(flags: &FieldAccessFlags) -> Self {
+        Self::from_flags(&MethodAccessFlags::from_bits_truncate(flags.bits()))
     }
 }
 
@@ -129,40 +342,8 @@ pub struct MethodData<'class> {
     pub descriptor: &'class str,
     pub visibility: Visibility,
     pub flags: MethodFlags,
-    pub code: &'class CodeAttribute,
-}
-
-#[derive(PartialEq, Debug)]
-pub enum DescriptorEntry {
-    Class(String),
-    Byte,
-    Char,
-    Double,
-    Float,
-    Int,
-    Long,
-    Short,
-    Boolean,
-    Void,
-    Array(Box<DescriptorEntry>)
-}
-
-impl DescriptorEntry {
-    pub fn byte_size(&self) -> u64 {
-        match self {
-            DescriptorEntry::Class(_) => todo!(),
-            Byte => 1,
-            Char => 1,
-            Double => 8,
-            Float => 4,
-            Int => 4,
-            Long => 8,
-            Short => 2,
-            Boolean => 1,
-            Void => 0,
-            Array(_) => todo!(),
-        }
-    }
+    /// Native and abstract methods have no code
+    pub code: Option<&'class CodeAttribute>,
 }
 
 impl<'class> MethodData<'class> {
@@ -184,7 +365,7 @@ impl<'class> MethodData<'class> {
             descriptor,
             visibility,
             flags,
-            code: code.unwrap()
+            code,
         })
     }
 
@@ -197,66 +378,27 @@ impl<'class> MethodData<'class> {
         self.flags.contains(MethodFlags::STATIC)
     }
 
-    pub fn is_public(&self) -> bool {
-        matches!(self.visibility, Visibility::Public)
+    pub fn is_native(&self) -> bool {
+        self.flags.contains(MethodFlags::NATIVE)
     }
 
-    pub fn parse_descriptors(&self) -> (Vec<DescriptorEntry>, DescriptorEntry) {
-        let chars =  &mut self.descriptor.chars();
-        assert_eq!(chars.next(), Some('('));
-
-        let mut acc = vec![];
-        loop {
-            let n = MethodData::parse_next_descriptor(chars);
-            match n {
-                None => {
-                    return (acc, MethodData::parse_next_descriptor(chars).unwrap());
-                }
-                Some(x) => {
-                    assert_ne!(x, Void, "Can't use void as a parameter");
-                    acc.push(x);
-                }
-            }
-        }
+    pub fn is_public(&self) -> bool {
+        matches!(self.visibility, Visibility::Public)
     }
 
-    fn parse_next_descriptor(chars: &mut Chars) -> Option<DescriptorEntry> {
-        match chars.next() {
-            None => {
-                None
-            }
-            Some(x) => {
-                Some(match x {
-                    'B' => Byte,
-                    'C' => Char,
-                    'D' => Double,
-                    'F' => Float,
-                    'I' => Int,
-                    'J' => Long,
-                    'L' => DescriptorEntry::Class(chars.take_while(|c| c != &';').collect()),
-                    'S' => Short,
-                    'Z' => Boolean,
-                    'V' => Void,
-                    '[' => Array(Box::new(MethodData::parse_next_descriptor(chars).unwrap())),
-                    ')' => {
-                        return None; // TODO this can be better
-                    }
-                    _ => {
-                        //TODO proper failure
-                        panic!("Invalid descriptor")
-                    }
-                })
-            }
-        }
+    pub fn parse_descriptor(&self) -> Result<MethodDescriptor, DescriptorError> {
+        self.descriptor.parse()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use classfile_parser::bytecode::Code;
+    use crate::LoaderId;
+    use crate::test_util::{class_bytes, NoJit};
+    use classfile_parser::descriptor::FieldDescriptor;
 
     use crate::class_store::*;
-    use crate::class_store::DescriptorEntry::Class;
     use crate::class_store::MethodData;
 
     #[test]
@@ -266,23 +408,47 @@ mod tests {
             descriptor: "([Ljava/lang/String;DSZ)V",
             visibility: Visibility::Public,
             flags: MethodFlags { bits: 0 },
-            code: &CodeAttribute {
+            code: Some(&CodeAttribute {
                 max_stack: 0,
                 max_locals: 0,
                 code: Code::from_vec(vec![]),
                 exception_table: vec![],
                 attributes: vec![]
-            }
+            })
         };
 
-        assert_eq!(method.parse_descriptors(), (
-            vec![
-                Array(Box::new(Class("java/lang/String".to_string()))),
-                Double,
-                Short,
-                Boolean
+        assert_eq!(method.parse_descriptor().unwrap(), MethodDescriptor {
+            parameters: vec![
+                FieldDescriptor::Array(Box::new(FieldDescriptor::Object("java/lang/String".to_string()))),
+                FieldDescriptor::Double,
+                FieldDescriptor::Short,
+                FieldDescriptor::Boolean
             ],
-            Void
-            ));
+            return_type: None
+        });
     }
-}
\ No newline at end of file
+
+    fn class_data(name: &str, loader: LoaderId) -> ClassData<NoJit> {
+        let class = classfile_parser::parse_bytes(&class_bytes(name)).unwrap();
+        let statics = StaticStorage::prepare(&class).unwrap();
+        let layout = InstanceLayout::compute(&class, None).unwrap();
+        ClassData::new(class, loader, None, vec![], statics, layout, DispatchTables::default(), ())
+    }
+
+    #[test]
+    fn classes_are_keyed_by_defining_loader() {
+        let mut store = ClassStore::<NoJit>::default();
+        let (first, second, initiating) = (LoaderId::new(), LoaderId::new(), LoaderId::new());
+
+        let a = store.store(first, class_data("a/A", first));
+        let other_a = store.store(initiating, class_data("a/A", second));
+        assert_ne!(a, other_a);
+        assert_eq!(store.lookup(first, "a/A"), Some(a));
+        assert_eq!(store.lookup(second, "a/A"), Some(other_a));
+        assert_eq!(store.lookup(initiating, "a/A"), Some(other_a));
+
+        // Defining the same class twice keeps the first one
+        assert_eq!(store.store(initiating, class_data("a/A", first)), a);
+        assert_eq!(store.len(), 2);
+    }
+}
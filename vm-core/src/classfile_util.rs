@@ -4,18 +4,29 @@ use std::ops::Range;
 use classfile_parser::bytecode::Code;
 use classfile_parser::bytecode::Instruction;
 use classfile_parser::class_file::MethodInfo;
-use classfile_parser::constant_pool::ConstantPool;
+use classfile_parser::constant_pool::{ConstantPool, ConstantPoolEntry};
 use classfile_parser::constant_pool::types;
 use classfile_parser::attributes::{AttributeEntry, CodeAttribute};
+use classfile_parser::ClassParseError;
 
 pub trait ConstantPoolExtensions: ConstantPool {
     fn get_as_string(&self, index: u16) -> Option<&str> {
         self.get_as::<types::Utf8Info>(index).map(|v| v.inner.as_str())
     }
+
+    /// Gets the name of a `Class` entry, like `java/lang/Object`
+    fn get_class_name(&self, index: u16) -> Option<&str> {
+        self.get_as_string(self.get_as::<types::Class>(index)?.name_index)
+    }
 }
 
 impl<R: ConstantPool + ?Sized> ConstantPoolExtensions for R {}
 
+/// The package part of a binary class name, like `java/lang` for `java/lang/Object`
+pub fn package_of(class: &str) -> &str {
+    class.rsplit_once('/').map_or("", |(package, _)| package)
+}
+
 pub fn get_code_attribute(method: &MethodInfo) -> Option<&CodeAttribute> {
     for attribute in &method.attributes {
         if let AttributeEntry::Code(inner) = attribute {
@@ -25,13 +36,54 @@ pub fn get_code_attribute(method: &MethodInfo) -> Option<&CodeAttribute> {
     None
 }
 
+/// The names of the classes that the code refers to through field, method and class constants.
+/// Array types are replaced by the class of their elements, if they have one. Names can be returned more than once.
+pub fn referenced_classes<'pool>(code: &Code, pool: &'pool impl ConstantPool) -> Result<Vec<&'pool str>, ClassParseError> {
+    let mut names = Vec::new();
+    for (_, instruction) in code.decode()? {
+        let class_index = match instruction {
+            Instruction::GetStatic(index) |
+            Instruction::PutStatic(index) |
+            Instruction::GetField(index) |
+            Instruction::Putfield(index) |
+            Instruction::InvokeStatic(index) |
+            Instruction::InvokeVirtual(index) |
+            Instruction::InvokeSpecial(index) |
+            Instruction::InvokeInterface(index, _) => {
+                match pool.get_entry(index) {
+                    Some(ConstantPoolEntry::FieldRef(reference) | ConstantPoolEntry::MethodRef(reference) | ConstantPoolEntry::InterfaceMethodRef(reference)) => reference.class_index,
+                    _ => continue,
+                }
+            }
+            Instruction::New(index) |
+            Instruction::ANewArray(index) |
+            Instruction::Checkcast(index) |
+            Instruction::InstanceOf(index) |
+            Instruction::MultiANewArray(index, _) => index,
+            _ => continue,
+        };
+        if let Some(name) = pool.get_class_name(class_index) {
+            let element = name.trim_start_matches('[');
+            if element.len() == name.len() {
+                names.push(name);
+            } else if let Some(class) = element.strip_prefix('L').and_then(|class| class.strip_suffix(';')) {
+                names.push(class);
+            }
+        }
+    }
+    Ok(names)
+}
+
 /// Splits java bytecode into blocks, such that the only jumps
 /// made by the bytecode are into the start of the blocks.
+/// Exception handlers are jumped to as well, so they start blocks too.
 /// Returned is a list of byte-ranges into the bytecode. 
-pub fn split_code_into_basic_blocks(code: &Code) -> Vec<Range<usize>> {
+pub fn split_code_into_basic_blocks(code: &CodeAttribute) -> Vec<Range<usize>> {
     let mut starting_positions = HashSet::new();
 
     starting_positions.insert(0);
+    starting_positions.extend(code.exception_table.iter().map(|entry| entry.handler_pc as usize));
+    let code = &code.code;
 
     for (byte, inst) in code.iter(..) {
         match inst {
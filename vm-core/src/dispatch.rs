@@ -0,0 +1,428 @@
+//! Method selection for `invokevirtual`, `invokeinterface` and `invokespecial`.
+//! Every class gets a vtable and an itable when it's linked. A vtable starts with the slots of the superclass,
+//! so a slot stands for the same method in every subclass.
+//! See: https://docs.oracle.com/javase/specs/jvms/se16/html/jvms-5.html#jvms-5.4.6
+
+use std::collections::HashMap;
+
+use classfile_parser::class_file::{ClassAccessFlags, ClassFile, MethodAccessFlags, MethodInfo};
+use classfile_parser::constant_pool::ConstantPool;
+use classfile_parser::ClassParseError;
+
+use crate::class_loaders::LoadError;
+use crate::class_store::{ClassData, ClassStoreIsh, LoadedClassRef, LoadedMethodRef};
+use crate::classfile_util::{package_of, ConstantPoolExtensions};
+use crate::resolution::ResolveError;
+use crate::{JitCompiler, LoaderId};
+
+/// The dispatch tables of a class. Interfaces have empty tables, they're never the class of an object.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DispatchTables {
+    pub vtable: VTable,
+    pub itable: ITable,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VTable {
+    slots: Vec<VTableSlot>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VTableSlot {
+    pub name: String,
+    pub descriptor: String,
+    /// What gets called for this slot
+    pub selected: Selection,
+    /// Flags of the method that last declared this slot
+    access_flags: MethodAccessFlags,
+    /// The runtime package of the class that last declared this slot
+    package: (LoaderId, String),
+    /// The slot was added for an interface method that no class method implements
+    from_interface: bool,
+}
+
+/// The methods that are selected for each interface a class implements, indexed by [itable_index]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ITable {
+    interfaces: HashMap<LoadedClassRef, Vec<Selection>>,
+}
+
+/// The outcome of selecting a method for a vtable slot or an itable entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Selection {
+    Method(LoadedMethodRef),
+    /// Only abstract methods were found, calling it throws an `AbstractMethodError`
+    Abstract,
+    /// Several superinterfaces provide a maximally-specific default method, calling it throws an `IncompatibleClassChangeError`.
+    /// See: https://docs.oracle.com/javase/specs/jvms/se16/html/jvms-6.html#jvms-6.5.invokeinterface
+    Conflict,
+}
+
+impl VTable {
+    pub fn get(&self, slot: usize) -> Option<&VTableSlot> {
+        self.slots.get(slot)
+    }
+
+    pub fn slots(&self) -> &[VTableSlot] {
+        &self.slots
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// The last slot with this name and descriptor. Earlier ones are package-private methods that were hidden by a later declaration.
+    pub fn find(&self, name: &str, descriptor: &str) -> Option<usize> {
+        self.slots.iter().rposition(|slot| slot.name == name && slot.descriptor == descriptor)
+    }
+}
+
+impl VTableSlot {
+    /// Checks if a method declared in `package` overrides the method in this slot.
+    /// See: https://docs.oracle.com/javase/specs/jvms/se16/html/jvms-5.html#jvms-5.4.5
+    fn overridable_from(&self, package: &(LoaderId, String)) -> bool {
+        self.access_flags.intersects(MethodAccessFlags::PUBLIC | MethodAccessFlags::PROTECTED) || &self.package == package
+    }
+}
+
+impl ITable {
+    /// The methods selected for the methods of `interface`, or `None` if the class doesn't implement it
+    pub fn get(&self, interface: LoadedClassRef) -> Option<&[Selection]> {
+        self.interfaces.get(&interface).map(Vec::as_slice)
+    }
+}
+
+impl Selection {
+    pub fn method(self) -> Option<LoadedMethodRef> {
+        match self {
+            Selection::Method(method) => Some(method),
+            Selection::Abstract | Selection::Conflict => None,
+        }
+    }
+
+    /// The selected method, or the error that calling `resolved` throws
+    fn or_error<J: JitCompiler>(self, resolver: &impl ClassStoreIsh<J>, resolved: LoadedMethodRef) -> Result<LoadedMethodRef, ResolveError> {
+        match self {
+            Selection::Method(method) => Ok(method),
+            Selection::Abstract => Err(ResolveError::AbstractMethodError(method_name(resolver, resolved))),
+            Selection::Conflict => Err(ResolveError::IncompatibleClassChangeError(format!("conflicting default methods for {}", method_name(resolver, resolved)))),
+        }
+    }
+}
+
+impl DispatchTables {
+    /// Builds the tables of a class that's being linked, after its supertypes were linked.
+    /// `this` is the reference the class is going to be stored under.
+    pub(crate) fn link<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, this: LoadedClassRef, class: &ClassFile, loader: LoaderId, super_class: Option<LoadedClassRef>, interfaces: &[LoadedClassRef]) -> Result<Self, LoadError> {
+        if class.access_flags.contains(ClassAccessFlags::INTERFACE) {
+            return Ok(Self::default());
+        }
+        let pool = &class.constant_pool;
+        let class_name = pool.get_class_name(class.this_class).unwrap_or_default();
+        let package = (loader, package_of(class_name).to_owned());
+        let mut slots = super_class.map_or_else(Vec::new, |class| resolver.retrieve(class).dispatch.vtable.slots.clone());
+
+        for (index, method) in class.methods.iter().enumerate() {
+            let (name, descriptor) = signature(method, pool)
+                .ok_or_else(|| LoadError::ParseError(class_name.to_owned(), ClassParseError::InvalidConstantPoolIndex(method.name_index)))?;
+            if !is_dispatched(method, name) {
+                continue;
+            }
+            let selected = match method.access_flags.contains(MethodAccessFlags::ABSTRACT) {
+                true => Selection::Abstract,
+                false => Selection::Method(LoadedMethodRef::new(this, index)),
+            };
+            let mut overrides = false;
+            for slot in slots.iter_mut().filter(|slot| slot.name == name && slot.descriptor == descriptor && slot.overridable_from(&package)) {
+                if slot.access_flags.contains(MethodAccessFlags::FINAL) {
+                    return Err(LoadError::VerifyError(format!("{}.{}{} overrides a final method", class_name, name, descriptor)));
+                }
+                slot.selected = selected;
+                slot.access_flags = method.access_flags;
+                slot.package = package.clone();
+                slot.from_interface = false;
+                overrides = true;
+            }
+            if !overrides {
+                slots.push(VTableSlot { name: name.to_owned(), descriptor: descriptor.to_owned(), selected, access_flags: method.access_flags, package: package.clone(), from_interface: false });
+            }
+        }
+
+        // Interface methods only get selected if no class method implements them
+        let superinterfaces = all_interfaces(resolver, super_class, interfaces);
+        for &interface in &superinterfaces {
+            let data = resolver.retrieve(interface);
+            for (name, descriptor) in dispatched_methods(data).map(|index| signature(&data.java_class.methods[index], &data.java_class.constant_pool).unwrap()) {
+                let selected = maximally_specific(resolver, &superinterfaces, name, descriptor);
+                match slots.iter_mut().rfind(|slot| slot.name == name && slot.descriptor == descriptor) {
+                    Some(slot) if !slot.from_interface => {}
+                    Some(slot) => slot.selected = selected,
+                    None => slots.push(VTableSlot {
+                        name: name.to_owned(),
+                        descriptor: descriptor.to_owned(),
+                        selected,
+                        access_flags: MethodAccessFlags::PUBLIC | MethodAccessFlags::ABSTRACT,
+                        package: package.clone(),
+                        from_interface: true,
+                    }),
+                }
+            }
+        }
+
+        let vtable = VTable { slots };
+        let itable = superinterfaces.iter().map(|&interface| {
+            let data = resolver.retrieve(interface);
+            let methods = dispatched_methods(data).map(|index| {
+                let (name, descriptor) = signature(&data.java_class.methods[index], &data.java_class.constant_pool).unwrap();
+                vtable.find(name, descriptor).map_or(Selection::Abstract, |slot| vtable.slots[slot].selected)
+            }).collect();
+            (interface, methods)
+        }).collect();
+        Ok(DispatchTables { vtable, itable: ITable { interfaces: itable } })
+    }
+}
+
+/// The vtable slot of a method, or `None` if the method isn't selected dynamically,
+/// like static and private methods, constructors and methods of interfaces.
+pub fn vtable_slot<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, method: LoadedMethodRef) -> Option<usize> {
+    let data = resolver.retrieve(method.class_ref);
+    let info = data.method_info(method);
+    let (name, descriptor) = signature(info, &data.java_class.constant_pool)?;
+    if !is_dispatched(info, name) {
+        return None;
+    }
+    data.dispatch.vtable.find(name, descriptor)
+}
+
+/// The index of an interface method inside of the itable entry for its interface
+pub fn itable_index<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, method: LoadedMethodRef) -> Option<usize> {
+    let data = resolver.retrieve(method.class_ref);
+    if !data.is_interface() {
+        return None;
+    }
+    dispatched_methods(data).position(|index| LoadedMethodRef::new(method.class_ref, index) == method)
+}
+
+/// Selects the method that `invokevirtual` calls for the `resolved` method on an object of class `receiver`
+pub fn select_virtual<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, receiver: LoadedClassRef, resolved: LoadedMethodRef) -> Result<LoadedMethodRef, ResolveError> {
+    if resolver.retrieve(resolved.class_ref).is_interface() {
+        return select_interface(resolver, receiver, resolved);
+    }
+    let slot = match vtable_slot(resolver, resolved) {
+        Some(slot) => slot,
+        None => return Ok(resolved),
+    };
+    resolver.retrieve(receiver).dispatch.vtable.get(slot)
+        .ok_or_else(|| ResolveError::IncompatibleClassChangeError(format!("{} isn't a {}", resolver.retrieve(receiver).name(), resolver.retrieve(resolved.class_ref).name())))?
+        .selected.or_error(resolver, resolved)
+}
+
+/// Selects the method that `invokeinterface` calls for the `resolved` method on an object of class `receiver`
+pub fn select_interface<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, receiver: LoadedClassRef, resolved: LoadedMethodRef) -> Result<LoadedMethodRef, ResolveError> {
+    let interface = resolved.class_ref;
+    let index = match itable_index(resolver, resolved) {
+        Some(index) => index,
+        // Private interface methods, and methods of `Object` called through an interface
+        None if resolver.retrieve(interface).is_interface() => return Ok(resolved),
+        None => return select_virtual(resolver, receiver, resolved),
+    };
+    let methods = resolver.retrieve(receiver).dispatch.itable.get(interface)
+        .ok_or_else(|| ResolveError::IncompatibleClassChangeError(format!("{} doesn't implement {}", resolver.retrieve(receiver).name(), resolver.retrieve(interface).name())))?;
+    methods[index].or_error(resolver, resolved)
+}
+
+/// Selects the method that `invokespecial` calls from code in `referrer`.
+/// Constructors and private methods are called directly, `super.` calls start looking in the superclass of `referrer`.
+pub fn select_special<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, referrer: LoadedClassRef, resolved: LoadedMethodRef) -> Result<LoadedMethodRef, ResolveError> {
+    let data = resolver.retrieve(resolved.class_ref);
+    let info = data.method_info(resolved);
+    let (name, descriptor) = signature(info, &data.java_class.constant_pool).ok_or_else(|| ResolveError::AbstractMethodError(method_name(resolver, resolved)))?;
+    if !is_dispatched(info, name) {
+        return Ok(resolved);
+    }
+
+    let referrer_data = resolver.retrieve(referrer);
+    let is_super_call = !data.is_interface()
+        && resolved.class_ref != referrer
+        && resolver.is_subclass_of(referrer, resolved.class_ref)
+        && referrer_data.java_class.access_flags.contains(ClassAccessFlags::SUPER);
+    let class = match referrer_data.super_class {
+        Some(super_class) if is_super_call => super_class,
+        _ => resolved.class_ref,
+    };
+
+    if resolver.retrieve(class).is_interface() {
+        return if info.access_flags.contains(MethodAccessFlags::ABSTRACT) { Selection::Abstract.or_error(resolver, resolved) } else { Ok(resolved) };
+    }
+    let vtable = &resolver.retrieve(class).dispatch.vtable;
+    vtable.find(name, descriptor).map_or(Selection::Abstract, |slot| vtable.slots[slot].selected).or_error(resolver, resolved)
+}
+
+/// Selects among the maximally-specific superinterface methods with this name and descriptor.
+/// Only a single one that isn't abstract can be selected.
+pub(crate) fn maximally_specific<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, interfaces: &[LoadedClassRef], name: &str, descriptor: &str) -> Selection {
+    let mut selected = maximally_specific_methods(resolver, interfaces, name, descriptor).into_iter()
+        .filter(|&method| !resolver.retrieve(method.class_ref).method_info(method).access_flags.contains(MethodAccessFlags::ABSTRACT));
+    match (selected.next(), selected.next()) {
+        (Some(method), None) => Selection::Method(method),
+        (Some(_), Some(_)) => Selection::Conflict,
+        (None, _) => Selection::Abstract,
+    }
+}
+
+/// The methods with this name and descriptor that are declared in one of `interfaces`,
+/// and not in a subinterface of the one that declares them
+pub(crate) fn maximally_specific_methods<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, interfaces: &[LoadedClassRef], name: &str, descriptor: &str) -> Vec<LoadedMethodRef> {
+    let candidates = interfaces.iter()
+        .filter_map(|&interface| resolver.retrieve_method_ref(interface, name, descriptor))
+        .filter(|&method| is_dispatched(resolver.retrieve(method.class_ref).method_info(method), name))
+        .collect::<Vec<_>>();
+    candidates.iter().copied()
+        .filter(|method| !candidates.iter().any(|other| other.class_ref != method.class_ref && resolver.implements(other.class_ref, method.class_ref)))
+        .collect()
+}
+
+/// Every interface that is implemented by the class, its superclasses or its superinterfaces, without duplicates
+pub(crate) fn all_interfaces<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, super_class: Option<LoadedClassRef>, interfaces: &[LoadedClassRef]) -> Vec<LoadedClassRef> {
+    let mut pending = interfaces.to_vec();
+    let mut class = super_class;
+    while let Some(current) = class {
+        let data = resolver.retrieve(current);
+        pending.extend(&data.interfaces);
+        class = data.super_class;
+    }
+
+    let mut found = Vec::new();
+    while let Some(interface) = pending.pop() {
+        if !found.contains(&interface) {
+            found.push(interface);
+            pending.extend(&resolver.retrieve(interface).interfaces);
+        }
+    }
+    found
+}
+
+/// Indices of the methods that can be selected dynamically
+fn dispatched_methods<J: JitCompiler>(class: &ClassData<J>) -> impl Iterator<Item = usize> + '_ {
+    let pool = &class.java_class.constant_pool;
+    class.java_class.methods.iter().enumerate()
+        .filter(move |(_, method)| signature(method, pool).is_some_and(|(name, _)| is_dispatched(method, name)))
+        .map(|(index, _)| index)
+}
+
+fn is_dispatched(method: &MethodInfo, name: &str) -> bool {
+    !method.access_flags.intersects(MethodAccessFlags::STATIC | MethodAccessFlags::PRIVATE) && !name.starts_with('<')
+}
+
+fn signature<'a>(method: &MethodInfo, pool: &'a impl ConstantPool) -> Option<(&'a str, &'a str)> {
+    Some((pool.get_as_string(method.name_index)?, pool.get_as_string(method.descriptor)?))
+}
+
+fn method_name<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, method: LoadedMethodRef) -> String {
+    let data = resolver.retrieve(method.class_ref);
+    let (name, descriptor) = signature(data.method_info(method), &data.java_class.constant_pool).unwrap_or_default();
+    format!("{}.{}{}", data.name(), name, descriptor)
+}
+
+#[cfg(test)]
+mod tests {
+    use classfile_parser::builder::ClassBuilder;
+    use classfile_parser::class_file::MethodAccessFlags;
+    use crate::class_loaders::LoadError;
+    use crate::class_store::{ClassStoreIsh, LoadedMethodRef};
+    use crate::dispatch::{select_interface, select_special, select_virtual, vtable_slot};
+    use crate::resolution::ResolveError;
+    use crate::test_util::{class, interface, BuiltClassLoader, NoJit, PUBLIC};
+    use crate::VirtualMachine;
+
+    fn vm(classes: Vec<ClassBuilder>) -> VirtualMachine<BuiltClassLoader, NoJit> {
+        VirtualMachine::new(BuiltClassLoader::new(classes.into_iter().map(|class| class.build().unwrap()).collect()), NoJit)
+    }
+
+    fn method(vm: &mut VirtualMachine<BuiltClassLoader, NoJit>, class: &str, name: &str) -> LoadedMethodRef {
+        let class = vm.load_class(class).unwrap();
+        vm.class_store.retrieve_method_ref(class, name, "()V").unwrap()
+    }
+
+    #[test]
+    fn virtual_and_special() {
+        let mut vm = vm(vec![
+            class("A", None, &[], &[(PUBLIC, "v"), (PUBLIC | MethodAccessFlags::FINAL, "f"), (MethodAccessFlags::PRIVATE, "p")]),
+            class("B", Some("A"), &[], &[(PUBLIC, "v"), (PUBLIC, "w")]),
+            class("C", Some("B"), &[], &[(PUBLIC | MethodAccessFlags::ABSTRACT, "v")]),
+            class("Final", Some("A"), &[], &[(PUBLIC, "f")]),
+        ]);
+        let (a_v, b_v, a_p) = (method(&mut vm, "A", "v"), method(&mut vm, "B", "v"), method(&mut vm, "A", "p"));
+        let (a, b, c) = (a_v.class_ref, b_v.class_ref, vm.load_class("C").unwrap());
+        let store = &vm.class_store;
+
+        assert_eq!(store.retrieve(a).dispatch.vtable.len(), 2);
+        assert_eq!(store.retrieve(b).dispatch.vtable.len(), 3);
+        assert_eq!(vtable_slot(store, a_v), vtable_slot(store, b_v));
+        assert_eq!(vtable_slot(store, a_p), None);
+
+        assert_eq!(select_virtual(store, a, a_v), Ok(a_v));
+        assert_eq!(select_virtual(store, b, a_v), Ok(b_v));
+        assert_eq!(select_virtual(store, b, a_p), Ok(a_p));
+        assert!(matches!(select_virtual(store, c, a_v), Err(ResolveError::AbstractMethodError(_))));
+
+        // `super.v()` from C calls the method of B, even though the reference names A
+        assert_eq!(select_special(store, c, a_v), Ok(b_v));
+        assert_eq!(select_special(store, a, a_v), Ok(a_v));
+        assert_eq!(select_special(store, b, a_p), Ok(a_p));
+
+        assert!(matches!(vm.load_class("Final"), Err(LoadError::VerifyError(_))));
+    }
+
+    #[test]
+    fn package_private() {
+        let mut vm = vm(vec![
+            class("a/P", None, &[], &[(MethodAccessFlags::empty(), "m")]),
+            class("a/Same", Some("a/P"), &[], &[(MethodAccessFlags::empty(), "m")]),
+            class("b/Other", Some("a/P"), &[], &[(MethodAccessFlags::empty(), "m")]),
+        ]);
+        let (p_m, same_m, other_m) = (method(&mut vm, "a/P", "m"), method(&mut vm, "a/Same", "m"), method(&mut vm, "b/Other", "m"));
+        let store = &vm.class_store;
+
+        assert_eq!(select_virtual(store, same_m.class_ref, p_m), Ok(same_m));
+        assert_eq!(select_virtual(store, other_m.class_ref, p_m), Ok(p_m));
+        assert_eq!(select_virtual(store, other_m.class_ref, other_m), Ok(other_m));
+        assert_eq!(store.retrieve(other_m.class_ref).dispatch.vtable.len(), 2);
+    }
+
+    #[test]
+    fn interfaces_and_defaults() {
+        let mut vm = vm(vec![
+            interface("I", &[], &[(PUBLIC, "d"), (PUBLIC | MethodAccessFlags::ABSTRACT, "a")]),
+            interface("J", &["I"], &[(PUBLIC, "d")]),
+            interface("K", &[], &[(PUBLIC, "d")]),
+            interface("Unimplemented", &[], &[(PUBLIC | MethodAccessFlags::ABSTRACT, "u")]),
+            class("A", None, &["I"], &[(PUBLIC, "a")]),
+            class("B", Some("A"), &["J"], &[]),
+            class("Conflict", None, &["J", "K"], &[]),
+            class("Missing", None, &["Unimplemented"], &[]),
+        ]);
+        let (i_d, i_a, j_d, u) = (method(&mut vm, "I", "d"), method(&mut vm, "I", "a"), method(&mut vm, "J", "d"), method(&mut vm, "Unimplemented", "u"));
+        let (a_a, b) = (method(&mut vm, "A", "a"), vm.load_class("B").unwrap());
+        let (conflict, missing) = (vm.load_class("Conflict").unwrap(), vm.load_class("Missing").unwrap());
+        let a = a_a.class_ref;
+        let store = &vm.class_store;
+
+        assert_eq!(select_interface(store, a, i_d), Ok(i_d));
+        assert_eq!(select_interface(store, a, i_a), Ok(a_a));
+        assert_eq!(select_interface(store, b, i_d), Ok(j_d));
+        assert_eq!(select_interface(store, b, i_a), Ok(a_a));
+        assert_eq!(select_virtual(store, b, a_a), Ok(a_a));
+        assert!(matches!(select_interface(store, conflict, j_d), Err(ResolveError::IncompatibleClassChangeError(_))));
+        assert!(matches!(select_virtual(store, conflict, j_d), Err(ResolveError::IncompatibleClassChangeError(_))));
+        assert!(matches!(select_interface(store, missing, u), Err(ResolveError::AbstractMethodError(_))));
+        assert!(matches!(select_interface(store, a, u), Err(ResolveError::IncompatibleClassChangeError(_))));
+
+        // `J.super.d()`
+        assert_eq!(select_special(store, b, j_d), Ok(j_d));
+    }
+}
@@ -0,0 +1,353 @@
+//! Lets rust code that embeds the vm hold on to java objects, and create them, use their fields and call their methods.
+//!
+//! A [JavaRef] pins its object: the object can't be collected while the reference exists, and because collectors never
+//! move objects, its address stays the same. References belong to the innermost local scope that's active when they're
+//! created, see [VirtualMachine::local_scope], and are released when that scope ends. References that are created outside
+//! of a local scope, or with [VirtualMachine::new_global_ref], are global. They stay until [VirtualMachine::release_ref].
+//!
+//! Methods and fields that hold references use [JavaObject]s, which are only the address of an object.
+//! Objects that are returned by java code should be put in a [JavaRef] before running java code again.
+//!
+//! Primitive arrays can be borrowed as slices without copying them. The slice borrows the vm, so no java code can run,
+//! and the reference can't be released, until the slice is dropped.
+
+use std::slice;
+
+use classfile_parser::class_file::ClassAccessFlags;
+use classfile_parser::descriptor::FieldDescriptor;
+
+use crate::class_store::ClassStoreIsh;
+use crate::heap::{Handle, ObjectKind};
+use crate::interop::{JavaArrayElement, JavaObject, JavaValue};
+use crate::object::{allocate_array, allocate_object, class_of, find_instance_field, is_instance, ArrayType, ReferenceType, ARRAY_HEADER_SIZE};
+use crate::{check_arguments, ClassLoader, JitCompiler, VirtualMachine, VmError};
+
+/// Keeps an object alive and in place, until it's released or its scope ends
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub struct JavaRef {
+    slot: usize,
+    /// Tells apart the references that used the same slot
+    generation: u32,
+}
+
+/// The references of a vm, and the local scopes they were created in
+#[derive(Default)]
+pub(crate) struct References {
+    slots: Vec<Slot>,
+    /// The references created in each local scope, innermost last. Some of them may have been released already.
+    scopes: Vec<Vec<(usize, u32)>>,
+}
+
+#[derive(Default)]
+struct Slot {
+    handle: Option<Handle>,
+    generation: u32,
+}
+
+impl References {
+    fn insert(&mut self, handle: Handle, global: bool) -> JavaRef {
+        let slot = match self.slots.iter().position(|slot| slot.handle.is_none()) {
+            Some(slot) => slot,
+            None => {
+                self.slots.push(Slot::default());
+                self.slots.len() - 1
+            }
+        };
+        self.slots[slot].handle = Some(handle);
+        let generation = self.slots[slot].generation;
+        if let (false, Some(scope)) = (global, self.scopes.last_mut()) {
+            scope.push((slot, generation));
+        }
+        JavaRef { slot, generation }
+    }
+
+    fn get(&self, reference: &JavaRef) -> &Handle {
+        let slot = &self.slots[reference.slot];
+        match &slot.handle {
+            Some(handle) if slot.generation == reference.generation => handle,
+            _ => panic!("used a java reference after it was released"),
+        }
+    }
+
+    /// Frees a slot, unless it was freed before and now holds a different reference
+    fn remove(&mut self, slot: usize, generation: u32) -> Option<Handle> {
+        let slot = &mut self.slots[slot];
+        if slot.generation != generation {
+            return None;
+        }
+        slot.generation = slot.generation.wrapping_add(1);
+        slot.handle.take()
+    }
+}
+
+impl<L: ClassLoader, T: JitCompiler> VirtualMachine<L, T> {
+    /// Pins an object in the current scope, or returns `None` for null
+    pub fn new_ref(&mut self, object: JavaObject) -> Option<JavaRef> {
+        self.hold(object, false)
+    }
+
+    /// Pins the object of a reference until the returned reference is released, regardless of scopes
+    pub fn new_global_ref(&mut self, reference: &JavaRef) -> JavaRef {
+        let object = self.get_ref(reference);
+        self.hold(object, true).unwrap()
+    }
+
+    fn hold(&mut self, object: JavaObject, global: bool) -> Option<JavaRef> {
+        if object.is_null() {
+            return None;
+        }
+        let handle = self.heap().hold(object as *mut u8);
+        Some(self.references.insert(handle, global))
+    }
+
+    /// Lets the object of a reference be collected again, if nothing else refers to it
+    pub fn release_ref(&mut self, reference: JavaRef) {
+        if let Some(handle) = self.references.remove(reference.slot, reference.generation) {
+            self.class_store.heap.release(handle);
+        }
+    }
+
+    /// The address of the object of a reference, which stays valid while the reference exists.
+    /// Panics if the reference was released.
+    pub fn get_ref(&self, reference: &JavaRef) -> JavaObject {
+        self.heap().get(self.references.get(reference)) as JavaObject
+    }
+
+    /// Runs `f` in a new local scope. The references that are created in it are released when it returns,
+    /// except for global ones.
+    pub fn local_scope<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        self.references.scopes.push(Vec::new());
+        let result = f(self);
+        for (slot, generation) in self.references.scopes.pop().unwrap() {
+            if let Some(handle) = self.references.remove(slot, generation) {
+                self.class_store.heap.release(handle);
+            }
+        }
+        result
+    }
+
+    /// Creates an object by calling the constructor of `class` with the given descriptor, like `(I)V`
+    pub fn new_object(&mut self, class: &str, descriptor: &str, arguments: &[JavaValue]) -> Result<JavaRef, VmError> {
+        check_arguments(self.get_resolver(), self.class_loader.id(), (class, "<init>", descriptor), true, arguments)?;
+        let class_ref = self.load_class(class)?;
+        self.initialize_or_throw(class_ref)?;
+        let data = self.class_store.retrieve(class_ref);
+        if data.is_interface() || data.java_class.access_flags.contains(ClassAccessFlags::ABSTRACT) {
+            return Err(VmError::InstantiationError(class.to_owned()));
+        }
+        let constructor = self.class_store.retrieve_method_ref(class_ref, "<init>", descriptor)
+            .ok_or_else(|| VmError::NoSuchMethod(format!("{}.<init>{}", class, descriptor)))?;
+
+        let object = allocate_object(&self.class_store, &self.jit_engine, class_ref);
+        let reference = self.hold(object, false).unwrap();
+        let arguments = [&[JavaValue::Object(object)], arguments].concat();
+        self.jit_engine.invoke_method(constructor, self.get_resolver(), &arguments);
+        if let Err(e) = self.check_exception() {
+            self.release_ref(reference);
+            return Err(e);
+        }
+        Ok(reference)
+    }
+
+    /// Calls an instance method on an object. The method is selected like `invokevirtual` does, from the class of the object.
+    pub fn call_method(&mut self, object: &JavaRef, name: &str, descriptor: &str, arguments: &[JavaValue]) -> Result<JavaValue, VmError> {
+        let class = match class_of(&self.class_store, self.get_ref(object)) {
+            Some(class) => class,
+            // Arrays have the methods of `java/lang/Object`
+            None => self.load_class("java/lang/Object")?,
+        };
+        let this = self.get_ref(object);
+        let full_name = || format!("{}.{}{}", self.class_store.retrieve(class).name(), name, descriptor);
+        check_arguments(self.get_resolver(), self.class_loader.id(), (self.class_store.retrieve(class).name(), name, descriptor), true, arguments)?;
+
+        let vtable = &self.class_store.retrieve(class).dispatch.vtable;
+        let method = vtable.find(name, descriptor).and_then(|slot| vtable.get(slot)?.selected.method())
+            // Private methods aren't selected dynamically
+            .or_else(|| self.class_store.retrieve_method_ref(class, name, descriptor))
+            .filter(|&method| {
+                let data = self.class_store.retrieve(method.class_ref).retrieve_method(method);
+                !data.is_static() && (data.code.is_some() || data.is_native())
+            })
+            .ok_or_else(|| VmError::NoSuchMethod(full_name()))?;
+
+        let arguments = [&[JavaValue::Object(this)], arguments].concat();
+        let result = self.jit_engine.invoke_method(method, self.get_resolver(), &arguments);
+        self.check_exception()?;
+        Ok(result)
+    }
+
+    /// Reads an instance field of an object, which may be declared by a superclass
+    pub fn get_field(&self, object: &JavaRef, name: &str, descriptor: &str) -> Result<JavaValue, VmError> {
+        let (address, field) = self.find_field(object, name, descriptor)?;
+        // Safe because the layout of the object contains the field
+        Ok(unsafe { JavaValue::read(address, &field) })
+    }
+
+    /// Writes an instance field of an object, which may be declared by a superclass
+    pub fn set_field(&mut self, object: &JavaRef, name: &str, descriptor: &str, value: JavaValue) -> Result<(), VmError> {
+        let (address, field) = self.find_field(object, name, descriptor)?;
+        if !value.fits(self.get_resolver(), self.class_loader.id(), &field) {
+            return Err(VmError::ArgumentMismatch(format!("{}:{}", name, descriptor)));
+        }
+        // Safe because the layout of the object contains the field, and the value has its type
+        unsafe { value.write(address) };
+        Ok(())
+    }
+
+    fn find_field(&self, object: &JavaRef, name: &str, descriptor: &str) -> Result<(*mut u8, FieldDescriptor), VmError> {
+        let object = self.get_ref(object);
+        let no_such_field = || VmError::NoSuchField(format!("{}:{}", name, descriptor));
+        // Arrays don't have fields
+        let class = class_of(&self.class_store, object).ok_or_else(no_such_field)?;
+        let field = find_instance_field(&self.class_store, class, name, descriptor).ok_or_else(no_such_field)?;
+        // Safe because the field lies inside of the object
+        Ok((unsafe { (object as *mut u8).add(field.offset) }, field.descriptor))
+    }
+
+    /// Checks if an object is an instance of a class, like `instanceof` does. The class is loaded if it wasn't yet.
+    pub fn is_instance_of(&mut self, object: &JavaRef, class: &str) -> Result<bool, VmError> {
+        let class = self.load_class(class)?;
+        Ok(is_instance(&self.class_store, self.get_ref(object), ReferenceType::Class(class)))
+    }
+
+    /// Creates a primitive array that holds a copy of `elements`
+    pub fn new_array<E: JavaArrayElement>(&mut self, elements: &[E]) -> JavaRef {
+        let ty = ArrayType::primitive(&E::DESCRIPTOR).expect("array elements are primitives");
+        let array = allocate_array(&self.class_store, &self.jit_engine, ty, elements.len());
+        // Safe because the new array has room for the elements
+        unsafe { (array.add(ARRAY_HEADER_SIZE) as *mut E).copy_from_nonoverlapping(elements.as_ptr(), elements.len()) };
+        self.hold(array as JavaObject, false).unwrap()
+    }
+
+    /// Borrows the elements of a primitive array, whose elements have to be of type `E`
+    pub fn array_elements<E: JavaArrayElement>(&self, array: &JavaRef) -> Result<&[E], VmError> {
+        let (elements, length) = self.find_elements::<E>(array)?;
+        // Safe because the array is pinned by the reference, which can't be released while the vm is borrowed
+        Ok(unsafe { slice::from_raw_parts(elements, length) })
+    }
+
+    /// Borrows the elements of a primitive array mutably, see [VirtualMachine::array_elements]
+    pub fn array_elements_mut<E: JavaArrayElement>(&mut self, array: &JavaRef) -> Result<&mut [E], VmError> {
+        let (elements, length) = self.find_elements::<E>(array)?;
+        // Safe because the array is pinned by the reference, and nothing else can use it while the vm is borrowed mutably
+        Ok(unsafe { slice::from_raw_parts_mut(elements, length) })
+    }
+
+    fn find_elements<E: JavaArrayElement>(&self, array: &JavaRef) -> Result<(*mut E, usize), VmError> {
+        let array = self.get_ref(array) as *mut u8;
+        match self.heap().kind(array) {
+            Some(ObjectKind::Array(ty)) if ArrayType::primitive(&E::DESCRIPTOR) == Some(ty) => {}
+            _ => return Err(VmError::ArrayTypeMismatch(E::DESCRIPTOR.to_string())),
+        }
+        // Safe because arrays start with their length, and allocations are aligned for every primitive type
+        Ok(unsafe { (array.add(ARRAY_HEADER_SIZE) as *mut E, *(array as *const usize)) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interop::JavaValue;
+    use crate::object::{allocate_object, ARRAY_HEADER_SIZE};
+    use crate::test_util::bootstrapped_vm as vm;
+    use crate::{VirtualMachine, VmError};
+
+    #[test]
+    fn scopes() {
+        let mut vm = vm();
+        let integer = vm.load_class("java/lang/Integer").unwrap();
+        let allocate = |vm: &VirtualMachine<_, _>| allocate_object(&vm.class_store, &vm.jit_engine, integer);
+        let (global, local, promoted) = (allocate(&vm), allocate(&vm), allocate(&vm));
+
+        let global_ref = vm.new_ref(global).unwrap();
+        assert!(vm.new_ref(std::ptr::null_mut()).is_none());
+        let promoted_ref = vm.local_scope(|vm| {
+            let local_ref = vm.new_ref(local).unwrap();
+            assert_eq!(vm.get_ref(&local_ref), local);
+            let promoted_ref = vm.new_ref(promoted).unwrap();
+            let promoted_ref = vm.local_scope(|vm| vm.new_global_ref(&promoted_ref));
+            vm.collect_garbage();
+            assert!(vm.heap().kind(local as *const u8).is_some());
+            promoted_ref
+        });
+        vm.collect_garbage();
+        assert!(vm.heap().kind(local as *const u8).is_none());
+        assert_eq!(vm.get_ref(&global_ref), global);
+        assert_eq!(vm.get_ref(&promoted_ref), promoted);
+
+        vm.release_ref(global_ref);
+        vm.release_ref(promoted_ref);
+        vm.collect_garbage();
+        assert!(vm.heap().kind(global as *const u8).is_none());
+        assert!(vm.heap().kind(promoted as *const u8).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "released")]
+    fn released_reference() {
+        let mut vm = vm();
+        let integer = vm.load_class("java/lang/Integer").unwrap();
+        let object = allocate_object(&vm.class_store, &vm.jit_engine, integer);
+        let reference = vm.local_scope(|vm| vm.new_ref(object).unwrap());
+        // The slot is used again, but the old reference still can't be used
+        vm.new_ref(object).unwrap();
+        vm.get_ref(&reference);
+    }
+
+    #[test]
+    fn fields_and_instances() {
+        let mut vm = vm();
+        let integer = vm.load_class("java/lang/Integer").unwrap();
+        let object = allocate_object(&vm.class_store, &vm.jit_engine, integer);
+        let object = vm.new_ref(object).unwrap();
+
+        assert_eq!(vm.get_field(&object, "value", "I").unwrap(), JavaValue::Int(0));
+        vm.set_field(&object, "value", "I", JavaValue::Int(-7)).unwrap();
+        assert_eq!(vm.get_field(&object, "value", "I").unwrap(), JavaValue::Int(-7));
+        assert!(matches!(vm.set_field(&object, "value", "I", JavaValue::Long(1)), Err(VmError::ArgumentMismatch(_))));
+        assert!(matches!(vm.get_field(&object, "value", "J"), Err(VmError::NoSuchField(_))));
+        assert!(matches!(vm.get_field(&object, "missing", "I"), Err(VmError::NoSuchField(_))));
+
+        assert!(vm.is_instance_of(&object, "java/lang/Number").unwrap());
+        assert!(vm.is_instance_of(&object, "java/lang/Object").unwrap());
+        assert!(!vm.is_instance_of(&object, "java/lang/String").unwrap());
+        assert!(matches!(vm.is_instance_of(&object, "Missing"), Err(VmError::LoadError(_))));
+        assert!(matches!(vm.new_object("java/lang/Number", "()V", &[]), Err(VmError::InstantiationError(_))));
+        // References are checked against the class of the object
+        let integer = JavaValue::Object(vm.get_ref(&object));
+        assert!(matches!(vm.new_object("java/lang/Integer", "(Ljava/lang/String;)V", &[integer]), Err(VmError::ArgumentMismatch(_))));
+        assert!(matches!(vm.invoke("java/lang/Integer", "valueOf", "([I)V", &[integer]), Err(VmError::ArgumentMismatch(_))));
+        // The object a constructor is called on takes a register too
+        assert!(matches!(vm.new_object("java/lang/Integer", "(IIIIII)V", &[JavaValue::Int(0); 6]), Err(VmError::TooManyArguments(_))));
+        assert!(matches!(vm.invoke("java/lang/Integer", "sum", "(IIIIIII)I", &[JavaValue::Int(0); 7]), Err(VmError::TooManyArguments(_))));
+    }
+
+    #[test]
+    fn arrays() {
+        let mut vm = vm();
+        let ints = vm.new_array(&[1, -2, i32::MAX]);
+        let doubles = vm.new_array(&[0.5f64; 1000]);
+        let empty = vm.new_array::<u16>(&[]);
+        vm.collect_garbage();
+
+        assert_eq!(vm.array_elements::<i32>(&ints).unwrap(), &[1, -2, i32::MAX]);
+        vm.array_elements_mut::<i32>(&ints).unwrap()[1] = 7;
+        assert_eq!(vm.array_elements::<i32>(&ints).unwrap(), &[1, 7, i32::MAX]);
+        assert!(vm.array_elements::<f64>(&doubles).unwrap().iter().all(|&element| element == 0.5));
+        assert!(vm.array_elements::<u16>(&empty).unwrap().is_empty());
+        // The elements are used in place
+        let address = vm.get_ref(&doubles) as usize;
+        assert_eq!(vm.array_elements::<f64>(&doubles).unwrap().as_ptr() as usize, address + ARRAY_HEADER_SIZE);
+
+        assert!(matches!(vm.array_elements::<i64>(&ints), Err(VmError::ArrayTypeMismatch(_))));
+        assert!(matches!(vm.array_elements::<i8>(&doubles), Err(VmError::ArrayTypeMismatch(_))));
+        assert!(vm.is_instance_of(&ints, "java/lang/Object").unwrap());
+        assert!(!vm.is_instance_of(&ints, "java/lang/Integer").unwrap());
+        // Elements of the same size but another type don't match either
+        assert!(matches!(vm.array_elements::<f32>(&ints), Err(VmError::ArrayTypeMismatch(_))));
+        assert!(matches!(vm.array_elements::<i64>(&doubles), Err(VmError::ArrayTypeMismatch(_))));
+        let integer = vm.load_class("java/lang/Integer").unwrap();
+        let object = allocate_object(&vm.class_store, &vm.jit_engine, integer);
+        let object = vm.new_ref(object).unwrap();
+        assert!(matches!(vm.array_elements::<i32>(&object), Err(VmError::ArrayTypeMismatch(_))));
+    }
+}
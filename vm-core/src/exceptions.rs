@@ -0,0 +1,359 @@
+//! Throwing and catching java exceptions.
+//!
+//! A thrown exception is stored as the pending exception of the vm. Compiled code checks it after everything
+//! that can throw, then either jumps to the handler that catches it or returns to its caller, which does the same.
+//! Once it reaches rust code, it becomes a [JavaException].
+//!
+//! Some instructions throw by themselves, like a field access on null. Those are the [RuntimeException]s,
+//! which also include the linkage errors of references that can't be resolved.
+
+use std::cell::Cell;
+
+use classfile_parser::bytecode::Instruction;
+use thiserror::Error;
+
+use crate::class_store::{ClassStoreIsh, LoadedClassRef, LoadedMethodRef};
+use crate::classfile_util::get_code_attribute;
+use crate::embedding::JavaRef;
+use crate::object::{allocate_object, ObjectHeader};
+use crate::initialization::InitError;
+use crate::resolution::{resolve_class, ResolveError};
+use crate::strings::StringError;
+use crate::{ClassResolver, JitCompiler};
+
+/// The exception that's being thrown, if any. It's a garbage collection root.
+#[derive(Default)]
+pub struct PendingException {
+    /// The address of the exception, or 0
+    exception: Cell<usize>,
+}
+
+impl PendingException {
+    pub fn get(&self) -> Option<*mut ObjectHeader> {
+        let exception = self.exception.get();
+        (exception != 0).then_some(exception as *mut ObjectHeader)
+    }
+
+    /// Starts throwing an exception
+    pub fn set(&self, exception: *mut ObjectHeader) {
+        self.exception.set(exception as usize);
+    }
+
+    /// Stops throwing the exception, because it was caught
+    pub fn take(&self) -> Option<*mut ObjectHeader> {
+        let exception = self.get();
+        self.exception.set(0);
+        exception
+    }
+
+    /// Where compiled code can read and write the pending exception. It's null when nothing is thrown.
+    pub fn address(&self) -> *mut usize {
+        self.exception.as_ptr()
+    }
+}
+
+/// An exception that java code threw, and that wasn't caught
+#[derive(Error, Debug)]
+#[error("uncaught exception {class}")]
+pub struct JavaException {
+    /// The binary name of the class of the exception, like `java/lang/IllegalStateException`
+    pub class: String,
+    /// Pins the exception like any other reference, so it's released with the scope it was created in
+    pub exception: JavaRef,
+}
+
+/// Exceptions that the vm throws when an instruction or a native of the vm can't complete normally
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RuntimeException {
+    NullPointer,
+    ArrayIndexOutOfBounds,
+    Arithmetic,
+    NegativeArraySize,
+    ClassCast,
+    ArrayStore,
+    StringIndexOutOfBounds,
+    NoClassDefFound,
+    ExceptionInInitializer,
+    IncompatibleClassChange,
+    NoSuchField,
+    NoSuchMethod,
+    AbstractMethod,
+    IllegalAccess,
+    Instantiation,
+    BootstrapMethod,
+    Verify,
+    UnsatisfiedLink,
+}
+
+impl RuntimeException {
+    pub const ALL: [RuntimeException; 18] = [
+        RuntimeException::NullPointer,
+        RuntimeException::ArrayIndexOutOfBounds,
+        RuntimeException::Arithmetic,
+        RuntimeException::NegativeArraySize,
+        RuntimeException::ClassCast,
+        RuntimeException::ArrayStore,
+        RuntimeException::StringIndexOutOfBounds,
+        RuntimeException::NoClassDefFound,
+        RuntimeException::ExceptionInInitializer,
+        RuntimeException::IncompatibleClassChange,
+        RuntimeException::NoSuchField,
+        RuntimeException::NoSuchMethod,
+        RuntimeException::AbstractMethod,
+        RuntimeException::IllegalAccess,
+        RuntimeException::Instantiation,
+        RuntimeException::BootstrapMethod,
+        RuntimeException::Verify,
+        RuntimeException::UnsatisfiedLink,
+    ];
+
+    /// The errors of resolving and initializing classes, which any code that references another class can throw
+    pub const LINKAGE_ERRORS: [RuntimeException; 10] = [
+        RuntimeException::NoClassDefFound,
+        RuntimeException::ExceptionInInitializer,
+        RuntimeException::IncompatibleClassChange,
+        RuntimeException::NoSuchField,
+        RuntimeException::NoSuchMethod,
+        RuntimeException::AbstractMethod,
+        RuntimeException::IllegalAccess,
+        RuntimeException::Instantiation,
+        RuntimeException::BootstrapMethod,
+        RuntimeException::Verify,
+    ];
+
+    pub fn class_name(self) -> &'static str {
+        match self {
+            RuntimeException::NullPointer => "java/lang/NullPointerException",
+            RuntimeException::ArrayIndexOutOfBounds => "java/lang/ArrayIndexOutOfBoundsException",
+            RuntimeException::Arithmetic => "java/lang/ArithmeticException",
+            RuntimeException::NegativeArraySize => "java/lang/NegativeArraySizeException",
+            RuntimeException::ClassCast => "java/lang/ClassCastException",
+            RuntimeException::ArrayStore => "java/lang/ArrayStoreException",
+            RuntimeException::StringIndexOutOfBounds => "java/lang/StringIndexOutOfBoundsException",
+            RuntimeException::NoClassDefFound => "java/lang/NoClassDefFoundError",
+            RuntimeException::ExceptionInInitializer => "java/lang/ExceptionInInitializerError",
+            RuntimeException::IncompatibleClassChange => "java/lang/IncompatibleClassChangeError",
+            RuntimeException::NoSuchField => "java/lang/NoSuchFieldError",
+            RuntimeException::NoSuchMethod => "java/lang/NoSuchMethodError",
+            RuntimeException::AbstractMethod => "java/lang/AbstractMethodError",
+            RuntimeException::IllegalAccess => "java/lang/IllegalAccessError",
+            RuntimeException::Instantiation => "java/lang/InstantiationError",
+            RuntimeException::BootstrapMethod => "java/lang/BootstrapMethodError",
+            RuntimeException::Verify => "java/lang/VerifyError",
+            RuntimeException::UnsatisfiedLink => "java/lang/UnsatisfiedLinkError",
+        }
+    }
+
+    /// The exceptions that an instruction can throw by itself.
+    /// See: https://docs.oracle.com/javase/specs/jvms/se21/html/jvms-6.html#jvms-6.5
+    pub fn thrown_by(instruction: &Instruction) -> &'static [RuntimeException] {
+        use RuntimeException::*;
+        match instruction {
+            Instruction::AAStore => &[NullPointer, ArrayIndexOutOfBounds, ArrayStore],
+            Instruction::AALoad | Instruction::BALoad | Instruction::CALoad | Instruction::DALoad |
+            Instruction::FAload | Instruction::IALoad | Instruction::LALoad | Instruction::SALoad |
+            Instruction::BAStore | Instruction::CAStore | Instruction::DAStore |
+            Instruction::FAstore | Instruction::IAstore | Instruction::LAStore | Instruction::SAStore => &[NullPointer, ArrayIndexOutOfBounds],
+            Instruction::ArrayLength | Instruction::AThrow | Instruction::GetField(_) | Instruction::Putfield(_) |
+            Instruction::InvokeVirtual(_) | Instruction::InvokeSpecial(_) | Instruction::InvokeInterface(..) |
+            Instruction::MonitorEnter | Instruction::MonitorExit => &[NullPointer],
+            Instruction::IDiv | Instruction::IRem | Instruction::LDiv | Instruction::LRem => &[Arithmetic],
+            Instruction::NewArray(_) | Instruction::ANewArray(_) | Instruction::MultiANewArray(..) => &[NegativeArraySize],
+            Instruction::Checkcast(_) => &[ClassCast],
+            _ => &[],
+        }
+    }
+}
+
+impl From<&ResolveError> for RuntimeException {
+    fn from(error: &ResolveError) -> Self {
+        match error {
+            // Verification would reject the class before it runs, if the vm verified classes
+            ResolveError::InvalidConstantPoolIndex(_) => RuntimeException::Verify,
+            ResolveError::NotLoaded(_) => RuntimeException::NoClassDefFound,
+            ResolveError::NoSuchFieldError(_) => RuntimeException::NoSuchField,
+            ResolveError::NoSuchMethodError(_) => RuntimeException::NoSuchMethod,
+            ResolveError::IncompatibleClassChangeError(_) => RuntimeException::IncompatibleClassChange,
+            ResolveError::InstantiationError(_) => RuntimeException::Instantiation,
+            ResolveError::AbstractMethodError(_) => RuntimeException::AbstractMethod,
+            ResolveError::IllegalAccessError(_) => RuntimeException::IllegalAccess,
+            ResolveError::BootstrapMethodError(_) => RuntimeException::BootstrapMethod,
+        }
+    }
+}
+
+impl From<&InitError> for RuntimeException {
+    fn from(error: &InitError) -> Self {
+        match error {
+            InitError::ExceptionInInitializerError(_) => RuntimeException::ExceptionInInitializer,
+            InitError::NoClassDefFoundError(_) => RuntimeException::NoClassDefFound,
+            InitError::StringError(error) => error.into(),
+        }
+    }
+}
+
+impl From<&StringError> for RuntimeException {
+    /// Strings can't be created without a usable `java/lang/String`
+    fn from(_error: &StringError) -> Self {
+        RuntimeException::NoClassDefFound
+    }
+}
+
+/// The classes of the [RuntimeException]s, once they're loaded. The vm loads them when it loads code that
+/// can throw them, and defines stand-ins for the ones that the class library doesn't have.
+#[derive(Default)]
+pub struct RuntimeExceptionClasses {
+    classes: [Cell<Option<LoadedClassRef>>; RuntimeException::ALL.len()],
+}
+
+impl RuntimeExceptionClasses {
+    pub fn class(&self, exception: RuntimeException) -> Option<LoadedClassRef> {
+        self.classes[exception as usize].get()
+    }
+
+    pub(crate) fn set_class(&self, exception: RuntimeException, class: LoadedClassRef) {
+        self.classes[exception as usize].set(Some(class));
+    }
+}
+
+/// Creates a runtime exception and makes it the pending exception. Its constructor isn't run,
+/// so it has no message. The vm loads the class with any code that can throw the exception, see
+/// [RuntimeExceptionClasses]. If the class couldn't be initialized, the exception is still thrown.
+pub fn throw_runtime_exception<J: JitCompiler>(resolver: &impl ClassResolver<J>, jit: &J, exception: RuntimeException) -> *mut ObjectHeader {
+    let class = resolver.runtime_exceptions().class(exception)
+        .expect("the vm loads the runtime exceptions with the code that throws them");
+    // The object doesn't need the static fields, so failing to initialize them doesn't stop the throw
+    let _ = resolver.initialize(class, jit);
+    let object = allocate_object(resolver, jit, class);
+    resolver.exception().set(object);
+    object
+}
+
+/// Finds the handler in `method` that catches an exception of class `exception`, thrown by the instruction at `pc`.
+/// Handlers are tried in the order of the exception table. A handler catches subclasses of its `catch_type`,
+/// and a `catch_type` of 0 catches everything, which is how `finally` is compiled.
+/// Returns the address of the handler in the code.
+pub fn find_handler<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, method: LoadedMethodRef, pc: usize, exception: LoadedClassRef) -> Result<Option<usize>, ResolveError> {
+    let code = match get_code_attribute(resolver.retrieve(method.class_ref).method_info(method)) {
+        Some(code) => code,
+        None => return Ok(None),
+    };
+    for entry in &code.exception_table {
+        if pc < entry.start_pc as usize || pc >= entry.end_pc as usize {
+            continue;
+        }
+        if entry.catch_type == 0 || resolver.is_subclass_of(exception, resolve_class(resolver, method.class_ref, entry.catch_type)?) {
+            return Ok(Some(entry.handler_pc as usize));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use classfile_parser::attributes::ExceptionTableEntry;
+    use classfile_parser::builder::ClassBuilder;
+    use classfile_parser::bytecode::Instruction;
+    use classfile_parser::class_file::{ClassAccessFlags, MethodAccessFlags};
+    use crate::class_store::ClassStoreIsh;
+    use crate::class_loaders::{BootstrapClassLoader, ParentFirstClassLoader};
+    use crate::exceptions::{find_handler, throw_runtime_exception, JavaException, RuntimeException};
+    use crate::object::allocate_object;
+    use crate::resolution::ResolveError;
+    use crate::test_util::{build_class, BuiltClassLoader, NoJit};
+    use crate::{ClassResolver, VirtualMachine, VmError};
+
+    #[test]
+    fn handlers() {
+        let mut main = ClassBuilder::new("Main", None);
+        let state = main.class("IllegalStateException");
+        let runtime = main.class("RuntimeException");
+        let missing = main.class("Missing");
+        let handler = |start_pc, end_pc, handler_pc, catch_type| ExceptionTableEntry { start_pc, end_pc, handler_pc, catch_type };
+        let throw = || [Instruction::AConstNull, Instruction::AThrow];
+        let mut code = [throw(), throw(), throw()].concat();
+        code.extend(vec![Instruction::AThrow; 4]);
+        main.method_with_handlers(MethodAccessFlags::STATIC, "run", "()V", code, vec![
+            handler(0, 4, 6, state),
+            handler(0, 6, 7, runtime),
+            handler(2, 4, 8, 0),
+            handler(4, 6, 9, missing),
+        ]);
+        let mut vm = VirtualMachine::new(BuiltClassLoader::new(vec![
+            main.build().unwrap(),
+            build_class("Throwable", None, &[], ClassAccessFlags::PUBLIC),
+            build_class("RuntimeException", Some("Throwable"), &[], ClassAccessFlags::PUBLIC),
+            build_class("IllegalStateException", Some("RuntimeException"), &[], ClassAccessFlags::PUBLIC),
+        ]), NoJit);
+        let main = vm.load_class("Main").unwrap();
+        let run = vm.class_store.retrieve_method_ref(main, "run", "()V").unwrap();
+        let throwable = vm.load_class("Throwable").unwrap();
+        let runtime = vm.load_class("RuntimeException").unwrap();
+        let state = vm.load_class("IllegalStateException").unwrap();
+        let store = &vm.class_store;
+
+        assert_eq!(find_handler(store, run, 1, state), Ok(Some(6)));
+        assert_eq!(find_handler(store, run, 1, runtime), Ok(Some(7)));
+        assert_eq!(find_handler(store, run, 1, throwable), Ok(None));
+        assert_eq!(find_handler(store, run, 3, throwable), Ok(Some(8)));
+        assert_eq!(find_handler(store, run, 5, runtime), Ok(Some(7)));
+        assert_eq!(find_handler(store, run, 5, throwable), Err(ResolveError::NotLoaded("Missing".to_owned())));
+        assert_eq!(find_handler(store, run, 6, state), Ok(None));
+
+        let exception = allocate_object(store, &vm.jit_engine, state);
+        store.exception().set(exception);
+        store.collect_garbage(&vm.jit_engine);
+        assert_eq!(store.exception().get(), Some(exception));
+        let uncaught = match vm.check_exception() {
+            Err(VmError::Exception(uncaught)) => uncaught,
+            _ => panic!("expected an uncaught exception"),
+        };
+        assert_eq!(uncaught.class, "IllegalStateException");
+        assert_eq!(vm.get_ref(&uncaught.exception), exception);
+        assert_eq!(vm.class_store.exception().get(), None);
+        assert!(vm.check_exception().is_ok());
+
+        // The reference keeps the exception alive until it's released
+        vm.class_store.collect_garbage(&vm.jit_engine);
+        assert!(vm.class_store.heap().kind(exception as *const u8).is_some());
+        vm.release_ref(uncaught.exception);
+        vm.class_store.collect_garbage(&vm.jit_engine);
+        assert!(vm.class_store.heap().kind(exception as *const u8).is_none());
+    }
+
+    #[test]
+    fn runtime_exceptions() {
+        let classes = || {
+            let mut main = ClassBuilder::new("Main", None);
+            main.method(MethodAccessFlags::STATIC, "get", "([II)I", vec![Instruction::ALoad(0), Instruction::ILoad(1), Instruction::IALoad, Instruction::IReturn]);
+            main.method(MethodAccessFlags::STATIC, "divide", "(II)I", vec![Instruction::ILoad(0), Instruction::ILoad(1), Instruction::IDiv, Instruction::IReturn]);
+            BuiltClassLoader::new(vec![main.build().unwrap()])
+        };
+
+        let mut vm = VirtualMachine::new(ParentFirstClassLoader::new(BootstrapClassLoader::new(), classes()), NoJit);
+        let main = vm.load_class("Main").unwrap();
+        vm.initialize(main).unwrap();
+        let exceptions = vm.class_store.runtime_exceptions();
+        for exception in [RuntimeException::NullPointer, RuntimeException::ArrayIndexOutOfBounds, RuntimeException::Arithmetic] {
+            assert_eq!(exceptions.class(exception), vm.lookup(exception.class_name()));
+            assert!(exceptions.class(exception).is_some());
+        }
+        // The natives of the bootstrap Object can throw the others
+        assert_eq!(exceptions.class(RuntimeException::ClassCast), vm.lookup("java/lang/ClassCastException"));
+
+        let exception = throw_runtime_exception(&vm.class_store, &vm.jit_engine, RuntimeException::Arithmetic);
+        assert_eq!(vm.class_store.exception().get(), Some(exception));
+        let uncaught = vm.check_exception().unwrap_err();
+        assert!(matches!(uncaught, VmError::Exception(JavaException { class, .. }) if class == "java/lang/ArithmeticException"));
+
+        // Class libraries without the exceptions get stand-ins
+        let mut vm = VirtualMachine::new(classes(), NoJit);
+        let main = vm.load_class("Main").unwrap();
+        vm.initialize(main).unwrap();
+        let stand_in = vm.class_store.runtime_exceptions().class(RuntimeException::Arithmetic).unwrap();
+        assert_eq!(vm.class_store.retrieve(stand_in).name(), "java/lang/ArithmeticException");
+        throw_runtime_exception(&vm.class_store, &vm.jit_engine, RuntimeException::Arithmetic);
+        let uncaught = vm.check_exception().unwrap_err();
+        assert!(matches!(uncaught, VmError::Exception(JavaException { class, .. }) if class == "java/lang/ArithmeticException"));
+    }
+}
@@ -0,0 +1,316 @@
+//! The heap that objects and arrays are allocated on, and the garbage collectors that free them again
+
+use std::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+
+use classfile_parser::descriptor::FieldDescriptor;
+
+use crate::class_store::LoadedClassRef;
+use crate::object::{ArrayType, ObjectHeader, ARRAY_HEADER_SIZE};
+use crate::{ClassResolver, JitCompiler};
+
+/// Every allocation is aligned to this, so that longs and doubles can be stored anywhere their size allows
+const ALIGNMENT: usize = 8;
+
+/// A collection starts once this many bytes are allocated. After a collection, the threshold becomes
+/// twice the amount of bytes that survived, so that the time spent collecting stays proportional to the allocations.
+const MIN_THRESHOLD: usize = 1 << 20;
+
+/// What an allocation contains, which tells a collector where its references are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectKind {
+    /// An object starting with an [ObjectHeader]. The class in the header knows where its reference fields are.
+    Instance,
+    /// An array, which starts with its length. The elements follow after the header.
+    Array(ArrayType),
+}
+
+/// Decides where objects go and when they are freed. Collectors don't need to know about classes or the vm,
+/// they're given the roots and a way to find the references inside of objects.
+pub trait GarbageCollector {
+    /// Allocates `size` zeroed bytes
+    fn allocate(&mut self, size: usize, kind: ObjectKind) -> *mut u8;
+
+    /// Frees every allocation that can't be reached from `roots`. Roots that are null or that
+    /// don't point to an allocation are ignored. `references` returns the offsets of the reference fields
+    /// in instances of a class, including the inherited ones.
+    fn collect<'a>(&mut self, roots: &[*mut u8], references: &dyn Fn(LoadedClassRef) -> &'a [usize]);
+
+    /// Amount of bytes that are currently allocated
+    fn allocated(&self) -> usize;
+
+    /// What the allocation at `address` contains, or `None` if nothing was allocated there
+    fn kind(&self, address: *const u8) -> Option<ObjectKind>;
+}
+
+/// A precise stop-the-world collector that marks everything reachable from the roots, and then frees the rest.
+/// Objects never move.
+#[derive(Default)]
+pub struct MarkSweep {
+    /// Every allocation by its address
+    allocations: HashMap<usize, Allocation>,
+    allocated: usize,
+}
+
+struct Allocation {
+    size: usize,
+    kind: ObjectKind,
+    marked: bool,
+}
+
+impl MarkSweep {
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size.max(1), ALIGNMENT).unwrap()
+    }
+}
+
+impl GarbageCollector for MarkSweep {
+    fn allocate(&mut self, size: usize, kind: ObjectKind) -> *mut u8 {
+        let layout = Self::layout(size);
+        // Safe because the size is never zero
+        let address = unsafe { alloc_zeroed(layout) };
+        if address.is_null() {
+            handle_alloc_error(layout);
+        }
+        self.allocations.insert(address as usize, Allocation { size, kind, marked: false });
+        self.allocated += size;
+        address
+    }
+
+    fn collect<'a>(&mut self, roots: &[*mut u8], references: &dyn Fn(LoadedClassRef) -> &'a [usize]) {
+        let mut pending: Vec<usize> = roots.iter().map(|&root| root as usize).collect();
+        while let Some(address) = pending.pop() {
+            let allocation = match self.allocations.get_mut(&address) {
+                Some(allocation) if !allocation.marked => allocation,
+                _ => continue,
+            };
+            allocation.marked = true;
+            // Safe because the allocation is still alive, and was filled in by the code that allocated it
+            unsafe {
+                match allocation.kind {
+                    ObjectKind::Instance => {
+                        let class = (*(address as *const ObjectHeader)).class;
+                        pending.extend(references(class).iter().map(|offset| *((address + offset) as *const usize)));
+                    }
+                    ObjectKind::Array(ty) if ty.component().is_some() => {
+                        let length = *(address as *const usize);
+                        pending.extend((0..length).map(|index| *((address + ARRAY_HEADER_SIZE + index * ty.element_size()) as *const usize)));
+                    }
+                    ObjectKind::Array(_) => {}
+                }
+            }
+        }
+
+        let allocated = &mut self.allocated;
+        self.allocations.retain(|&address, allocation| {
+            if std::mem::take(&mut allocation.marked) {
+                return true;
+            }
+            *allocated -= allocation.size;
+            // Safe because nothing can reach the allocation anymore
+            unsafe { dealloc(address as *mut u8, Self::layout(allocation.size)) };
+            false
+        });
+    }
+
+    fn allocated(&self) -> usize {
+        self.allocated
+    }
+
+    fn kind(&self, address: *const u8) -> Option<ObjectKind> {
+        self.allocations.get(&(address as usize)).map(|allocation| allocation.kind)
+    }
+}
+
+impl Drop for MarkSweep {
+    fn drop(&mut self) {
+        for (&address, allocation) in &self.allocations {
+            // Safe because the vm that used these objects is gone
+            unsafe { dealloc(address as *mut u8, Self::layout(allocation.size)) };
+        }
+    }
+}
+
+/// The java heap. Allocations should go through [ClassResolver::allocate], which collects garbage when needed.
+pub struct Heap {
+    collector: RefCell<Box<dyn GarbageCollector>>,
+    /// Objects that rust code holds on to, by handle. Freed slots are `None` and get reused.
+    handles: RefCell<Vec<Option<*mut u8>>>,
+    threshold: Cell<usize>,
+}
+
+/// Keeps an object alive while rust code holds on to it. The handle has to be given back
+/// with [Heap::release], otherwise the object is never freed.
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub struct Handle(usize);
+
+impl Heap {
+    pub fn new(collector: Box<dyn GarbageCollector>) -> Self {
+        Heap { collector: RefCell::new(collector), handles: Default::default(), threshold: Cell::new(MIN_THRESHOLD) }
+    }
+
+    /// Allocates `size` zeroed bytes without collecting first
+    pub fn allocate(&self, size: usize, kind: ObjectKind) -> *mut u8 {
+        self.collector.borrow_mut().allocate(size, kind)
+    }
+
+    /// Amount of bytes that are currently allocated
+    pub fn allocated(&self) -> usize {
+        self.collector.borrow().allocated()
+    }
+
+    /// What the allocation at `object` contains, or `None` if it isn't on this heap
+    pub fn kind(&self, object: *const u8) -> Option<ObjectKind> {
+        self.collector.borrow().kind(object)
+    }
+
+    /// Checks if enough was allocated since the last collection to collect again
+    pub fn should_collect(&self) -> bool {
+        self.allocated() >= self.threshold.get()
+    }
+
+    /// Frees everything that isn't reachable from `roots` or from a handle
+    pub fn collect<'a>(&self, roots: &[*mut u8], references: &dyn Fn(LoadedClassRef) -> &'a [usize]) {
+        let mut roots = roots.to_vec();
+        roots.extend(self.handles.borrow().iter().flatten());
+        let mut collector = self.collector.borrow_mut();
+        collector.collect(&roots, references);
+        self.threshold.set(MIN_THRESHOLD.max(collector.allocated() * 2));
+    }
+
+    /// Makes an object a root until the handle is released
+    pub fn hold(&self, object: *mut u8) -> Handle {
+        let mut handles = self.handles.borrow_mut();
+        match handles.iter().position(Option::is_none) {
+            Some(index) => {
+                handles[index] = Some(object);
+                Handle(index)
+            }
+            None => {
+                handles.push(Some(object));
+                Handle(handles.len() - 1)
+            }
+        }
+    }
+
+    /// The object a handle holds on to
+    pub fn get(&self, handle: &Handle) -> *mut u8 {
+        self.handles.borrow()[handle.0].unwrap()
+    }
+
+    /// Lets the object of a handle be collected again, if nothing else refers to it
+    pub fn release(&self, handle: Handle) {
+        self.handles.borrow_mut()[handle.0] = None;
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap::new(Box::<MarkSweep>::default())
+    }
+}
+
+/// Collects garbage. The roots are the reference fields in static storage, the references
+/// in frames of compiled code that are running, the exception that's being thrown and the handles held by rust code.
+pub fn collect_garbage<J: JitCompiler>(resolver: &impl ClassResolver<J>, jit: &J) {
+    let mut roots = Vec::new();
+    for index in 0..resolver.class_count() {
+        let statics = &resolver.retrieve(LoadedClassRef::from_raw(index)).statics;
+        for (slot, field) in statics.fields().iter().enumerate() {
+            if matches!(field.descriptor, FieldDescriptor::Object(_) | FieldDescriptor::Array(_)) {
+                // Safe because reference fields always hold an address
+                roots.push(unsafe { *statics.address(slot) } as usize as *mut u8);
+            }
+        }
+    }
+    roots.extend(resolver.exception().get().map(|exception| exception as *mut u8));
+    jit.visit_frame_roots(&mut |object| roots.push(object));
+    resolver.heap().collect(&roots, &|class| resolver.retrieve(class).layout.references());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::size_of;
+    use std::ptr::null_mut;
+
+    use classfile_parser::builder::ClassBuilder;
+    use classfile_parser::class_file::FieldAccessFlags;
+    use crate::class_store::ClassStoreIsh;
+    use crate::heap::{GarbageCollector, MarkSweep, ObjectKind};
+    use crate::object::{allocate_object, ArrayType, BaseType, ARRAY_HEADER_SIZE, HEADER_SIZE};
+    use crate::test_util::{BuiltClassLoader, NoJit};
+    use crate::{ClassResolver, VirtualMachine};
+
+    /// `int[][]`, whose elements are references
+    const REFERENCES: ObjectKind = ObjectKind::Array(ArrayType { base: BaseType::Int, dimensions: 2 });
+
+    /// Allocates an array of references with the given elements
+    fn array(collector: &mut MarkSweep, elements: &[*mut u8]) -> *mut u8 {
+        let array = collector.allocate(ARRAY_HEADER_SIZE + elements.len() * size_of::<usize>(), REFERENCES);
+        unsafe {
+            *(array as *mut usize) = elements.len();
+            for (index, &element) in elements.iter().enumerate() {
+                *(array.add(ARRAY_HEADER_SIZE) as *mut *mut u8).add(index) = element;
+            }
+        }
+        array
+    }
+
+    #[test]
+    fn mark_sweep() {
+        let mut collector = MarkSweep::default();
+        let bytes = collector.allocate(3, ObjectKind::Array(ArrayType { base: BaseType::Byte, dimensions: 1 }));
+        let cycle = array(&mut collector, &[null_mut()]);
+        unsafe { *(cycle.add(ARRAY_HEADER_SIZE) as *mut *mut u8) = cycle };
+        let garbage = array(&mut collector, &[bytes, cycle]);
+        let root = array(&mut collector, &[bytes, null_mut()]);
+        assert_eq!(collector.allocated(), 3 + 3 * ARRAY_HEADER_SIZE + 5 * size_of::<usize>());
+        assert_eq!(collector.kind(root), Some(REFERENCES));
+        assert_eq!(collector.kind(unsafe { root.add(1) }), None);
+
+        collector.collect(&[root, null_mut()], &|_| unreachable!());
+        assert_eq!(collector.allocated(), 3 + ARRAY_HEADER_SIZE + 2 * size_of::<usize>());
+        collector.collect(&[root, garbage], &|_| unreachable!());
+        assert_eq!(collector.allocated(), 3 + ARRAY_HEADER_SIZE + 2 * size_of::<usize>());
+        collector.collect(&[], &|_| unreachable!());
+        assert_eq!(collector.allocated(), 0);
+    }
+
+    #[test]
+    fn roots() {
+        let mut builder = ClassBuilder::new("Node", None);
+        builder.field(FieldAccessFlags::empty(), "value", "I");
+        builder.field(FieldAccessFlags::empty(), "next", "LNode;");
+        builder.field(FieldAccessFlags::STATIC, "head", "LNode;");
+        let mut vm = VirtualMachine::new(BuiltClassLoader::new(vec![builder.build().unwrap()]), NoJit);
+        let node = vm.load_class("Node").unwrap();
+        let (store, jit) = (&vm.class_store, &vm.jit_engine);
+        let data = store.retrieve(node);
+        let next = data.layout.find("next", &"LNode;".parse().unwrap()).unwrap().offset;
+        let head = data.statics.address(data.statics.find("head", &"LNode;".parse().unwrap()).unwrap());
+        assert_eq!(data.layout.references(), [HEADER_SIZE]);
+
+        let new_node = |next_node: *mut u8| {
+            let object = allocate_object(store, jit, node) as *mut u8;
+            unsafe { *(object.add(next) as *mut *mut u8) = next_node };
+            object
+        };
+        let size = data.layout.size;
+        let linked = new_node(null_mut());
+        let first = new_node(linked);
+        unsafe { *head = first as u64 };
+        let held = new_node(null_mut());
+        let handle = store.heap().hold(held);
+        new_node(first);
+        assert_eq!(store.heap().allocated(), 4 * size);
+
+        store.collect_garbage(jit);
+        assert_eq!(store.heap().allocated(), 3 * size);
+        assert_eq!(store.heap().get(&handle), held);
+        store.heap().release(handle);
+        unsafe { *head = 0 };
+        store.collect_garbage(jit);
+        assert_eq!(store.heap().allocated(), 0);
+    }
+}
@@ -0,0 +1,206 @@
+use classfile_parser::class_file::MethodAccessFlags;
+use thiserror::Error;
+
+use crate::class_store::{ClassData, LoadedClassRef};
+use crate::exceptions::{throw_runtime_exception, RuntimeException};
+use crate::object::{class_of, find_instance_field, ObjectHeader};
+use crate::strings::{intern, StringError};
+use crate::{ClassResolver, JitCompiler};
+
+/// How far along a class is with initialization.
+/// See: https://docs.oracle.com/javase/specs/jvms/se16/html/jvms-5.html#jvms-5.5
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitState {
+    /// Loaded and linked, but `<clinit>` hasn't run yet
+    Linked,
+    /// The class is being initialized. Requests to initialize it during this time succeed immediately,
+    /// so that `<clinit>` can use its own class.
+    InProgress,
+    Initialized,
+    /// Initialization failed, the class can't be used
+    Erroneous,
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum InitError {
+    /// The static initializer of the class threw. The error that it threw, or an `ExceptionInInitializerError`
+    /// with the exception as its cause, is left as the pending exception.
+    #[error("exception in the static initializer of {0}")]
+    ExceptionInInitializerError(String),
+    /// The class failed to initialize before
+    #[error("could not initialize {0}")]
+    NoClassDefFoundError(String),
+    /// A static field has a string constant, but strings can't be created
+    #[error(transparent)]
+    StringError(#[from] StringError),
+}
+
+/// Initializes a class if it isn't initialized yet. This should happen right before the first
+/// `new`, `getstatic`, `putstatic` or `invokestatic` that targets the class.
+///
+/// The constant values of static final fields are assigned first, then the superclass and the superinterfaces
+/// with default methods are initialized, then `<clinit>` runs.
+pub fn initialize<J: JitCompiler>(resolver: &impl ClassResolver<J>, jit: &J, class: LoadedClassRef) -> Result<(), InitError> {
+    let data = resolver.retrieve(class);
+    match data.init_state() {
+        InitState::Initialized | InitState::InProgress => return Ok(()),
+        InitState::Erroneous => return Err(InitError::NoClassDefFoundError(data.name().to_owned())),
+        InitState::Linked => {}
+    }
+    data.set_init_state(InitState::InProgress);
+    if let Err(e) = data.statics.apply_constants(|value| intern(resolver, jit, value)) {
+        data.set_init_state(InitState::Erroneous);
+        return Err(e.into());
+    }
+
+    let mut supertypes = data.super_class.into_iter().collect::<Vec<_>>();
+    if !data.is_interface() {
+        // Interfaces only need to be initialized if one of their methods could run
+        let mut interfaces = Vec::new();
+        superinterfaces(resolver, &data.interfaces, &mut interfaces);
+        supertypes.extend(interfaces.into_iter().filter(|&interface| has_default_methods(resolver.retrieve(interface))));
+    }
+    for supertype in supertypes {
+        if let Err(e) = initialize(resolver, jit, supertype) {
+            data.set_init_state(InitState::Erroneous);
+            return Err(e);
+        }
+    }
+
+    if let Some(clinit) = resolver.retrieve_method_ref(class, "<clinit>", "()V") {
+        if data.retrieve_method(clinit).is_static() {
+            if let Err(exception) = jit.run_initializer(clinit, resolver) {
+                data.set_init_state(InitState::Erroneous);
+                throw_initializer_error(resolver, jit, exception);
+                return Err(InitError::ExceptionInInitializerError(data.name().to_owned()));
+            }
+        }
+    }
+
+    data.set_init_state(InitState::Initialized);
+    Ok(())
+}
+
+/// Makes what `<clinit>` threw pending. Errors are thrown as they are, other exceptions are wrapped
+/// in an `ExceptionInInitializerError` whose cause they become.
+fn throw_initializer_error<J: JitCompiler>(resolver: &impl ClassResolver<J>, jit: &J, exception: *mut ObjectHeader) {
+    // Pending exceptions stay alive while the wrapper is allocated
+    resolver.exception().set(exception);
+    let loaded = resolver.runtime_exceptions().class(RuntimeException::ExceptionInInitializer).is_some();
+    if !loaded || class_of(resolver, exception).is_none_or(|class| is_error(resolver, class)) {
+        return;
+    }
+    let wrapper = throw_runtime_exception(resolver, jit, RuntimeException::ExceptionInInitializer);
+    // A `Throwable` of another library might not have the field
+    if let Some(cause) = find_instance_field(resolver, unsafe { (*wrapper).class }, "cause", "Ljava/lang/Throwable;") {
+        // Safe because the field holds a reference
+        unsafe { *((wrapper as *mut u8).add(cause.offset) as *mut *mut ObjectHeader) = exception };
+    }
+}
+
+fn is_error<J: JitCompiler>(resolver: &impl ClassResolver<J>, class: LoadedClassRef) -> bool {
+    let mut current = Some(class);
+    while let Some(class) = current {
+        let data = resolver.retrieve(class);
+        if data.name() == "java/lang/Error" {
+            return true;
+        }
+        current = data.super_class;
+    }
+    false
+}
+
+/// Every superinterface of `interfaces` followed by the interface itself, in the order of the `interfaces` arrays
+fn superinterfaces<J: JitCompiler>(resolver: &impl ClassResolver<J>, interfaces: &[LoadedClassRef], found: &mut Vec<LoadedClassRef>) {
+    for &interface in interfaces {
+        superinterfaces(resolver, &resolver.retrieve(interface).interfaces, found);
+        if !found.contains(&interface) {
+            found.push(interface);
+        }
+    }
+}
+
+fn has_default_methods<J: JitCompiler>(interface: &ClassData<J>) -> bool {
+    interface.java_class.methods.iter().any(|method| !method.access_flags.intersects(MethodAccessFlags::ABSTRACT | MethodAccessFlags::STATIC))
+}
+
+#[cfg(test)]
+mod tests {
+    use classfile_parser::attributes::ConstantValue;
+    use classfile_parser::builder::ClassBuilder;
+    use classfile_parser::bytecode::Instruction;
+    use classfile_parser::class_file::{ClassAccessFlags, FieldAccessFlags, MethodAccessFlags};
+    use crate::class_store::ClassStoreIsh;
+    use crate::initialization::{InitError, InitState};
+    use crate::test_util::{BuiltClassLoader, InitJit};
+    use crate::VirtualMachine;
+
+    fn class_with_clinit(name: &str, super_class: Option<&str>, interfaces: &[&str], access_flags: ClassAccessFlags) -> ClassBuilder {
+        let mut builder = ClassBuilder::new(name, super_class);
+        builder.access_flags(access_flags);
+        for interface in interfaces {
+            builder.interface(interface);
+        }
+        builder.method(MethodAccessFlags::STATIC, "<clinit>", "()V", vec![Instruction::Return]);
+        builder
+    }
+
+    #[test]
+    fn superclass_first() {
+        let mut a = class_with_clinit("A", None, &[], ClassAccessFlags::PUBLIC);
+        a.constant_field(FieldAccessFlags::STATIC | FieldAccessFlags::FINAL, "LONG", "J", ConstantValue::Long(-7));
+        a.constant_field(FieldAccessFlags::STATIC | FieldAccessFlags::FINAL, "BYTE", "B", ConstantValue::Int(-1));
+        a.constant_field(FieldAccessFlags::STATIC, "NOT_FINAL", "I", ConstantValue::Int(3));
+        let b = class_with_clinit("B", Some("A"), &[], ClassAccessFlags::PUBLIC);
+        let mut vm = VirtualMachine::new(BuiltClassLoader::new(vec![a.build().unwrap(), b.build().unwrap()]), InitJit::default());
+
+        let b = vm.load_class("B").unwrap();
+        let a = vm.lookup("A").unwrap();
+        assert_eq!(vm.class_store.retrieve(a).init_state(), InitState::Linked);
+        vm.initialize(b).unwrap();
+        vm.initialize(b).unwrap();
+        assert_eq!(*vm.jit_engine.ran.borrow(), vec!["A", "B"]);
+        assert_eq!(vm.class_store.retrieve(a).init_state(), InitState::Initialized);
+        assert_eq!(vm.class_store.retrieve(b).init_state(), InitState::Initialized);
+
+        let statics = &vm.class_store.retrieve(a).statics;
+        unsafe {
+            assert_eq!(*(statics.address(0) as *const i64), -7);
+            assert_eq!(*(statics.address(1) as *const i8), -1);
+            assert_eq!(*(statics.address(2) as *const i32), 0);
+        }
+    }
+
+    #[test]
+    fn failed_initialization() {
+        let failing = class_with_clinit("FailingA", None, &[], ClassAccessFlags::PUBLIC);
+        let b = class_with_clinit("B", Some("FailingA"), &[], ClassAccessFlags::PUBLIC);
+        let mut vm = VirtualMachine::new(BuiltClassLoader::new(vec![failing.build().unwrap(), b.build().unwrap()]), InitJit::default());
+
+        let b = vm.load_class("B").unwrap();
+        let failing = vm.lookup("FailingA").unwrap();
+        assert_eq!(vm.initialize(b), Err(InitError::ExceptionInInitializerError("FailingA".to_owned())));
+        assert_eq!(vm.class_store.retrieve(b).init_state(), InitState::Erroneous);
+        assert_eq!(vm.initialize(failing), Err(InitError::NoClassDefFoundError("FailingA".to_owned())));
+        assert_eq!(vm.initialize(b), Err(InitError::NoClassDefFoundError("B".to_owned())));
+        assert_eq!(*vm.jit_engine.ran.borrow(), vec!["FailingA"]);
+    }
+
+    #[test]
+    fn interfaces_with_default_methods() {
+        let interface = ClassAccessFlags::PUBLIC | ClassAccessFlags::INTERFACE | ClassAccessFlags::ABSTRACT;
+        let mut with_default = class_with_clinit("I", None, &[], interface);
+        with_default.method(MethodAccessFlags::PUBLIC, "run", "()V", vec![Instruction::Return]);
+        let mut without_default = class_with_clinit("J", None, &["K"], interface);
+        without_default.method_without_code(MethodAccessFlags::PUBLIC | MethodAccessFlags::ABSTRACT, "run", "()V");
+        let mut indirect = class_with_clinit("K", None, &[], interface);
+        indirect.method(MethodAccessFlags::PUBLIC, "other", "()V", vec![Instruction::Return]);
+        let class = class_with_clinit("C", None, &["I", "J"], ClassAccessFlags::PUBLIC);
+        let classes = vec![with_default.build().unwrap(), without_default.build().unwrap(), indirect.build().unwrap(), class.build().unwrap()];
+        let mut vm = VirtualMachine::new(BuiltClassLoader::new(classes), InitJit::default());
+
+        let class = vm.load_class("C").unwrap();
+        vm.initialize(class).unwrap();
+        assert_eq!(*vm.jit_engine.ran.borrow(), vec!["I", "K", "C"]);
+    }
+}
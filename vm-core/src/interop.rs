@@ -1,11 +1,26 @@
+use std::mem::transmute;
+
+use classfile_parser::descriptor::{FieldDescriptor, MethodDescriptor};
+
+use crate::object::{is_instance, ObjectHeader, ReferenceType};
+use crate::{ClassResolver, JitCompiler, LoaderId};
+
 ////////////////////
 // Type constants //
 ////////////////////
 
 pub type JavaInt = i32;
-pub type JavaChar = i8;
+pub type JavaLong = i64;
+pub type JavaBoolean = bool;
+pub type JavaByte = i8;
+pub type JavaShort = i16;
+/// A UTF-16 code unit
+pub type JavaChar = u16;
 pub type JavaFloat = f32;
+pub type JavaDouble = f64;
 pub type JavaVoid = ();
+/// Any reference, the descriptor fragment is `java/lang/Object` but it fits every reference type
+pub type JavaObject = *mut ObjectHeader;
 
 ////////////
 // Traits //
@@ -20,7 +35,20 @@ pub unsafe trait JavaCompatibleReturnType {
 }
 
 pub unsafe trait JavaCompatibleFunction {
-    const DESCRIPTOR: &'static str;
+    /// The method descriptor of the function, like `(IJ)V`
+    fn descriptor() -> String;
+
+    /// Checks if the function can call a method with this descriptor. Any reference type fits a [JavaObject].
+    fn fits(descriptor: &MethodDescriptor) -> bool;
+}
+
+/// The elements of primitive arrays that rust code can borrow as slices. Every bit pattern has to be a valid value,
+/// so booleans are left out: java code can store any byte in a `boolean[]`.
+///
+/// # Safety
+/// Every bit pattern of the size of `DESCRIPTOR` has to be a valid value of the type
+pub unsafe trait JavaArrayElement: Copy {
+    const DESCRIPTOR: FieldDescriptor;
 }
 
 ///////////
@@ -36,6 +64,22 @@ unsafe impl JavaCompatibleArgumentType for JavaInt {
     const DESCRIPTOR_FRAGMENT: &'static str = "I";
 }
 
+unsafe impl JavaCompatibleArgumentType for JavaLong {
+    const DESCRIPTOR_FRAGMENT: &'static str = "J";
+}
+
+unsafe impl JavaCompatibleArgumentType for JavaBoolean {
+    const DESCRIPTOR_FRAGMENT: &'static str = "Z";
+}
+
+unsafe impl JavaCompatibleArgumentType for JavaByte {
+    const DESCRIPTOR_FRAGMENT: &'static str = "B";
+}
+
+unsafe impl JavaCompatibleArgumentType for JavaShort {
+    const DESCRIPTOR_FRAGMENT: &'static str = "S";
+}
+
 unsafe impl JavaCompatibleArgumentType for JavaChar {
     const DESCRIPTOR_FRAGMENT: &'static str = "C";
 }
@@ -44,6 +88,42 @@ unsafe impl JavaCompatibleArgumentType for JavaFloat {
     const DESCRIPTOR_FRAGMENT: &'static str = "F";
 }
 
+unsafe impl JavaCompatibleArgumentType for JavaDouble {
+    const DESCRIPTOR_FRAGMENT: &'static str = "D";
+}
+
+unsafe impl JavaCompatibleArgumentType for JavaObject {
+    const DESCRIPTOR_FRAGMENT: &'static str = "Ljava/lang/Object;";
+}
+
+unsafe impl JavaArrayElement for JavaByte {
+    const DESCRIPTOR: FieldDescriptor = FieldDescriptor::Byte;
+}
+
+unsafe impl JavaArrayElement for JavaShort {
+    const DESCRIPTOR: FieldDescriptor = FieldDescriptor::Short;
+}
+
+unsafe impl JavaArrayElement for JavaChar {
+    const DESCRIPTOR: FieldDescriptor = FieldDescriptor::Char;
+}
+
+unsafe impl JavaArrayElement for JavaInt {
+    const DESCRIPTOR: FieldDescriptor = FieldDescriptor::Int;
+}
+
+unsafe impl JavaArrayElement for JavaLong {
+    const DESCRIPTOR: FieldDescriptor = FieldDescriptor::Long;
+}
+
+unsafe impl JavaArrayElement for JavaFloat {
+    const DESCRIPTOR: FieldDescriptor = FieldDescriptor::Float;
+}
+
+unsafe impl JavaArrayElement for JavaDouble {
+    const DESCRIPTOR: FieldDescriptor = FieldDescriptor::Double;
+}
+
 unsafe impl JavaCompatibleReturnType for JavaVoid {
     const DESCRIPTOR_FRAGMENT: &'static str = "V";
 }
@@ -52,32 +132,277 @@ unsafe impl JavaCompatibleReturnType for JavaVoid {
 // Functions //
 ///////////////
 
+/// Checks if a parameter or return type of a rust function can be used for a java type, `None` being void
+pub(crate) fn fragment_fits(fragment: &str, ty: Option<&FieldDescriptor>) -> bool {
+    match ty {
+        Some(FieldDescriptor::Object(_) | FieldDescriptor::Array(_)) if fragment == <JavaObject as JavaCompatibleArgumentType>::DESCRIPTOR_FRAGMENT => true,
+        Some(ty) => fragment.parse::<FieldDescriptor>().is_ok_and(|fragment| &fragment == ty),
+        None => fragment == <JavaVoid as JavaCompatibleReturnType>::DESCRIPTOR_FRAGMENT,
+    }
+}
+
 macro_rules! compatible_function_impl {
-    ($ret:ty) => {
-        unsafe impl JavaCompatibleFunction for extern "C" fn () -> $ret {
-            const DESCRIPTOR: &'static str = const_format::concatcp!("()", <$ret as JavaCompatibleReturnType>::DESCRIPTOR_FRAGMENT);
+    ($($param:ident),*) => {
+        unsafe impl<Ret: JavaCompatibleReturnType, $($param: JavaCompatibleArgumentType),*> JavaCompatibleFunction for extern "C" fn($($param),*) -> Ret {
+            fn descriptor() -> String {
+                let parameters: &[&str] = &[$(<$param as JavaCompatibleArgumentType>::DESCRIPTOR_FRAGMENT),*];
+                format!("({}){}", parameters.concat(), Ret::DESCRIPTOR_FRAGMENT)
+            }
+
+            fn fits(descriptor: &MethodDescriptor) -> bool {
+                let parameters: &[&str] = &[$(<$param as JavaCompatibleArgumentType>::DESCRIPTOR_FRAGMENT),*];
+                parameters.len() == descriptor.parameters.len()
+                    && parameters.iter().zip(&descriptor.parameters).all(|(fragment, ty)| fragment_fits(fragment, Some(ty)))
+                    && fragment_fits(Ret::DESCRIPTOR_FRAGMENT, descriptor.return_type.as_ref())
+            }
         }
     };
-    ($param_a:ty, $ret:ty) => {
-        unsafe impl JavaCompatibleFunction for extern "C" fn ($param_a) -> $ret {
-            const DESCRIPTOR: &'static str = const_format::concatcp!("(", <$param_a as JavaCompatibleArgumentType>::DESCRIPTOR_FRAGMENT, ")", <$ret as JavaCompatibleReturnType>::DESCRIPTOR_FRAGMENT);
+}
+
+// Up to six parameters, as many integers as the calling conventions of [call_dynamic] pass in registers
+
+compatible_function_impl!();
+compatible_function_impl!(A);
+compatible_function_impl!(A, B);
+compatible_function_impl!(A, B, C);
+compatible_function_impl!(A, B, C, D);
+compatible_function_impl!(A, B, C, D, E);
+compatible_function_impl!(A, B, C, D, E, F);
+
+////////////
+// Values //
+////////////
+
+/// A value of any java type, for calls whose signature is only known at runtime
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JavaValue {
+    Void,
+    Boolean(JavaBoolean),
+    Byte(JavaByte),
+    Char(JavaChar),
+    Short(JavaShort),
+    Int(JavaInt),
+    Long(JavaLong),
+    Float(JavaFloat),
+    Double(JavaDouble),
+    /// Null, or any object or array
+    Object(JavaObject),
+}
+
+impl JavaValue {
+    /// Checks if the value can be passed as a parameter of type `ty`. A reference has to be null,
+    /// or an instance of the type as `loader` sees it.
+    pub fn fits<J: JitCompiler>(&self, resolver: &impl ClassResolver<J>, loader: LoaderId, ty: &FieldDescriptor) -> bool {
+        if let JavaValue::Object(object) = *self {
+            return matches!(ty, FieldDescriptor::Object(_) | FieldDescriptor::Array(_)) && (object.is_null()
+                || ReferenceType::of_descriptor(resolver, loader, ty).is_some_and(|ty| is_instance(resolver, object, ty)));
+        }
+        matches!((self, ty),
+            (JavaValue::Boolean(_), FieldDescriptor::Boolean) |
+            (JavaValue::Byte(_), FieldDescriptor::Byte) |
+            (JavaValue::Char(_), FieldDescriptor::Char) |
+            (JavaValue::Short(_), FieldDescriptor::Short) |
+            (JavaValue::Int(_), FieldDescriptor::Int) |
+            (JavaValue::Long(_), FieldDescriptor::Long) |
+            (JavaValue::Float(_), FieldDescriptor::Float) |
+            (JavaValue::Double(_), FieldDescriptor::Double))
+    }
+
+    /// Reads a field or array element of type `ty`
+    ///
+    /// # Safety
+    /// `address` has to point to a value of type `ty`
+    pub unsafe fn read(address: *const u8, ty: &FieldDescriptor) -> Self {
+        match ty {
+            FieldDescriptor::Boolean => JavaValue::Boolean(*address != 0),
+            FieldDescriptor::Byte => JavaValue::Byte(*(address as *const JavaByte)),
+            FieldDescriptor::Char => JavaValue::Char(*(address as *const JavaChar)),
+            FieldDescriptor::Short => JavaValue::Short(*(address as *const JavaShort)),
+            FieldDescriptor::Int => JavaValue::Int(*(address as *const JavaInt)),
+            FieldDescriptor::Long => JavaValue::Long(*(address as *const JavaLong)),
+            FieldDescriptor::Float => JavaValue::Float(*(address as *const JavaFloat)),
+            FieldDescriptor::Double => JavaValue::Double(*(address as *const JavaDouble)),
+            FieldDescriptor::Object(_) | FieldDescriptor::Array(_) => JavaValue::Object(*(address as *const JavaObject)),
+        }
+    }
+
+    /// Writes the value to a field or array element of its type
+    ///
+    /// # Safety
+    /// `address` has to point to a value of the same type as this one
+    pub unsafe fn write(self, address: *mut u8) {
+        match self {
+            JavaValue::Void => panic!("void isn't a value"),
+            JavaValue::Boolean(value) => *address = value as u8,
+            JavaValue::Byte(value) => *(address as *mut JavaByte) = value,
+            JavaValue::Char(value) => *(address as *mut JavaChar) = value,
+            JavaValue::Short(value) => *(address as *mut JavaShort) = value,
+            JavaValue::Int(value) => *(address as *mut JavaInt) = value,
+            JavaValue::Long(value) => *(address as *mut JavaLong) = value,
+            JavaValue::Float(value) => *(address as *mut JavaFloat) = value,
+            JavaValue::Double(value) => *(address as *mut JavaDouble) = value,
+            JavaValue::Object(value) => *(address as *mut JavaObject) = value,
+        }
+    }
+}
+
+macro_rules! java_value_from {
+    ($($ty:ty => $variant:ident),*) => {
+        $(
+            impl From<$ty> for JavaValue {
+                fn from(value: $ty) -> Self {
+                    JavaValue::$variant(value)
+                }
+            }
+        )*
+    };
+}
+
+java_value_from!(JavaBoolean => Boolean, JavaByte => Byte, JavaChar => Char, JavaShort => Short, JavaInt => Int,
+    JavaLong => Long, JavaFloat => Float, JavaDouble => Double, JavaObject => Object);
+
+///////////////////
+// Dynamic calls //
+///////////////////
+
+/// If [call_dynamic] works on this platform
+pub const DYNAMIC_CALLS_SUPPORTED: bool = cfg!(all(any(target_arch = "x86_64", target_arch = "aarch64"), not(windows)));
+/// Integer and reference arguments that a dynamic call can pass
+pub const INT_REGISTERS: usize = 6;
+/// Floating point arguments that a dynamic call can pass
+pub const FLOAT_REGISTERS: usize = 8;
+
+type IntTrampoline = extern "C" fn(u64, u64, u64, u64, u64, u64, f64, f64, f64, f64, f64, f64, f64, f64) -> u64;
+type FloatTrampoline = extern "C" fn(u64, u64, u64, u64, u64, u64, f64, f64, f64, f64, f64, f64, f64, f64) -> f64;
+
+/// Calls an `extern "C"` function whose signature is only known at runtime.
+///
+/// The System V calling convention of x86-64 and the one of aarch64 pass the first integer and pointer arguments in
+/// general purpose registers, and the first floating point arguments in vector registers, each in order. So calling the
+/// function as one that takes [INT_REGISTERS] integers and [FLOAT_REGISTERS] doubles passes every argument in the right place.
+/// Panics if the platform isn't supported, or if there are more arguments of a kind than registers for them.
+///
+/// # Safety
+/// `address` has to be a function that takes `arguments` and returns `return_type`
+pub unsafe fn call_dynamic(address: usize, arguments: &[JavaValue], return_type: Option<&FieldDescriptor>) -> JavaValue {
+    call_in_registers(address, None, arguments, return_type)
+}
+
+/// Checks that parameters of types `parameters` fit in the registers of a dynamic call,
+/// after `taken` integer registers that are used for something else
+pub fn fits_in_registers(taken: usize, parameters: &[FieldDescriptor]) -> bool {
+    let floats = parameters.iter().filter(|ty| matches!(ty, FieldDescriptor::Float | FieldDescriptor::Double)).count();
+    taken + parameters.len() - floats <= INT_REGISTERS && floats <= FLOAT_REGISTERS
+}
+
+/// Like [call_dynamic], but passes `first` in front of the arguments if there is one
+pub(crate) unsafe fn call_in_registers(address: usize, first: Option<u64>, arguments: &[JavaValue], return_type: Option<&FieldDescriptor>) -> JavaValue {
+    if !DYNAMIC_CALLS_SUPPORTED {
+        panic!("dynamic calls aren't supported on this platform");
+    }
+    let (mut ints, mut floats) = ([0u64; INT_REGISTERS], [0f64; FLOAT_REGISTERS]);
+    let (mut int, mut float) = (0, 0);
+    if let Some(first) = first {
+        ints[0] = first;
+        int += 1;
+    }
+    for argument in arguments {
+        // Narrow values only need to be in the low bits, but are extended like a C compiler would
+        let (is_float, bits) = match *argument {
+            JavaValue::Float(value) => (true, value.to_bits() as u64),
+            JavaValue::Double(value) => (true, value.to_bits()),
+            JavaValue::Boolean(value) => (false, value as u64),
+            JavaValue::Byte(value) => (false, value as i64 as u64),
+            JavaValue::Char(value) => (false, value as u64),
+            JavaValue::Short(value) => (false, value as i64 as u64),
+            JavaValue::Int(value) => (false, value as i64 as u64),
+            JavaValue::Long(value) => (false, value as u64),
+            JavaValue::Object(value) => (false, value as u64),
+            JavaValue::Void => panic!("void isn't an argument"),
+        };
+        if is_float {
+            assert!(float < FLOAT_REGISTERS, "more than {} floating point arguments", FLOAT_REGISTERS);
+            floats[float] = f64::from_bits(bits);
+            float += 1;
+        } else {
+            assert!(int < INT_REGISTERS, "more than {} integer arguments", INT_REGISTERS);
+            ints[int] = bits;
+            int += 1;
+        }
+    }
+
+    let [i0, i1, i2, i3, i4, i5] = ints;
+    let [f0, f1, f2, f3, f4, f5, f6, f7] = floats;
+    let bits = match return_type {
+        Some(FieldDescriptor::Float) | Some(FieldDescriptor::Double) => {
+            let function: FloatTrampoline = transmute(address);
+            function(i0, i1, i2, i3, i4, i5, f0, f1, f2, f3, f4, f5, f6, f7).to_bits()
+        }
+        _ => {
+            let function: IntTrampoline = transmute(address);
+            function(i0, i1, i2, i3, i4, i5, f0, f1, f2, f3, f4, f5, f6, f7)
         }
     };
+    match return_type {
+        None => JavaValue::Void,
+        Some(FieldDescriptor::Boolean) => JavaValue::Boolean(bits as u8 != 0),
+        Some(FieldDescriptor::Byte) => JavaValue::Byte(bits as JavaByte),
+        Some(FieldDescriptor::Char) => JavaValue::Char(bits as JavaChar),
+        Some(FieldDescriptor::Short) => JavaValue::Short(bits as JavaShort),
+        Some(FieldDescriptor::Int) => JavaValue::Int(bits as JavaInt),
+        Some(FieldDescriptor::Long) => JavaValue::Long(bits as JavaLong),
+        Some(FieldDescriptor::Float) => JavaValue::Float(f32::from_bits(bits as u32)),
+        Some(FieldDescriptor::Double) => JavaValue::Double(f64::from_bits(bits)),
+        Some(FieldDescriptor::Object(_)) | Some(FieldDescriptor::Array(_)) => JavaValue::Object(bits as JavaObject),
+    }
 }
 
-compatible_function_impl!(JavaInt);
-compatible_function_impl!(JavaChar);
-compatible_function_impl!(JavaFloat);
-compatible_function_impl!(JavaVoid);
-compatible_function_impl!(JavaInt, JavaInt);
-compatible_function_impl!(JavaChar, JavaInt);
-compatible_function_impl!(JavaFloat, JavaInt);
-compatible_function_impl!(JavaInt, JavaChar);
-compatible_function_impl!(JavaChar, JavaChar);
-compatible_function_impl!(JavaFloat, JavaChar);
-compatible_function_impl!(JavaInt, JavaVoid);
-compatible_function_impl!(JavaChar, JavaVoid);
-compatible_function_impl!(JavaFloat, JavaVoid);
-compatible_function_impl!(JavaInt, JavaFloat);
-compatible_function_impl!(JavaChar, JavaFloat);
-compatible_function_impl!(JavaFloat, JavaFloat);
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use classfile_parser::descriptor::FieldDescriptor;
+    use crate::interop::{call_dynamic, fits_in_registers, JavaBoolean, JavaByte, JavaChar, JavaCompatibleFunction, JavaDouble, JavaFloat, JavaInt, JavaLong, JavaObject, JavaShort, JavaValue, JavaVoid, DYNAMIC_CALLS_SUPPORTED};
+
+    fn descriptor<F: JavaCompatibleFunction>() -> String {
+        F::descriptor()
+    }
+
+    #[test]
+    fn descriptors() {
+        assert_eq!(descriptor::<extern "C" fn() -> JavaVoid>(), "()V");
+        assert_eq!(descriptor::<extern "C" fn(JavaInt) -> JavaChar>(), "(I)C");
+        assert_eq!(descriptor::<extern "C" fn(JavaLong, JavaDouble, JavaObject) -> JavaBoolean>(), "(JDLjava/lang/Object;)Z");
+        assert_eq!(descriptor::<extern "C" fn(JavaByte, JavaShort, JavaChar, JavaBoolean, JavaInt, JavaLong) -> JavaFloat>(), "(BSCZIJ)F");
+        type Concat = extern "C" fn(JavaObject, JavaInt) -> JavaObject;
+        assert!(Concat::fits(&"(Ljava/lang/String;I)[I".parse().unwrap()));
+        assert!(!Concat::fits(&"(JI)Ljava/lang/String;".parse().unwrap()));
+        assert!(!Concat::fits(&"(Ljava/lang/String;I)V".parse().unwrap()));
+        assert!(!<extern "C" fn(JavaInt)>::fits(&"(I)I".parse().unwrap()));
+    }
+
+    extern "C" fn mix(a: JavaByte, b: JavaDouble, c: JavaLong, d: JavaFloat, e: JavaBoolean, f: JavaChar) -> JavaDouble {
+        (a as f64 + b + c as f64 + d as f64) * if e { f as f64 } else { -1.0 }
+    }
+
+    extern "C" fn negate(value: JavaShort) -> JavaShort {
+        -value
+    }
+
+    #[test]
+    fn dynamic_calls() {
+        if !DYNAMIC_CALLS_SUPPORTED {
+            return;
+        }
+        let arguments = [JavaValue::Byte(-1), JavaValue::Double(0.5), JavaValue::Long(3), JavaValue::Float(0.25), JavaValue::Boolean(true), JavaValue::Char(2)];
+        assert_eq!(unsafe { call_dynamic(mix as *const () as usize, &arguments, Some(&FieldDescriptor::Double)) }, JavaValue::Double(5.5));
+        assert_eq!(unsafe { call_dynamic(negate as *const () as usize, &[JavaValue::Short(7)], Some(&FieldDescriptor::Short)) }, JavaValue::Short(-7));
+    }
+
+    #[test]
+    fn register_limits() {
+        let ints: Vec<FieldDescriptor> = vec![FieldDescriptor::Int; 6];
+        assert!(fits_in_registers(0, &ints));
+        assert!(!fits_in_registers(1, &ints));
+        assert!(fits_in_registers(1, &vec![FieldDescriptor::Double; 8]));
+        assert!(!fits_in_registers(0, &vec![FieldDescriptor::Float; 9]));
+    }
+}
@@ -2,46 +2,79 @@ pub mod classfile_util;
 pub mod class_store;
 pub mod class_loaders;
 pub mod types;
+pub mod statics;
+pub mod initialization;
+pub mod resolution;
+pub mod object;
+pub mod dispatch;
+pub mod heap;
+pub mod exceptions;
+pub mod natives;
+pub mod runtime;
+pub mod strings;
 /// Interop between rust functions and java ones
 pub mod interop;
+pub mod embedding;
+#[cfg(test)]
+mod test_util;
 
-use std::{collections::HashMap, mem::transmute_copy};
+use std::{collections::HashSet, mem::{transmute, transmute_copy}};
+use std::sync::atomic::{AtomicU32, Ordering};
 
-use class_store::{ClassData, ClassStore, ClassStoreIsh, LoadedMethodRef};
-use classfile_parser::class_file::ClassFile;
-use interop::JavaCompatibleFunction;
+use class_store::{ClassData, ClassStore, ClassStoreIsh, LoadedClassRef, LoadedMethodRef};
+use classfile_parser::attributes::AttributeEntry;
+use classfile_parser::builder::ClassBuilder;
+use classfile_parser::class_file::{ClassAccessFlags, ClassFile};
+use classfile_parser::constant_pool::ConstantPoolEntry;
+use classfile_parser::descriptor::MethodDescriptor;
+use classfile_parser::ClassParseError;
+use class_loaders::LoadError;
+use classfile_util::{get_code_attribute, referenced_classes, ConstantPoolExtensions};
+use dispatch::DispatchTables;
+use exceptions::{JavaException, PendingException, RuntimeException, RuntimeExceptionClasses};
+use heap::{Handle, Heap, ObjectKind};
+use object::{InstanceLayout, ObjectHeader};
+use statics::StaticStorage;
+use initialization::InitError;
+use natives::{NativeError, NativeFunction, NativeMethods};
+use strings::{StringError, StringTable};
+use interop::{call_dynamic, fits_in_registers, JavaCompatibleFunction, JavaValue};
+use thiserror::Error;
 
 pub struct VirtualMachine<L: ClassLoader, T: JitCompiler> {
     class_store: ClassStore<T>,
     class_loader: L,
     jit_engine: T,
+    references: embedding::References,
 }
 
 impl<L: ClassLoader, T: JitCompiler> VirtualMachine<L, T> {
     pub fn new(class_loader: L, jit_engine: T) -> Self {
+        Self::with_heap(class_loader, jit_engine, Default::default())
+    }
+
+    /// Creates a vm whose objects live on `heap`, which allows using a different garbage collector
+    pub fn with_heap(class_loader: L, jit_engine: T, heap: Heap) -> Self {
         VirtualMachine {
-            class_store: Default::default(),
+            class_store: ClassStore::with_heap(heap),
             class_loader,
             jit_engine,
+            references: Default::default(),
         }
     }
 
-    pub fn run(&mut self, class: &str, name: &str, descriptor: &str) -> Result<(),()> {
-        let classfile = self.class_loader.load(class);
-        let jit_data = self.jit_engine.load(&classfile)?;
-        let classref = self.class_store.store(ClassData {
-            java_class: classfile,
-            jit_data
-        });
-        let method = self.class_store.retrieve_method_ref(classref, name, descriptor).ok_or(())?;
+    pub fn run(&mut self, class: &str, name: &str, descriptor: &str) -> Result<(), VmError> {
+        let method = self.prepare_static_method(class, name, descriptor)?;
 
         self.jit_engine.get_fn_pointer(method, self.get_resolver());
 
         Ok(())
     }
 
-    pub fn get_fn_pointer<F: JavaCompatibleFunction>(&mut self, class: &str, name: &str) -> Result<F, ()> {
-        let fn_ptr = self.get_fn_pointer_raw(class, name, F::DESCRIPTOR)?;
+    /// Gets the compiled code of a static method. If the method throws, the exception stays pending
+    /// until [Self::check_exception] is called.
+    pub fn get_fn_pointer<F: JavaCompatibleFunction>(&mut self, class: &str, name: &str) -> Result<F, VmError> {
+        let fn_ptr = self.get_fn_pointer_raw(class, name, &F::descriptor())?;
         
         // Safe as long as the descriptor of the function matches its signature
         unsafe {
@@ -49,41 +82,509 @@ impl<L: ClassLoader, T: JitCompiler> VirtualMachine<L, T> {
         }
     }
 
-    pub fn get_fn_pointer_raw(&mut self, class: &str, name: &str, descriptor: &str) -> Result<usize, ()> {
+    /// Like [Self::get_fn_pointer], for a method whose descriptor names more specific reference types
+    /// than the `java/lang/Object` of a [interop::JavaObject]
+    pub fn get_fn_pointer_with<F: JavaCompatibleFunction>(&mut self, class: &str, name: &str, descriptor: &str) -> Result<F, VmError> {
+        let parsed: MethodDescriptor = descriptor.parse().map_err(|_| VmError::InvalidDescriptor(descriptor.to_owned()))?;
+        if !F::fits(&parsed) {
+            return Err(VmError::ArgumentMismatch(format!("{}.{}{}", class, name, descriptor)));
+        }
+        let fn_ptr = self.get_fn_pointer_raw(class, name, descriptor)?;
+
+        // Safe because the function fits the descriptor
+        unsafe {
+            Ok(transmute_copy(&fn_ptr))
+        }
+    }
+
+    pub fn get_fn_pointer_raw(&mut self, class: &str, name: &str, descriptor: &str) -> Result<usize, VmError> {
         // TODO encode descriptor in JavaCompatibleFunction
-        let classfile = self.class_loader.load(class);
-        let jit_data = self.jit_engine.load(&classfile)?;
-        let classref = self.class_store.store(ClassData {
-            java_class: classfile,
-            jit_data
-        });
-        let method = self.class_store.retrieve_method_ref(classref, name, descriptor).ok_or(())?;
+        let method = self.prepare_static_method(class, name, descriptor)?;
 
         return Ok(self.jit_engine.get_fn_pointer(method, self.get_resolver()));
     }
 
+    /// Loads and initializes a class, so one of its static methods can be compiled and called
+    fn prepare_static_method(&mut self, class: &str, name: &str, descriptor: &str) -> Result<LoadedMethodRef, VmError> {
+        let classref = self.load_class(class)?;
+        self.initialize_or_throw(classref)?;
+        let method = self.class_store.retrieve_method_ref(classref, name, descriptor)
+            .filter(|&method| self.class_store.retrieve(classref).retrieve_method(method).is_static())
+            .ok_or_else(|| VmError::NoSuchMethod(format!("{}.{}{}", class, name, descriptor)))?;
+        self.load_references(method, &mut HashSet::new())?;
+        Ok(method)
+    }
+
+    /// Calls a static method that's chosen at runtime, after checking that the arguments fit its descriptor.
+    /// Void methods return [JavaValue::Void]. References among the arguments have to be kept alive by the caller,
+    /// with a [Handle] for example. An exception that the method doesn't catch is returned as an error.
+    pub fn invoke(&mut self, class: &str, name: &str, descriptor: &str, arguments: &[JavaValue]) -> Result<JavaValue, VmError> {
+        check_arguments(self.get_resolver(), self.class_loader.id(), (class, name, descriptor), false, arguments)?;
+        let method = self.prepare_static_method(class, name, descriptor)?;
+        let result = self.jit_engine.invoke_method(method, self.get_resolver(), arguments);
+        self.check_exception()?;
+        Ok(result)
+    }
+
+    /// Returns the exception that compiled code threw and didn't catch, if there is one.
+    /// Code called through [Self::get_fn_pointer] can throw, so this should be checked after calling it.
+    /// The exception is pinned by a [embedding::JavaRef] in the current scope.
+    pub fn check_exception(&mut self) -> Result<(), VmError> {
+        let exception = match self.class_store.exception().take() {
+            Some(exception) => exception,
+            None => return Ok(()),
+        };
+        // Safe because pending exceptions are kept alive
+        let class = self.class_store.retrieve(unsafe { (*exception).class }).name().to_owned();
+        Err(JavaException { class, exception: self.new_ref(exception).unwrap() }.into())
+    }
+
+    /// Finds a class that was already loaded
+    pub fn lookup(&self, class: &str) -> Option<LoadedClassRef> {
+        self.class_store.lookup(self.class_loader.id(), class)
+    }
+
+    /// Loads and links a class, or returns the existing one if it was already loaded.
+    /// Superclasses and superinterfaces are loaded first, through the same class loader.
+    /// See: https://docs.oracle.com/javase/specs/jvms/se16/html/jvms-5.html#jvms-5.4
+    pub fn load_class(&mut self, class: &str) -> Result<LoadedClassRef, LoadError> {
+        self.load_class_inner(self.class_loader.id(), class, &mut Vec::new())
+    }
+
+    /// Loads a class through the `initiating` loader, which has to be part of the class loader of the vm.
+    /// `loading` contains the classes whose superclasses are currently being loaded.
+    fn load_class_inner(&mut self, initiating: LoaderId, class: &str, loading: &mut Vec<String>) -> Result<LoadedClassRef, LoadError> {
+        if let Some(classref) = self.class_store.lookup(initiating, class) {
+            return Ok(classref);
+        }
+        if loading.iter().any(|name| name == class) {
+            return Err(LoadError::ClassCircularityError(class.to_owned()));
+        }
+
+        let (loader, classfile) = self.class_loader.load_for(initiating, class)
+            .unwrap_or_else(|| Err(LoadError::NotFound(class.to_owned())))?;
+        // The defining loader might have been asked for this class before, through another loader
+        if let Some(classref) = self.class_store.lookup_defined(loader, class) {
+            self.class_store.record_initiated(initiating, class, classref);
+            return Ok(classref);
+        }
+        self.define_class(initiating, loader, class, classfile, loading)
+    }
+
+    /// Links a class that `loader` defined, and stores it
+    fn define_class(&mut self, initiating: LoaderId, loader: LoaderId, class: &str, classfile: ClassFile, loading: &mut Vec<String>) -> Result<LoadedClassRef, LoadError> {
+        loading.push(class.to_owned());
+        let supertypes = self.load_supertypes(loader, class, &classfile, loading);
+        loading.pop();
+        let (super_class, interfaces) = supertypes?;
+
+        let statics = StaticStorage::prepare(&classfile)?;
+        let layout = InstanceLayout::compute(&classfile, super_class.map(|class| &self.class_store.retrieve(class).layout))?;
+        let dispatch = DispatchTables::link(&self.class_store, self.class_store.next_ref(), &classfile, loader, super_class, &interfaces)?;
+        let jit_data = self.jit_engine.load(&classfile, &dispatch).map_err(|e| LoadError::JitError(class.to_owned(), e))?;
+        let nest_host = classfile.attributes.iter().find_map(|attribute| match attribute {
+            AttributeEntry::NestHost(host) => classfile.constant_pool.get_class_name(host.host_class_index).map(str::to_owned),
+            _ => None,
+        });
+        let classref = self.class_store.store(initiating, ClassData::new(classfile, loader, super_class, interfaces, statics, layout, dispatch, jit_data));
+
+        // Access checks need the nest host, which is loaded by the loader of the member.
+        // If it can't be loaded, the class is its own host.
+        if let Some(host) = nest_host {
+            let _ = self.load_class_inner(loader, &host, loading);
+        }
+        Ok(classref)
+    }
+
+    /// Loads the superclass and superinterfaces of a class through the `loader` that defines it
+    fn load_supertypes(&mut self, loader: LoaderId, class: &str, classfile: &ClassFile, loading: &mut Vec<String>) -> Result<(Option<LoadedClassRef>, Vec<LoadedClassRef>), LoadError> {
+        let pool = &classfile.constant_pool;
+        let invalid_index = |index| LoadError::ParseError(class.to_owned(), ClassParseError::InvalidConstantPoolIndex(index));
+
+        let super_class = if classfile.super_class == 0 {
+            None
+        } else {
+            let name = pool.get_class_name(classfile.super_class).ok_or_else(|| invalid_index(classfile.super_class))?;
+            let super_ref = self.load_class_inner(loader, name, loading)?;
+            let super_data = self.class_store.retrieve(super_ref);
+            if super_data.is_interface() {
+                return Err(LoadError::IncompatibleClassChangeError(format!("{} has interface {} as superclass", class, name)));
+            }
+            if super_data.java_class.access_flags.contains(ClassAccessFlags::FINAL) {
+                return Err(LoadError::VerifyError(format!("{} inherits from final class {}", class, name)));
+            }
+            Some(super_ref)
+        };
+
+        let mut interfaces = Vec::with_capacity(classfile.interfaces.len());
+        for &index in &classfile.interfaces {
+            let name = pool.get_class_name(index).ok_or_else(|| invalid_index(index))?;
+            let interface = self.load_class_inner(loader, name, loading)?;
+            if !self.class_store.retrieve(interface).is_interface() {
+                return Err(LoadError::IncompatibleClassChangeError(format!("{} implements class {}", class, name)));
+            }
+            interfaces.push(interface);
+        }
+
+        Ok((super_class, interfaces))
+    }
+
+    /// Initializes a class and its superclasses, if that didn't happen yet.
+    /// If a static initializer throws, the error is pending until [Self::check_exception] is called.
+    pub fn initialize(&mut self, class: LoadedClassRef) -> Result<(), InitError> {
+        if let Err(e) = self.load_class_references(class, &mut HashSet::new()) {
+            let missing = match e {
+                LoadError::NotFound(name) => name,
+                _ => self.class_store.retrieve(class).name().to_owned(),
+            };
+            return Err(InitError::NoClassDefFoundError(missing));
+        }
+        self.class_store.initialize(class, &self.jit_engine)
+    }
+
+    /// Like [Self::initialize], but returns the error that a static initializer threw as a java exception
+    fn initialize_or_throw(&mut self, class: LoadedClassRef) -> Result<(), VmError> {
+        if let Err(e) = self.initialize(class) {
+            self.check_exception()?;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Loads every class that the code of `method` refers to, so the compiler can resolve them.
+    /// Compiling a method might initialize the classes it uses, and methods of those classes can be selected
+    /// by virtual calls later. So the references of all of their methods are loaded too.
+    fn load_references(&mut self, method: LoadedMethodRef, visited: &mut HashSet<LoadedMethodRef>) -> Result<(), LoadError> {
+        if !visited.insert(method) {
+            return Ok(());
+        }
+        let data = self.class_store.retrieve(method.class_ref);
+        let loader = data.loader;
+        let code = match get_code_attribute(data.method_info(method)) {
+            Some(code) => code,
+            None if data.retrieve_method(method).is_native() => {
+                // Natives can throw any of them, and can create strings
+                for exception in RuntimeException::ALL {
+                    self.load_runtime_exception(exception, visited)?;
+                }
+                match self.load_string_class() {
+                    Ok(_) | Err(LoadError::NotFound(_)) => {}
+                    Err(e) => return Err(e),
+                }
+                return Ok(());
+            }
+            // Calling an abstract method throws
+            None => return self.load_runtime_exception(RuntimeException::AbstractMethod, visited),
+        };
+        let pool = &data.java_class.constant_pool;
+        let mut names = referenced_classes(&code.code, pool)
+            .map_err(|e| LoadError::ParseError(data.name().to_owned(), e))?
+            .into_iter().map(str::to_owned).collect::<Vec<_>>();
+        // Exception handlers need their catch type to find out if they catch something
+        names.extend(code.exception_table.iter()
+            .filter(|entry| entry.catch_type != 0)
+            .filter_map(|entry| pool.get_class_name(entry.catch_type).map(str::to_owned)));
+
+        // Any reference of the code can fail to resolve
+        let mut thrown = RuntimeException::LINKAGE_ERRORS.iter().copied().collect::<HashSet<_>>();
+        for (_, instruction) in code.code.decode().map_err(|e| LoadError::ParseError(data.name().to_owned(), e))? {
+            thrown.extend(RuntimeException::thrown_by(&instruction));
+        }
+
+        for name in names {
+            // The code is resolved in the context of the loader that defined it
+            let class = match self.load_class_inner(loader, &name, &mut Vec::new()) {
+                Ok(class) => class,
+                // The code only fails once it uses the class, with a `NoClassDefFoundError`
+                Err(LoadError::NotFound(_)) => continue,
+                Err(e) => return Err(e),
+            };
+            self.load_class_references(class, visited)?;
+        }
+        for exception in thrown {
+            self.load_runtime_exception(exception, visited)?;
+        }
+        Ok(())
+    }
+
+    /// Loads the class of an exception that instructions throw by themselves. If the class library doesn't have it,
+    /// the vm defines a stand-in that extends `java/lang/Throwable`, so the exception can always be thrown.
+    fn load_runtime_exception(&mut self, exception: RuntimeException, visited: &mut HashSet<LoadedMethodRef>) -> Result<(), LoadError> {
+        if self.class_store.runtime_exceptions.class(exception).is_some() {
+            return Ok(());
+        }
+        let class = match self.load_class(exception.class_name()) {
+            Err(LoadError::NotFound(_)) => {
+                let throwable = match self.load_class("java/lang/Throwable") {
+                    Ok(_) => Some("java/lang/Throwable"),
+                    Err(LoadError::NotFound(_)) => None,
+                    Err(e) => return Err(e),
+                };
+                // A class without members always builds
+                let stand_in = ClassBuilder::new(exception.class_name(), throwable).build().expect("invalid stand-in class");
+                let loader = self.class_loader.id();
+                self.define_class(loader, loader, exception.class_name(), stand_in, &mut Vec::new())?
+            }
+            result => result?,
+        };
+        self.class_store.runtime_exceptions.set_class(exception, class);
+        self.load_class_references(class, visited)
+    }
+
+    /// Loads the references of every method of a class and of all of its supertypes
+    fn load_class_references(&mut self, class: LoadedClassRef, visited: &mut HashSet<LoadedMethodRef>) -> Result<(), LoadError> {
+        let mut pending = vec![class];
+        while let Some(class) = pending.pop() {
+            let data = self.class_store.retrieve(class);
+            pending.extend(data.super_class);
+            pending.extend(&data.interfaces);
+            if data.java_class.constant_pool.iter().any(|entry| matches!(entry, ConstantPoolEntry::StringInfo(_))) {
+                self.load_string_class()?;
+            }
+            let data = self.class_store.retrieve(class);
+            for index in 0..data.java_class.methods.len() {
+                self.load_references(LoadedMethodRef::new(class, index), visited)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads `java/lang/String`, which string constants are instances of
+    fn load_string_class(&mut self) -> Result<LoadedClassRef, LoadError> {
+        if let Some(class) = self.class_store.strings.class() {
+            return Ok(class);
+        }
+        let class = self.load_class("java/lang/String")?;
+        self.class_store.strings.set_class(class);
+        Ok(class)
+    }
+
+    /// Registers a rust function as the implementation of a native method, see [natives].
+    /// `function` has to fit the descriptor, with the object the method is called on as an extra first parameter for instance methods.
+    pub fn register_native<F: NativeFunction>(&mut self, class: &str, name: &str, descriptor: &str, function: F) -> Result<(), NativeError> {
+        self.class_store.natives.register(class, name, descriptor, function)
+    }
+
+    /// Creates a java string, which is kept alive until the handle is released
+    pub fn new_string(&mut self, value: &str) -> Result<Handle, StringError> {
+        self.load_string_class().map_err(|_| StringError::NotLoaded)?;
+        let string = strings::new_string(&self.class_store, &self.jit_engine, &strings::to_utf16(value))?;
+        Ok(self.heap().hold(string as *mut u8))
+    }
+
+    /// Converts a java string to a rust one
+    pub fn read_string(&self, string: &Handle) -> Result<String, StringError> {
+        // Safe because handles keep their object alive
+        let units = unsafe { strings::read_string(&self.class_store, self.heap().get(string) as *const _)? };
+        Ok(strings::from_utf16(&units))
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.class_store.heap
+    }
+
+    /// Frees every object that can't be reached anymore. This also happens by itself when enough was allocated.
+    pub fn collect_garbage(&self) {
+        self.class_store.collect_garbage(&self.jit_engine);
+    }
+
     fn get_resolver(&self) -> &impl ClassResolver<T> {
         &self.class_store
     }
 }
 
+/// Why a call from rust into the vm failed
+#[derive(Error, Debug)]
+pub enum VmError {
+    #[error(transparent)]
+    LoadError(#[from] LoadError),
+    #[error(transparent)]
+    InitError(#[from] InitError),
+    #[error("no such method: {0}")]
+    NoSuchMethod(String),
+    #[error("invalid descriptor: {0}")]
+    InvalidDescriptor(String),
+    #[error("the arguments don't fit {0}")]
+    ArgumentMismatch(String),
+    /// More arguments of a kind than [interop::call_dynamic] can pass
+    #[error("too many arguments to call {0}")]
+    TooManyArguments(String),
+    #[error("no such field: {0}")]
+    NoSuchField(String),
+    #[error("{0} can't be instantiated")]
+    InstantiationError(String),
+    #[error("the object isn't an array of {0}")]
+    ArrayTypeMismatch(String),
+    /// The java code threw an exception that it didn't catch
+    #[error(transparent)]
+    Exception(#[from] JavaException),
+}
+
+/// Checks that `arguments` fit the parameters of a method, as classes of `loader` see them,
+/// and that they can be passed along with the object the method is called on if it's an instance method
+fn check_arguments<J: JitCompiler>(resolver: &impl ClassResolver<J>, loader: LoaderId, method: (&str, &str, &str), instance: bool, arguments: &[JavaValue]) -> Result<(), VmError> {
+    let (class, name, descriptor) = method;
+    let parsed: MethodDescriptor = descriptor.parse().map_err(|_| VmError::InvalidDescriptor(descriptor.to_owned()))?;
+    if arguments.len() != parsed.parameters.len()
+        || !arguments.iter().zip(&parsed.parameters).all(|(argument, ty)| argument.fits(resolver, loader, ty)) {
+        return Err(VmError::ArgumentMismatch(format!("{}.{}{}", class, name, descriptor)));
+    }
+    if !fits_in_registers(instance as usize, &parsed.parameters) {
+        return Err(VmError::TooManyArguments(format!("{}.{}{}", class, name, descriptor)));
+    }
+    Ok(())
+}
+
+/// Identifies a class loader. Two classes are only the same if they have the same name
+/// and were defined by the same loader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LoaderId(u32);
+
+impl LoaderId {
+    #[allow(clippy::new_without_default)]
+    /// Returns an id that's different from every id returned before
+    pub fn new() -> Self {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        LoaderId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
 pub trait ClassLoader {
-    fn load(&self, class: &str) -> ClassFile;
+    fn id(&self) -> LoaderId;
+
+    /// Loads the class with the given binary name, like `java/lang/Object`
+    fn load(&self, class: &str) -> Result<ClassFile, LoadError> {
+        self.load_defined(class).map(|(_, class)| class)
+    }
+
+    /// Loads a class and returns which loader defined it.
+    /// Loaders that delegate return the id of the loader they delegated to.
+    fn load_defined(&self, class: &str) -> Result<(LoaderId, ClassFile), LoadError>;
+
+    /// Checks if `loader` is this loader, or one it delegates to
+    fn contains(&self, loader: LoaderId) -> bool {
+        loader == self.id()
+    }
+
+    /// Loads a class that a class defined by `loader` refers to. That goes through the loader that `loader`
+    /// is part of, which isn't necessarily this one. Returns `None` if this loader doesn't contain `loader`.
+    fn load_for(&self, loader: LoaderId, class: &str) -> Option<Result<(LoaderId, ClassFile), LoadError>> {
+        self.contains(loader).then(|| self.load_defined(class))
+    }
 }
 
+impl<L: ClassLoader + ?Sized> ClassLoader for Box<L> {
+    fn id(&self) -> LoaderId {
+        (**self).id()
+    }
+
+    fn load_defined(&self, class: &str) -> Result<(LoaderId, ClassFile), LoadError> {
+        (**self).load_defined(class)
+    }
+
+    fn contains(&self, loader: LoaderId) -> bool {
+        (**self).contains(loader)
+    }
+
+    fn load_for(&self, loader: LoaderId, class: &str) -> Option<Result<(LoaderId, ClassFile), LoadError>> {
+        (**self).load_for(loader, class)
+    }
+}
+
+/// A jit compiler couldn't accept a class
+#[derive(Error, Debug)]
+#[error("{0}")]
+pub struct JitError(pub String);
+
 pub trait JitCompiler: Sized {
     type ClassData;
 
-    fn load(&mut self, class: &ClassFile) -> Result<Self::ClassData,()>;
+    /// Called when a class is linked, `dispatch` contains its vtable and itable
+    fn load(&mut self, class: &ClassFile, dispatch: &DispatchTables) -> Result<Self::ClassData, JitError>;
 
     fn get_fn_pointer(&self, method: LoadedMethodRef, resolver: &impl ClassResolver<Self>) -> usize;
+
+    /// Runs a static method without parameters or return value, like `<clinit>`.
+    /// Returns the exception if the method threw, it's no longer pending afterwards.
+    fn run_initializer(&self, method: LoadedMethodRef, resolver: &impl ClassResolver<Self>) -> Result<(), *mut ObjectHeader> {
+        // Safe as long as the compiled code matches the `()V` descriptor
+        let function: extern "C" fn() = unsafe { transmute(self.get_fn_pointer(method, resolver)) };
+        function();
+        match resolver.exception().take() {
+            Some(exception) => Err(exception),
+            None => Ok(()),
+        }
+    }
+
+    /// Calls a method with arguments that fit its descriptor, starting with the object it's called on for instance methods.
+    /// If the method throws, the exception stays pending. By default this calls the compiled code through [call_dynamic].
+    fn invoke_method(&self, method: LoadedMethodRef, resolver: &impl ClassResolver<Self>, arguments: &[JavaValue]) -> JavaValue {
+        let descriptor = resolver.retrieve(method.class_ref).retrieve_method(method).parse_descriptor()
+            .unwrap_or_else(|e| panic!("{}", e));
+        let address = self.get_fn_pointer(method, resolver);
+        // Safe as long as the compiled code matches the descriptor, which the arguments fit
+        unsafe { call_dynamic(address, arguments, descriptor.return_type.as_ref()) }
+    }
+
+    /// Passes every reference held by frames of compiled code that are currently running to `visitor`.
+    /// Garbage collection can happen whenever compiled code allocates, so anything it still uses has to be visited.
+    fn visit_frame_roots(&self, _visitor: &mut dyn FnMut(*mut u8)) {}
 }
 
 pub trait ClassResolver<J: JitCompiler>: ClassStoreIsh<J> {
+    /// Initializes a class if that didn't happen yet. Compilers should call this before running
+    /// a `new`, `getstatic`, `putstatic` or `invokestatic` instruction targeting the class.
+    /// Compilers that can't call back into the vm at runtime may call this when compiling the instruction instead.
+    fn initialize(&self, class: LoadedClassRef, jit: &J) -> Result<(), InitError> where Self: Sized {
+        initialization::initialize(self, jit, class)
+    }
+
+    fn heap(&self) -> &Heap;
+
+    fn strings(&self) -> &StringTable;
+
+    /// The exception that's being thrown
+    fn exception(&self) -> &PendingException;
+
+    /// The classes of the exceptions that instructions throw by themselves
+    fn runtime_exceptions(&self) -> &RuntimeExceptionClasses;
+
+    /// The rust functions that implement native methods
+    fn natives(&self) -> &NativeMethods;
+
+    /// Allocates zeroed memory on the heap. Garbage is collected first if enough was allocated since the last collection.
+    fn allocate(&self, jit: &J, size: usize, kind: ObjectKind) -> *mut u8 where Self: Sized {
+        if self.heap().should_collect() {
+            self.collect_garbage(jit);
+        }
+        self.heap().allocate(size, kind)
+    }
+
+    fn collect_garbage(&self, jit: &J) where Self: Sized {
+        heap::collect_garbage(self, jit)
+    }
 }
 
 impl<J: JitCompiler> ClassResolver<J> for ClassStore<J> {
+    fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    fn strings(&self) -> &StringTable {
+        &self.strings
+    }
+
+    fn exception(&self) -> &PendingException {
+        &self.exception
+    }
+
+    fn runtime_exceptions(&self) -> &RuntimeExceptionClasses {
+        &self.runtime_exceptions
+    }
 
+    fn natives(&self) -> &NativeMethods {
+        &self.natives
+    }
 }
 
 pub trait ClassShell {
@@ -96,4 +597,167 @@ pub trait ClassShell {
 
 #[cfg(test)]
 mod tests {
+    use classfile_parser::bytecode::Instruction;
+    use classfile_parser::class_file::{ClassAccessFlags, FieldAccessFlags, MethodAccessFlags};
+    use classfile_parser::attributes::ConstantValue;
+    use classfile_parser::builder::ClassBuilder;
+    use crate::class_loaders::{LoadError, MemoryClassLoader, ParentFirstClassLoader};
+    use crate::class_store::ClassStoreIsh;
+    use crate::test_util::{build_class, class_bytes, BuiltClassLoader, NoJit, INTERFACE};
+    use crate::{ClassLoader, VirtualMachine};
+
+    const CLASS: ClassAccessFlags = ClassAccessFlags::PUBLIC;
+
+    fn memory_loader(names: &[&str]) -> MemoryClassLoader {
+        MemoryClassLoader::new(names.iter().map(|name| (name.to_string(), class_bytes(name))).collect())
+    }
+
+    #[test]
+    fn load_once() {
+        let mut vm = VirtualMachine::new(memory_loader(&["a/A", "b/B"]), NoJit);
+        assert_eq!(vm.lookup("a/A"), None);
+
+        let a = vm.load_class("a/A").unwrap();
+        assert_eq!(vm.load_class("a/A").unwrap(), a);
+        assert_eq!(vm.lookup("a/A"), Some(a));
+        assert_ne!(vm.load_class("b/B").unwrap(), a);
+        assert_eq!(vm.class_store.len(), 2);
+        assert!(vm.load_class("c/C").is_err());
+    }
+
+    #[test]
+    fn delegated_classes_are_recorded() {
+        let parent = memory_loader(&["java/lang/Object", "a/A"]);
+        let child = memory_loader(&["a/A"]);
+        let parent_id = parent.id();
+        let child_id = child.id();
+        let mut vm = VirtualMachine::new(ParentFirstClassLoader::new(parent, child), NoJit);
+
+        // Delegated to the parent, so the store knows the parent can find it too
+        let object = vm.load_class("java/lang/Object").unwrap();
+        assert_eq!(vm.class_store.lookup(parent_id, "java/lang/Object"), Some(object));
+        assert_eq!(vm.class_store.lookup(child_id, "java/lang/Object"), Some(object));
+        assert_eq!(vm.class_store.retrieve(object).loader, parent_id);
+
+        assert_eq!(vm.class_store.lookup(child_id, "a/A"), None);
+        let a = vm.load_class("a/A").unwrap();
+        assert_eq!(vm.class_store.retrieve(a).loader, parent_id);
+    }
+
+    #[test]
+    fn nest_host_from_defining_loader() {
+        let mut member = ClassBuilder::new("a/Member", None);
+        member.nest_host("a/Host");
+        let parent = BuiltClassLoader::new(vec![member.build().unwrap()]);
+        let child = BuiltClassLoader::new(vec![build_class("a/Host", None, &[], CLASS)]);
+        let parent_id = parent.id();
+        let mut vm = VirtualMachine::new(ParentFirstClassLoader::new(parent, child), NoJit);
+
+        // The parent can't see the classes of the child, so the member is its own host
+        let member = vm.load_class("a/Member").unwrap();
+        assert_eq!(vm.class_store.retrieve(member).loader, parent_id);
+        assert_eq!(vm.lookup("a/Host"), None);
+        assert_eq!(vm.class_store.lookup(parent_id, "a/Host"), None);
+    }
+
+    #[test]
+    fn supertypes_and_references_from_defining_loader() {
+        let mut user = ClassBuilder::new("p/User", None);
+        let hidden = user.class("p/Hidden");
+        user.method(MethodAccessFlags::STATIC, "make", "()V", vec![Instruction::New(hidden), Instruction::Return]);
+        let parent = BuiltClassLoader::new(vec![build_class("p/Sub", Some("p/Base"), &[], CLASS), user.build().unwrap()]);
+        let child = BuiltClassLoader::new(vec![build_class("p/Base", None, &[], CLASS), build_class("p/Hidden", None, &[], CLASS)]);
+        let parent_id = parent.id();
+        let mut vm = VirtualMachine::new(ParentFirstClassLoader::new(parent, child), NoJit);
+
+        // Only the child can see the superclass
+        assert!(matches!(vm.load_class("p/Sub"), Err(LoadError::NotFound(name)) if name == "p/Base"));
+        let user = vm.load_class("p/User").unwrap();
+        vm.initialize(user).unwrap();
+        assert_eq!(vm.class_store.lookup(parent_id, "p/Hidden"), None);
+    }
+
+    #[test]
+    fn link_hierarchy() {
+        let mut vm = VirtualMachine::new(BuiltClassLoader::new(vec![
+            build_class("java/lang/Object", None, &[], CLASS),
+            build_class("I", Some("java/lang/Object"), &[], INTERFACE),
+            build_class("J", Some("java/lang/Object"), &["I"], INTERFACE),
+            build_class("A", Some("java/lang/Object"), &["J"], CLASS),
+            build_class("B", Some("A"), &[], CLASS),
+        ]), NoJit);
+
+        let b = vm.load_class("B").unwrap();
+        let a = vm.lookup("A").unwrap();
+        let object = vm.lookup("java/lang/Object").unwrap();
+        let (i, j) = (vm.lookup("I").unwrap(), vm.lookup("J").unwrap());
+        let store = &vm.class_store;
+        assert_eq!(store.retrieve(b).super_class, Some(a));
+        assert_eq!(store.retrieve(object).super_class, None);
+        assert_eq!(store.retrieve(a).interfaces, vec![j]);
+
+        assert!(store.is_subclass_of(b, a));
+        assert!(store.is_subclass_of(b, object));
+        assert!(store.is_subclass_of(a, a));
+        assert!(!store.is_subclass_of(a, b));
+        assert!(store.implements(b, i));
+        assert!(store.implements(j, i));
+        assert!(!store.implements(i, j));
+        assert!(!store.implements(object, i));
+        assert!(store.is_instance_of(b, i));
+        assert!(store.is_instance_of(b, a));
+        assert!(!store.is_instance_of(a, b));
+        assert!(!store.is_instance_of(object, j));
+    }
+
+    #[test]
+    fn circularity() {
+        let mut vm = VirtualMachine::new(BuiltClassLoader::new(vec![
+            build_class("A", Some("B"), &[], CLASS),
+            build_class("B", Some("A"), &[], CLASS),
+            build_class("I", None, &["I"], INTERFACE),
+        ]), NoJit);
+        assert!(matches!(vm.load_class("A"), Err(LoadError::ClassCircularityError(name)) if name == "A"));
+        assert!(matches!(vm.load_class("I"), Err(LoadError::ClassCircularityError(_))));
+        assert!(vm.class_store.is_empty());
+    }
+
+    #[test]
+    fn incompatible_supertypes() {
+        let mut vm = VirtualMachine::new(BuiltClassLoader::new(vec![
+            build_class("I", None, &[], INTERFACE),
+            build_class("C", None, &[], CLASS),
+            build_class("F", None, &[], CLASS | ClassAccessFlags::FINAL),
+            build_class("ExtendsInterface", Some("I"), &[], CLASS),
+            build_class("ImplementsClass", None, &["C"], CLASS),
+            build_class("ExtendsFinal", Some("F"), &[], CLASS),
+            build_class("MissingSuper", Some("Missing"), &[], CLASS),
+        ]), NoJit);
+        assert!(matches!(vm.load_class("ExtendsInterface"), Err(LoadError::IncompatibleClassChangeError(_))));
+        assert!(matches!(vm.load_class("ImplementsClass"), Err(LoadError::IncompatibleClassChangeError(_))));
+        assert!(matches!(vm.load_class("ExtendsFinal"), Err(LoadError::VerifyError(_))));
+        assert!(matches!(vm.load_class("MissingSuper"), Err(LoadError::NotFound(name)) if name == "Missing"));
+        assert_eq!(vm.lookup("ExtendsInterface"), None);
+    }
+
+    #[test]
+    fn prepare_statics() {
+        let mut builder = ClassBuilder::new("A", None);
+        builder.field(FieldAccessFlags::STATIC, "a", "J");
+        builder.field(FieldAccessFlags::empty(), "b", "I");
+        builder.field(FieldAccessFlags::STATIC, "c", "Ljava/lang/Object;");
+        let mut vm = VirtualMachine::new(BuiltClassLoader::new(vec![builder.build().unwrap()]), NoJit);
+
+        let a = vm.load_class("A").unwrap();
+        let statics = &vm.class_store.retrieve(a).statics;
+        assert_eq!(statics.len(), 2);
+        let slot = statics.find("c", &"Ljava/lang/Object;".parse().unwrap()).unwrap();
+        assert_eq!(unsafe { *statics.address(slot) }, 0);
+        assert_eq!(statics.find("b", &"I".parse().unwrap()), None);
+
+        let mut builder = ClassBuilder::new("B", None);
+        builder.constant_field(FieldAccessFlags::STATIC | FieldAccessFlags::FINAL, "a", "I", ConstantValue::Long(1));
+        let mut vm = VirtualMachine::new(BuiltClassLoader::new(vec![builder.build().unwrap()]), NoJit);
+        assert!(matches!(vm.load_class("B"), Err(LoadError::ClassFormatError(_))));
+    }
 }
@@ -0,0 +1,491 @@
+//! Methods that are implemented in rust instead of java, the ones flagged `ACC_NATIVE`.
+//!
+//! A native is an `extern "C"` function that gets a [NativeEnv], then the object the method was called on
+//! if it's an instance method, and then the arguments of the method.
+
+use std::collections::HashMap;
+use std::ptr;
+
+use classfile_parser::descriptor::{FieldDescriptor, MethodDescriptor};
+use thiserror::Error;
+
+use crate::class_store::LoadedMethodRef;
+use crate::exceptions::{throw_runtime_exception, RuntimeException};
+use crate::heap::Heap;
+use crate::interop::{call_in_registers, fragment_fits, JavaCompatibleArgumentType, JavaCompatibleReturnType, JavaInt, JavaObject, JavaValue, JavaVoid};
+use crate::object::{class_of, find_instance_field, is_assignable, is_instance, type_of, ReferenceType, ARRAY_HEADER_SIZE};
+use crate::{runtime, strings};
+use crate::{ClassResolver, JitCompiler};
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum NativeError {
+    #[error("invalid method descriptor {0}")]
+    InvalidDescriptor(String),
+    /// The parameters or return type of the rust function don't fit the method
+    #[error("native function does not match {0}")]
+    SignatureMismatch(String),
+    /// Java calls this an `UnsatisfiedLinkError`
+    #[error("no native function was registered for {0}")]
+    NotRegistered(String),
+}
+
+/// Lets natives use the vm. It's passed to every native as its first argument.
+pub struct NativeEnv {
+    resolver: *const (),
+    jit: *const (),
+    /// Calls the closure with the vm that the pointers above point to
+    with_vm: WithVm,
+}
+
+type WithVm = unsafe fn(*const (), *const (), &mut dyn FnMut(&dyn NativeVm));
+
+/// What natives can do with the vm, without knowing the types of the resolver and compiler
+trait NativeVm {
+    fn throw(&self, exception: RuntimeException);
+    fn heap(&self) -> *const Heap;
+    fn new_string(&self, units: &[u16]) -> JavaObject;
+    fn read_string(&self, string: JavaObject) -> Vec<u16>;
+    fn class_name(&self, object: JavaObject) -> String;
+    fn field(&self, object: JavaObject, name: &str, descriptor: &str) -> Option<*mut u8>;
+    fn type_of(&self, object: JavaObject) -> Option<ReferenceType>;
+    fn is_assignable(&self, from: ReferenceType, to: ReferenceType) -> bool;
+    fn is_instance_of(&self, object: JavaObject, class: &str) -> bool;
+    fn java_to_string(&self, object: JavaObject) -> Option<JavaObject>;
+}
+
+struct Vm<'a, J, R> {
+    resolver: &'a R,
+    jit: &'a J,
+}
+
+impl<J: JitCompiler, R: ClassResolver<J>> NativeVm for Vm<'_, J, R> {
+    fn throw(&self, exception: RuntimeException) {
+        throw_runtime_exception(self.resolver, self.jit, exception);
+    }
+
+    fn heap(&self) -> *const Heap {
+        self.resolver.heap()
+    }
+
+    fn new_string(&self, units: &[u16]) -> JavaObject {
+        strings::new_string(self.resolver, self.jit, units).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn read_string(&self, string: JavaObject) -> Vec<u16> {
+        // Safe because natives only get live objects
+        unsafe { strings::read_string(self.resolver, string) }.unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn class_name(&self, object: JavaObject) -> String {
+        match type_of(self.resolver, object).expect("natives only get live objects") {
+            ReferenceType::Class(class) => self.resolver.retrieve(class).name().to_owned(),
+            ReferenceType::Array(ty) => ty.descriptor(self.resolver).to_string(),
+        }
+    }
+
+    fn field(&self, object: JavaObject, name: &str, descriptor: &str) -> Option<*mut u8> {
+        // Arrays don't have fields
+        let field = find_instance_field(self.resolver, class_of(self.resolver, object)?, name, descriptor)?;
+        // Safe because the field lies inside of the object
+        Some(unsafe { (object as *mut u8).add(field.offset) })
+    }
+
+    fn type_of(&self, object: JavaObject) -> Option<ReferenceType> {
+        type_of(self.resolver, object)
+    }
+
+    fn is_assignable(&self, from: ReferenceType, to: ReferenceType) -> bool {
+        is_assignable(self.resolver, from, to)
+    }
+
+    fn is_instance_of(&self, object: JavaObject, class: &str) -> bool {
+        let loader = match class_of(self.resolver, object) {
+            Some(object_class) => self.resolver.retrieve(object_class).loader,
+            None => return class == "java/lang/Object",
+        };
+        self.resolver.lookup(loader, class).is_some_and(|class| is_instance(self.resolver, object, ReferenceType::Class(class)))
+    }
+
+    fn java_to_string(&self, object: JavaObject) -> Option<JavaObject> {
+        let class = class_of(self.resolver, object)?;
+        let vtable = &self.resolver.retrieve(class).dispatch.vtable;
+        let method = vtable.find("toString", "()Ljava/lang/String;").and_then(|slot| vtable.get(slot)?.selected.method())?;
+        let data = self.resolver.retrieve(method.class_ref).retrieve_method(method);
+        if data.is_native() || data.code.is_none() {
+            return None;
+        }
+        match self.jit.invoke_method(method, self.resolver, &[JavaValue::Object(object)]) {
+            JavaValue::Object(string) => Some(string),
+            _ => None,
+        }
+    }
+}
+
+impl NativeEnv {
+    /// # Safety
+    /// The resolver and jit have to stay in place for as long as natives use the env
+    pub unsafe fn new<J: JitCompiler, R: ClassResolver<J>>(resolver: &R, jit: &J) -> Self {
+        unsafe fn with_vm<J: JitCompiler, R: ClassResolver<J>>(resolver: *const (), jit: *const (), f: &mut dyn FnMut(&dyn NativeVm)) {
+            f(&Vm { resolver: &*(resolver as *const R), jit: &*(jit as *const J) })
+        }
+        NativeEnv {
+            resolver: resolver as *const R as *const (),
+            jit: jit as *const J as *const (),
+            with_vm: with_vm::<J, R>,
+        }
+    }
+
+    fn with<T>(&self, f: impl FnOnce(&dyn NativeVm) -> T) -> T {
+        let mut f = Some(f);
+        let mut result = None;
+        // Safe because the creator of the env promised the vm stays in place
+        unsafe { (self.with_vm)(self.resolver, self.jit, &mut |vm| result = Some((f.take().unwrap())(vm))) };
+        result.unwrap()
+    }
+
+    /// Makes a runtime exception pending. The native should return right after, its return value is ignored.
+    pub fn throw(&self, exception: RuntimeException) {
+        self.with(|vm| vm.throw(exception))
+    }
+
+    pub fn heap(&self) -> &Heap {
+        // Safe because the heap lives as long as the vm
+        unsafe { &*self.with(|vm| vm.heap()) }
+    }
+
+    /// Creates a `java/lang/String`. It can be collected once the native returns, unless java code holds on to it.
+    pub fn new_string(&self, value: &str) -> JavaObject {
+        self.with(|vm| vm.new_string(&strings::to_utf16(value)))
+    }
+
+    /// Reads a `java/lang/String`, which can't be null
+    pub fn read_string(&self, string: JavaObject) -> String {
+        strings::from_utf16(&self.read_utf16(string))
+    }
+
+    /// The UTF-16 units of a `java/lang/String`, which can't be null
+    pub fn read_utf16(&self, string: JavaObject) -> Vec<u16> {
+        self.with(|vm| vm.read_string(string))
+    }
+
+    /// The binary name of the class of an object, which can't be null
+    pub fn class_name(&self, object: JavaObject) -> String {
+        self.with(|vm| vm.class_name(object))
+    }
+
+    /// Where a field of an object is stored, including inherited fields. The object can't be null.
+    pub fn field(&self, object: JavaObject, name: &str, descriptor: &str) -> Option<*mut u8> {
+        self.with(|vm| vm.field(object, name, descriptor))
+    }
+
+    /// The type of an object or array, or `None` if it isn't on the heap
+    pub fn type_of(&self, object: JavaObject) -> Option<ReferenceType> {
+        self.with(|vm| vm.type_of(object))
+    }
+
+    /// Checks if values of type `from` are also values of type `to`, see [is_assignable]
+    pub fn is_assignable(&self, from: ReferenceType, to: ReferenceType) -> bool {
+        self.with(|vm| vm.is_assignable(from, to))
+    }
+
+    /// Checks if an object is an instance of the class with the binary name `class`, as the loader
+    /// of the class of the object sees that name. The object can't be null.
+    pub fn is_instance_of(&self, object: JavaObject, class: &str) -> bool {
+        self.with(|vm| vm.is_instance_of(object, class))
+    }
+
+    /// Calls `toString` on an object whose class overrides it with java code, and returns the string.
+    /// Returns `None` if the selected `toString` is a native, the natives of the library are left to format
+    /// their objects without a call. If the call throws, the exception is pending and the string is null.
+    pub fn java_to_string(&self, object: JavaObject) -> Option<JavaObject> {
+        self.with(|vm| vm.java_to_string(object))
+    }
+}
+
+/// Rust functions that can implement a native method
+///
+/// # Safety
+/// [Self::address] has to return an `extern "C"` function with the parameters and return type described by the other functions
+pub unsafe trait NativeFunction: Copy {
+    /// Descriptor fragments of the parameters that come after the env
+    fn parameters() -> Vec<&'static str>;
+
+    fn return_type() -> &'static str;
+
+    fn address(self) -> usize;
+}
+
+macro_rules! native_function_impl {
+    ($($param:ident),*) => {
+        unsafe impl<Ret: JavaCompatibleReturnType, $($param: JavaCompatibleArgumentType),*> NativeFunction for extern "C" fn(&NativeEnv, $($param),*) -> Ret {
+            fn parameters() -> Vec<&'static str> {
+                vec![$(<$param as JavaCompatibleArgumentType>::DESCRIPTOR_FRAGMENT),*]
+            }
+
+            fn return_type() -> &'static str {
+                Ret::DESCRIPTOR_FRAGMENT
+            }
+
+            fn address(self) -> usize {
+                self as usize
+            }
+        }
+    };
+}
+
+native_function_impl!();
+native_function_impl!(A);
+native_function_impl!(A, B);
+native_function_impl!(A, B, C);
+native_function_impl!(A, B, C, D);
+native_function_impl!(A, B, C, D, E);
+native_function_impl!(A, B, C, D, E, F);
+
+/// A registered native
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Native {
+    /// The address of the `extern "C"` function
+    pub address: usize,
+    /// If the function takes the object the method was called on, which means it implements an instance method
+    pub instance: bool,
+}
+
+/// Natives by the binary name of their class, their name and their descriptor
+#[derive(Default)]
+pub struct NativeMethods {
+    methods: HashMap<(String, String, String), Native>,
+}
+
+impl NativeMethods {
+    /// Registers a native, after checking that the function fits the descriptor.
+    /// Any reference type in the descriptor fits a [JavaObject].
+    pub fn register<F: NativeFunction>(&mut self, class: &str, name: &str, descriptor: &str, function: F) -> Result<(), NativeError> {
+        let method = format!("{}.{}{}", class, name, descriptor);
+        let parsed: MethodDescriptor = descriptor.parse().map_err(|_| NativeError::InvalidDescriptor(descriptor.to_owned()))?;
+        let mut parameters = F::parameters();
+        let instance = match parameters.len().checked_sub(parsed.parameters.len()) {
+            Some(0) => false,
+            Some(1) if parameters[0] == <JavaObject as JavaCompatibleArgumentType>::DESCRIPTOR_FRAGMENT => true,
+            _ => return Err(NativeError::SignatureMismatch(method)),
+        };
+        if instance {
+            parameters.remove(0);
+        }
+        if !parameters.iter().zip(&parsed.parameters).all(|(&fragment, ty)| fragment_fits(fragment, Some(ty)))
+            || !fragment_fits(F::return_type(), parsed.return_type.as_ref()) {
+            return Err(NativeError::SignatureMismatch(method));
+        }
+
+        self.methods.insert((class.to_owned(), name.to_owned(), descriptor.to_owned()), Native { address: function.address(), instance });
+        Ok(())
+    }
+
+    pub fn get(&self, class: &str, name: &str, descriptor: &str) -> Option<Native> {
+        self.methods.get(&(class.to_owned(), name.to_owned(), descriptor.to_owned())).copied()
+    }
+}
+
+/// Finds the native that implements a method
+pub fn find_native<J: JitCompiler>(resolver: &impl ClassResolver<J>, method: LoadedMethodRef) -> Result<Native, NativeError> {
+    let class = resolver.retrieve(method.class_ref);
+    let data = class.retrieve_method(method);
+    let name = || format!("{}.{}{}", class.name(), data.name, data.descriptor);
+    let native = resolver.natives().get(class.name(), data.name, data.descriptor).ok_or_else(|| NativeError::NotRegistered(name()))?;
+    if native.instance == data.is_static() {
+        return Err(NativeError::SignatureMismatch(name()));
+    }
+    Ok(native)
+}
+
+/// Calls the rust function of a native, passing `env` in front of `arguments`, which start with the object
+/// the method is called on for instance methods. Panics like [crate::interop::call_dynamic] does.
+///
+/// # Safety
+/// `address` has to be a native that fits the method with these arguments and `return_type`
+pub unsafe fn call_native(address: usize, env: &NativeEnv, arguments: &[JavaValue], return_type: Option<&FieldDescriptor>) -> JavaValue {
+    call_in_registers(address, Some(env as *const NativeEnv as u64), arguments, return_type)
+}
+
+/// Registers the natives of the class library that the vm implements itself
+pub fn register_builtins(natives: &mut NativeMethods) {
+    let hash_code: extern "C" fn(&NativeEnv, JavaObject) -> JavaInt = hash_code;
+    natives.register("java/lang/Object", "hashCode", "()I", hash_code).unwrap();
+    let arraycopy: extern "C" fn(&NativeEnv, JavaObject, JavaInt, JavaObject, JavaInt, JavaInt) -> JavaVoid = arraycopy;
+    natives.register("java/lang/System", "arraycopy", "(Ljava/lang/Object;ILjava/lang/Object;II)V", arraycopy).unwrap();
+    runtime::register_natives(natives);
+}
+
+/// `Object.hashCode`. Objects never move, so their address identifies them for as long as they live.
+extern "C" fn hash_code(_env: &NativeEnv, object: JavaObject) -> JavaInt {
+    identity_hash(object)
+}
+
+/// The hash code of an object that doesn't override `hashCode`
+pub(crate) fn identity_hash(object: JavaObject) -> JavaInt {
+    (object as usize >> 3) as JavaInt
+}
+
+/// `System.arraycopy`, the arrays may overlap. Primitive arrays need the same element type. Elements of arrays
+/// of references are checked one by one if the source elements can't all be stored in the destination, the ones
+/// before an element that doesn't fit are still copied.
+extern "C" fn arraycopy(env: &NativeEnv, src: JavaObject, src_pos: JavaInt, dest: JavaObject, dest_pos: JavaInt, length: JavaInt) {
+    if src.is_null() || dest.is_null() {
+        return env.throw(RuntimeException::NullPointer);
+    }
+    let (src_type, dest_type) = match (env.type_of(src), env.type_of(dest)) {
+        (Some(ReferenceType::Array(src_type)), Some(ReferenceType::Array(dest_type))) => (src_type, dest_type),
+        _ => return env.throw(RuntimeException::ArrayStore),
+    };
+    // The type that each element has to be checked against, if they need to be checked
+    let checked = match (src_type.component(), dest_type.component()) {
+        (None, None) if src_type == dest_type => None,
+        (Some(from), Some(to)) => (!env.is_assignable(from, to)).then_some(to),
+        _ => return env.throw(RuntimeException::ArrayStore),
+    };
+    let element_size = dest_type.element_size();
+    let (src, dest) = (src as *mut u8, dest as *mut u8);
+    // Safe because both are arrays, which start with their length
+    let in_bounds = |array: *mut u8, position: JavaInt| unsafe {
+        position >= 0 && length >= 0 && position as usize + length as usize <= *(array as *const usize)
+    };
+    if !in_bounds(src, src_pos) || !in_bounds(dest, dest_pos) {
+        return env.throw(RuntimeException::ArrayIndexOutOfBounds);
+    }
+    // Safe because both ranges were checked to be inside of the arrays
+    unsafe {
+        let element = |array: *mut u8, position: JavaInt| array.add(ARRAY_HEADER_SIZE + position as usize * element_size);
+        let component = match checked {
+            Some(component) => component,
+            None => return ptr::copy(element(src, src_pos), element(dest, dest_pos), length as usize * element_size),
+        };
+        // The arrays have different types, so they don't overlap
+        for offset in 0..length {
+            let object = *(element(src, src_pos + offset) as *const JavaObject);
+            if !object.is_null() && !env.type_of(object).is_some_and(|ty| env.is_assignable(ty, component)) {
+                return env.throw(RuntimeException::ArrayStore);
+            }
+            *(element(dest, dest_pos + offset) as *mut JavaObject) = object;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use classfile_parser::builder::ClassBuilder;
+    use classfile_parser::class_file::MethodAccessFlags;
+    use crate::class_loaders::{BootstrapClassLoader, ParentFirstClassLoader};
+    use crate::class_store::ClassStoreIsh;
+    use crate::exceptions::RuntimeException;
+    use crate::interop::{JavaInt, JavaObject};
+    use crate::natives::{arraycopy, find_native, hash_code, NativeEnv, NativeError, NativeMethods};
+    use crate::object::{allocate_array, ArrayType, BaseType, ARRAY_HEADER_SIZE};
+    use crate::strings::new_string;
+    use crate::test_util::{BuiltClassLoader, NoJit};
+    use crate::{ClassResolver, VirtualMachine};
+
+    #[test]
+    fn registration() {
+        let mut natives = NativeMethods::default();
+        extern "C" fn static_fn(_env: &NativeEnv, _a: JavaInt, _b: JavaObject) -> JavaInt { 0 }
+        extern "C" fn instance_fn(_env: &NativeEnv, _object: JavaObject) {}
+        let static_fn: extern "C" fn(&NativeEnv, JavaInt, JavaObject) -> JavaInt = static_fn;
+        let instance_fn: extern "C" fn(&NativeEnv, JavaObject) = instance_fn;
+        assert_eq!(natives.register("Foo", "a", "(I[B)I", static_fn), Ok(()));
+        assert_eq!(natives.register("Foo", "b", "(ILjava/lang/String;)I", static_fn), Ok(()));
+        assert_eq!(natives.register("Foo", "c", "()V", instance_fn), Ok(()));
+        assert_eq!(natives.register("Foo", "d", "(Ljava/lang/Object;)V", instance_fn), Ok(()));
+        assert!(natives.get("Foo", "a", "(I[B)I").is_some_and(|native| !native.instance));
+        assert!(natives.get("Foo", "c", "()V").is_some_and(|native| native.instance));
+        assert!(natives.get("Foo", "d", "(Ljava/lang/Object;)V").is_some_and(|native| !native.instance));
+        assert_eq!(natives.get("Foo", "a", "(I[B)V"), None);
+
+        let mismatch = |descriptor: &str| Err(NativeError::SignatureMismatch(format!("Foo.e{}", descriptor)));
+        assert_eq!(natives.register("Foo", "e", "(FLFoo;)I", static_fn), mismatch("(FLFoo;)I"));
+        assert_eq!(natives.register("Foo", "e", "(ILFoo;)V", static_fn), mismatch("(ILFoo;)V"));
+        assert_eq!(natives.register("Foo", "e", "(II)I", static_fn), mismatch("(II)I"));
+        assert_eq!(natives.register("Foo", "e", "()I", static_fn), mismatch("()I"));
+        assert_eq!(natives.register("Foo", "e", "(I)I", static_fn), mismatch("(I)I"));
+        assert_eq!(natives.register("Foo", "e", "(I", static_fn), Err(NativeError::InvalidDescriptor("(I".to_owned())));
+    }
+
+    #[test]
+    fn builtins() {
+        let mut main = ClassBuilder::new("Main", None);
+        main.method_without_code(MethodAccessFlags::STATIC | MethodAccessFlags::NATIVE, "hash", "(LMain;)I");
+        main.method_without_code(MethodAccessFlags::NATIVE, "hashCode", "()I");
+        main.method_without_code(MethodAccessFlags::STATIC | MethodAccessFlags::NATIVE, "count", "()I");
+        main.method_without_code(MethodAccessFlags::NATIVE, "missing", "()V");
+        let loader = ParentFirstClassLoader::new(BootstrapClassLoader::new(), BuiltClassLoader::new(vec![main.build().unwrap()]));
+        let mut vm = VirtualMachine::new(loader, NoJit);
+        let hash: extern "C" fn(&NativeEnv, JavaObject) -> JavaInt = hash_code;
+        vm.register_native("Main", "hash", "(LMain;)I", hash).unwrap();
+        vm.register_native("Main", "hashCode", "()I", hash).unwrap();
+        vm.register_native("Main", "count", "()I", hash).unwrap();
+        let main = vm.load_class("Main").unwrap();
+        vm.initialize(main).unwrap();
+        vm.load_class("java/lang/Object").unwrap();
+        let (store, jit) = (&vm.class_store, &vm.jit_engine);
+        let method = |name, descriptor| store.retrieve_method_ref(main, name, descriptor).unwrap();
+        assert!(store.retrieve(main).retrieve_method(method("hashCode", "()I")).is_native());
+        assert!(find_native(store, method("hash", "(LMain;)I")).is_ok_and(|native| !native.instance));
+        assert_eq!(find_native(store, method("count", "()I")), Err(NativeError::SignatureMismatch("Main.count()I".to_owned())));
+        assert_eq!(find_native(store, method("hashCode", "()I")).map(|native| native.address), Ok(hash as usize));
+        assert_eq!(find_native(store, method("missing", "()V")), Err(NativeError::NotRegistered("Main.missing()V".to_owned())));
+        assert!(store.natives().get("java/lang/System", "arraycopy", "(Ljava/lang/Object;ILjava/lang/Object;II)V").is_some());
+        // Natives can throw any runtime exception
+        assert!(RuntimeException::ALL.iter().all(|&exception| store.runtime_exceptions().class(exception).is_some()));
+
+        let env = unsafe { NativeEnv::new(store, jit) };
+        let ints = |values: &[JavaInt]| {
+            let array = allocate_array(store, jit, ArrayType::of(BaseType::Int), values.len());
+            unsafe { (array.add(ARRAY_HEADER_SIZE) as *mut JavaInt).copy_from(values.as_ptr(), values.len()) };
+            array as JavaObject
+        };
+        let read = |array: JavaObject| unsafe { std::slice::from_raw_parts((array as *const u8).add(ARRAY_HEADER_SIZE) as *const JavaInt, 4).to_vec() };
+        let (src, dest) = (ints(&[1, 2, 3, 4]), ints(&[0; 4]));
+        arraycopy(&env, src, 1, dest, 0, 3);
+        assert_eq!(read(dest), [2, 3, 4, 0]);
+        arraycopy(&env, src, 0, src, 1, 3);
+        assert_eq!(read(src), [1, 1, 2, 3]);
+        assert_eq!(store.exception().get(), None);
+        assert_ne!(hash_code(&env, src), hash_code(&env, dest));
+
+        let thrown = |exception: RuntimeException| store.exception().take().map(|object| unsafe { (*object).class }) == store.runtime_exceptions().class(exception);
+        arraycopy(&env, src, 2, dest, 0, 3);
+        assert!(thrown(RuntimeException::ArrayIndexOutOfBounds));
+        arraycopy(&env, src, 0, dest, 0, -1);
+        assert!(thrown(RuntimeException::ArrayIndexOutOfBounds));
+        arraycopy(&env, src, 0, std::ptr::null_mut(), 0, 1);
+        assert!(thrown(RuntimeException::NullPointer));
+        let bytes = allocate_array(store, jit, ArrayType::of(BaseType::Byte), 4) as JavaObject;
+        arraycopy(&env, src, 0, bytes, 0, 1);
+        assert!(thrown(RuntimeException::ArrayStore));
+        assert_eq!(read(dest), [2, 3, 4, 0]);
+        // Primitives of the same size but another type don't fit either
+        let floats = allocate_array(store, jit, ArrayType::of(BaseType::Float), 4) as JavaObject;
+        arraycopy(&env, src, 0, floats, 0, 1);
+        assert!(thrown(RuntimeException::ArrayStore));
+        let (shorts, chars) = (allocate_array(store, jit, ArrayType::of(BaseType::Short), 1), allocate_array(store, jit, ArrayType::of(BaseType::Char), 1));
+        arraycopy(&env, shorts as JavaObject, 0, chars as JavaObject, 0, 1);
+        assert!(thrown(RuntimeException::ArrayStore));
+
+        // Arrays of references are checked element by element
+        let class = |name| BaseType::Class(store.lookup(store.retrieve(main).loader, name).unwrap());
+        let objects = allocate_array(store, jit, ArrayType::of(class("java/lang/Object")), 3);
+        let strings = allocate_array(store, jit, ArrayType::of(class("java/lang/String")), 3);
+        let string = new_string(store, jit, &[0x61]).unwrap();
+        let elements = [string, std::ptr::null_mut(), src];
+        unsafe { (objects.add(ARRAY_HEADER_SIZE) as *mut JavaObject).copy_from(elements.as_ptr(), 3) };
+        let element = |array: *mut u8, index: usize| unsafe { *(array.add(ARRAY_HEADER_SIZE) as *const JavaObject).add(index) };
+        arraycopy(&env, objects as JavaObject, 0, strings as JavaObject, 0, 2);
+        assert_eq!(store.exception().get(), None);
+        assert_eq!((element(strings, 0), element(strings, 1)), (string, std::ptr::null_mut()));
+        arraycopy(&env, objects as JavaObject, 1, strings as JavaObject, 0, 2);
+        assert!(thrown(RuntimeException::ArrayStore));
+        // The elements before the one that doesn't fit are copied
+        assert_eq!(element(strings, 0), std::ptr::null_mut());
+        arraycopy(&env, strings as JavaObject, 0, objects as JavaObject, 0, 1);
+        assert_eq!(store.exception().get(), None);
+        assert_eq!(element(objects, 0), std::ptr::null_mut());
+    }
+}
@@ -0,0 +1,439 @@
+use std::mem::{align_of, size_of};
+
+use classfile_parser::class_file::{ClassAccessFlags, ClassFile, FieldAccessFlags};
+use classfile_parser::descriptor::FieldDescriptor;
+use classfile_parser::ClassParseError;
+
+use crate::class_loaders::LoadError;
+use crate::class_store::{ClassStoreIsh, LoadedClassRef};
+use crate::classfile_util::ConstantPoolExtensions;
+use crate::heap::ObjectKind;
+use crate::resolution::{resolve_class, resolve_field, ResolveError};
+use crate::{ClassResolver, JitCompiler, LoaderId};
+
+/// The start of every object. The fields of the object follow directly after it.
+#[repr(C)]
+#[derive(Debug)]
+pub struct ObjectHeader {
+    pub class: LoadedClassRef,
+    /// Reserved for the identity hash code and locking
+    pub lock_word: usize,
+}
+
+/// Offset of the first field in an object
+pub const HEADER_SIZE: usize = size_of::<ObjectHeader>();
+
+/// Arrays start with their length as a `usize`, the elements follow after it. The length is padded to 8 bytes,
+/// so that longs and doubles are aligned on every target.
+pub const ARRAY_HEADER_SIZE: usize = 8;
+
+/// The type of an array: `dimensions` levels of arrays around elements of type `base`. The heap records it
+/// for every array, see [ObjectKind::Array]. For example, `int[][]` has `Int` as its base and 2 dimensions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ArrayType {
+    pub base: BaseType,
+    /// At least 1
+    pub dimensions: u8,
+}
+
+/// The innermost element type of an array
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BaseType {
+    Boolean,
+    Byte,
+    Char,
+    Short,
+    Int,
+    Float,
+    Long,
+    Double,
+    Class(LoadedClassRef),
+}
+
+/// The type of a reference that isn't null
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReferenceType {
+    Class(LoadedClassRef),
+    Array(ArrayType),
+}
+
+impl BaseType {
+    /// The primitive type of a descriptor, or `None` for classes and arrays
+    pub fn primitive(descriptor: &FieldDescriptor) -> Option<BaseType> {
+        Some(match descriptor {
+            FieldDescriptor::Boolean => BaseType::Boolean,
+            FieldDescriptor::Byte => BaseType::Byte,
+            FieldDescriptor::Char => BaseType::Char,
+            FieldDescriptor::Short => BaseType::Short,
+            FieldDescriptor::Int => BaseType::Int,
+            FieldDescriptor::Float => BaseType::Float,
+            FieldDescriptor::Long => BaseType::Long,
+            FieldDescriptor::Double => BaseType::Double,
+            FieldDescriptor::Object(_) | FieldDescriptor::Array(_) => return None,
+        })
+    }
+}
+
+impl ArrayType {
+    /// An array with a single dimension
+    pub const fn of(base: BaseType) -> ArrayType {
+        ArrayType { base, dimensions: 1 }
+    }
+
+    /// An array of a primitive type, or `None` if `element` isn't primitive
+    pub fn primitive(element: &FieldDescriptor) -> Option<ArrayType> {
+        Some(ArrayType::of(BaseType::primitive(element)?))
+    }
+
+    /// The type of the elements, or `None` if they are primitives
+    pub fn component(self) -> Option<ReferenceType> {
+        match (self.dimensions, self.base) {
+            (1, BaseType::Class(class)) => Some(ReferenceType::Class(class)),
+            (1, _) => None,
+            (dimensions, base) => Some(ReferenceType::Array(ArrayType { base, dimensions: dimensions - 1 })),
+        }
+    }
+
+    /// The descriptor of the elements, like `I` for `int[]`
+    pub fn element_descriptor<J: JitCompiler>(self, resolver: &impl ClassStoreIsh<J>) -> FieldDescriptor {
+        let mut descriptor = match self.base {
+            BaseType::Boolean => FieldDescriptor::Boolean,
+            BaseType::Byte => FieldDescriptor::Byte,
+            BaseType::Char => FieldDescriptor::Char,
+            BaseType::Short => FieldDescriptor::Short,
+            BaseType::Int => FieldDescriptor::Int,
+            BaseType::Float => FieldDescriptor::Float,
+            BaseType::Long => FieldDescriptor::Long,
+            BaseType::Double => FieldDescriptor::Double,
+            BaseType::Class(class) => FieldDescriptor::Object(resolver.retrieve(class).name().to_owned()),
+        };
+        for _ in 1..self.dimensions {
+            descriptor = FieldDescriptor::Array(Box::new(descriptor));
+        }
+        descriptor
+    }
+
+    /// The descriptor of the array itself, like `[I`, which is also the name of its class
+    pub fn descriptor<J: JitCompiler>(self, resolver: &impl ClassStoreIsh<J>) -> FieldDescriptor {
+        FieldDescriptor::Array(Box::new(self.element_descriptor(resolver)))
+    }
+
+    /// Amount of bytes each element takes up
+    pub fn element_size(self) -> usize {
+        match (self.component(), self.base) {
+            (Some(_), _) => size_of::<usize>(),
+            (None, BaseType::Boolean | BaseType::Byte) => 1,
+            (None, BaseType::Char | BaseType::Short) => 2,
+            (None, BaseType::Int | BaseType::Float) => 4,
+            (None, _) => 8,
+        }
+    }
+}
+
+impl ReferenceType {
+    /// The type that a reference descriptor names, as `loader` sees it. `None` for primitives,
+    /// and if a class of the descriptor isn't loaded.
+    pub fn of_descriptor<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, loader: LoaderId, descriptor: &FieldDescriptor) -> Option<ReferenceType> {
+        let mut element = descriptor;
+        let mut dimensions = 0u8;
+        while let FieldDescriptor::Array(component) = element {
+            element = component;
+            dimensions = dimensions.checked_add(1)?;
+        }
+        let base = match element {
+            FieldDescriptor::Object(class) => BaseType::Class(resolver.lookup(loader, class)?),
+            primitive => BaseType::primitive(primitive)?,
+        };
+        match (dimensions, base) {
+            (0, BaseType::Class(class)) => Some(ReferenceType::Class(class)),
+            (0, _) => None,
+            (dimensions, base) => Some(ReferenceType::Array(ArrayType { base, dimensions })),
+        }
+    }
+
+    /// The type of an array with elements of this type, or `None` if it would have more than 255 dimensions
+    pub fn array(self) -> Option<ArrayType> {
+        match self {
+            ReferenceType::Class(class) => Some(ArrayType::of(BaseType::Class(class))),
+            ReferenceType::Array(array) => Some(ArrayType { dimensions: array.dimensions.checked_add(1)?, ..array }),
+        }
+    }
+}
+
+/// Where the instance fields of a class are stored inside of its objects.
+/// A class starts where the layout of its superclass ends, so an object can be used as an instance of its superclass.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstanceLayout {
+    /// Size of an object of this class, including the header
+    pub size: usize,
+    /// Only the fields declared by this class, the fields of superclasses are in their layouts
+    fields: Vec<InstanceField>,
+    /// Offsets of all reference fields, including inherited ones
+    references: Vec<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstanceField {
+    pub name: String,
+    pub descriptor: FieldDescriptor,
+    /// Byte offset from the start of the object
+    pub offset: usize,
+}
+
+/// An instance field that a `FieldRef` constant resolved to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstanceFieldRef {
+    /// The class that declares the field
+    pub class: LoadedClassRef,
+    pub offset: usize,
+    pub descriptor: FieldDescriptor,
+}
+
+/// Amount of bytes a field of this type takes up inside an object. Fields are aligned to their size.
+pub fn field_size(descriptor: &FieldDescriptor) -> usize {
+    match descriptor {
+        FieldDescriptor::Boolean | FieldDescriptor::Byte => 1,
+        FieldDescriptor::Char | FieldDescriptor::Short => 2,
+        FieldDescriptor::Int | FieldDescriptor::Float => 4,
+        FieldDescriptor::Long | FieldDescriptor::Double => 8,
+        FieldDescriptor::Object(_) | FieldDescriptor::Array(_) => size_of::<usize>(),
+    }
+}
+
+impl InstanceLayout {
+    /// Lays out the instance fields of a class after the ones of its superclass.
+    /// Larger fields go first, so that little space is lost to padding.
+    pub fn compute(class: &ClassFile, super_layout: Option<&InstanceLayout>) -> Result<Self, LoadError> {
+        let pool = &class.constant_pool;
+        let class_name = || pool.get_class_name(class.this_class).unwrap_or_default().to_owned();
+        let invalid_index = |index| LoadError::ParseError(class_name(), ClassParseError::InvalidConstantPoolIndex(index));
+
+        let mut fields = Vec::new();
+        for field in &class.fields {
+            if field.access_flags.contains(FieldAccessFlags::STATIC) {
+                continue;
+            }
+            let name = pool.get_as_string(field.name_index).ok_or_else(|| invalid_index(field.name_index))?;
+            let descriptor = pool.get_as_string(field.descriptor).ok_or_else(|| invalid_index(field.descriptor))?;
+            let descriptor: FieldDescriptor = descriptor.parse().map_err(|e| LoadError::InvalidDescriptor(descriptor.to_owned(), e))?;
+            fields.push(InstanceField { name: name.to_owned(), descriptor, offset: 0 });
+        }
+        fields.sort_by_key(|field| std::cmp::Reverse(field_size(&field.descriptor)));
+
+        let mut end = super_layout.map_or(HEADER_SIZE, |layout| layout.size);
+        for field in &mut fields {
+            let size = field_size(&field.descriptor);
+            field.offset = align_up(end, size);
+            end = field.offset + size;
+        }
+        let mut references = super_layout.map_or_else(Vec::new, |layout| layout.references.clone());
+        references.extend(fields.iter().filter(|field| matches!(field.descriptor, FieldDescriptor::Object(_) | FieldDescriptor::Array(_))).map(|field| field.offset));
+        Ok(InstanceLayout { size: align_up(end, align_of::<ObjectHeader>()), fields, references })
+    }
+
+    /// Finds a field declared by this class
+    pub fn find(&self, name: &str, descriptor: &FieldDescriptor) -> Option<&InstanceField> {
+        self.fields.iter().find(|field| field.name == name && &field.descriptor == descriptor)
+    }
+
+    pub fn fields(&self) -> &[InstanceField] {
+        &self.fields
+    }
+
+    /// Where the garbage collector finds the references in an object
+    pub fn references(&self) -> &[usize] {
+        &self.references
+    }
+}
+
+fn align_up(offset: usize, alignment: usize) -> usize {
+    offset.next_multiple_of(alignment)
+}
+
+/// Resolves the class of a `New` instruction, which needs to be a class that can be instantiated
+pub fn resolve_new<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, referrer: LoadedClassRef, index: u16) -> Result<LoadedClassRef, ResolveError> {
+    let class = resolve_class(resolver, referrer, index)?;
+    let data = resolver.retrieve(class);
+    if data.java_class.access_flags.intersects(ClassAccessFlags::INTERFACE | ClassAccessFlags::ABSTRACT) {
+        return Err(ResolveError::InstantiationError(data.name().to_owned()));
+    }
+    Ok(class)
+}
+
+/// Resolves the `FieldRef` at `index` in the constant pool of `referrer` to an instance field
+pub fn resolve_instance_field<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, referrer: LoadedClassRef, index: u16) -> Result<InstanceFieldRef, ResolveError> {
+    let field = resolve_field(resolver, referrer, index)?;
+    let class = resolver.retrieve(field.class);
+    let full_name = || format!("{}.{}:{}", class.name(), field.name, field.descriptor);
+    if field.is_static() {
+        return Err(ResolveError::IncompatibleClassChangeError(format!("{} is static", full_name())));
+    }
+    let offset = class.layout.find(&field.name, &field.descriptor).ok_or_else(|| ResolveError::NoSuchFieldError(full_name()))?.offset;
+    Ok(InstanceFieldRef { class: field.class, offset, descriptor: field.descriptor })
+}
+
+/// Finds an instance field by name in `class` or one of its superclasses
+pub fn find_instance_field<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, class: LoadedClassRef, name: &str, descriptor: &str) -> Option<InstanceFieldRef> {
+    let (class, _) = resolver.find_field(class, name, descriptor)?;
+    let descriptor = descriptor.parse().ok()?;
+    let offset = resolver.retrieve(class).layout.find(name, &descriptor)?.offset;
+    Some(InstanceFieldRef { class, offset, descriptor })
+}
+
+/// The class of an object, or `None` for arrays, which don't have a header, and for anything that isn't on the heap
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn class_of<J: JitCompiler>(resolver: &impl ClassResolver<J>, object: *mut ObjectHeader) -> Option<LoadedClassRef> {
+    match type_of(resolver, object)? {
+        ReferenceType::Class(class) => Some(class),
+        ReferenceType::Array(_) => None,
+    }
+}
+
+/// The type of an object or an array, or `None` for anything that isn't on the heap
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn type_of<J: JitCompiler>(resolver: &impl ClassResolver<J>, object: *mut ObjectHeader) -> Option<ReferenceType> {
+    match resolver.heap().kind(object as *const u8)? {
+        // Safe because the heap has an object with a header there
+        ObjectKind::Instance => Some(ReferenceType::Class(unsafe { (*object).class })),
+        ObjectKind::Array(ty) => Some(ReferenceType::Array(ty)),
+    }
+}
+
+/// The class that selects the methods called on an object: its own class, or `java/lang/Object` for arrays.
+/// `referrer` can be any class, it's only used to find `java/lang/Object`.
+pub fn dispatch_class<J: JitCompiler>(resolver: &impl ClassResolver<J>, object: *mut ObjectHeader, referrer: LoadedClassRef) -> LoadedClassRef {
+    class_of(resolver, object).unwrap_or_else(|| resolver.root_class(referrer))
+}
+
+/// Checks if an object can be cast to `ty`, like `instanceof` does
+pub fn is_instance<J: JitCompiler>(resolver: &impl ClassResolver<J>, object: *mut ObjectHeader, ty: ReferenceType) -> bool {
+    type_of(resolver, object).is_some_and(|object_type| is_assignable(resolver, object_type, ty))
+}
+
+/// Checks if values of type `from` are also values of type `to`. Arrays are instances of `java/lang/Object`,
+/// `java/lang/Cloneable` and `java/io/Serializable`, and of arrays whose elements their own elements can be assigned to.
+/// See: https://docs.oracle.com/javase/specs/jvms/se16/html/jvms-6.html#jvms-6.5.checkcast
+pub fn is_assignable<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, from: ReferenceType, to: ReferenceType) -> bool {
+    match (from, to) {
+        (ReferenceType::Class(from), ReferenceType::Class(to)) => resolver.is_instance_of(from, to),
+        (ReferenceType::Array(_), ReferenceType::Class(to)) => {
+            let data = resolver.retrieve(to);
+            data.super_class.is_none() || matches!(data.name(), "java/lang/Cloneable" | "java/io/Serializable")
+        }
+        (ReferenceType::Class(_), ReferenceType::Array(_)) => false,
+        (ReferenceType::Array(from), ReferenceType::Array(to)) => match (from.component(), to.component()) {
+            (Some(from), Some(to)) => is_assignable(resolver, from, to),
+            (None, None) => from == to,
+            _ => false,
+        },
+    }
+}
+
+/// Allocates a zeroed instance of a class on the heap, and fills in the header. Compiled code calls this for `New`.
+pub fn allocate_object<J: JitCompiler>(resolver: &impl ClassResolver<J>, jit: &J, class: LoadedClassRef) -> *mut ObjectHeader {
+    let size = resolver.retrieve(class).layout.size;
+    let object = resolver.allocate(jit, size, ObjectKind::Instance) as *mut ObjectHeader;
+    // Safe because the allocation is large enough for the header, and aligned for it
+    unsafe {
+        object.write(ObjectHeader { class, lock_word: 0 });
+    }
+    object
+}
+
+/// Allocates a zeroed array of `length` elements on the heap, and stores its length
+pub fn allocate_array<J: JitCompiler>(resolver: &impl ClassResolver<J>, jit: &J, ty: ArrayType, length: usize) -> *mut u8 {
+    let array = resolver.allocate(jit, ARRAY_HEADER_SIZE + ty.element_size() * length, ObjectKind::Array(ty));
+    // Safe because the allocation starts with room for the length
+    unsafe {
+        (array as *mut usize).write(length);
+    }
+    array
+}
+
+#[cfg(test)]
+mod tests {
+    use classfile_parser::builder::ClassBuilder;
+    use classfile_parser::bytecode::Instruction;
+    use classfile_parser::class_file::{ClassAccessFlags, FieldAccessFlags, MethodAccessFlags};
+    use classfile_parser::descriptor::FieldDescriptor;
+    use crate::class_store::ClassStoreIsh;
+    use crate::object::{allocate_array, allocate_object, resolve_instance_field, resolve_new, ArrayType, BaseType, ARRAY_HEADER_SIZE, HEADER_SIZE};
+    use crate::resolution::ResolveError;
+    use crate::test_util::{BuiltClassLoader, NoJit};
+    use crate::{ClassResolver, VirtualMachine};
+
+    #[test]
+    fn layout_and_resolution() {
+        let mut a = ClassBuilder::new("A", None);
+        a.field(FieldAccessFlags::empty(), "a", "B");
+        a.field(FieldAccessFlags::empty(), "b", "J");
+        a.field(FieldAccessFlags::empty(), "c", "I");
+        a.field(FieldAccessFlags::STATIC, "s", "J");
+        a.field(FieldAccessFlags::empty(), "d", "Ljava/lang/Object;");
+        let mut b = ClassBuilder::new("B", Some("A"));
+        b.field(FieldAccessFlags::empty(), "e", "S");
+        b.field(FieldAccessFlags::empty(), "c", "I");
+        let mut abstract_class = ClassBuilder::new("C", None);
+        abstract_class.access_flags(ClassAccessFlags::PUBLIC | ClassAccessFlags::ABSTRACT);
+
+        let mut main = ClassBuilder::new("Main", None);
+        let new_b = main.class("B");
+        let new_c = main.class("C");
+        let shadowing = main.field_ref("B", "c", "I");
+        let inherited = main.field_ref("B", "a", "B");
+        let static_field = main.field_ref("B", "s", "J");
+        main.method(MethodAccessFlags::STATIC, "run", "()V", vec![
+            Instruction::New(new_b),
+            Instruction::New(new_c),
+            Instruction::GetField(shadowing),
+            Instruction::GetField(inherited),
+            Instruction::GetField(static_field),
+            Instruction::Return,
+        ]);
+
+        let classes = vec![a.build().unwrap(), b.build().unwrap(), abstract_class.build().unwrap(), main.build().unwrap()];
+        let mut vm = VirtualMachine::new(BuiltClassLoader::new(classes), NoJit);
+        let main = vm.load_class("Main").unwrap();
+        let run = vm.class_store.retrieve_method_ref(main, "run", "()V").unwrap();
+        vm.load_references(run, &mut Default::default()).unwrap();
+        let store = &vm.class_store;
+        let (a, b) = (vm.lookup("A").unwrap(), vm.lookup("B").unwrap());
+
+        let layout = &store.retrieve(a).layout;
+        let offsets = ["b", "d", "c", "a"].map(|name| layout.fields().iter().find(|field| field.name == name).unwrap().offset);
+        assert_eq!(offsets, [HEADER_SIZE, HEADER_SIZE + 8, HEADER_SIZE + 16, HEADER_SIZE + 20]);
+        assert_eq!(layout.size, HEADER_SIZE + 24);
+        let layout = &store.retrieve(b).layout;
+        assert_eq!(layout.find("c", &FieldDescriptor::Int).unwrap().offset, HEADER_SIZE + 24);
+        assert_eq!(layout.find("e", &FieldDescriptor::Short).unwrap().offset, HEADER_SIZE + 28);
+        assert_eq!(layout.size, HEADER_SIZE + 32);
+
+        assert_eq!(resolve_new(store, main, new_b), Ok(b));
+        assert_eq!(resolve_new(store, main, new_c), Err(ResolveError::InstantiationError("C".to_owned())));
+        let field = resolve_instance_field(store, main, shadowing).unwrap();
+        assert_eq!((field.class, field.offset), (b, HEADER_SIZE + 24));
+        let field = resolve_instance_field(store, main, inherited).unwrap();
+        assert_eq!((field.class, field.offset), (a, HEADER_SIZE + 20));
+        assert!(matches!(resolve_instance_field(store, main, static_field), Err(ResolveError::IncompatibleClassChangeError(_))));
+    }
+
+    #[test]
+    fn allocate() {
+        let mut a = ClassBuilder::new("A", None);
+        a.field(FieldAccessFlags::empty(), "a", "J");
+        let mut vm = VirtualMachine::new(BuiltClassLoader::new(vec![a.build().unwrap()]), NoJit);
+        let a = vm.load_class("A").unwrap();
+        let (store, jit) = (&vm.class_store, &vm.jit_engine);
+
+        let object = allocate_object(store, jit, a);
+        let array = allocate_array(store, jit, ArrayType::of(BaseType::Short), 3);
+        unsafe {
+            assert_eq!((*object).class, a);
+            assert_eq!((*object).lock_word, 0);
+            assert_eq!(*((object as *const u8).add(HEADER_SIZE) as *const u64), 0);
+            assert_eq!(*(array as *const usize), 3);
+            assert_eq!(*(array.add(ARRAY_HEADER_SIZE + 4) as *const u16), 0);
+        }
+        assert_eq!(store.heap().allocated(), HEADER_SIZE + 8 + ARRAY_HEADER_SIZE + 6);
+    }
+}
@@ -0,0 +1,427 @@
+//! Turns symbolic references from the constant pool into loaded classes and members.
+//! The classes that are referenced need to be loaded already.
+//! See: https://docs.oracle.com/javase/specs/jvms/se16/html/jvms-5.html#jvms-5.4.3
+
+use classfile_parser::attributes::{AttributeEntry, ConstantValue, ConstantValueAttribute};
+use classfile_parser::class_file::{ClassAccessFlags, FieldAccessFlags, MethodAccessFlags};
+use classfile_parser::constant_pool::{types, ConstantPool, TypeRefInfo};
+use classfile_parser::descriptor::{FieldDescriptor, MethodDescriptor};
+use thiserror::Error;
+
+use crate::class_store::{ClassStoreIsh, LoadedClassRef, LoadedMethodRef, Visibility};
+use crate::dispatch::{all_interfaces, maximally_specific, maximally_specific_methods};
+use crate::classfile_util::ConstantPoolExtensions;
+use crate::object::{ArrayType, BaseType, ReferenceType};
+use crate::JitCompiler;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ResolveError {
+    #[error("invalid constant pool reference: {0}")]
+    InvalidConstantPoolIndex(u16),
+    #[error("{0} is referenced but wasn't loaded")]
+    NotLoaded(String),
+    #[error("no field {0}")]
+    NoSuchFieldError(String),
+    #[error("no method {0}")]
+    NoSuchMethodError(String),
+    #[error("{0}")]
+    IncompatibleClassChangeError(String),
+    #[error("{0} can't be instantiated")]
+    InstantiationError(String),
+    #[error("{0} is abstract")]
+    AbstractMethodError(String),
+    #[error("{0}")]
+    IllegalAccessError(String),
+    #[error("{0}")]
+    BootstrapMethodError(String),
+}
+
+/// A field that a `FieldRef` constant resolved to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedField {
+    /// The class that declares the field, which isn't necessarily the class the `FieldRef` names
+    pub class: LoadedClassRef,
+    pub name: String,
+    pub descriptor: FieldDescriptor,
+    pub access_flags: FieldAccessFlags,
+}
+
+impl ResolvedField {
+    pub fn is_static(&self) -> bool {
+        self.access_flags.contains(FieldAccessFlags::STATIC)
+    }
+}
+
+/// Resolves the `Class` constant at `index` in the constant pool of `referrer`
+pub fn resolve_class<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, referrer: LoadedClassRef, index: u16) -> Result<LoadedClassRef, ResolveError> {
+    let name = resolver.retrieve(referrer).java_class.constant_pool.get_class_name(index).ok_or(ResolveError::InvalidConstantPoolIndex(index))?;
+    resolve_class_name(resolver, referrer, name)
+}
+
+/// Resolves the `Class` constant at `index` in the constant pool of `referrer`, which can also name an array type
+/// like `[I`. An array type can be accessed if its element class can.
+pub fn resolve_type<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, referrer: LoadedClassRef, index: u16) -> Result<ReferenceType, ResolveError> {
+    let name = resolver.retrieve(referrer).java_class.constant_pool.get_class_name(index).ok_or(ResolveError::InvalidConstantPoolIndex(index))?;
+    if !name.starts_with('[') {
+        return resolve_class_name(resolver, referrer, name).map(ReferenceType::Class);
+    }
+    let mut element: FieldDescriptor = name.parse().map_err(|_| ResolveError::InvalidConstantPoolIndex(index))?;
+    let mut dimensions = 0;
+    while let FieldDescriptor::Array(component) = element {
+        element = *component;
+        // Array types can't have more than 255 dimensions
+        dimensions = u8::checked_add(dimensions, 1).ok_or(ResolveError::InvalidConstantPoolIndex(index))?;
+    }
+    let base = match &element {
+        FieldDescriptor::Object(class) => BaseType::Class(resolve_class_name(resolver, referrer, class)?),
+        primitive => BaseType::primitive(primitive).unwrap(),
+    };
+    Ok(ReferenceType::Array(ArrayType { base, dimensions }))
+}
+
+fn resolve_class_name<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, referrer: LoadedClassRef, name: &str) -> Result<LoadedClassRef, ResolveError> {
+    let data = resolver.retrieve(referrer);
+    let class = resolver.lookup(data.loader, name).ok_or_else(|| ResolveError::NotLoaded(name.to_owned()))?;
+    let target = resolver.retrieve(class);
+    if !target.java_class.access_flags.contains(ClassAccessFlags::PUBLIC) && target.runtime_package() != data.runtime_package() {
+        return Err(ResolveError::IllegalAccessError(format!("{} can't access {}", data.name(), name)));
+    }
+    Ok(class)
+}
+
+/// Resolves the `FieldRef` constant at `index` in the constant pool of `referrer`
+pub fn resolve_field<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, referrer: LoadedClassRef, index: u16) -> Result<ResolvedField, ResolveError> {
+    let pool = &resolver.retrieve(referrer).java_class.constant_pool;
+    let field_ref = pool.get_as::<types::FieldRef>(index).ok_or(ResolveError::InvalidConstantPoolIndex(index))?;
+    let (name, descriptor) = name_and_type(pool, field_ref)?;
+
+    let class = resolve_class(resolver, referrer, field_ref.class_index)?;
+    let full_name = || format!("{}.{}:{}", resolver.retrieve(class).name(), name, descriptor);
+    let (declaring, field) = resolver.find_field(class, name, descriptor).ok_or_else(|| ResolveError::NoSuchFieldError(full_name()))?;
+    let is_static = field.access_flags.contains(FieldAccessFlags::STATIC);
+    if !is_accessible(resolver, referrer, class, declaring, Visibility::from_field_flags(&field.access_flags), is_static) {
+        return Err(illegal_access(resolver, referrer, full_name()));
+    }
+    Ok(ResolvedField {
+        class: declaring,
+        name: name.to_owned(),
+        descriptor: descriptor.parse().map_err(|_| ResolveError::NoSuchFieldError(full_name()))?,
+        access_flags: field.access_flags,
+    })
+}
+
+/// Resolves the `MethodRef` or `InterfaceMethodRef` constant at `index` in the constant pool of `referrer`.
+/// See: https://docs.oracle.com/javase/specs/jvms/se16/html/jvms-5.html#jvms-5.4.3.3
+pub fn resolve_method<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, referrer: LoadedClassRef, index: u16) -> Result<LoadedMethodRef, ResolveError> {
+    let pool = &resolver.retrieve(referrer).java_class.constant_pool;
+    let (method_ref, interface_ref) = match pool.get_as::<types::MethodRef>(index) {
+        Some(method_ref) => (method_ref, false),
+        None => (pool.get_as::<types::InterfaceMethodRef>(index).ok_or(ResolveError::InvalidConstantPoolIndex(index))?, true),
+    };
+    let (name, descriptor) = name_and_type(pool, method_ref)?;
+
+    let class = match resolve_type(resolver, referrer, method_ref.class_index)? {
+        ReferenceType::Class(class) => class,
+        // Arrays have the methods of `java/lang/Object`
+        ReferenceType::Array(_) => resolver.root_class(referrer),
+    };
+    let data = resolver.retrieve(class);
+    let full_name = || format!("{}.{}{}", data.name(), name, descriptor);
+    if data.is_interface() != interface_ref {
+        let expected = if interface_ref { "an interface" } else { "a class" };
+        return Err(ResolveError::IncompatibleClassChangeError(format!("{} isn't {}", data.name(), expected)));
+    }
+
+    let method = if interface_ref {
+        lookup_interface_method(resolver, class, name, descriptor)
+    } else {
+        lookup_method(resolver, class, name, descriptor)
+    }.ok_or_else(|| ResolveError::NoSuchMethodError(full_name()))?;
+
+    let access_flags = resolver.retrieve(method.class_ref).method_info(method).access_flags;
+    if !is_accessible(resolver, referrer, class, method.class_ref, Visibility::from_flags(&access_flags), access_flags.contains(MethodAccessFlags::STATIC)) {
+        return Err(illegal_access(resolver, referrer, full_name()));
+    }
+    Ok(method)
+}
+
+const STRING_CONCAT_FACTORY: &str = "java/lang/invoke/StringConcatFactory";
+
+/// A piece of the string that an `invokedynamic` call site for string concatenation builds
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConcatPart {
+    /// Text, or another constant that's turned into text
+    Constant(ConstantValue),
+    /// The next argument of the call, which is of this type
+    Argument(FieldDescriptor),
+}
+
+/// Resolves the `InvokeDynamic` constant at `index` in the constant pool of `referrer`. The only call sites the vm
+/// can link are the string concatenations of `StringConcatFactory`, which `javac` emits for `+` on strings since java 9.
+/// See: https://docs.oracle.com/en/java/javase/16/docs/api/java.base/java/lang/invoke/StringConcatFactory.html
+pub fn resolve_string_concat<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, referrer: LoadedClassRef, index: u16) -> Result<Vec<ConcatPart>, ResolveError> {
+    let class = &resolver.retrieve(referrer).java_class;
+    let pool = &class.constant_pool;
+    let invalid = ResolveError::InvalidConstantPoolIndex;
+    let site = pool.get_as::<types::InvokeDynamicInfo>(index).ok_or(invalid(index))?;
+    let site_type = pool.get_as::<types::NameAndTypeInfo>(site.name_and_type_index).ok_or(invalid(site.name_and_type_index))?;
+    let descriptor: MethodDescriptor = pool.get_as_string(site_type.descriptor_index)
+        .and_then(|descriptor| descriptor.parse().ok())
+        .ok_or(invalid(site_type.descriptor_index))?;
+    let bootstrap = class.attributes.iter()
+        .find_map(|attribute| match attribute {
+            AttributeEntry::BootstrapMethods(bootstrap) => bootstrap.methods.get(site.bootstrap_method_attr_index as usize),
+            _ => None,
+        })
+        .ok_or(invalid(index))?;
+    let handle = pool.get_as::<types::MethodHandleInfo>(bootstrap.method_ref).ok_or(invalid(bootstrap.method_ref))?;
+    let method_ref = pool.get_as::<types::MethodRef>(handle.reference_index).ok_or(invalid(handle.reference_index))?;
+    let class_name = pool.get_class_name(method_ref.class_index).ok_or(invalid(method_ref.class_index))?;
+    let (name, _) = name_and_type(pool, method_ref)?;
+
+    let error = |message: &str| ResolveError::BootstrapMethodError(format!("{}.{}: {}", class_name, name, message));
+    if descriptor.return_type != Some(FieldDescriptor::Object("java/lang/String".to_owned())) {
+        return Err(error("the call site doesn't return a string"));
+    }
+    let mut parameters = descriptor.parameters.into_iter();
+    let parts = match (class_name, name) {
+        (STRING_CONCAT_FACTORY, "makeConcat") => parameters.by_ref().map(ConcatPart::Argument).collect(),
+        (STRING_CONCAT_FACTORY, "makeConcatWithConstants") => {
+            let constant = |index: u16| ConstantValueAttribute { value_index: index }.resolve(pool).map_err(|_| invalid(index));
+            let mut constants = bootstrap.arguments.iter();
+            let recipe = match constants.next().map(|&index| constant(index)).transpose()? {
+                Some(ConstantValue::String(recipe)) => recipe,
+                _ => return Err(error("the recipe isn't a string")),
+            };
+            // The recipe has \u0001 in place of an argument, and \u0002 in place of the next constant
+            let mut parts = Vec::new();
+            let mut text = Vec::new();
+            for unit in recipe {
+                let part = match unit {
+                    1 => ConcatPart::Argument(parameters.next().ok_or_else(|| error("the recipe has more arguments than the call site"))?),
+                    2 => ConcatPart::Constant(constant(*constants.next().ok_or_else(|| error("the recipe has more constants than the bootstrap method"))?)?),
+                    unit => {
+                        text.push(unit);
+                        continue;
+                    }
+                };
+                if !text.is_empty() {
+                    parts.push(ConcatPart::Constant(ConstantValue::String(std::mem::take(&mut text))));
+                }
+                parts.push(part);
+            }
+            if !text.is_empty() {
+                parts.push(ConcatPart::Constant(ConstantValue::String(text)));
+            }
+            parts
+        }
+        _ => return Err(error("unsupported bootstrap method")),
+    };
+    if parameters.next().is_some() {
+        return Err(error("the recipe has fewer arguments than the call site"));
+    }
+    Ok(parts)
+}
+
+/// Checks if code in `accessor` can use a member that's declared in `declaring`, through a reference that names `symbolic`.
+/// See: https://docs.oracle.com/javase/specs/jvms/se16/html/jvms-5.html#jvms-5.4.4
+pub fn is_accessible<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, accessor: LoadedClassRef, symbolic: LoadedClassRef, declaring: LoadedClassRef, visibility: Visibility, is_static: bool) -> bool {
+    let same_package = || resolver.retrieve(accessor).runtime_package() == resolver.retrieve(declaring).runtime_package();
+    match visibility {
+        Visibility::Public => true,
+        Visibility::Protected => same_package() || (resolver.is_subclass_of(accessor, declaring)
+            && (is_static || resolver.is_subclass_of(symbolic, accessor) || resolver.is_subclass_of(accessor, symbolic))),
+        Visibility::Package => same_package(),
+        Visibility::Private => accessor == declaring || nest_host(resolver, accessor) == nest_host(resolver, declaring),
+    }
+}
+
+/// The host of the nest that `class` belongs to. Classes that don't name a host,
+/// or whose host doesn't list them as a member, are their own host.
+pub fn nest_host<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, class: LoadedClassRef) -> LoadedClassRef {
+    let data = resolver.retrieve(class);
+    let pool = &data.java_class.constant_pool;
+    let host = data.java_class.attributes.iter()
+        .find_map(|attribute| match attribute {
+            AttributeEntry::NestHost(host) => pool.get_class_name(host.host_class_index),
+            _ => None,
+        })
+        .and_then(|name| resolver.lookup(data.loader, name));
+    let host = match host {
+        Some(host) => host,
+        None => return class,
+    };
+
+    let host_data = resolver.retrieve(host);
+    let host_pool = &host_data.java_class.constant_pool;
+    let is_member = host_data.java_class.attributes.iter().any(|attribute| matches!(attribute,
+        AttributeEntry::NestMembers(members) if members.classes.iter().any(|&index| host_pool.get_class_name(index) == Some(data.name()))));
+    if is_member && host_data.runtime_package() == data.runtime_package() {
+        host
+    } else {
+        class
+    }
+}
+
+/// Looks for a method in a class and its superclasses, and then in its superinterfaces
+fn lookup_method<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, class: LoadedClassRef, name: &str, descriptor: &str) -> Option<LoadedMethodRef> {
+    let mut current = Some(class);
+    while let Some(class) = current {
+        if let Some(method) = resolver.retrieve_method_ref(class, name, descriptor) {
+            return Some(method);
+        }
+        current = resolver.retrieve(class).super_class;
+    }
+    let data = resolver.retrieve(class);
+    lookup_superinterfaces(resolver, &all_interfaces(resolver, data.super_class, &data.interfaces), name, descriptor)
+}
+
+/// Looks for a method in an interface, then for a public method in `Object`, and then in its superinterfaces
+fn lookup_interface_method<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, interface: LoadedClassRef, name: &str, descriptor: &str) -> Option<LoadedMethodRef> {
+    if let Some(method) = resolver.retrieve_method_ref(interface, name, descriptor) {
+        return Some(method);
+    }
+    let data = resolver.retrieve(interface);
+    data.super_class
+        .and_then(|object| resolver.retrieve_method_ref(object, name, descriptor))
+        .filter(|&method| {
+            let access_flags = resolver.retrieve(method.class_ref).method_info(method).access_flags;
+            access_flags.contains(MethodAccessFlags::PUBLIC) && !access_flags.contains(MethodAccessFlags::STATIC)
+        })
+        .or_else(|| lookup_superinterfaces(resolver, &all_interfaces(resolver, None, &data.interfaces), name, descriptor))
+}
+
+/// Prefers the only maximally-specific method that isn't abstract, and takes any of them otherwise
+fn lookup_superinterfaces<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, interfaces: &[LoadedClassRef], name: &str, descriptor: &str) -> Option<LoadedMethodRef> {
+    maximally_specific(resolver, interfaces, name, descriptor).method()
+        .or_else(|| maximally_specific_methods(resolver, interfaces, name, descriptor).first().copied())
+}
+
+fn illegal_access<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, accessor: LoadedClassRef, member: String) -> ResolveError {
+    ResolveError::IllegalAccessError(format!("{} can't access {}", resolver.retrieve(accessor).name(), member))
+}
+
+/// The name and descriptor of a field or method reference
+fn name_and_type<'pool>(pool: &'pool impl ConstantPool, member: &TypeRefInfo) -> Result<(&'pool str, &'pool str), ResolveError> {
+    let invalid = ResolveError::InvalidConstantPoolIndex;
+    let name_and_type = pool.get_as::<types::NameAndTypeInfo>(member.name_and_type_index).ok_or(invalid(member.name_and_type_index))?;
+    let name = pool.get_as_string(name_and_type.name_index).ok_or(invalid(name_and_type.name_index))?;
+    let descriptor = pool.get_as_string(name_and_type.descriptor_index).ok_or(invalid(name_and_type.descriptor_index))?;
+    Ok((name, descriptor))
+}
+
+#[cfg(test)]
+mod tests {
+    use classfile_parser::builder::ClassBuilder;
+    use classfile_parser::class_file::{ClassAccessFlags, FieldAccessFlags, MethodAccessFlags};
+    use crate::class_store::{ClassStoreIsh, LoadedMethodRef};
+    use crate::classfile_util::ConstantPoolExtensions;
+    use crate::resolution::{resolve_class, resolve_field, resolve_method, ResolveError};
+    use crate::test_util::{class, BuiltClassLoader, NoJit, INTERFACE, PUBLIC};
+    use crate::VirtualMachine;
+
+    /// Builds and loads every class
+    fn load(classes: Vec<ClassBuilder>) -> VirtualMachine<BuiltClassLoader, NoJit> {
+        let classes = classes.into_iter().map(|class| class.build().unwrap()).collect::<Vec<_>>();
+        let names = classes.iter().map(|class| class.constant_pool.get_class_name(class.this_class).unwrap().to_owned()).collect::<Vec<_>>();
+        let mut vm = VirtualMachine::new(BuiltClassLoader::new(classes), NoJit);
+        for name in names {
+            vm.load_class(&name).unwrap();
+        }
+        vm
+    }
+
+    fn method_name(vm: &VirtualMachine<BuiltClassLoader, NoJit>, result: Result<LoadedMethodRef, ResolveError>) -> Result<String, ResolveError> {
+        result.map(|method| {
+            let class = vm.class_store.retrieve(method.class_ref);
+            let name = class.java_class.constant_pool.get_as_string(class.method_info(method).name_index).unwrap();
+            format!("{}.{}", class.name(), name)
+        })
+    }
+
+    #[test]
+    fn lookup_order() {
+        let mut object = ClassBuilder::new("java/lang/Object", None);
+        object.method_without_code(PUBLIC | MethodAccessFlags::NATIVE, "hashCode", "()I");
+        let mut i = class("I", Some("java/lang/Object"), &[], &[(PUBLIC, "m"), (PUBLIC, "n")]);
+        i.access_flags(INTERFACE);
+        let mut j = class("J", Some("java/lang/Object"), &["I"], &[(PUBLIC, "m")]);
+        j.access_flags(INTERFACE);
+        let sup = class("Sup", Some("java/lang/Object"), &[], &[(PUBLIC, "n")]);
+        let c = class("C", Some("Sup"), &["J"], &[]);
+
+        let mut main = ClassBuilder::new("Main", Some("java/lang/Object"));
+        let default = main.method_ref("C", "m", "()V");
+        let inherited = main.method_ref("C", "n", "()V");
+        let object_method = main.interface_method_ref("I", "hashCode", "()I");
+        let interface_as_class = main.method_ref("I", "m", "()V");
+        let class_as_interface = main.interface_method_ref("C", "m", "()V");
+        let missing = main.method_ref("C", "missing", "()V");
+        let vm = load(vec![object, i, j, sup, c, main]);
+        let main = vm.lookup("Main").unwrap();
+        let resolve = |index| method_name(&vm, resolve_method(&vm.class_store, main, index));
+
+        assert_eq!(resolve(default), Ok("J.m".to_owned()));
+        assert_eq!(resolve(inherited), Ok("Sup.n".to_owned()));
+        assert_eq!(resolve(object_method), Ok("java/lang/Object.hashCode".to_owned()));
+        assert!(matches!(resolve(interface_as_class), Err(ResolveError::IncompatibleClassChangeError(_))));
+        assert!(matches!(resolve(class_as_interface), Err(ResolveError::IncompatibleClassChangeError(_))));
+        assert_eq!(resolve(missing), Err(ResolveError::NoSuchMethodError("C.missing()V".to_owned())));
+    }
+
+    #[test]
+    fn access_checks() {
+        let mut base = class("a/Base", None, &[], &[
+            (PUBLIC, "public"),
+            (MethodAccessFlags::PROTECTED, "protected"),
+            (MethodAccessFlags::empty(), "package"),
+            (MethodAccessFlags::PRIVATE, "private"),
+        ]);
+        base.field(FieldAccessFlags::PRIVATE, "secret", "I");
+        base.nest_member("a/Base$Inner");
+        let mut hidden = ClassBuilder::new("a/Hidden", None);
+        hidden.access_flags(ClassAccessFlags::SUPER);
+
+        // Refers to every method, the field and the hidden class, and returns their indices
+        let accessor = |name: &str, super_class: Option<&str>, host: Option<&str>| {
+            let mut builder = ClassBuilder::new(name, super_class);
+            if let Some(host) = host {
+                builder.nest_host(host);
+            }
+            let mut indices = ["public", "protected", "package", "private"].map(|method| builder.method_ref("a/Base", method, "()V")).to_vec();
+            indices.push(builder.field_ref("a/Base", "secret", "I"));
+            indices.push(builder.class("a/Hidden"));
+            (builder, indices)
+        };
+        let (accessors, indices): (Vec<_>, Vec<_>) = vec![
+            accessor("a/Base$Inner", None, Some("a/Base")),
+            accessor("a/Liar", None, Some("a/Base")),
+            accessor("a/Neighbour", None, None),
+            accessor("b/Sub", Some("a/Base"), None),
+            accessor("b/Other", None, None),
+        ].into_iter().unzip();
+        let mut classes = vec![base, hidden];
+        classes.extend(accessors);
+        let vm = load(classes);
+
+        let allowed = |class: &str, indices: &[u16]| -> Vec<bool> {
+            let referrer = vm.lookup(class).unwrap();
+            let store = &vm.class_store;
+            let mut results = indices[..4].iter().map(|&index| resolve_method(store, referrer, index).map(|_| ())).collect::<Vec<_>>();
+            results.push(resolve_field(store, referrer, indices[4]).map(|_| ()));
+            results.push(resolve_class(store, referrer, indices[5]).map(|_| ()));
+            results.into_iter().map(|result| match result {
+                Ok(()) => true,
+                Err(ResolveError::IllegalAccessError(_)) => false,
+                Err(e) => panic!("{}", e),
+            }).collect()
+        };
+
+        // public, protected, package-private, private, private field, package-private class
+        assert_eq!(allowed("a/Base$Inner", &indices[0]), vec![true, true, true, true, true, true]);
+        assert_eq!(allowed("a/Liar", &indices[1]), vec![true, true, true, false, false, true]);
+        assert_eq!(allowed("a/Neighbour", &indices[2]), vec![true, true, true, false, false, true]);
+        assert_eq!(allowed("b/Sub", &indices[3]), vec![true, true, false, false, false, false]);
+        assert_eq!(allowed("b/Other", &indices[4]), vec![true, false, false, false, false, false]);
+    }
+}
@@ -0,0 +1,869 @@
+//! The small class library that comes with the vm, so that simple programs run without a JDK.
+//! It has `Object`, `String`, `StringBuilder`, `System.out`, `Math`, the boxed primitives and the common exceptions.
+//!
+//! The classes are generated with a [ClassBuilder]. Constructors and getters are bytecode, everything else is a native
+//! implemented in this module. Natives that turn objects into strings call the `toString` methods that programs define,
+//! and build the strings of the library classes themselves.
+
+use std::convert::TryFrom;
+use std::io::Write;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use classfile_parser::attributes::ConstantValue;
+use classfile_parser::builder::ClassBuilder;
+use classfile_parser::bytecode::Instruction;
+use classfile_parser::class_file::{ClassAccessFlags, ClassFile, FieldAccessFlags, MethodAccessFlags};
+
+use crate::exceptions::RuntimeException;
+use crate::interop::{JavaBoolean, JavaChar, JavaDouble, JavaFloat, JavaInt, JavaLong, JavaObject, JavaValue, JavaVoid};
+use crate::natives::{NativeEnv, NativeFunction, NativeMethods};
+use crate::resolution::ConcatPart;
+use crate::strings::from_utf16;
+
+const OBJECT: &str = "java/lang/Object";
+const STRING: &str = "java/lang/String";
+const STRING_BUILDER: &str = "java/lang/StringBuilder";
+const SYSTEM: &str = "java/lang/System";
+const PRINT_STREAM: &str = "java/io/PrintStream";
+const MATH: &str = "java/lang/Math";
+const NUMBER: &str = "java/lang/Number";
+const THROWABLE: &str = "java/lang/Throwable";
+
+/// The boxed primitives, with the class they extend and the descriptor of their value
+const BOXES: [(&str, &str, &str); 8] = [
+    ("java/lang/Integer", NUMBER, "I"),
+    ("java/lang/Long", NUMBER, "J"),
+    ("java/lang/Float", NUMBER, "F"),
+    ("java/lang/Double", NUMBER, "D"),
+    ("java/lang/Short", NUMBER, "S"),
+    ("java/lang/Byte", NUMBER, "B"),
+    ("java/lang/Character", OBJECT, "C"),
+    ("java/lang/Boolean", OBJECT, "Z"),
+];
+
+/// The exceptions and errors, with the class they extend
+const THROWABLES: [(&str, &str); 32] = [
+    ("java/lang/Exception", THROWABLE),
+    ("java/lang/Error", THROWABLE),
+    ("java/lang/RuntimeException", "java/lang/Exception"),
+    ("java/lang/InterruptedException", "java/lang/Exception"),
+    ("java/lang/CloneNotSupportedException", "java/lang/Exception"),
+    ("java/lang/NullPointerException", "java/lang/RuntimeException"),
+    ("java/lang/ArithmeticException", "java/lang/RuntimeException"),
+    ("java/lang/ClassCastException", "java/lang/RuntimeException"),
+    ("java/lang/ArrayStoreException", "java/lang/RuntimeException"),
+    ("java/lang/NegativeArraySizeException", "java/lang/RuntimeException"),
+    ("java/lang/IllegalArgumentException", "java/lang/RuntimeException"),
+    ("java/lang/IllegalStateException", "java/lang/RuntimeException"),
+    ("java/lang/UnsupportedOperationException", "java/lang/RuntimeException"),
+    ("java/lang/NumberFormatException", "java/lang/IllegalArgumentException"),
+    ("java/lang/IndexOutOfBoundsException", "java/lang/RuntimeException"),
+    ("java/lang/ArrayIndexOutOfBoundsException", "java/lang/IndexOutOfBoundsException"),
+    ("java/lang/StringIndexOutOfBoundsException", "java/lang/IndexOutOfBoundsException"),
+    ("java/lang/AssertionError", "java/lang/Error"),
+    ("java/lang/StackOverflowError", "java/lang/Error"),
+    ("java/lang/OutOfMemoryError", "java/lang/Error"),
+    ("java/lang/LinkageError", "java/lang/Error"),
+    ("java/lang/NoClassDefFoundError", "java/lang/LinkageError"),
+    ("java/lang/ExceptionInInitializerError", "java/lang/LinkageError"),
+    ("java/lang/BootstrapMethodError", "java/lang/LinkageError"),
+    ("java/lang/VerifyError", "java/lang/LinkageError"),
+    ("java/lang/UnsatisfiedLinkError", "java/lang/LinkageError"),
+    ("java/lang/IncompatibleClassChangeError", "java/lang/LinkageError"),
+    ("java/lang/NoSuchFieldError", "java/lang/IncompatibleClassChangeError"),
+    ("java/lang/NoSuchMethodError", "java/lang/IncompatibleClassChangeError"),
+    ("java/lang/AbstractMethodError", "java/lang/IncompatibleClassChangeError"),
+    ("java/lang/IllegalAccessError", "java/lang/IncompatibleClassChangeError"),
+    ("java/lang/InstantiationError", "java/lang/IncompatibleClassChangeError"),
+];
+
+/// The types that `print`, `println`, `String.valueOf` and `StringBuilder.append` are overloaded for
+const PRINTABLE: [&str; 8] = ["Ljava/lang/String;", "Ljava/lang/Object;", "I", "J", "F", "D", "Z", "C"];
+
+/// Builds a class of the library, or returns `None` if it isn't part of it
+pub fn build_class(name: &str) -> Option<ClassFile> {
+    let builder = match name {
+        OBJECT => object(),
+        STRING => string(),
+        STRING_BUILDER => string_builder(),
+        SYSTEM => system(),
+        PRINT_STREAM => print_stream(),
+        MATH => math(),
+        NUMBER => {
+            let mut builder = ClassBuilder::new(NUMBER, Some(OBJECT));
+            builder.access_flags(ClassAccessFlags::PUBLIC | ClassAccessFlags::ABSTRACT);
+            constructor(&mut builder, OBJECT, "()V");
+            builder
+        }
+        THROWABLE => throwable(),
+        _ => match BOXES.iter().find(|(class, ..)| *class == name) {
+            Some(&(class, super_class, descriptor)) => boxed(class, super_class, descriptor),
+            None => {
+                let &(class, super_class) = THROWABLES.iter().find(|(class, _)| *class == name)?;
+                let mut builder = ClassBuilder::new(class, Some(super_class));
+                constructor(&mut builder, super_class, "()V");
+                constructor(&mut builder, super_class, "(Ljava/lang/String;)V");
+                builder
+            }
+        },
+    };
+    // The library is fixed, so failing to build one of its classes is a bug
+    Some(builder.build().expect("invalid runtime class"))
+}
+
+/// A constructor that passes its arguments to the constructor of the superclass
+fn constructor(builder: &mut ClassBuilder, super_class: &str, descriptor: &str) {
+    let super_init = builder.method_ref(super_class, "<init>", descriptor);
+    let mut code = vec![Instruction::ALoad(0)];
+    if descriptor != "()V" {
+        code.push(Instruction::ALoad(1));
+    }
+    code.extend([Instruction::InvokeSpecial(super_init), Instruction::Return]);
+    builder.method(MethodAccessFlags::PUBLIC, "<init>", descriptor, code);
+}
+
+fn native(builder: &mut ClassBuilder, flags: MethodAccessFlags, name: &str, descriptor: &str) {
+    builder.method_without_code(flags | MethodAccessFlags::PUBLIC | MethodAccessFlags::NATIVE, name, descriptor);
+}
+
+fn object() -> ClassBuilder {
+    let mut builder = ClassBuilder::new(OBJECT, None);
+    builder.method(MethodAccessFlags::PUBLIC, "<init>", "()V", vec![Instruction::Return]);
+    native(&mut builder, MethodAccessFlags::empty(), "hashCode", "()I");
+    native(&mut builder, MethodAccessFlags::empty(), "equals", "(Ljava/lang/Object;)Z");
+    native(&mut builder, MethodAccessFlags::empty(), "toString", "()Ljava/lang/String;");
+    builder
+}
+
+fn string() -> ClassBuilder {
+    let mut builder = ClassBuilder::new(STRING, Some(OBJECT));
+    builder.access_flags(ClassAccessFlags::PUBLIC | ClassAccessFlags::FINAL);
+    builder.field(FieldAccessFlags::PRIVATE | FieldAccessFlags::FINAL, "value", "[C");
+    constructor(&mut builder, OBJECT, "()V");
+    for (name, descriptor) in [
+        ("length", "()I"), ("isEmpty", "()Z"), ("charAt", "(I)C"), ("hashCode", "()I"),
+        ("equals", "(Ljava/lang/Object;)Z"), ("concat", "(Ljava/lang/String;)Ljava/lang/String;"),
+    ] {
+        native(&mut builder, MethodAccessFlags::empty(), name, descriptor);
+    }
+    builder.method(MethodAccessFlags::PUBLIC, "toString", "()Ljava/lang/String;", vec![Instruction::ALoad(0), Instruction::AReturn]);
+    for parameter in &PRINTABLE[1..] {
+        native(&mut builder, MethodAccessFlags::STATIC, "valueOf", &format!("({})Ljava/lang/String;", parameter));
+    }
+    builder
+}
+
+fn string_builder() -> ClassBuilder {
+    let mut builder = ClassBuilder::new(STRING_BUILDER, Some(OBJECT));
+    builder.access_flags(ClassAccessFlags::PUBLIC | ClassAccessFlags::FINAL);
+    // Strings can't be changed, so appending replaces the string
+    builder.field(FieldAccessFlags::PRIVATE, "value", "Ljava/lang/String;");
+    let super_init = builder.method_ref(OBJECT, "<init>", "()V");
+    let value = builder.field_ref(STRING_BUILDER, "value", "Ljava/lang/String;");
+    let empty = builder.string("");
+    builder.method(MethodAccessFlags::PUBLIC, "<init>", "()V", vec![
+        Instruction::ALoad(0), Instruction::InvokeSpecial(super_init),
+        Instruction::ALoad(0), Instruction::LdC_w(empty), Instruction::Putfield(value),
+        Instruction::Return,
+    ]);
+    builder.method(MethodAccessFlags::PUBLIC, "<init>", "(Ljava/lang/String;)V", vec![
+        Instruction::ALoad(0), Instruction::InvokeSpecial(super_init),
+        Instruction::ALoad(0), Instruction::ALoad(1), Instruction::Putfield(value),
+        Instruction::Return,
+    ]);
+    for parameter in PRINTABLE {
+        native(&mut builder, MethodAccessFlags::empty(), "append", &format!("({})Ljava/lang/StringBuilder;", parameter));
+    }
+    native(&mut builder, MethodAccessFlags::empty(), "length", "()I");
+    builder.method(MethodAccessFlags::PUBLIC, "toString", "()Ljava/lang/String;", vec![Instruction::ALoad(0), Instruction::GetField(value), Instruction::AReturn]);
+    builder
+}
+
+fn system() -> ClassBuilder {
+    let mut builder = ClassBuilder::new(SYSTEM, Some(OBJECT));
+    builder.access_flags(ClassAccessFlags::PUBLIC | ClassAccessFlags::FINAL);
+    let mut clinit = Vec::new();
+    for (name, fd) in [("out", 1), ("err", 2)] {
+        builder.field(FieldAccessFlags::PUBLIC | FieldAccessFlags::STATIC | FieldAccessFlags::FINAL, name, "Ljava/io/PrintStream;");
+        let stream = builder.class(PRINT_STREAM);
+        let init = builder.method_ref(PRINT_STREAM, "<init>", "(I)V");
+        let field = builder.field_ref(SYSTEM, name, "Ljava/io/PrintStream;");
+        clinit.extend([Instruction::New(stream), Instruction::Dup, Instruction::IConst(fd), Instruction::InvokeSpecial(init), Instruction::PutStatic(field)]);
+    }
+    clinit.push(Instruction::Return);
+    builder.method(MethodAccessFlags::STATIC, "<clinit>", "()V", clinit);
+    native(&mut builder, MethodAccessFlags::STATIC, "arraycopy", "(Ljava/lang/Object;ILjava/lang/Object;II)V");
+    native(&mut builder, MethodAccessFlags::STATIC, "currentTimeMillis", "()J");
+    native(&mut builder, MethodAccessFlags::STATIC, "nanoTime", "()J");
+    native(&mut builder, MethodAccessFlags::STATIC, "identityHashCode", "(Ljava/lang/Object;)I");
+    builder
+}
+
+fn print_stream() -> ClassBuilder {
+    let mut builder = ClassBuilder::new(PRINT_STREAM, Some(OBJECT));
+    // 1 for standard output, 2 for standard error
+    builder.field(FieldAccessFlags::PRIVATE | FieldAccessFlags::FINAL, "fd", "I");
+    let super_init = builder.method_ref(OBJECT, "<init>", "()V");
+    let fd = builder.field_ref(PRINT_STREAM, "fd", "I");
+    builder.method(MethodAccessFlags::PUBLIC, "<init>", "(I)V", vec![
+        Instruction::ALoad(0), Instruction::InvokeSpecial(super_init),
+        Instruction::ALoad(0), Instruction::ILoad(1), Instruction::Putfield(fd),
+        Instruction::Return,
+    ]);
+    native(&mut builder, MethodAccessFlags::empty(), "println", "()V");
+    for parameter in PRINTABLE {
+        native(&mut builder, MethodAccessFlags::empty(), "print", &format!("({})V", parameter));
+        native(&mut builder, MethodAccessFlags::empty(), "println", &format!("({})V", parameter));
+    }
+    builder
+}
+
+fn math() -> ClassBuilder {
+    let mut builder = ClassBuilder::new(MATH, Some(OBJECT));
+    builder.access_flags(ClassAccessFlags::PUBLIC | ClassAccessFlags::FINAL);
+    let constant = FieldAccessFlags::PUBLIC | FieldAccessFlags::STATIC | FieldAccessFlags::FINAL;
+    builder.constant_field(constant, "PI", "D", ConstantValue::Double(std::f64::consts::PI));
+    builder.constant_field(constant, "E", "D", ConstantValue::Double(std::f64::consts::E));
+    for ty in ["I", "J", "F", "D"] {
+        native(&mut builder, MethodAccessFlags::STATIC, "abs", &format!("({}){}", ty, ty));
+        native(&mut builder, MethodAccessFlags::STATIC, "max", &format!("({}{}){}", ty, ty, ty));
+        native(&mut builder, MethodAccessFlags::STATIC, "min", &format!("({}{}){}", ty, ty, ty));
+    }
+    for name in ["sqrt", "cbrt", "floor", "ceil", "sin", "cos", "tan", "exp", "log", "log10"] {
+        native(&mut builder, MethodAccessFlags::STATIC, name, "(D)D");
+    }
+    native(&mut builder, MethodAccessFlags::STATIC, "pow", "(DD)D");
+    native(&mut builder, MethodAccessFlags::STATIC, "atan2", "(DD)D");
+    builder
+}
+
+/// A boxed primitive, with `valueOf`, a getter like `intValue` and natives for `toString`, `hashCode` and `equals`
+fn boxed(class: &str, super_class: &str, descriptor: &str) -> ClassBuilder {
+    let mut builder = ClassBuilder::new(class, Some(super_class));
+    builder.access_flags(ClassAccessFlags::PUBLIC | ClassAccessFlags::FINAL);
+    builder.field(FieldAccessFlags::PRIVATE | FieldAccessFlags::FINAL, "value", descriptor);
+    let (load, ret, getter) = match descriptor {
+        "J" => (Instruction::LLoad(1), Instruction::LReturn, "longValue"),
+        "F" => (Instruction::FLoad(1), Instruction::FReturn, "floatValue"),
+        "D" => (Instruction::DLoad(1), Instruction::DReturn, "doubleValue"),
+        "S" => (Instruction::ILoad(1), Instruction::IReturn, "shortValue"),
+        "B" => (Instruction::ILoad(1), Instruction::IReturn, "byteValue"),
+        "C" => (Instruction::ILoad(1), Instruction::IReturn, "charValue"),
+        "Z" => (Instruction::ILoad(1), Instruction::IReturn, "booleanValue"),
+        _ => (Instruction::ILoad(1), Instruction::IReturn, "intValue"),
+    };
+    let super_init = builder.method_ref(super_class, "<init>", "()V");
+    let value = builder.field_ref(class, "value", descriptor);
+    let init_descriptor = format!("({})V", descriptor);
+    let init = builder.method_ref(class, "<init>", &init_descriptor);
+    let this_class = builder.class(class);
+    builder.method(MethodAccessFlags::PUBLIC, "<init>", &init_descriptor, vec![
+        Instruction::ALoad(0), Instruction::InvokeSpecial(super_init),
+        Instruction::ALoad(0), load.clone(), Instruction::Putfield(value),
+        Instruction::Return,
+    ]);
+    // Static methods have their parameters from local 0
+    let load_first = match load {
+        Instruction::LLoad(_) => Instruction::LLoad(0),
+        Instruction::FLoad(_) => Instruction::FLoad(0),
+        Instruction::DLoad(_) => Instruction::DLoad(0),
+        _ => Instruction::ILoad(0),
+    };
+    builder.method(MethodAccessFlags::PUBLIC | MethodAccessFlags::STATIC, "valueOf", &format!("({})L{};", descriptor, class), vec![
+        Instruction::New(this_class), Instruction::Dup, load_first, Instruction::InvokeSpecial(init), Instruction::AReturn,
+    ]);
+    builder.method(MethodAccessFlags::PUBLIC, getter, &format!("(){}", descriptor), vec![Instruction::ALoad(0), Instruction::GetField(value), ret]);
+    native(&mut builder, MethodAccessFlags::empty(), "toString", "()Ljava/lang/String;");
+    native(&mut builder, MethodAccessFlags::empty(), "hashCode", "()I");
+    native(&mut builder, MethodAccessFlags::empty(), "equals", "(Ljava/lang/Object;)Z");
+    builder
+}
+
+fn throwable() -> ClassBuilder {
+    let mut builder = ClassBuilder::new(THROWABLE, Some(OBJECT));
+    builder.field(FieldAccessFlags::PRIVATE, "detailMessage", "Ljava/lang/String;");
+    builder.field(FieldAccessFlags::PRIVATE, "cause", "Ljava/lang/Throwable;");
+    let super_init = builder.method_ref(OBJECT, "<init>", "()V");
+    let message = builder.field_ref(THROWABLE, "detailMessage", "Ljava/lang/String;");
+    let cause = builder.field_ref(THROWABLE, "cause", "Ljava/lang/Throwable;");
+    builder.method(MethodAccessFlags::PUBLIC, "<init>", "()V", vec![Instruction::ALoad(0), Instruction::InvokeSpecial(super_init), Instruction::Return]);
+    builder.method(MethodAccessFlags::PUBLIC, "<init>", "(Ljava/lang/String;)V", vec![
+        Instruction::ALoad(0), Instruction::InvokeSpecial(super_init),
+        Instruction::ALoad(0), Instruction::ALoad(1), Instruction::Putfield(message),
+        Instruction::Return,
+    ]);
+    builder.method(MethodAccessFlags::PUBLIC, "getMessage", "()Ljava/lang/String;", vec![Instruction::ALoad(0), Instruction::GetField(message), Instruction::AReturn]);
+    builder.method(MethodAccessFlags::PUBLIC, "getCause", "()Ljava/lang/Throwable;", vec![Instruction::ALoad(0), Instruction::GetField(cause), Instruction::AReturn]);
+    native(&mut builder, MethodAccessFlags::empty(), "toString", "()Ljava/lang/String;");
+    native(&mut builder, MethodAccessFlags::empty(), "printStackTrace", "()V");
+    builder
+}
+
+////////////
+// Natives //
+////////////
+
+type Fn0<R> = extern "C" fn(&NativeEnv) -> R;
+type Fn1<A, R> = extern "C" fn(&NativeEnv, A) -> R;
+type Fn2<A, B, R> = extern "C" fn(&NativeEnv, A, B) -> R;
+
+fn register<F: NativeFunction>(natives: &mut NativeMethods, class: &str, name: &str, descriptor: &str, function: F) {
+    // The natives are fixed, so a mismatch is a bug
+    natives.register(class, name, descriptor, function).unwrap();
+}
+
+/// Registers the natives of the library, except for the ones in [crate::natives]
+pub fn register_natives(natives: &mut NativeMethods) {
+    register(natives, OBJECT, "equals", "(Ljava/lang/Object;)Z", object_equals as Fn2<JavaObject, JavaObject, JavaBoolean>);
+    register(natives, OBJECT, "toString", "()Ljava/lang/String;", object_to_string as Fn1<JavaObject, JavaObject>);
+
+    register(natives, STRING, "length", "()I", string_length as Fn1<JavaObject, JavaInt>);
+    register(natives, STRING, "isEmpty", "()Z", string_is_empty as Fn1<JavaObject, JavaBoolean>);
+    register(natives, STRING, "charAt", "(I)C", string_char_at as Fn2<JavaObject, JavaInt, JavaChar>);
+    register(natives, STRING, "hashCode", "()I", string_hash_code as Fn1<JavaObject, JavaInt>);
+    register(natives, STRING, "equals", "(Ljava/lang/Object;)Z", string_equals as Fn2<JavaObject, JavaObject, JavaBoolean>);
+    register(natives, STRING, "concat", "(Ljava/lang/String;)Ljava/lang/String;", string_concat as Fn2<JavaObject, JavaObject, JavaObject>);
+    register(natives, STRING, "valueOf", "(Ljava/lang/Object;)Ljava/lang/String;", value_of::<JavaObject> as Fn1<JavaObject, JavaObject>);
+    register(natives, STRING, "valueOf", "(I)Ljava/lang/String;", value_of::<JavaInt> as Fn1<JavaInt, JavaObject>);
+    register(natives, STRING, "valueOf", "(J)Ljava/lang/String;", value_of::<JavaLong> as Fn1<JavaLong, JavaObject>);
+    register(natives, STRING, "valueOf", "(F)Ljava/lang/String;", value_of::<JavaFloat> as Fn1<JavaFloat, JavaObject>);
+    register(natives, STRING, "valueOf", "(D)Ljava/lang/String;", value_of::<JavaDouble> as Fn1<JavaDouble, JavaObject>);
+    register(natives, STRING, "valueOf", "(Z)Ljava/lang/String;", value_of::<JavaBoolean> as Fn1<JavaBoolean, JavaObject>);
+    register(natives, STRING, "valueOf", "(C)Ljava/lang/String;", value_of::<JavaChar> as Fn1<JavaChar, JavaObject>);
+
+    let append = |descriptor: &str| format!("({})Ljava/lang/StringBuilder;", descriptor);
+    register(natives, STRING_BUILDER, "append", &append("Ljava/lang/String;"), append_value::<JavaObject> as Fn2<JavaObject, JavaObject, JavaObject>);
+    register(natives, STRING_BUILDER, "append", &append("Ljava/lang/Object;"), append_value::<JavaObject> as Fn2<JavaObject, JavaObject, JavaObject>);
+    register(natives, STRING_BUILDER, "append", &append("I"), append_value::<JavaInt> as Fn2<JavaObject, JavaInt, JavaObject>);
+    register(natives, STRING_BUILDER, "append", &append("J"), append_value::<JavaLong> as Fn2<JavaObject, JavaLong, JavaObject>);
+    register(natives, STRING_BUILDER, "append", &append("F"), append_value::<JavaFloat> as Fn2<JavaObject, JavaFloat, JavaObject>);
+    register(natives, STRING_BUILDER, "append", &append("D"), append_value::<JavaDouble> as Fn2<JavaObject, JavaDouble, JavaObject>);
+    register(natives, STRING_BUILDER, "append", &append("Z"), append_value::<JavaBoolean> as Fn2<JavaObject, JavaBoolean, JavaObject>);
+    register(natives, STRING_BUILDER, "append", &append("C"), append_value::<JavaChar> as Fn2<JavaObject, JavaChar, JavaObject>);
+    register(natives, STRING_BUILDER, "length", "()I", builder_length as Fn1<JavaObject, JavaInt>);
+
+    register(natives, SYSTEM, "currentTimeMillis", "()J", current_time_millis as Fn0<JavaLong>);
+    register(natives, SYSTEM, "nanoTime", "()J", nano_time as Fn0<JavaLong>);
+    register(natives, SYSTEM, "identityHashCode", "(Ljava/lang/Object;)I", identity_hash_code as Fn1<JavaObject, JavaInt>);
+
+    register(natives, PRINT_STREAM, "println", "()V", println as Fn1<JavaObject, JavaVoid>);
+    for (name, newline) in [("print", false), ("println", true)] {
+        let printer = |descriptor: &str| format!("({})V", descriptor);
+        let (string, object, int, long, float, double, boolean, char) = if newline {
+            (print::<JavaObject, true> as Fn2<JavaObject, JavaObject, JavaVoid>, print::<JavaObject, true> as Fn2<JavaObject, JavaObject, JavaVoid>,
+             print::<JavaInt, true> as Fn2<JavaObject, JavaInt, JavaVoid>, print::<JavaLong, true> as Fn2<JavaObject, JavaLong, JavaVoid>,
+             print::<JavaFloat, true> as Fn2<JavaObject, JavaFloat, JavaVoid>, print::<JavaDouble, true> as Fn2<JavaObject, JavaDouble, JavaVoid>,
+             print::<JavaBoolean, true> as Fn2<JavaObject, JavaBoolean, JavaVoid>, print::<JavaChar, true> as Fn2<JavaObject, JavaChar, JavaVoid>)
+        } else {
+            (print::<JavaObject, false> as Fn2<JavaObject, JavaObject, JavaVoid>, print::<JavaObject, false> as Fn2<JavaObject, JavaObject, JavaVoid>,
+             print::<JavaInt, false> as Fn2<JavaObject, JavaInt, JavaVoid>, print::<JavaLong, false> as Fn2<JavaObject, JavaLong, JavaVoid>,
+             print::<JavaFloat, false> as Fn2<JavaObject, JavaFloat, JavaVoid>, print::<JavaDouble, false> as Fn2<JavaObject, JavaDouble, JavaVoid>,
+             print::<JavaBoolean, false> as Fn2<JavaObject, JavaBoolean, JavaVoid>, print::<JavaChar, false> as Fn2<JavaObject, JavaChar, JavaVoid>)
+        };
+        register(natives, PRINT_STREAM, name, &printer("Ljava/lang/String;"), string);
+        register(natives, PRINT_STREAM, name, &printer("Ljava/lang/Object;"), object);
+        register(natives, PRINT_STREAM, name, &printer("I"), int);
+        register(natives, PRINT_STREAM, name, &printer("J"), long);
+        register(natives, PRINT_STREAM, name, &printer("F"), float);
+        register(natives, PRINT_STREAM, name, &printer("D"), double);
+        register(natives, PRINT_STREAM, name, &printer("Z"), boolean);
+        register(natives, PRINT_STREAM, name, &printer("C"), char);
+    }
+
+    register_math(natives);
+
+    for (class, ..) in BOXES {
+        register(natives, class, "toString", "()Ljava/lang/String;", box_to_string as Fn1<JavaObject, JavaObject>);
+        register(natives, class, "hashCode", "()I", box_hash_code as Fn1<JavaObject, JavaInt>);
+        register(natives, class, "equals", "(Ljava/lang/Object;)Z", box_equals as Fn2<JavaObject, JavaObject, JavaBoolean>);
+    }
+
+    register(natives, THROWABLE, "toString", "()Ljava/lang/String;", throwable_to_string as Fn1<JavaObject, JavaObject>);
+    register(natives, THROWABLE, "printStackTrace", "()V", print_stack_trace as Fn1<JavaObject, JavaVoid>);
+}
+
+fn register_math(natives: &mut NativeMethods) {
+    extern "C" fn abs_int(_env: &NativeEnv, value: JavaInt) -> JavaInt { value.wrapping_abs() }
+    extern "C" fn abs_long(_env: &NativeEnv, value: JavaLong) -> JavaLong { value.wrapping_abs() }
+    extern "C" fn abs_float(_env: &NativeEnv, value: JavaFloat) -> JavaFloat { value.abs() }
+    extern "C" fn abs_double(_env: &NativeEnv, value: JavaDouble) -> JavaDouble { value.abs() }
+    extern "C" fn max_int(_env: &NativeEnv, a: JavaInt, b: JavaInt) -> JavaInt { a.max(b) }
+    extern "C" fn max_long(_env: &NativeEnv, a: JavaLong, b: JavaLong) -> JavaLong { a.max(b) }
+    extern "C" fn min_int(_env: &NativeEnv, a: JavaInt, b: JavaInt) -> JavaInt { a.min(b) }
+    extern "C" fn min_long(_env: &NativeEnv, a: JavaLong, b: JavaLong) -> JavaLong { a.min(b) }
+    // Unlike rust, java returns NaN if either value is NaN
+    extern "C" fn max_float(_env: &NativeEnv, a: JavaFloat, b: JavaFloat) -> JavaFloat { if a.is_nan() || b.is_nan() { JavaFloat::NAN } else { a.max(b) } }
+    extern "C" fn max_double(_env: &NativeEnv, a: JavaDouble, b: JavaDouble) -> JavaDouble { if a.is_nan() || b.is_nan() { JavaDouble::NAN } else { a.max(b) } }
+    extern "C" fn min_float(_env: &NativeEnv, a: JavaFloat, b: JavaFloat) -> JavaFloat { if a.is_nan() || b.is_nan() { JavaFloat::NAN } else { a.min(b) } }
+    extern "C" fn min_double(_env: &NativeEnv, a: JavaDouble, b: JavaDouble) -> JavaDouble { if a.is_nan() || b.is_nan() { JavaDouble::NAN } else { a.min(b) } }
+    extern "C" fn pow(_env: &NativeEnv, a: JavaDouble, b: JavaDouble) -> JavaDouble { a.powf(b) }
+    extern "C" fn atan2(_env: &NativeEnv, y: JavaDouble, x: JavaDouble) -> JavaDouble { y.atan2(x) }
+
+    register(natives, MATH, "abs", "(I)I", abs_int as Fn1<JavaInt, JavaInt>);
+    register(natives, MATH, "abs", "(J)J", abs_long as Fn1<JavaLong, JavaLong>);
+    register(natives, MATH, "abs", "(F)F", abs_float as Fn1<JavaFloat, JavaFloat>);
+    register(natives, MATH, "abs", "(D)D", abs_double as Fn1<JavaDouble, JavaDouble>);
+    register(natives, MATH, "max", "(II)I", max_int as Fn2<JavaInt, JavaInt, JavaInt>);
+    register(natives, MATH, "max", "(JJ)J", max_long as Fn2<JavaLong, JavaLong, JavaLong>);
+    register(natives, MATH, "max", "(FF)F", max_float as Fn2<JavaFloat, JavaFloat, JavaFloat>);
+    register(natives, MATH, "max", "(DD)D", max_double as Fn2<JavaDouble, JavaDouble, JavaDouble>);
+    register(natives, MATH, "min", "(II)I", min_int as Fn2<JavaInt, JavaInt, JavaInt>);
+    register(natives, MATH, "min", "(JJ)J", min_long as Fn2<JavaLong, JavaLong, JavaLong>);
+    register(natives, MATH, "min", "(FF)F", min_float as Fn2<JavaFloat, JavaFloat, JavaFloat>);
+    register(natives, MATH, "min", "(DD)D", min_double as Fn2<JavaDouble, JavaDouble, JavaDouble>);
+    register(natives, MATH, "pow", "(DD)D", pow as Fn2<JavaDouble, JavaDouble, JavaDouble>);
+    register(natives, MATH, "atan2", "(DD)D", atan2 as Fn2<JavaDouble, JavaDouble, JavaDouble>);
+
+    macro_rules! unary {
+        ($($name:ident),*) => {
+            $(
+                extern "C" fn $name(_env: &NativeEnv, value: JavaDouble) -> JavaDouble { value.$name() }
+                register(natives, MATH, stringify!($name), "(D)D", $name as Fn1<JavaDouble, JavaDouble>);
+            )*
+        };
+    }
+    unary!(sqrt, cbrt, floor, ceil, sin, cos, tan, exp, log10);
+    extern "C" fn log(_env: &NativeEnv, value: JavaDouble) -> JavaDouble { value.ln() }
+    register(natives, MATH, "log", "(D)D", log as Fn1<JavaDouble, JavaDouble>);
+}
+
+/// Values that natives can turn into java strings
+trait ToJavaString: Copy {
+    fn to_java_string(self, env: &NativeEnv) -> String;
+}
+
+/// Objects are turned into strings by their `toString`. Java code that overrides it is called,
+/// the strings of the library classes are built without a call.
+impl ToJavaString for JavaObject {
+    fn to_java_string(self, env: &NativeEnv) -> String {
+        if self.is_null() {
+            return "null".to_owned();
+        }
+        match env.class_name(self).as_str() {
+            STRING => env.read_string(self),
+            STRING_BUILDER => read_builder(env, self),
+            name if BOXES.iter().any(|(class, ..)| *class == name) => box_value(env, self).to_string(),
+            name => match env.java_to_string(self) {
+                // Also null if `toString` threw, the exception stays pending
+                Some(string) => string.to_java_string(env),
+                None if is_throwable(env, self) => throwable_string(env, self),
+                None => format!("{}@{:x}", name.replace('/', "."), crate::natives::identity_hash(self)),
+            },
+        }
+    }
+}
+
+impl ToJavaString for JavaInt {
+    fn to_java_string(self, _env: &NativeEnv) -> String {
+        self.to_string()
+    }
+}
+
+impl ToJavaString for JavaLong {
+    fn to_java_string(self, _env: &NativeEnv) -> String {
+        self.to_string()
+    }
+}
+
+impl ToJavaString for JavaFloat {
+    fn to_java_string(self, _env: &NativeEnv) -> String {
+        format_float(self as f64, self.to_string(), format!("{:e}", self))
+    }
+}
+
+impl ToJavaString for JavaDouble {
+    fn to_java_string(self, _env: &NativeEnv) -> String {
+        format_float(self, self.to_string(), format!("{:e}", self))
+    }
+}
+
+impl ToJavaString for JavaBoolean {
+    fn to_java_string(self, _env: &NativeEnv) -> String {
+        self.to_string()
+    }
+}
+
+impl ToJavaString for JavaChar {
+    fn to_java_string(self, _env: &NativeEnv) -> String {
+        from_utf16(&[self])
+    }
+}
+
+impl ToJavaString for JavaValue {
+    fn to_java_string(self, env: &NativeEnv) -> String {
+        match self {
+            JavaValue::Void => String::new(),
+            JavaValue::Boolean(value) => value.to_java_string(env),
+            JavaValue::Byte(value) => (value as JavaInt).to_java_string(env),
+            JavaValue::Char(value) => value.to_java_string(env),
+            JavaValue::Short(value) => (value as JavaInt).to_java_string(env),
+            JavaValue::Int(value) => value.to_java_string(env),
+            JavaValue::Long(value) => value.to_java_string(env),
+            JavaValue::Float(value) => value.to_java_string(env),
+            JavaValue::Double(value) => value.to_java_string(env),
+            JavaValue::Object(value) => value.to_java_string(env),
+        }
+    }
+}
+
+/// Builds the string of a concatenation that `invokedynamic` does, with `arguments` in place of the argument parts.
+/// The arguments should be reachable by the garbage collector, because calling `toString` can collect.
+pub fn concat(env: &NativeEnv, parts: &[ConcatPart], arguments: &[JavaValue]) -> JavaObject {
+    let mut arguments = arguments.iter();
+    let mut text = String::new();
+    for part in parts {
+        let value = match part {
+            ConcatPart::Constant(ConstantValue::String(units)) => {
+                text.push_str(&from_utf16(units));
+                continue;
+            }
+            ConcatPart::Constant(ConstantValue::Int(value)) => JavaValue::Int(*value),
+            ConcatPart::Constant(ConstantValue::Long(value)) => JavaValue::Long(*value),
+            ConcatPart::Constant(ConstantValue::Float(value)) => JavaValue::Float(*value),
+            ConcatPart::Constant(ConstantValue::Double(value)) => JavaValue::Double(*value),
+            ConcatPart::Argument(_) => *arguments.next().expect("fewer arguments than the recipe has"),
+        };
+        text.push_str(&value.to_java_string(env));
+    }
+    env.new_string(&text)
+}
+
+/// Formats a float like java does. `plain` and `scientific` are the shortest representations that rust gives,
+/// in its two notations. Java uses scientific notation below 10^-3 and from 10^7, and always has a fractional part.
+fn format_float(value: f64, plain: String, scientific: String) -> String {
+    if value.is_nan() {
+        return "NaN".to_owned();
+    }
+    if value.is_infinite() {
+        return if value > 0.0 { "Infinity" } else { "-Infinity" }.to_owned();
+    }
+    let magnitude = value.abs();
+    if magnitude == 0.0 || (1e-3..1e7).contains(&magnitude) {
+        return if plain.contains('.') { plain } else { format!("{}.0", plain) };
+    }
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let mantissa = if mantissa.contains('.') { mantissa.to_owned() } else { format!("{}.0", mantissa) };
+    format!("{}E{}", mantissa, exponent)
+}
+
+/// The value of a boxed primitive
+enum BoxValue {
+    Int(JavaInt),
+    Long(JavaLong),
+    Float(JavaFloat),
+    Double(JavaDouble),
+    Char(u16),
+    Boolean(bool),
+}
+
+impl std::fmt::Display for BoxValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            BoxValue::Int(value) => write!(f, "{}", value),
+            BoxValue::Long(value) => write!(f, "{}", value),
+            BoxValue::Float(value) => f.write_str(&format_float(value as f64, value.to_string(), format!("{:e}", value))),
+            BoxValue::Double(value) => f.write_str(&format_float(value, value.to_string(), format!("{:e}", value))),
+            BoxValue::Char(value) => f.write_str(&from_utf16(&[value])),
+            BoxValue::Boolean(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+fn box_value(env: &NativeEnv, object: JavaObject) -> BoxValue {
+    let class = env.class_name(object);
+    let &(_, _, descriptor) = BOXES.iter().find(|(name, ..)| *name == class).unwrap();
+    let field = env.field(object, "value", descriptor).unwrap();
+    // Safe because the field has the type of the descriptor
+    unsafe {
+        match descriptor {
+            "J" => BoxValue::Long(*(field as *const JavaLong)),
+            "F" => BoxValue::Float(*(field as *const JavaFloat)),
+            "D" => BoxValue::Double(*(field as *const JavaDouble)),
+            "S" => BoxValue::Int(*(field as *const i16) as JavaInt),
+            "B" => BoxValue::Int(*(field as *const i8) as JavaInt),
+            "C" => BoxValue::Char(*(field as *const u16)),
+            "Z" => BoxValue::Boolean(*field != 0),
+            _ => BoxValue::Int(*(field as *const JavaInt)),
+        }
+    }
+}
+
+fn is_throwable(env: &NativeEnv, object: JavaObject) -> bool {
+    env.is_instance_of(object, THROWABLE)
+}
+
+fn throwable_string(env: &NativeEnv, throwable: JavaObject) -> String {
+    let name = env.class_name(throwable).replace('/', ".");
+    // Safe because reference fields hold an address. A `Throwable` of another library might not have the field.
+    let message = env.field(throwable, "detailMessage", "Ljava/lang/String;").map_or(std::ptr::null_mut(), |field| unsafe { *(field as *const JavaObject) });
+    if message.is_null() {
+        name
+    } else {
+        format!("{}: {}", name, env.read_string(message))
+    }
+}
+
+fn builder_value(env: &NativeEnv, builder: JavaObject) -> *mut JavaObject {
+    env.field(builder, "value", "Ljava/lang/String;").unwrap() as *mut JavaObject
+}
+
+fn read_builder(env: &NativeEnv, builder: JavaObject) -> String {
+    // Safe because reference fields hold an address
+    env.read_string(unsafe { *builder_value(env, builder) })
+}
+
+extern "C" fn object_equals(_env: &NativeEnv, this: JavaObject, other: JavaObject) -> JavaBoolean {
+    this == other
+}
+
+extern "C" fn object_to_string(env: &NativeEnv, this: JavaObject) -> JavaObject {
+    let name = env.class_name(this).replace('/', ".");
+    env.new_string(&format!("{}@{:x}", name, crate::natives::identity_hash(this)))
+}
+
+extern "C" fn string_length(env: &NativeEnv, this: JavaObject) -> JavaInt {
+    env.read_utf16(this).len() as JavaInt
+}
+
+extern "C" fn string_is_empty(env: &NativeEnv, this: JavaObject) -> JavaBoolean {
+    env.read_utf16(this).is_empty()
+}
+
+extern "C" fn string_char_at(env: &NativeEnv, this: JavaObject, index: JavaInt) -> JavaChar {
+    match usize::try_from(index).ok().and_then(|index| env.read_utf16(this).get(index).copied()) {
+        Some(unit) => unit,
+        None => {
+            env.throw(RuntimeException::StringIndexOutOfBounds);
+            0
+        }
+    }
+}
+
+extern "C" fn string_hash_code(env: &NativeEnv, this: JavaObject) -> JavaInt {
+    env.read_utf16(this).iter().fold(0, |hash: JavaInt, &unit| hash.wrapping_mul(31).wrapping_add(unit as JavaInt))
+}
+
+extern "C" fn string_equals(env: &NativeEnv, this: JavaObject, other: JavaObject) -> JavaBoolean {
+    !other.is_null() && env.class_name(other) == STRING && env.read_utf16(this) == env.read_utf16(other)
+}
+
+extern "C" fn string_concat(env: &NativeEnv, this: JavaObject, other: JavaObject) -> JavaObject {
+    if other.is_null() {
+        env.throw(RuntimeException::NullPointer);
+        return std::ptr::null_mut();
+    }
+    let mut units = env.read_utf16(this);
+    units.extend(env.read_utf16(other));
+    env.new_string(&from_utf16(&units))
+}
+
+extern "C" fn value_of<T: ToJavaString>(env: &NativeEnv, value: T) -> JavaObject {
+    env.new_string(&value.to_java_string(env))
+}
+
+extern "C" fn append_value<T: ToJavaString>(env: &NativeEnv, this: JavaObject, value: T) -> JavaObject {
+    let appended = read_builder(env, this) + &value.to_java_string(env);
+    let string = env.new_string(&appended);
+    // Safe because the field holds a string. Creating the string could collect, so the field is looked up after.
+    unsafe { *builder_value(env, this) = string };
+    this
+}
+
+extern "C" fn builder_length(env: &NativeEnv, this: JavaObject) -> JavaInt {
+    // Safe because reference fields hold an address
+    env.read_utf16(unsafe { *builder_value(env, this) }).len() as JavaInt
+}
+
+extern "C" fn current_time_millis(_env: &NativeEnv) -> JavaLong {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_millis() as JavaLong)
+}
+
+extern "C" fn nano_time(_env: &NativeEnv) -> JavaLong {
+    thread_local! {
+        static START: Instant = Instant::now();
+    }
+    START.with(|start| start.elapsed().as_nanos() as JavaLong)
+}
+
+extern "C" fn identity_hash_code(_env: &NativeEnv, object: JavaObject) -> JavaInt {
+    crate::natives::identity_hash(object)
+}
+
+/// Writes to the stream of a `PrintStream`
+fn write(env: &NativeEnv, stream: JavaObject, text: &str) {
+    // Safe because int fields hold an int
+    let fd = unsafe { *(env.field(stream, "fd", "I").unwrap() as *const JavaInt) };
+    // Java ignores errors while printing too
+    let _ = if fd == 2 {
+        std::io::stderr().write_all(text.as_bytes())
+    } else {
+        std::io::stdout().write_all(text.as_bytes())
+    };
+}
+
+extern "C" fn println(env: &NativeEnv, this: JavaObject) {
+    write(env, this, "\n");
+}
+
+extern "C" fn print<T: ToJavaString, const NEWLINE: bool>(env: &NativeEnv, this: JavaObject, value: T) {
+    let mut text = value.to_java_string(env);
+    if NEWLINE {
+        text.push('\n');
+    }
+    write(env, this, &text);
+}
+
+extern "C" fn box_to_string(env: &NativeEnv, this: JavaObject) -> JavaObject {
+    env.new_string(&box_value(env, this).to_string())
+}
+
+extern "C" fn box_hash_code(env: &NativeEnv, this: JavaObject) -> JavaInt {
+    match box_value(env, this) {
+        BoxValue::Int(value) => value,
+        BoxValue::Long(value) => (value ^ (value >> 32)) as JavaInt,
+        BoxValue::Float(value) => value.to_bits() as JavaInt,
+        BoxValue::Double(value) => {
+            let bits = value.to_bits();
+            (bits ^ (bits >> 32)) as JavaInt
+        }
+        BoxValue::Char(value) => value as JavaInt,
+        BoxValue::Boolean(value) => if value { 1231 } else { 1237 },
+    }
+}
+
+extern "C" fn box_equals(env: &NativeEnv, this: JavaObject, other: JavaObject) -> JavaBoolean {
+    if other.is_null() || env.class_name(this) != env.class_name(other) {
+        return false;
+    }
+    // Floats are compared by their bits, so NaN equals itself
+    match (box_value(env, this), box_value(env, other)) {
+        (BoxValue::Int(a), BoxValue::Int(b)) => a == b,
+        (BoxValue::Long(a), BoxValue::Long(b)) => a == b,
+        (BoxValue::Float(a), BoxValue::Float(b)) => a.to_bits() == b.to_bits(),
+        (BoxValue::Double(a), BoxValue::Double(b)) => a.to_bits() == b.to_bits(),
+        (BoxValue::Char(a), BoxValue::Char(b)) => a == b,
+        (BoxValue::Boolean(a), BoxValue::Boolean(b)) => a == b,
+        _ => false,
+    }
+}
+
+extern "C" fn throwable_to_string(env: &NativeEnv, this: JavaObject) -> JavaObject {
+    env.new_string(&throwable_string(env, this))
+}
+
+extern "C" fn print_stack_trace(env: &NativeEnv, this: JavaObject) {
+    let _ = writeln!(std::io::stderr(), "{}", throwable_string(env, this));
+}
+
+#[cfg(test)]
+mod tests {
+    use classfile_parser::class_file::MethodAccessFlags;
+    use crate::class_store::ClassStoreIsh;
+    use crate::classfile_util::ConstantPoolExtensions;
+    use crate::interop::{JavaChar, JavaInt, JavaObject};
+    use crate::natives::{identity_hash, NativeEnv};
+    use crate::object::{allocate_array, allocate_object, ArrayType, BaseType};
+    use crate::runtime::{append_value, box_equals, box_hash_code, box_to_string, build_class, format_float, object_to_string, string_char_at, string_concat, string_equals, string_hash_code, throwable_to_string, value_of, BOXES, PRINT_STREAM, STRING_BUILDER, THROWABLES};
+    use crate::test_util::bootstrapped_vm as vm;
+    use crate::ClassResolver;
+
+    #[test]
+    fn classes() {
+        let mut vm = vm();
+        let names = ["java/lang/Object", "java/lang/String", STRING_BUILDER, "java/lang/System", PRINT_STREAM, "java/lang/Math", "java/lang/Number", "java/lang/Throwable"];
+        for name in names.iter().copied().chain(BOXES.iter().map(|(name, ..)| *name)).chain(THROWABLES.iter().map(|(name, _)| *name)) {
+            vm.load_class(name).unwrap();
+        }
+        assert!(build_class("java/lang/Thread").is_none());
+
+        let store = &vm.class_store;
+        let state = vm.lookup("java/lang/IllegalStateException").unwrap();
+        assert!(store.is_subclass_of(state, vm.lookup("java/lang/Throwable").unwrap()));
+        assert!(store.retrieve_method_ref(state, "<init>", "(Ljava/lang/String;)V").is_some());
+        let integer = vm.lookup("java/lang/Integer").unwrap();
+        assert!(store.is_subclass_of(integer, vm.lookup("java/lang/Number").unwrap()));
+        assert!(store.retrieve_method_ref(integer, "valueOf", "(I)Ljava/lang/Integer;").is_some());
+        // Every native of the library is registered
+        for name in names.iter().copied().chain(BOXES.iter().map(|(name, ..)| *name)) {
+            let class = &store.retrieve(vm.lookup(name).unwrap()).java_class;
+            for method in class.methods.iter().filter(|method| method.access_flags.contains(MethodAccessFlags::NATIVE)) {
+                let pool = &class.constant_pool;
+                let (method_name, descriptor) = (pool.get_as_string(method.name_index).unwrap(), pool.get_as_string(method.descriptor).unwrap());
+                assert!(store.natives().get(name, method_name, descriptor).is_some(), "{}.{}{}", name, method_name, descriptor);
+            }
+        }
+    }
+
+    #[test]
+    fn natives() {
+        let mut vm = vm();
+        for name in [STRING_BUILDER, "java/lang/Integer", "java/lang/Double", "java/lang/Character", "java/lang/IllegalStateException"] {
+            vm.load_class(name).unwrap();
+        }
+        let (store, jit) = (&vm.class_store, &vm.jit_engine);
+        let env = unsafe { NativeEnv::new(store, jit) };
+        let string = |value: &str| env.new_string(value);
+
+        let hello = string("hello");
+        assert_eq!(string_char_at(&env, hello, 1), b'e' as _);
+        assert_eq!(store.exception().get(), None);
+        string_char_at(&env, hello, 5);
+        assert!(store.exception().take().is_some());
+        assert_eq!(string_hash_code(&env, hello), 99162322);
+        assert!(string_equals(&env, hello, string("hello")));
+        assert!(!string_equals(&env, hello, string("hell")));
+        assert!(!string_equals(&env, hello, std::ptr::null_mut()));
+        assert_eq!(env.read_string(string_concat(&env, hello, string(" world"))), "hello world");
+
+        let instance = |name: &str, descriptor: &str, value: &[u8]| {
+            let object = allocate_object(store, jit, vm.lookup(name).unwrap());
+            let field = env.field(object, "value", descriptor).unwrap();
+            unsafe { field.copy_from(value.as_ptr(), value.len()) };
+            object
+        };
+        let seven = instance("java/lang/Integer", "I", &7i32.to_ne_bytes());
+        let half = instance("java/lang/Double", "D", &0.5f64.to_ne_bytes());
+        assert_eq!(env.read_string(box_to_string(&env, seven)), "7");
+        assert_eq!(env.read_string(box_to_string(&env, half)), "0.5");
+        assert_eq!(env.read_string(box_to_string(&env, instance("java/lang/Character", "C", &0x20ACu16.to_ne_bytes()))), "\u{20AC}");
+        assert_eq!(box_hash_code(&env, seven), 7);
+        assert!(box_equals(&env, seven, instance("java/lang/Integer", "I", &7i32.to_ne_bytes())));
+        assert!(!box_equals(&env, seven, half));
+
+        let builder = allocate_object(store, jit, vm.lookup(STRING_BUILDER).unwrap());
+        unsafe { *(env.field(builder, "value", "Ljava/lang/String;").unwrap() as *mut JavaObject) = string("") };
+        append_value::<JavaObject>(&env, builder, hello);
+        append_value::<JavaInt>(&env, builder, 42);
+        append_value::<f64>(&env, builder, 2.0);
+        append_value::<bool>(&env, builder, true);
+        append_value::<JavaChar>(&env, builder, 0x20AC);
+        append_value::<JavaObject>(&env, builder, std::ptr::null_mut());
+        append_value::<JavaObject>(&env, builder, seven);
+        assert_eq!(env.read_string(value_of::<JavaObject>(&env, builder)), "hello422.0true\u{20AC}null7");
+
+        let exception = allocate_object(store, jit, vm.lookup("java/lang/IllegalStateException").unwrap());
+        assert_eq!(env.read_string(throwable_to_string(&env, exception)), "java.lang.IllegalStateException");
+        unsafe { *(env.field(exception, "detailMessage", "Ljava/lang/String;").unwrap() as *mut JavaObject) = hello };
+        assert_eq!(env.read_string(value_of::<JavaObject>(&env, exception)), "java.lang.IllegalStateException: hello");
+
+        // Arrays are named by their descriptor
+        let ints = allocate_array(store, jit, ArrayType::of(BaseType::Int), 1) as JavaObject;
+        let strings = allocate_array(store, jit, ArrayType::of(BaseType::Class(vm.lookup("java/lang/String").unwrap())), 1) as JavaObject;
+        assert_eq!(env.read_string(value_of::<JavaObject>(&env, ints)), format!("[I@{:x}", identity_hash(ints)));
+        assert_eq!(env.read_string(object_to_string(&env, strings)), format!("[Ljava.lang.String;@{:x}", identity_hash(strings)));
+        assert_eq!(env.field(ints, "value", "I"), None);
+    }
+
+    #[test]
+    fn float_formatting() {
+        let format = |value: f64| format_float(value, value.to_string(), format!("{:e}", value));
+        assert_eq!(format(1.0), "1.0");
+        assert_eq!(format(-0.5), "-0.5");
+        assert_eq!(format(0.0), "0.0");
+        assert_eq!(format(1e7), "1.0E7");
+        assert_eq!(format(1.5e-4), "1.5E-4");
+        assert_eq!(format(123456.75), "123456.75");
+        assert_eq!(format(f64::NAN), "NaN");
+        assert_eq!(format(f64::NEG_INFINITY), "-Infinity");
+    }
+}
@@ -0,0 +1,194 @@
+use std::cell::UnsafeCell;
+
+use classfile_parser::attributes::ConstantValue;
+use classfile_parser::class_file::{ClassFile, FieldAccessFlags};
+use classfile_parser::descriptor::FieldDescriptor;
+use classfile_parser::ClassParseError;
+
+use crate::class_loaders::LoadError;
+use crate::class_store::{ClassStoreIsh, LoadedClassRef};
+use crate::classfile_util::ConstantPoolExtensions;
+use crate::object::ObjectHeader;
+use crate::resolution::{resolve_field, ResolveError};
+use crate::strings::StringError;
+use crate::JitCompiler;
+
+/// The static fields of a class. Every field gets its own 8 byte slot, which starts out zeroed.
+/// The slots are never moved, so their addresses stay valid for as long as the class is loaded.
+pub struct StaticStorage {
+    fields: Vec<StaticField>,
+    data: Box<[UnsafeCell<u64>]>,
+}
+
+pub struct StaticField {
+    pub name: String,
+    pub descriptor: FieldDescriptor,
+    /// The value from the `ConstantValue` attribute, which is assigned when the class is initialized.
+    /// Only final fields get one, the attribute is ignored on others.
+    pub constant: Option<ConstantValue>,
+}
+
+/// A static field that a `FieldRef` constant resolved to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StaticFieldRef {
+    /// The class that declares the field, which isn't necessarily the class the `FieldRef` names
+    pub class: LoadedClassRef,
+    pub slot: usize,
+}
+
+impl StaticStorage {
+    /// Allocates storage for the static fields of a class, which is the preparation step of linking.
+    /// See: https://docs.oracle.com/javase/specs/jvms/se16/html/jvms-5.html#jvms-5.4.2
+    pub fn prepare(class: &ClassFile) -> Result<Self, LoadError> {
+        let pool = &class.constant_pool;
+        let class_name = || pool.get_class_name(class.this_class).unwrap_or_default().to_owned();
+        let invalid_index = |index| LoadError::ParseError(class_name(), ClassParseError::InvalidConstantPoolIndex(index));
+
+        let mut fields = Vec::new();
+        for field in &class.fields {
+            if !field.access_flags.contains(FieldAccessFlags::STATIC) {
+                continue;
+            }
+            let name = pool.get_as_string(field.name_index).ok_or_else(|| invalid_index(field.name_index))?;
+            let descriptor = pool.get_as_string(field.descriptor).ok_or_else(|| invalid_index(field.descriptor))?;
+            let descriptor = descriptor.parse().map_err(|e| LoadError::InvalidDescriptor(descriptor.to_owned(), e))?;
+            let constant = match field.access_flags.contains(FieldAccessFlags::FINAL) {
+                true => field.constant_value(pool).map_err(|e| LoadError::ParseError(class_name(), e))?,
+                false => None,
+            };
+            if let Some(constant) = &constant {
+                if !constant_fits(constant, &descriptor) {
+                    return Err(LoadError::ClassFormatError(format!("{}.{} can't have {:?} as its constant value", class_name(), name, constant)));
+                }
+            }
+            fields.push(StaticField { name: name.to_owned(), descriptor, constant });
+        }
+        let data = fields.iter().map(|_| UnsafeCell::new(0)).collect();
+        Ok(StaticStorage { fields, data })
+    }
+
+    /// Finds the slot of a static field
+    pub fn find(&self, name: &str, descriptor: &FieldDescriptor) -> Option<usize> {
+        self.fields.iter().position(|field| field.name == name && &field.descriptor == descriptor)
+    }
+
+    /// The address of a slot. Writing to it is only sound while nothing else accesses the slot.
+    pub fn address(&self, slot: usize) -> *mut u64 {
+        self.data[slot].get()
+    }
+
+    /// Assigns the `ConstantValue` of every field that has one. Values are stored in the start of their slot,
+    /// with the natural size of the field's type. Strings are turned into objects by `intern`.
+    pub(crate) fn apply_constants(&self, mut intern: impl FnMut(&[u16]) -> Result<*mut ObjectHeader, StringError>) -> Result<(), StringError> {
+        for (slot, field) in self.fields.iter().enumerate() {
+            let address = self.address(slot);
+            // Safe because every slot is 8 bytes and aligned to 8 bytes, and nothing runs while a class is initialized
+            unsafe {
+                match (&field.constant, &field.descriptor) {
+                    (Some(ConstantValue::Int(value)), FieldDescriptor::Boolean | FieldDescriptor::Byte) => (address as *mut i8).write(*value as i8),
+                    (Some(ConstantValue::Int(value)), FieldDescriptor::Char | FieldDescriptor::Short) => (address as *mut i16).write(*value as i16),
+                    (Some(ConstantValue::Int(value)), _) => (address as *mut i32).write(*value),
+                    (Some(ConstantValue::Long(value)), _) => (address as *mut i64).write(*value),
+                    (Some(ConstantValue::Float(value)), _) => (address as *mut f32).write(*value),
+                    (Some(ConstantValue::Double(value)), _) => (address as *mut f64).write(*value),
+                    (Some(ConstantValue::String(value)), _) => (address as *mut *mut ObjectHeader).write(intern(value)?),
+                    (None, _) => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn fields(&self) -> &[StaticField] {
+        &self.fields
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+/// Checks if a constant can be the `ConstantValue` of a field with the given type
+fn constant_fits(constant: &ConstantValue, descriptor: &FieldDescriptor) -> bool {
+    match constant {
+        ConstantValue::Int(_) => matches!(descriptor, FieldDescriptor::Int | FieldDescriptor::Short | FieldDescriptor::Char | FieldDescriptor::Byte | FieldDescriptor::Boolean),
+        ConstantValue::Long(_) => descriptor == &FieldDescriptor::Long,
+        ConstantValue::Float(_) => descriptor == &FieldDescriptor::Float,
+        ConstantValue::Double(_) => descriptor == &FieldDescriptor::Double,
+        ConstantValue::String(_) => matches!(descriptor, FieldDescriptor::Object(name) if name == "java/lang/String"),
+    }
+}
+
+/// Resolves the `FieldRef` at `index` in the constant pool of `referrer` to a static field
+pub fn resolve_static_field<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, referrer: LoadedClassRef, index: u16) -> Result<StaticFieldRef, ResolveError> {
+    let field = resolve_field(resolver, referrer, index)?;
+    let class = resolver.retrieve(field.class);
+    let full_name = || format!("{}.{}:{}", class.name(), field.name, field.descriptor);
+    if !field.is_static() {
+        return Err(ResolveError::IncompatibleClassChangeError(format!("{} is not static", full_name())));
+    }
+    let slot = class.statics.find(&field.name, &field.descriptor).ok_or_else(|| ResolveError::NoSuchFieldError(full_name()))?;
+    Ok(StaticFieldRef { class: field.class, slot })
+}
+
+#[cfg(test)]
+mod tests {
+    use classfile_parser::builder::ClassBuilder;
+    use classfile_parser::bytecode::Instruction;
+    use classfile_parser::class_file::{ClassAccessFlags, FieldAccessFlags, MethodAccessFlags};
+    use crate::class_store::ClassStoreIsh;
+    use crate::resolution::ResolveError;
+    use crate::statics::{resolve_static_field, StaticFieldRef};
+    use crate::test_util::{BuiltClassLoader, NoJit};
+    use crate::VirtualMachine;
+
+    #[test]
+    fn resolve_through_hierarchy() {
+        let mut interface = ClassBuilder::new("I", None);
+        interface.access_flags(ClassAccessFlags::PUBLIC | ClassAccessFlags::INTERFACE | ClassAccessFlags::ABSTRACT);
+        interface.field(FieldAccessFlags::STATIC, "X", "I");
+        let mut a = ClassBuilder::new("A", None);
+        a.interface("I");
+        a.field(FieldAccessFlags::STATIC, "Z", "I");
+        a.field(FieldAccessFlags::STATIC, "Y", "J");
+        let mut b = ClassBuilder::new("B", Some("A"));
+        b.field(FieldAccessFlags::empty(), "Z", "I");
+
+        let mut main = ClassBuilder::new("Main", None);
+        let x = main.field_ref("B", "X", "I");
+        let y = main.field_ref("B", "Y", "J");
+        let z = main.field_ref("B", "Z", "I");
+        let missing = main.field_ref("B", "Missing", "I");
+        main.method(MethodAccessFlags::STATIC, "run", "()V", vec![
+            Instruction::GetStatic(x),
+            Instruction::Pop,
+            Instruction::LConst(0),
+            Instruction::PutStatic(y),
+            Instruction::GetStatic(z),
+            Instruction::Pop,
+            Instruction::GetStatic(missing),
+            Instruction::Pop,
+            Instruction::Return,
+        ]);
+
+        let classes = vec![interface.build().unwrap(), a.build().unwrap(), b.build().unwrap(), main.build().unwrap()];
+        let mut vm = VirtualMachine::new(BuiltClassLoader::new(classes), NoJit);
+        let main = vm.load_class("Main").unwrap();
+        assert_eq!(vm.lookup("B"), None);
+        let run = vm.class_store.retrieve_method_ref(main, "run", "()V").unwrap();
+        vm.load_references(run, &mut Default::default()).unwrap();
+
+        let store = &vm.class_store;
+        let (interface, a) = (vm.lookup("I").unwrap(), vm.lookup("A").unwrap());
+        assert_eq!(resolve_static_field(store, main, x), Ok(StaticFieldRef { class: interface, slot: 0 }));
+        assert_eq!(resolve_static_field(store, main, y), Ok(StaticFieldRef { class: a, slot: 1 }));
+        // The instance field in B hides the static one in A
+        assert!(matches!(resolve_static_field(store, main, z), Err(ResolveError::IncompatibleClassChangeError(_))));
+        assert!(matches!(resolve_static_field(store, main, missing), Err(ResolveError::NoSuchFieldError(_))));
+        assert_eq!(resolve_static_field(store, main, 1), Err(ResolveError::InvalidConstantPoolIndex(1)));
+    }
+}
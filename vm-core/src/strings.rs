@@ -0,0 +1,241 @@
+//! `java/lang/String` objects, and converting them from and to rust strings.
+//! Java strings are UTF-16, so characters outside of the basic multilingual plane take up two units as a surrogate pair.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::slice;
+
+use classfile_parser::descriptor::FieldDescriptor;
+use thiserror::Error;
+
+use crate::class_store::{ClassStoreIsh, LoadedClassRef};
+use crate::heap::Handle;
+use crate::initialization::InitError;
+use crate::object::{allocate_array, allocate_object, ArrayType, BaseType, ObjectHeader, ARRAY_HEADER_SIZE};
+use crate::{ClassResolver, JitCompiler};
+
+/// Values of the `coder` field of strings that store their characters in a `byte[]`
+const LATIN1: i8 = 0;
+const UTF16: i8 = 1;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum StringError {
+    #[error("java/lang/String is not loaded")]
+    NotLoaded,
+    /// Strings need either a `char[] value` field, or a `byte[] value` field together with a `byte coder` field
+    #[error("java/lang/String has no value field that the vm understands")]
+    UnknownLayout,
+    #[error("could not initialize java/lang/String: {0}")]
+    InitError(Box<InitError>),
+}
+
+/// The class of strings, and the strings that were interned. Interned strings are never collected.
+#[derive(Default)]
+pub struct StringTable {
+    class: Cell<Option<LoadedClassRef>>,
+    interned: RefCell<HashMap<Vec<u16>, Handle>>,
+}
+
+impl StringTable {
+    /// The `java/lang/String` class of the vm, once it's loaded
+    pub fn class(&self) -> Option<LoadedClassRef> {
+        self.class.get()
+    }
+
+    pub(crate) fn set_class(&self, class: LoadedClassRef) {
+        self.class.set(Some(class));
+    }
+}
+
+/// Where a string keeps its characters. Older class libraries use a `char[]`,
+/// newer ones a `byte[]` that holds either latin-1 or UTF-16, depending on the `coder`.
+#[derive(Clone, Copy)]
+enum StringLayout {
+    Chars { value: usize },
+    Bytes { value: usize, coder: usize },
+}
+
+fn string_layout<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, class: LoadedClassRef) -> Result<StringLayout, StringError> {
+    let layout = &resolver.retrieve(class).layout;
+    let offset = |name, descriptor| layout.find(name, &descriptor).map(|field| field.offset);
+    let array = |element| FieldDescriptor::Array(Box::new(element));
+    if let Some(value) = offset("value", array(FieldDescriptor::Char)) {
+        return Ok(StringLayout::Chars { value });
+    }
+    match (offset("value", array(FieldDescriptor::Byte)), offset("coder", FieldDescriptor::Byte)) {
+        (Some(value), Some(coder)) => Ok(StringLayout::Bytes { value, coder }),
+        _ => Err(StringError::UnknownLayout),
+    }
+}
+
+/// Creates a string with the given UTF-16 contents. `java/lang/String` is initialized first if needed.
+pub fn new_string<J: JitCompiler>(resolver: &impl ClassResolver<J>, jit: &J, units: &[u16]) -> Result<*mut ObjectHeader, StringError> {
+    let class = resolver.strings().class().ok_or(StringError::NotLoaded)?;
+    let layout = string_layout(resolver, class)?;
+    resolver.initialize(class, jit).map_err(|e| StringError::InitError(Box::new(e)))?;
+
+    let latin1 = units.iter().all(|&unit| unit <= 0xFF);
+    // Safe because the arrays are allocated with room for every unit
+    let value = unsafe {
+        match layout {
+            StringLayout::Chars { .. } => {
+                let array = allocate_array(resolver, jit, ArrayType::of(BaseType::Char), units.len());
+                (array.add(ARRAY_HEADER_SIZE) as *mut u16).copy_from_nonoverlapping(units.as_ptr(), units.len());
+                array
+            }
+            StringLayout::Bytes { .. } if latin1 => {
+                let array = allocate_array(resolver, jit, ArrayType::of(BaseType::Byte), units.len());
+                for (index, &unit) in units.iter().enumerate() {
+                    *array.add(ARRAY_HEADER_SIZE + index) = unit as u8;
+                }
+                array
+            }
+            StringLayout::Bytes { .. } => {
+                // In the byte order of the platform, like `java.lang.StringUTF16` expects
+                let array = allocate_array(resolver, jit, ArrayType::of(BaseType::Byte), units.len() * 2);
+                array.add(ARRAY_HEADER_SIZE).copy_from_nonoverlapping(units.as_ptr() as *const u8, units.len() * 2);
+                array
+            }
+        }
+    };
+
+    // Allocating the string could collect the array otherwise
+    let handle = resolver.heap().hold(value);
+    let string = allocate_object(resolver, jit, class);
+    resolver.heap().release(handle);
+    // Safe because the offsets come from the layout of the class
+    unsafe {
+        let base = string as *mut u8;
+        match layout {
+            StringLayout::Chars { value: offset } => *(base.add(offset) as *mut *mut u8) = value,
+            StringLayout::Bytes { value: offset, coder } => {
+                *(base.add(offset) as *mut *mut u8) = value;
+                *(base.add(coder) as *mut i8) = if latin1 { LATIN1 } else { UTF16 };
+            }
+        }
+    }
+    Ok(string)
+}
+
+/// Reads the UTF-16 contents of a string
+///
+/// # Safety
+/// `string` has to point to a `java/lang/String` that's still alive
+pub unsafe fn read_string<J: JitCompiler>(resolver: &impl ClassStoreIsh<J>, string: *const ObjectHeader) -> Result<Vec<u16>, StringError> {
+    let base = string as *const u8;
+    let array = |offset: usize| {
+        let array = *(base.add(offset) as *const *const u8);
+        (array.add(ARRAY_HEADER_SIZE), *(array as *const usize))
+    };
+    Ok(match string_layout(resolver, (*string).class)? {
+        StringLayout::Chars { value } => {
+            let (elements, length) = array(value);
+            slice::from_raw_parts(elements as *const u16, length).to_vec()
+        }
+        StringLayout::Bytes { value, coder } => {
+            let (elements, length) = array(value);
+            let bytes = slice::from_raw_parts(elements, length);
+            if *(base.add(coder) as *const i8) == LATIN1 {
+                bytes.iter().map(|&byte| byte as u16).collect()
+            } else {
+                bytes.chunks_exact(2).map(|unit| u16::from_ne_bytes([unit[0], unit[1]])).collect()
+            }
+        }
+    })
+}
+
+/// Returns the interned string with the given contents, creating it if there isn't one yet.
+/// String constants are interned, so equal literals are the same object.
+pub fn intern<J: JitCompiler>(resolver: &impl ClassResolver<J>, jit: &J, units: &[u16]) -> Result<*mut ObjectHeader, StringError> {
+    let table = resolver.strings();
+    if let Some(handle) = table.interned.borrow().get(units) {
+        return Ok(resolver.heap().get(handle) as *mut ObjectHeader);
+    }
+    let string = new_string(resolver, jit, units)?;
+    table.interned.borrow_mut().insert(units.to_vec(), resolver.heap().hold(string as *mut u8));
+    Ok(string)
+}
+
+/// The UTF-16 units of a rust string
+pub fn to_utf16(value: &str) -> Vec<u16> {
+    value.encode_utf16().collect()
+}
+
+/// Converts UTF-16 units to a rust string. Java strings can contain unpaired surrogates, which become U+FFFD.
+pub fn from_utf16(units: &[u16]) -> String {
+    String::from_utf16_lossy(units)
+}
+
+#[cfg(test)]
+mod tests {
+    use classfile_parser::attributes::ConstantValue;
+    use classfile_parser::builder::ClassBuilder;
+    use classfile_parser::bytecode::Instruction;
+    use classfile_parser::class_file::{ClassAccessFlags, FieldAccessFlags, MethodAccessFlags};
+    use crate::class_store::ClassStoreIsh;
+    use crate::strings::{from_utf16, intern, new_string, read_string, to_utf16, StringError};
+    use crate::test_util::{BuiltClassLoader, NoJit};
+    use crate::{ClassResolver, VirtualMachine};
+
+    /// A `java/lang/String` with the given fields, and a class that uses string constants
+    fn classes(fields: &[(&str, &str)]) -> BuiltClassLoader {
+        let mut string = ClassBuilder::new("java/lang/String", None);
+        string.access_flags(ClassAccessFlags::PUBLIC | ClassAccessFlags::FINAL);
+        for (name, descriptor) in fields {
+            string.field(FieldAccessFlags::PRIVATE | FieldAccessFlags::FINAL, name, descriptor);
+        }
+        let mut main = ClassBuilder::new("Main", None);
+        main.constant_field(FieldAccessFlags::STATIC | FieldAccessFlags::FINAL, "GREETING", "Ljava/lang/String;", ConstantValue::String(to_utf16("hi")));
+        main.constant_field(FieldAccessFlags::STATIC | FieldAccessFlags::FINAL, "UNPAIRED", "Ljava/lang/String;", ConstantValue::String(vec![0x61, 0xD83D]));
+        let hi = main.string("hi");
+        main.method(MethodAccessFlags::STATIC, "run", "()V", vec![Instruction::LdC_w(hi), Instruction::Return]);
+        BuiltClassLoader::new(vec![string.build().unwrap(), main.build().unwrap()])
+    }
+
+    #[test]
+    fn conversion() {
+        let value = "aé\u{1F600}";
+        let units = to_utf16(value);
+        assert_eq!(units, [0x61, 0xE9, 0xD83D, 0xDE00]);
+        assert_eq!(from_utf16(&units), value);
+        assert_eq!(from_utf16(&[0x61, 0xD83D]), "a\u{FFFD}");
+
+        for fields in [&[("value", "[C")][..], &[("value", "[B"), ("coder", "B")]] {
+            let mut vm = VirtualMachine::new(classes(fields), NoJit);
+            let main = vm.load_class("Main").unwrap();
+            vm.initialize(main).unwrap();
+            let (store, jit) = (&vm.class_store, &vm.jit_engine);
+            for units in [&units[..], &[0x61, 0xE9], &[], &[0xD83D, 0x62]] {
+                let string = new_string(store, jit, units).unwrap();
+                assert_eq!(unsafe { read_string(store, string) }.unwrap(), units);
+            }
+
+            let handle = vm.new_string(value).unwrap();
+            vm.collect_garbage();
+            assert_eq!(vm.read_string(&handle).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn interning() {
+        let mut vm = VirtualMachine::new(classes(&[("value", "[C")]), NoJit);
+        let main = vm.load_class("Main").unwrap();
+        vm.initialize(main).unwrap();
+        let (store, jit) = (&vm.class_store, &vm.jit_engine);
+        let constant = unsafe { *store.retrieve(main).statics.address(0) } as usize as *mut _;
+        assert_eq!(intern(store, jit, &to_utf16("hi")), Ok(constant));
+        assert_ne!(intern(store, jit, &to_utf16("ho")), Ok(constant));
+        assert_ne!(new_string(store, jit, &to_utf16("hi")), Ok(constant));
+
+        store.collect_garbage(jit);
+        assert_eq!(unsafe { read_string(store, constant) }.unwrap(), to_utf16("hi"));
+        // Constants keep unpaired surrogates
+        let unpaired = unsafe { *store.retrieve(main).statics.address(1) } as usize as *mut _;
+        assert_eq!(unsafe { read_string(store, unpaired) }.unwrap(), [0x61, 0xD83D]);
+
+        let vm = VirtualMachine::new(classes(&[("value", "I")]), NoJit);
+        assert_eq!(new_string(&vm.class_store, &vm.jit_engine, &[]), Err(StringError::NotLoaded));
+        let mut vm = VirtualMachine::new(classes(&[("value", "I")]), NoJit);
+        assert_eq!(vm.new_string("hi").unwrap_err(), StringError::UnknownLayout);
+    }
+}
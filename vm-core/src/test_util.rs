@@ -0,0 +1,144 @@
+//! Helpers shared by the tests of different modules
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ptr;
+
+use classfile_parser::builder::ClassBuilder;
+use classfile_parser::bytecode::Instruction;
+use classfile_parser::class_file::{ClassAccessFlags, ClassFile, MethodAccessFlags};
+
+use crate::class_loaders::{BootstrapClassLoader, LoadError, ParentFirstClassLoader};
+use crate::classfile_util::ConstantPoolExtensions;
+use crate::class_store::LoadedMethodRef;
+use crate::dispatch::DispatchTables;
+use crate::object::ObjectHeader;
+use crate::{ClassLoader, ClassResolver, JitCompiler, JitError, LoaderId, VirtualMachine};
+
+/// The bytes of an empty class with the given name
+pub fn class_bytes(name: &str) -> Vec<u8> {
+    let mut bytes = vec![0xCA, 0xFE, 0xBA, 0xBE, 0, 0, 0, 52];
+    bytes.extend_from_slice(&[0, 3]); // Constant pool count
+    bytes.push(1); // Utf8
+    bytes.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    bytes.extend_from_slice(name.as_bytes());
+    bytes.extend_from_slice(&[7, 0, 1]); // Class
+    bytes.extend_from_slice(&[0, 0x21, 0, 2, 0, 0]); // Flags, this class, super class
+    bytes.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0]); // Interfaces, fields, methods, attributes
+    bytes
+}
+
+/// A jit compiler that doesn't compile anything
+#[derive(Default)]
+pub struct NoJit;
+
+impl JitCompiler for NoJit {
+    type ClassData = ();
+
+    fn load(&mut self, _class: &ClassFile, _dispatch: &DispatchTables) -> Result<Self::ClassData, JitError> {
+        Ok(())
+    }
+
+    fn get_fn_pointer(&self, _method: LoadedMethodRef, _resolver: &impl ClassResolver<Self>) -> usize {
+        unimplemented!()
+    }
+}
+
+/// A jit compiler that doesn't compile anything, but records which initializers ran.
+/// Initializers of classes whose name starts with `Failing` throw.
+#[derive(Default)]
+pub struct InitJit {
+    pub ran: RefCell<Vec<String>>,
+}
+
+impl JitCompiler for InitJit {
+    type ClassData = ();
+
+    fn load(&mut self, _class: &ClassFile, _dispatch: &DispatchTables) -> Result<Self::ClassData, JitError> {
+        Ok(())
+    }
+
+    fn get_fn_pointer(&self, _method: LoadedMethodRef, _resolver: &impl ClassResolver<Self>) -> usize {
+        unimplemented!()
+    }
+
+    fn run_initializer(&self, method: LoadedMethodRef, resolver: &impl ClassResolver<Self>) -> Result<(), *mut ObjectHeader> {
+        let name = resolver.retrieve(method.class_ref).name().to_owned();
+        let failing = name.starts_with("Failing");
+        self.ran.borrow_mut().push(name);
+        if failing { Err(ptr::null_mut()) } else { Ok(()) }
+    }
+}
+
+pub const PUBLIC: MethodAccessFlags = MethodAccessFlags::PUBLIC;
+pub const INTERFACE: ClassAccessFlags = ClassAccessFlags::from_bits_truncate(ClassAccessFlags::INTERFACE.bits() | ClassAccessFlags::ABSTRACT.bits());
+
+/// A class with `()V` methods that just return, or have no code if they're abstract
+pub fn class(name: &str, super_class: Option<&str>, interfaces: &[&str], methods: &[(MethodAccessFlags, &str)]) -> ClassBuilder {
+    let mut builder = ClassBuilder::new(name, super_class);
+    for interface in interfaces {
+        builder.interface(interface);
+    }
+    for &(flags, method) in methods {
+        if flags.contains(MethodAccessFlags::ABSTRACT) {
+            builder.method_without_code(flags, method, "()V");
+        } else {
+            builder.method(flags, method, "()V", vec![Instruction::Return]);
+        }
+    }
+    builder
+}
+
+/// An interface like [class], without a superclass
+pub fn interface(name: &str, interfaces: &[&str], methods: &[(MethodAccessFlags, &str)]) -> ClassBuilder {
+    let mut builder = class(name, None, interfaces, methods);
+    builder.access_flags(INTERFACE);
+    builder
+}
+
+/// An empty class that extends `super_class` and implements `interfaces`
+pub fn build_class(name: &str, super_class: Option<&str>, interfaces: &[&str], access_flags: ClassAccessFlags) -> ClassFile {
+    let mut builder = ClassBuilder::new(name, super_class);
+    builder.access_flags(access_flags);
+    for interface in interfaces {
+        builder.interface(interface);
+    }
+    builder.build().unwrap()
+}
+
+/// Loads classes that were built in memory
+pub struct BuiltClassLoader {
+    id: LoaderId,
+    classes: HashMap<String, ClassFile>,
+}
+
+impl BuiltClassLoader {
+    pub fn new(classes: Vec<ClassFile>) -> Self {
+        let classes = classes.into_iter()
+            .map(|class| (class.constant_pool.get_class_name(class.this_class).unwrap().to_owned(), class))
+            .collect();
+        BuiltClassLoader { id: LoaderId::new(), classes }
+    }
+}
+
+impl ClassLoader for BuiltClassLoader {
+    fn id(&self) -> LoaderId {
+        self.id
+    }
+
+    fn load_defined(&self, class: &str) -> Result<(LoaderId, ClassFile), LoadError> {
+        let found = self.classes.get(class).ok_or_else(|| LoadError::NotFound(class.to_owned()))?;
+        Ok((self.id, found.clone()))
+    }
+}
+
+/// A vm with the bootstrap class library and a class that has a native method, so the vm loads what natives need
+pub fn bootstrapped_vm() -> VirtualMachine<ParentFirstClassLoader<BootstrapClassLoader, BuiltClassLoader>, NoJit> {
+    let mut main = ClassBuilder::new("Main", Some("java/lang/Object"));
+    main.method_without_code(MethodAccessFlags::STATIC | MethodAccessFlags::NATIVE, "run", "()V");
+    let loader = ParentFirstClassLoader::new(BootstrapClassLoader::new(), BuiltClassLoader::new(vec![main.build().unwrap()]));
+    let mut vm = VirtualMachine::new(loader, NoJit);
+    let main = vm.load_class("Main").unwrap();
+    vm.initialize(main).unwrap();
+    vm
+}
@@ -1,8 +1,8 @@
-use std::convert::{TryFrom, TryInto};
+use std::convert::TryFrom;
 
 use enum_map::Enum;
 
-use crate::class_store::DescriptorEntry;
+use classfile_parser::descriptor::FieldDescriptor;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Enum)]
 pub enum PrimitiveTypes {
@@ -25,14 +25,15 @@ pub enum LvtEntryType {
     Short,
     Int,
     Float,
+    /// Takes up two local variables, the entry is the first of them
+    Long,
+    Double,
     Reference,
     ReturnAddress
 }
 
 #[derive(Debug)]
 pub struct IsReturnAddress;
-#[derive(Debug)]
-pub struct IsVoidType;
 
 impl TryFrom<&LvtEntryType> for PrimitiveTypes {
     type Error = IsReturnAddress;
@@ -45,28 +46,27 @@ impl TryFrom<&LvtEntryType> for PrimitiveTypes {
             LvtEntryType::Short => Ok(PrimitiveTypes::Short),
             LvtEntryType::Int => Ok(PrimitiveTypes::Int),
             LvtEntryType::Float => Ok(PrimitiveTypes::Float),
+            LvtEntryType::Long => Ok(PrimitiveTypes::Long),
+            LvtEntryType::Double => Ok(PrimitiveTypes::Double),
             LvtEntryType::Reference => Ok(PrimitiveTypes::Reference),
             LvtEntryType::ReturnAddress => Err(IsReturnAddress),
         }
     }
 }
 
-impl TryFrom<&DescriptorEntry> for PrimitiveTypes {
-    type Error = IsVoidType;
-
-    fn try_from(value: &DescriptorEntry) -> Result<Self, Self::Error> {
+impl From<&FieldDescriptor> for PrimitiveTypes {
+    fn from(value: &FieldDescriptor) -> Self {
         match value {
-            DescriptorEntry::Class(_) =>    Ok(PrimitiveTypes::Reference),
-            DescriptorEntry::Byte =>        Ok(PrimitiveTypes::Byte),
-            DescriptorEntry::Char =>        Ok(PrimitiveTypes::Char),
-            DescriptorEntry::Double =>      Ok(PrimitiveTypes::Double),
-            DescriptorEntry::Float =>       Ok(PrimitiveTypes::Float),
-            DescriptorEntry::Int =>         Ok(PrimitiveTypes::Int),
-            DescriptorEntry::Long =>        Ok(PrimitiveTypes::Long),
-            DescriptorEntry::Short =>       Ok(PrimitiveTypes::Short),
-            DescriptorEntry::Boolean =>     Ok(PrimitiveTypes::Boolean),
-            DescriptorEntry::Array(_) =>    Ok(PrimitiveTypes::Reference),
-            DescriptorEntry::Void =>        Err(IsVoidType),
+            FieldDescriptor::Object(_) =>   PrimitiveTypes::Reference,
+            FieldDescriptor::Byte =>        PrimitiveTypes::Byte,
+            FieldDescriptor::Char =>        PrimitiveTypes::Char,
+            FieldDescriptor::Double =>      PrimitiveTypes::Double,
+            FieldDescriptor::Float =>       PrimitiveTypes::Float,
+            FieldDescriptor::Int =>         PrimitiveTypes::Int,
+            FieldDescriptor::Long =>        PrimitiveTypes::Long,
+            FieldDescriptor::Short =>       PrimitiveTypes::Short,
+            FieldDescriptor::Boolean =>     PrimitiveTypes::Boolean,
+            FieldDescriptor::Array(_) =>    PrimitiveTypes::Reference,
         }
     }
-}
\ No newline at end of file
+}
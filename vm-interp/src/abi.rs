@@ -0,0 +1,154 @@
+//! Calls between native code and the interpreter, without generating any code.
+//!
+//! Both the System V calling convention of x86-64 and the one of aarch64 pass the first integer and pointer arguments
+//! in general purpose registers, and the first floating point arguments in vector registers, each in order.
+//! So a function taking [INT_REGISTERS] integers and [FLOAT_REGISTERS] doubles receives the arguments of any
+//! signature that fits in registers. Narrower values sit in the low bits of their register.
+//! Floating point results are returned in a vector register, everything else in a general purpose one.
+//!
+//! Interpreted methods get an address from a fixed table of entry points, each of which looks up
+//! which method it stands for when it's called.
+
+use std::sync::{Arc, Mutex};
+
+use classfile_parser::descriptor::FieldDescriptor;
+use vm_core::class_store::LoadedMethodRef;
+use vm_core::interop::{fits_in_registers, DYNAMIC_CALLS_SUPPORTED, FLOAT_REGISTERS, INT_REGISTERS};
+use vm_core::object::ObjectHeader;
+use vm_core::ClassResolver;
+
+use crate::{Interpreter, Value};
+
+type IntFn = extern "C" fn(u64, u64, u64, u64, u64, u64, f64, f64, f64, f64, f64, f64, f64, f64) -> u64;
+type FloatFn = extern "C" fn(u64, u64, u64, u64, u64, u64, f64, f64, f64, f64, f64, f64, f64, f64) -> f64;
+
+fn is_float(ty: &FieldDescriptor) -> bool {
+    matches!(ty, FieldDescriptor::Float | FieldDescriptor::Double)
+}
+
+/// The contents of the register that holds `value`
+fn to_bits(value: Value) -> u64 {
+    match value {
+        Value::Int(value) => value as i64 as u64,
+        Value::Long(value) => value as u64,
+        Value::Float(value) => value.to_bits() as u64,
+        Value::Double(value) => value.to_bits(),
+        Value::Reference(value) => value as u64,
+    }
+}
+
+/// Reads a value of type `ty` out of a register. Only the low bits of narrow types are defined.
+fn from_bits(ty: &FieldDescriptor, bits: u64) -> Value {
+    match ty {
+        FieldDescriptor::Boolean => Value::Int((bits as u8 != 0) as i32),
+        FieldDescriptor::Byte => Value::Int(bits as i8 as i32),
+        FieldDescriptor::Char => Value::Int(bits as u16 as i32),
+        FieldDescriptor::Short => Value::Int(bits as i16 as i32),
+        FieldDescriptor::Int => Value::Int(bits as i32),
+        FieldDescriptor::Long => Value::Long(bits as i64),
+        FieldDescriptor::Float => Value::Float(f32::from_bits(bits as u32)),
+        FieldDescriptor::Double => Value::Double(f64::from_bits(bits)),
+        FieldDescriptor::Object(_) | FieldDescriptor::Array(_) => Value::Reference(bits as *mut ObjectHeader),
+    }
+}
+
+/// Entry points are generic over their slot, split in two so the tables stay small
+const ROW: usize = 16;
+
+/// A method that an entry point stands for
+struct Entry {
+    interpreter: *const Interpreter,
+    resolver: *const (),
+    invoke: unsafe fn(*const Interpreter, *const (), LoadedMethodRef, &[Value]) -> Option<Value>,
+    method: LoadedMethodRef,
+    /// Including the object the method is called on
+    parameters: Arc<[FieldDescriptor]>,
+}
+
+// Safe because entries are only used on the thread of the vm that they point to
+unsafe impl Send for Entry {}
+
+static ENTRIES: Mutex<Vec<Option<Entry>>> = Mutex::new(Vec::new());
+
+/// The address of an interpreted method, which stays valid until this is dropped
+pub(crate) struct EntryPoint {
+    slot: usize,
+    pub(crate) address: usize,
+}
+
+impl EntryPoint {
+    /// Reserves an entry point for `method`.
+    /// The interpreter and resolver can't move or be dropped while the entry point is in use.
+    pub(crate) fn new<R: ClassResolver<Interpreter>>(interpreter: &Interpreter, resolver: &R, method: LoadedMethodRef, parameters: Vec<FieldDescriptor>, return_type: Option<&FieldDescriptor>) -> Self {
+        if !DYNAMIC_CALLS_SUPPORTED {
+            panic!("calling interpreted methods from native code isn't supported on this platform");
+        }
+        assert!(fits_in_registers(0, &parameters), "too many parameters to call the method from native code");
+
+        let entry = Entry { interpreter, resolver: resolver as *const R as *const (), invoke: invoke::<R>, method, parameters: parameters.into() };
+        let mut entries = ENTRIES.lock().unwrap();
+        let slot = match entries.iter().position(Option::is_none) {
+            Some(slot) => slot,
+            None => {
+                entries.push(None);
+                entries.len() - 1
+            }
+        };
+        assert!(slot < ROW * ROW, "ran out of entry points, at most {} methods can be called from native code", ROW * ROW);
+        entries[slot] = Some(entry);
+        let address = match return_type {
+            Some(FieldDescriptor::Float | FieldDescriptor::Double) => FLOAT_ENTRIES[slot / ROW][slot % ROW] as usize,
+            _ => INT_ENTRIES[slot / ROW][slot % ROW] as usize,
+        };
+        EntryPoint { slot, address }
+    }
+}
+
+impl Drop for EntryPoint {
+    fn drop(&mut self) {
+        ENTRIES.lock().unwrap()[self.slot] = None;
+    }
+}
+
+unsafe fn invoke<R: ClassResolver<Interpreter>>(interpreter: *const Interpreter, resolver: *const (), method: LoadedMethodRef, arguments: &[Value]) -> Option<Value> {
+    (*interpreter).invoke(&*(resolver as *const R), method, arguments)
+}
+
+/// Runs the method of an entry point with the arguments from the registers
+fn enter(slot: usize, ints: [u64; INT_REGISTERS], floats: [f64; FLOAT_REGISTERS]) -> Option<Value> {
+    let (interpreter, resolver, invoke, method, parameters) = {
+        let entries = ENTRIES.lock().unwrap();
+        let entry = entries[slot].as_ref().expect("called an entry point that was released");
+        (entry.interpreter, entry.resolver, entry.invoke, entry.method, entry.parameters.clone())
+    };
+    let (mut ints, mut floats) = (ints.iter().copied(), floats.iter().copied());
+    let arguments: Vec<Value> = parameters.iter().map(|ty| match is_float(ty) {
+        true => from_bits(ty, floats.next().unwrap().to_bits()),
+        false => from_bits(ty, ints.next().unwrap()),
+    }).collect();
+    // Safe because the entry is released before the interpreter is dropped
+    unsafe { invoke(interpreter, resolver, method, &arguments) }
+}
+
+extern "C" fn int_entry<const HIGH: usize, const LOW: usize>(i0: u64, i1: u64, i2: u64, i3: u64, i4: u64, i5: u64, f0: f64, f1: f64, f2: f64, f3: f64, f4: f64, f5: f64, f6: f64, f7: f64) -> u64 {
+    enter(HIGH * ROW + LOW, [i0, i1, i2, i3, i4, i5], [f0, f1, f2, f3, f4, f5, f6, f7]).map_or(0, to_bits)
+}
+
+extern "C" fn float_entry<const HIGH: usize, const LOW: usize>(i0: u64, i1: u64, i2: u64, i3: u64, i4: u64, i5: u64, f0: f64, f1: f64, f2: f64, f3: f64, f4: f64, f5: f64, f6: f64, f7: f64) -> f64 {
+    f64::from_bits(enter(HIGH * ROW + LOW, [i0, i1, i2, i3, i4, i5], [f0, f1, f2, f3, f4, f5, f6, f7]).map_or(0, to_bits))
+}
+
+macro_rules! entries {
+    ($entry:ident) => {
+        entries!(@table $entry; 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15)
+    };
+    (@table $entry:ident; $($high:literal)*) => {
+        [$(entries!(@row $entry, $high; 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15)),*]
+    };
+    (@row $entry:ident, $high:literal; $($low:literal)*) => {
+        [$($entry::<$high, $low>),*]
+    };
+}
+
+static INT_ENTRIES: [[IntFn; ROW]; ROW] = entries!(int_entry);
+static FLOAT_ENTRIES: [[FloatFn; ROW]; ROW] = entries!(float_entry);
@@ -0,0 +1,1513 @@
+//! A [JitCompiler] that doesn't compile anything, but interprets the bytecode of methods instead.
+//! It runs anywhere rust does, which makes it useful where LLVM isn't available, and to compare the LLVM compiler against.
+//!
+//! Every running method has a frame on a single stack of [Value]s, with its local variables followed by its operand stack.
+//! Garbage collection can happen whenever an instruction allocates, so the references on that stack are the roots of
+//! the interpreter. Longs and doubles take up one value, but two local variable indices.
+
+mod abi;
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use classfile_parser::bytecode::Instruction;
+use classfile_parser::class_file::ClassFile;
+use classfile_parser::constant_pool::{types, ConstantPool, ConstantPoolEntry};
+use classfile_parser::descriptor::FieldDescriptor;
+use vm_core::class_store::{LoadedClassRef, LoadedMethodRef};
+use vm_core::dispatch::{select_interface, select_special, select_virtual, DispatchTables};
+use vm_core::exceptions::{find_handler, throw_runtime_exception, RuntimeException};
+use vm_core::initialization::InitError;
+use vm_core::interop::JavaValue;
+use vm_core::natives::{call_native, find_native, NativeEnv};
+use vm_core::object::{allocate_array, allocate_object, class_of, dispatch_class, field_size, is_instance, resolve_instance_field, type_of, resolve_new, ArrayType, BaseType, ObjectHeader, ReferenceType, ARRAY_HEADER_SIZE};
+use vm_core::resolution::{resolve_method, resolve_string_concat, resolve_type, ConcatPart};
+use vm_core::runtime::concat;
+use vm_core::statics::resolve_static_field;
+use vm_core::strings::{intern, StringError};
+use vm_core::{ClassResolver, JitCompiler, JitError};
+
+use crate::abi::EntryPoint;
+
+#[derive(Default)]
+pub struct Interpreter {
+    /// Methods that were decoded before
+    methods: RefCell<HashMap<LoadedMethodRef, Rc<Method>>>,
+    /// The frames of the methods that are running, each after the frame of its caller
+    values: RefCell<Vec<Value>>,
+    /// Addresses that native code can call, see [JitCompiler::get_fn_pointer]
+    entry_points: RefCell<HashMap<LoadedMethodRef, EntryPoint>>,
+}
+
+/// A value on the operand stack or in a local variable. Booleans, bytes, chars and shorts are ints.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    /// Null, or the address of an object or array
+    Reference(*mut ObjectHeader),
+}
+
+impl Value {
+    /// The default value of a field of type `ty`
+    pub fn zero(ty: &FieldDescriptor) -> Self {
+        match ty {
+            FieldDescriptor::Long => Value::Long(0),
+            FieldDescriptor::Float => Value::Float(0.0),
+            FieldDescriptor::Double => Value::Double(0.0),
+            FieldDescriptor::Object(_) | FieldDescriptor::Array(_) => Value::Reference(std::ptr::null_mut()),
+            _ => Value::Int(0),
+        }
+    }
+
+    /// Longs and doubles take up two local variables
+    fn is_wide(self) -> bool {
+        matches!(self, Value::Long(_) | Value::Double(_))
+    }
+
+    // The bytecode is assumed to be valid, so the operands of an instruction always have the right type
+
+    fn int(self) -> i32 {
+        match self {
+            Value::Int(value) => value,
+            value => panic!("expected an int, found {:?}", value),
+        }
+    }
+
+    fn long(self) -> i64 {
+        match self {
+            Value::Long(value) => value,
+            value => panic!("expected a long, found {:?}", value),
+        }
+    }
+
+    fn float(self) -> f32 {
+        match self {
+            Value::Float(value) => value,
+            value => panic!("expected a float, found {:?}", value),
+        }
+    }
+
+    fn double(self) -> f64 {
+        match self {
+            Value::Double(value) => value,
+            value => panic!("expected a double, found {:?}", value),
+        }
+    }
+
+    fn reference(self) -> *mut ObjectHeader {
+        match self {
+            Value::Reference(value) => value,
+            value => panic!("expected a reference, found {:?}", value),
+        }
+    }
+
+    /// Converts the value to a [JavaValue] of type `ty`
+    pub fn to_java(self, ty: &FieldDescriptor) -> JavaValue {
+        match ty {
+            FieldDescriptor::Boolean => JavaValue::Boolean(self.int() != 0),
+            FieldDescriptor::Byte => JavaValue::Byte(self.int() as i8),
+            FieldDescriptor::Char => JavaValue::Char(self.int() as u16),
+            FieldDescriptor::Short => JavaValue::Short(self.int() as i16),
+            FieldDescriptor::Int => JavaValue::Int(self.int()),
+            FieldDescriptor::Long => JavaValue::Long(self.long()),
+            FieldDescriptor::Float => JavaValue::Float(self.float()),
+            FieldDescriptor::Double => JavaValue::Double(self.double()),
+            FieldDescriptor::Object(_) | FieldDescriptor::Array(_) => JavaValue::Object(self.reference()),
+        }
+    }
+}
+
+impl From<JavaValue> for Value {
+    fn from(value: JavaValue) -> Self {
+        match value {
+            JavaValue::Boolean(value) => Value::Int(value as i32),
+            JavaValue::Byte(value) => Value::Int(value as i32),
+            JavaValue::Char(value) => Value::Int(value as i32),
+            JavaValue::Short(value) => Value::Int(value as i32),
+            JavaValue::Int(value) => Value::Int(value),
+            JavaValue::Long(value) => Value::Long(value),
+            JavaValue::Float(value) => Value::Float(value),
+            JavaValue::Double(value) => Value::Double(value),
+            JavaValue::Object(value) => Value::Reference(value),
+            JavaValue::Void => panic!("void isn't a value"),
+        }
+    }
+}
+
+/// A decoded method
+struct Method {
+    /// Including the object the method is called on
+    parameters: Vec<FieldDescriptor>,
+    return_type: Option<FieldDescriptor>,
+    body: Body,
+}
+
+enum Body {
+    Code {
+        instructions: Vec<(usize, Instruction)>,
+        /// The index in `instructions` of the instruction at each address
+        indices: HashMap<usize, usize>,
+        max_locals: usize,
+    },
+    /// The address of the rust function that implements the method
+    Native(usize),
+    /// A native that no rust function was registered for, which throws an `UnsatisfiedLinkError` when it's called
+    Unlinked,
+    Abstract,
+}
+
+/// What to do after an instruction
+enum Step {
+    Next,
+    /// Continue at the instruction at an address
+    Jump(usize),
+    Return(Option<Value>),
+    /// The instruction threw, the exception is pending
+    Throw,
+}
+
+impl JitCompiler for Interpreter {
+    type ClassData = ();
+
+    fn load(&mut self, _class: &ClassFile, _dispatch: &DispatchTables) -> Result<Self::ClassData, JitError> {
+        Ok(())
+    }
+
+    /// Returns an address from which native code can call the method, see [abi].
+    /// The vm can't move or be dropped while the address is in use.
+    fn get_fn_pointer(&self, method: LoadedMethodRef, resolver: &impl ClassResolver<Self>) -> usize {
+        if let Some(entry_point) = self.entry_points.borrow().get(&method) {
+            return entry_point.address;
+        }
+        let decoded = self.method(resolver, method);
+        let entry_point = EntryPoint::new(self, resolver, method, decoded.parameters.clone(), decoded.return_type.as_ref());
+        let address = entry_point.address;
+        self.entry_points.borrow_mut().insert(method, entry_point);
+        address
+    }
+
+    fn run_initializer(&self, method: LoadedMethodRef, resolver: &impl ClassResolver<Self>) -> Result<(), *mut ObjectHeader> {
+        self.invoke(resolver, method, &[]);
+        match resolver.exception().take() {
+            Some(exception) => Err(exception),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs the method directly, without going through an entry point
+    fn invoke_method(&self, method: LoadedMethodRef, resolver: &impl ClassResolver<Self>, arguments: &[JavaValue]) -> JavaValue {
+        let arguments = arguments.iter().map(|&argument| Value::from(argument)).collect::<Vec<_>>();
+        let result = self.invoke(resolver, method, &arguments);
+        match (result, &self.method(resolver, method).return_type) {
+            (Some(value), Some(ty)) => value.to_java(ty),
+            _ => JavaValue::Void,
+        }
+    }
+
+    fn visit_frame_roots(&self, visitor: &mut dyn FnMut(*mut u8)) {
+        for value in self.values.borrow().iter() {
+            if let Value::Reference(object) = *value {
+                if !object.is_null() {
+                    visitor(object as *mut u8);
+                }
+            }
+        }
+    }
+}
+
+impl Interpreter {
+    /// Runs a method, `arguments` start with the object it's called on for instance methods.
+    /// If the method throws, the exception stays pending and the zero value of the return type is returned.
+    pub fn invoke<R: ClassResolver<Self>>(&self, resolver: &R, method: LoadedMethodRef, arguments: &[Value]) -> Option<Value> {
+        let decoded = self.method(resolver, method);
+        match &decoded.body {
+            Body::Code { instructions, indices, max_locals } => {
+                let base = self.values.borrow().len();
+                {
+                    let mut values = self.values.borrow_mut();
+                    for &argument in arguments {
+                        values.push(argument);
+                        if argument.is_wide() {
+                            values.push(Value::Int(0));
+                        }
+                    }
+                    let locals = (values.len() - base).max(*max_locals);
+                    values.resize(base + locals, Value::Int(0));
+                }
+                let frame = Frame { values: &self.values, locals: base, stack: self.values.borrow().len() };
+                let result = self.run(resolver, method, &decoded, instructions, indices, &frame);
+                self.values.borrow_mut().truncate(base);
+                result
+            }
+            Body::Native(address) => {
+                // Safe because the vm outlives the call
+                let env = unsafe { NativeEnv::new(resolver, self) };
+                let arguments: Vec<JavaValue> = arguments.iter().zip(&decoded.parameters).map(|(value, ty)| value.to_java(ty)).collect();
+                // Safe because natives are checked to fit their descriptor when they're registered
+                let result = unsafe { call_native(*address, &env, &arguments, decoded.return_type.as_ref()) };
+                decoded.return_type.as_ref().map(|_| Value::from(result))
+            }
+            Body::Unlinked => {
+                throw_runtime_exception(resolver, self, RuntimeException::UnsatisfiedLink);
+                decoded.return_type.as_ref().map(Value::zero)
+            }
+            Body::Abstract => {
+                throw_runtime_exception(resolver, self, RuntimeException::AbstractMethod);
+                decoded.return_type.as_ref().map(Value::zero)
+            }
+        }
+    }
+
+    /// Decodes a method, or returns it if that happened before
+    fn method<R: ClassResolver<Self>>(&self, resolver: &R, method: LoadedMethodRef) -> Rc<Method> {
+        if let Some(decoded) = self.methods.borrow().get(&method) {
+            return decoded.clone();
+        }
+        let class = resolver.retrieve(method.class_ref);
+        let data = class.retrieve_method(method);
+        let descriptor = data.parse_descriptor().unwrap_or_else(|e| panic!("{}: {}", method_name(resolver, method), e));
+        let this = (!data.is_static()).then(|| FieldDescriptor::Object(class.name().to_owned()));
+        let body = match data.code {
+            Some(code) => {
+                let instructions = code.code.decode().unwrap_or_else(|e| panic!("{}: {}", method_name(resolver, method), e));
+                let indices = instructions.iter().enumerate().map(|(index, &(address, _))| (address, index)).collect();
+                Body::Code { instructions, indices, max_locals: code.max_locals as usize }
+            }
+            None if data.is_native() => match find_native(resolver, method) {
+                Ok(native) => Body::Native(native.address),
+                Err(_) => Body::Unlinked,
+            },
+            None => Body::Abstract,
+        };
+        let decoded = Rc::new(Method {
+            parameters: this.into_iter().chain(descriptor.parameters).collect(),
+            return_type: descriptor.return_type,
+            body,
+        });
+        self.methods.borrow_mut().insert(method, decoded.clone());
+        decoded
+    }
+
+    /// Runs the instructions of a method until it returns, or until it throws an exception that it doesn't catch
+    fn run<R: ClassResolver<Self>>(&self, resolver: &R, method: LoadedMethodRef, decoded: &Method, instructions: &[(usize, Instruction)], indices: &HashMap<usize, usize>, frame: &Frame) -> Option<Value> {
+        let mut index = 0;
+        loop {
+            let (pc, instruction) = &instructions[index];
+            match self.step(resolver, method, frame, *pc, instruction) {
+                Step::Next => index += 1,
+                Step::Jump(target) => index = indices[&target],
+                Step::Return(value) => return value,
+                Step::Throw => {
+                    let exception = resolver.exception().get().expect("threw without a pending exception");
+                    let class = class_of(resolver, exception).expect("exceptions are instances of a class");
+                    let handler = match find_handler(resolver, method, *pc, class) {
+                        Ok(handler) => handler,
+                        Err(error) => {
+                            // A catch type that can't be resolved replaces the exception
+                            throw_runtime_exception(resolver, self, RuntimeException::from(&error));
+                            return decoded.return_type.as_ref().map(Value::zero);
+                        }
+                    };
+                    match handler {
+                        Some(handler) => {
+                            // Handlers start with only the caught exception on the operand stack
+                            resolver.exception().take();
+                            frame.clear_stack();
+                            frame.push(Value::Reference(exception));
+                            index = indices[&handler];
+                        }
+                        None => return decoded.return_type.as_ref().map(Value::zero),
+                    }
+                }
+            }
+        }
+    }
+
+    fn step<R: ClassResolver<Self>>(&self, resolver: &R, method: LoadedMethodRef, frame: &Frame, pc: usize, instruction: &Instruction) -> Step {
+        let class = method.class_ref;
+        let throw = |exception| {
+            throw_runtime_exception(resolver, self, exception);
+            Step::Throw
+        };
+        let branch = |condition: bool, offset: i32| match condition {
+            true => Step::Jump((pc as i64 + offset as i64) as usize),
+            false => Step::Next,
+        };
+        // Resolving and initializing classes throw linkage errors
+        macro_rules! linked {
+            ($result:expr) => {
+                match $result {
+                    Ok(value) => value,
+                    Err(error) => return throw(RuntimeException::from(&error)),
+                }
+            };
+        }
+        // A static initializer that throws leaves its error pending
+        macro_rules! initialized {
+            ($class:expr) => {
+                match resolver.initialize($class, self) {
+                    Ok(()) => {}
+                    Err(InitError::ExceptionInInitializerError(_)) => return Step::Throw,
+                    Err(error) => return throw(RuntimeException::from(&error)),
+                }
+            };
+        }
+        macro_rules! binary {
+            ($pop:ident, $value:ident, |$a:ident, $b:ident| $result:expr) => {{
+                let $b = frame.pop().$pop();
+                let $a = frame.pop().$pop();
+                frame.push(Value::$value($result));
+            }};
+        }
+        macro_rules! unary {
+            ($pop:ident, $value:ident, |$a:ident| $result:expr) => {{
+                let $a = frame.pop().$pop();
+                frame.push(Value::$value($result));
+            }};
+        }
+        macro_rules! compare {
+            ($pop:ident, |$a:ident, $b:ident| $condition:expr, $offset:expr) => {{
+                let $b = frame.pop().$pop();
+                let $a = frame.pop().$pop();
+                return branch($condition, $offset as i32);
+            }};
+        }
+        macro_rules! array_load {
+            ($ty:expr) => {{
+                let index = frame.pop().int();
+                let array = frame.pop().reference();
+                match element(array, index, &$ty) {
+                    // Safe because the element lies inside of the array
+                    Ok(address) => frame.push(unsafe { read(address, &$ty) }),
+                    Err(exception) => return throw(exception),
+                }
+            }};
+        }
+        macro_rules! array_store {
+            ($ty:expr) => {{
+                let value = frame.pop();
+                let index = frame.pop().int();
+                let array = frame.pop().reference();
+                match element(array, index, &$ty) {
+                    // Safe because the element lies inside of the array
+                    Ok(address) => unsafe { write(address, &$ty, value) },
+                    Err(exception) => return throw(exception),
+                }
+            }};
+        }
+
+        match *instruction {
+            Instruction::Nop => {}
+            Instruction::AConstNull => frame.push(Value::Reference(std::ptr::null_mut())),
+            Instruction::IConst(value) => frame.push(Value::Int(value)),
+            Instruction::LConst(value) => frame.push(Value::Long(value)),
+            Instruction::FConst(value) => frame.push(Value::Float(value)),
+            Instruction::DConst(value) => frame.push(Value::Double(value)),
+            Instruction::BIPush(value) => frame.push(Value::Int(value as i32)),
+            Instruction::SIPush(value) => frame.push(Value::Int(value as i32)),
+            Instruction::LdC(index) => frame.push(linked!(self.constant(resolver, class, index as u16))),
+            Instruction::LdC_w(index) | Instruction::LdC2_w(index) => frame.push(linked!(self.constant(resolver, class, index))),
+
+            Instruction::ILoad(local) | Instruction::LLoad(local) | Instruction::FLoad(local) | Instruction::DLoad(local) | Instruction::ALoad(local) => frame.push(frame.local(local)),
+            Instruction::IStore(local) | Instruction::LStore(local) | Instruction::FStore(local) | Instruction::DStore(local) | Instruction::AStore(local) => frame.set_local(local, frame.pop()),
+            Instruction::IInc(local, constant) => frame.set_local(local, Value::Int(frame.local(local).int().wrapping_add(constant as i32))),
+
+            Instruction::IALoad => array_load!(FieldDescriptor::Int),
+            Instruction::LALoad => array_load!(FieldDescriptor::Long),
+            Instruction::FAload => array_load!(FieldDescriptor::Float),
+            Instruction::DALoad => array_load!(FieldDescriptor::Double),
+            Instruction::AALoad => array_load!(FieldDescriptor::Object(String::new())),
+            Instruction::BALoad => array_load!(FieldDescriptor::Byte),
+            Instruction::CALoad => array_load!(FieldDescriptor::Char),
+            Instruction::SALoad => array_load!(FieldDescriptor::Short),
+            Instruction::IAstore => array_store!(FieldDescriptor::Int),
+            Instruction::LAStore => array_store!(FieldDescriptor::Long),
+            Instruction::FAstore => array_store!(FieldDescriptor::Float),
+            Instruction::DAStore => array_store!(FieldDescriptor::Double),
+            Instruction::AAStore => {
+                let ty = FieldDescriptor::Object(String::new());
+                let value = frame.pop();
+                let index = frame.pop().int();
+                let array = frame.pop().reference();
+                let address = match element(array, index, &ty) {
+                    Ok(address) => address,
+                    Err(exception) => return throw(exception),
+                };
+                // Null can be stored in any array of references
+                let object = value.reference();
+                let component = match type_of(resolver, array) {
+                    Some(ReferenceType::Array(ty)) => ty.component(),
+                    _ => None,
+                };
+                if !object.is_null() && !component.is_some_and(|component| is_instance(resolver, object, component)) {
+                    return throw(RuntimeException::ArrayStore);
+                }
+                // Safe because the element lies inside of the array
+                unsafe { write(address, &ty, value) };
+            }
+            Instruction::BAStore => array_store!(FieldDescriptor::Byte),
+            Instruction::CAStore => array_store!(FieldDescriptor::Char),
+            Instruction::SAStore => array_store!(FieldDescriptor::Short),
+
+            Instruction::Pop => {
+                frame.pop();
+            }
+            Instruction::Pop2 => {
+                if !frame.pop().is_wide() {
+                    frame.pop();
+                }
+            }
+            Instruction::Dup => frame.push(frame.peek()),
+            Instruction::Dup_x1 => {
+                let (first, second) = (frame.pop(), frame.pop());
+                frame.push_all(&[first, second, first]);
+            }
+            Instruction::Dup_x2 => {
+                let (first, second) = (frame.pop(), frame.pop());
+                if second.is_wide() {
+                    frame.push_all(&[first, second, first]);
+                } else {
+                    let third = frame.pop();
+                    frame.push_all(&[first, third, second, first]);
+                }
+            }
+            Instruction::Dup2 => {
+                let first = frame.pop();
+                if first.is_wide() {
+                    frame.push_all(&[first, first]);
+                } else {
+                    let second = frame.pop();
+                    frame.push_all(&[second, first, second, first]);
+                }
+            }
+            Instruction::Dup2_x1 => {
+                let (first, second) = (frame.pop(), frame.pop());
+                if first.is_wide() {
+                    frame.push_all(&[first, second, first]);
+                } else {
+                    let third = frame.pop();
+                    frame.push_all(&[second, first, third, second, first]);
+                }
+            }
+            Instruction::Dup2_x2 => {
+                let (first, second) = (frame.pop(), frame.pop());
+                match (first.is_wide(), second.is_wide()) {
+                    (true, true) => frame.push_all(&[first, second, first]),
+                    (true, false) => {
+                        let third = frame.pop();
+                        frame.push_all(&[first, third, second, first]);
+                    }
+                    (false, _) => {
+                        let third = frame.pop();
+                        if third.is_wide() {
+                            frame.push_all(&[second, first, third, second, first]);
+                        } else {
+                            let fourth = frame.pop();
+                            frame.push_all(&[second, first, fourth, third, second, first]);
+                        }
+                    }
+                }
+            }
+            Instruction::Swap => {
+                let (first, second) = (frame.pop(), frame.pop());
+                frame.push_all(&[first, second]);
+            }
+
+            Instruction::IAdd => binary!(int, Int, |a, b| a.wrapping_add(b)),
+            Instruction::LAdd => binary!(long, Long, |a, b| a.wrapping_add(b)),
+            Instruction::FAdd => binary!(float, Float, |a, b| a + b),
+            Instruction::DAdd => binary!(double, Double, |a, b| a + b),
+            Instruction::ISub => binary!(int, Int, |a, b| a.wrapping_sub(b)),
+            Instruction::LSub => binary!(long, Long, |a, b| a.wrapping_sub(b)),
+            Instruction::FSub => binary!(float, Float, |a, b| a - b),
+            Instruction::DSub => binary!(double, Double, |a, b| a - b),
+            Instruction::IMul => binary!(int, Int, |a, b| a.wrapping_mul(b)),
+            Instruction::LMul => binary!(long, Long, |a, b| a.wrapping_mul(b)),
+            Instruction::FMul => binary!(float, Float, |a, b| a * b),
+            Instruction::DMul => binary!(double, Double, |a, b| a * b),
+            Instruction::IDiv | Instruction::IRem => {
+                let (divisor, dividend) = (frame.pop().int(), frame.pop().int());
+                if divisor == 0 {
+                    return throw(RuntimeException::Arithmetic);
+                }
+                let result = match instruction {
+                    Instruction::IDiv => dividend.wrapping_div(divisor),
+                    _ => dividend.wrapping_rem(divisor),
+                };
+                frame.push(Value::Int(result));
+            }
+            Instruction::LDiv | Instruction::LRem => {
+                let (divisor, dividend) = (frame.pop().long(), frame.pop().long());
+                if divisor == 0 {
+                    return throw(RuntimeException::Arithmetic);
+                }
+                let result = match instruction {
+                    Instruction::LDiv => dividend.wrapping_div(divisor),
+                    _ => dividend.wrapping_rem(divisor),
+                };
+                frame.push(Value::Long(result));
+            }
+            Instruction::FDiv => binary!(float, Float, |a, b| a / b),
+            Instruction::DDiv => binary!(double, Double, |a, b| a / b),
+            Instruction::FRem => binary!(float, Float, |a, b| a % b),
+            Instruction::DRem => binary!(double, Double, |a, b| a % b),
+            Instruction::INeg => unary!(int, Int, |a| a.wrapping_neg()),
+            Instruction::LNeg => unary!(long, Long, |a| a.wrapping_neg()),
+            Instruction::FNeg => unary!(float, Float, |a| -a),
+            Instruction::DNeg => unary!(double, Double, |a| -a),
+
+            // Shifts only use the low bits of the distance, which the wrapping shifts do too
+            Instruction::IShL => binary!(int, Int, |a, b| a.wrapping_shl(b as u32)),
+            Instruction::IShR => binary!(int, Int, |a, b| a.wrapping_shr(b as u32)),
+            Instruction::IUShR => binary!(int, Int, |a, b| (a as u32).wrapping_shr(b as u32) as i32),
+            Instruction::LShL | Instruction::LShR | Instruction::LUShR => {
+                let (distance, value) = (frame.pop().int() as u32, frame.pop().long());
+                let result = match instruction {
+                    Instruction::LShL => value.wrapping_shl(distance),
+                    Instruction::LShR => value.wrapping_shr(distance),
+                    _ => (value as u64).wrapping_shr(distance) as i64,
+                };
+                frame.push(Value::Long(result));
+            }
+            Instruction::IAnd => binary!(int, Int, |a, b| a & b),
+            Instruction::LanD => binary!(long, Long, |a, b| a & b),
+            Instruction::IOr => binary!(int, Int, |a, b| a | b),
+            Instruction::LOr => binary!(long, Long, |a, b| a | b),
+            Instruction::IXor => binary!(int, Int, |a, b| a ^ b),
+            Instruction::LXor => binary!(long, Long, |a, b| a ^ b),
+
+            // Casting floats to integers saturates and turns NaN into zero, just like java
+            Instruction::I2L => unary!(int, Long, |a| a as i64),
+            Instruction::I2F => unary!(int, Float, |a| a as f32),
+            Instruction::I2D => unary!(int, Double, |a| a as f64),
+            Instruction::L2I => unary!(long, Int, |a| a as i32),
+            Instruction::L2F => unary!(long, Float, |a| a as f32),
+            Instruction::L2D => unary!(long, Double, |a| a as f64),
+            Instruction::FSI => unary!(float, Int, |a| a as i32),
+            Instruction::F2L => unary!(float, Long, |a| a as i64),
+            Instruction::F2D => unary!(float, Double, |a| a as f64),
+            Instruction::D2I => unary!(double, Int, |a| a as i32),
+            Instruction::D2L => unary!(double, Long, |a| a as i64),
+            Instruction::D2F => unary!(double, Float, |a| a as f32),
+            Instruction::I2B => unary!(int, Int, |a| a as i8 as i32),
+            Instruction::I2C => unary!(int, Int, |a| a as u16 as i32),
+            Instruction::I2S => unary!(int, Int, |a| a as i16 as i32),
+
+            Instruction::LCmp => binary!(long, Int, |a, b| compare(a, b, 0)),
+            Instruction::FCmpL => binary!(float, Int, |a, b| compare(a, b, -1)),
+            Instruction::FCmpG => binary!(float, Int, |a, b| compare(a, b, 1)),
+            Instruction::DCmpL => binary!(double, Int, |a, b| compare(a, b, -1)),
+            Instruction::DCmpG => binary!(double, Int, |a, b| compare(a, b, 1)),
+            Instruction::IfEq(offset) => return branch(frame.pop().int() == 0, offset as i32),
+            Instruction::IfNe(offset) => return branch(frame.pop().int() != 0, offset as i32),
+            Instruction::IfLt(offset) => return branch(frame.pop().int() < 0, offset as i32),
+            Instruction::IfGe(offset) => return branch(frame.pop().int() >= 0, offset as i32),
+            Instruction::IfGt(offset) => return branch(frame.pop().int() > 0, offset as i32),
+            Instruction::IfLe(offset) => return branch(frame.pop().int() <= 0, offset as i32),
+            Instruction::IfICmpEq(offset) => compare!(int, |a, b| a == b, offset),
+            Instruction::IfICmpNe(offset) => compare!(int, |a, b| a != b, offset),
+            Instruction::IfICmpLt(offset) => compare!(int, |a, b| a < b, offset),
+            Instruction::IfICmpGe(offset) => compare!(int, |a, b| a >= b, offset),
+            Instruction::IfICmpGt(offset) => compare!(int, |a, b| a > b, offset),
+            Instruction::IfICmpLe(offset) => compare!(int, |a, b| a <= b, offset),
+            Instruction::IfACmpEq(offset) => compare!(reference, |a, b| a == b, offset),
+            Instruction::IfACmpNe(offset) => compare!(reference, |a, b| a != b, offset),
+            Instruction::IfNull(offset) => return branch(frame.pop().reference().is_null(), offset as i32),
+            Instruction::IfNonNull(offset) => return branch(!frame.pop().reference().is_null(), offset as i32),
+            Instruction::Goto(offset) => return branch(true, offset as i32),
+            Instruction::Goto_w(offset) => return branch(true, offset),
+            Instruction::TableSwitch(ref table) => {
+                let key = frame.pop().int();
+                let offset = if (table.low..=table.high).contains(&key) {
+                    // Can't overflow, because the key lies between low and high
+                    table.offsets[(key as i64 - table.low as i64) as usize]
+                } else {
+                    table.default
+                };
+                return branch(true, offset);
+            }
+            Instruction::LookupSwitch(ref lookup) => {
+                let key = frame.pop().int();
+                let offset = match lookup.pairs.binary_search_by_key(&key, |&(value, _)| value) {
+                    Ok(index) => lookup.pairs[index].1,
+                    Err(_) => lookup.default,
+                };
+                return branch(true, offset);
+            }
+
+            Instruction::IReturn | Instruction::LReturn | Instruction::FReturn | Instruction::DReturn | Instruction::AReturn => {
+                let decoded = self.method(resolver, method);
+                let value = frame.pop();
+                // Methods returning a narrower type than int return the low bits
+                let value = match (decoded.return_type.as_ref(), value) {
+                    (Some(FieldDescriptor::Boolean), Value::Int(value)) => Value::Int(value & 1),
+                    (Some(FieldDescriptor::Byte), Value::Int(value)) => Value::Int(value as i8 as i32),
+                    (Some(FieldDescriptor::Char), Value::Int(value)) => Value::Int(value as u16 as i32),
+                    (Some(FieldDescriptor::Short), Value::Int(value)) => Value::Int(value as i16 as i32),
+                    _ => value,
+                };
+                return Step::Return(Some(value));
+            }
+            Instruction::Return => return Step::Return(None),
+
+            Instruction::GetStatic(index) | Instruction::PutStatic(index) => {
+                let field = linked!(resolve_static_field(resolver, class, index));
+                initialized!(field.class);
+                let statics = &resolver.retrieve(field.class).statics;
+                let ty = &statics.fields()[field.slot].descriptor;
+                let address = statics.address(field.slot) as *mut u8;
+                // Safe because static fields are stored at the start of their slot, with the size of their type
+                unsafe {
+                    match instruction {
+                        Instruction::GetStatic(_) => frame.push(read(address, ty)),
+                        _ => write(address, ty, frame.pop()),
+                    }
+                }
+            }
+            Instruction::GetField(index) => {
+                let field = linked!(resolve_instance_field(resolver, class, index));
+                let object = frame.pop().reference();
+                if object.is_null() {
+                    return throw(RuntimeException::NullPointer);
+                }
+                // Safe because the layout of the object contains the field
+                frame.push(unsafe { read((object as *mut u8).add(field.offset), &field.descriptor) });
+            }
+            Instruction::Putfield(index) => {
+                let field = linked!(resolve_instance_field(resolver, class, index));
+                let value = frame.pop();
+                let object = frame.pop().reference();
+                if object.is_null() {
+                    return throw(RuntimeException::NullPointer);
+                }
+                // Safe because the layout of the object contains the field
+                unsafe { write((object as *mut u8).add(field.offset), &field.descriptor, value) };
+            }
+
+            Instruction::InvokeStatic(index) => {
+                let target = linked!(resolve_method(resolver, class, index));
+                initialized!(target.class_ref);
+                return self.call(resolver, frame, target);
+            }
+            Instruction::InvokeSpecial(index) | Instruction::InvokeVirtual(index) | Instruction::InvokeInterface(index, _) => {
+                let resolved = linked!(resolve_method(resolver, class, index));
+                let parameters = self.method(resolver, resolved).parameters.len();
+                let receiver = frame.peek_at(parameters - 1).reference();
+                if receiver.is_null() {
+                    return throw(RuntimeException::NullPointer);
+                }
+                let receiver_class = dispatch_class(resolver, receiver, class);
+                let target = match instruction {
+                    Instruction::InvokeSpecial(_) => select_special(resolver, class, resolved),
+                    Instruction::InvokeVirtual(_) => select_virtual(resolver, receiver_class, resolved),
+                    _ => select_interface(resolver, receiver_class, resolved),
+                };
+                return self.call(resolver, frame, linked!(target));
+            }
+            Instruction::InvokeDynamic(index, _) => {
+                let parts = linked!(resolve_string_concat(resolver, class, index));
+                let types = parts.iter().filter_map(|part| match part {
+                    ConcatPart::Argument(ty) => Some(ty),
+                    ConcatPart::Constant(_) => None,
+                }).collect::<Vec<_>>();
+                // The arguments stay on the operand stack while `toString` methods run, which keeps them alive
+                let arguments = frame.top(types.len()).into_iter().zip(&types).map(|(value, ty)| value.to_java(ty)).collect::<Vec<_>>();
+                // Safe because the vm outlives the call
+                let env = unsafe { NativeEnv::new(resolver, self) };
+                let string = concat(&env, &parts, &arguments);
+                frame.drop_top(types.len());
+                if resolver.exception().get().is_some() {
+                    return Step::Throw;
+                }
+                frame.push(Value::Reference(string));
+            }
+
+            Instruction::New(index) => {
+                let class = linked!(resolve_new(resolver, class, index));
+                initialized!(class);
+                frame.push(Value::Reference(allocate_object(resolver, self, class)));
+            }
+            Instruction::NewArray(atype) => {
+                let base = match atype {
+                    4 => BaseType::Boolean,
+                    5 => BaseType::Char,
+                    6 => BaseType::Float,
+                    7 => BaseType::Double,
+                    8 => BaseType::Byte,
+                    9 => BaseType::Short,
+                    10 => BaseType::Int,
+                    11 => BaseType::Long,
+                    _ => return throw(RuntimeException::Verify),
+                };
+                let length = frame.pop().int();
+                match usize::try_from(length) {
+                    Ok(length) => frame.push(Value::Reference(allocate_array(resolver, self, ArrayType::of(base), length) as *mut ObjectHeader)),
+                    Err(_) => return throw(RuntimeException::NegativeArraySize),
+                }
+            }
+            Instruction::ANewArray(index) => {
+                let ty = match linked!(resolve_type(resolver, class, index)).array() {
+                    Some(ty) => ty,
+                    // Array types can't have more than 255 dimensions
+                    None => return throw(RuntimeException::Verify),
+                };
+                let length = frame.pop().int();
+                match usize::try_from(length) {
+                    Ok(length) => frame.push(Value::Reference(allocate_array(resolver, self, ty, length) as *mut ObjectHeader)),
+                    Err(_) => return throw(RuntimeException::NegativeArraySize),
+                }
+            }
+            Instruction::MultiANewArray(index, dimensions) => {
+                let ty = match linked!(resolve_type(resolver, class, index)) {
+                    ReferenceType::Array(ty) => ty,
+                    ReferenceType::Class(_) => return throw(RuntimeException::Verify),
+                };
+                let mut lengths = (0..dimensions).map(|_| frame.pop().int()).collect::<Vec<_>>();
+                lengths.reverse();
+                if lengths.iter().any(|&length| length < 0) {
+                    return throw(RuntimeException::NegativeArraySize);
+                }
+                frame.push(self.new_multi_array(resolver, frame, ty, &lengths));
+            }
+            Instruction::ArrayLength => {
+                let array = frame.pop().reference();
+                if array.is_null() {
+                    return throw(RuntimeException::NullPointer);
+                }
+                // Safe because arrays start with their length
+                frame.push(Value::Int(unsafe { *(array as *const usize) } as i32));
+            }
+
+            Instruction::AThrow => {
+                let exception = frame.pop().reference();
+                if exception.is_null() {
+                    return throw(RuntimeException::NullPointer);
+                }
+                resolver.exception().set(exception);
+                return Step::Throw;
+            }
+            Instruction::Checkcast(index) => {
+                let target = linked!(resolve_type(resolver, class, index));
+                let object = frame.peek().reference();
+                if !object.is_null() && !is_instance(resolver, object, target) {
+                    return throw(RuntimeException::ClassCast);
+                }
+            }
+            Instruction::InstanceOf(index) => {
+                let target = linked!(resolve_type(resolver, class, index));
+                let object = frame.pop().reference();
+                frame.push(Value::Int((!object.is_null() && is_instance(resolver, object, target)) as i32));
+            }
+            // There's only a single thread
+            Instruction::MonitorEnter | Instruction::MonitorExit => {
+                if frame.pop().reference().is_null() {
+                    return throw(RuntimeException::NullPointer);
+                }
+            }
+
+            ref instruction => panic!("the interpreter doesn't support {:?}", instruction),
+        }
+        Step::Next
+    }
+
+    /// Calls a method with the arguments on top of the operand stack, and pushes its result
+    fn call<R: ClassResolver<Self>>(&self, resolver: &R, frame: &Frame, target: LoadedMethodRef) -> Step {
+        let count = self.method(resolver, target).parameters.len();
+        // The arguments stay on the operand stack during the call, which keeps them alive
+        let arguments = frame.top(count);
+        let result = self.invoke(resolver, target, &arguments);
+        frame.drop_top(count);
+        if resolver.exception().get().is_some() {
+            return Step::Throw;
+        }
+        if let Some(result) = result {
+            frame.push(result);
+        }
+        Step::Next
+    }
+
+    /// A constant from the pool of `class`, for `ldc`
+    fn constant<R: ClassResolver<Self>>(&self, resolver: &R, class: LoadedClassRef, index: u16) -> Result<Value, StringError> {
+        let pool = &resolver.retrieve(class).java_class.constant_pool;
+        Ok(match pool.get_entry(index) {
+            Some(ConstantPoolEntry::IntegerInfo(value)) => Value::Int(value.inner as i32),
+            Some(ConstantPoolEntry::FloatInfo(value)) => Value::Float(value.inner),
+            Some(ConstantPoolEntry::LongInfo(value)) => Value::Long(value.inner as i64),
+            Some(ConstantPoolEntry::DoubleInfo(value)) => Value::Double(value.inner),
+            Some(ConstantPoolEntry::StringInfo(string)) => {
+                let value = pool.get_as::<types::Utf8Info>(string.string_index).unwrap().to_utf16();
+                Value::Reference(intern(resolver, self, &value)?)
+            }
+            entry => panic!("the interpreter doesn't support ldc of {:?}", entry),
+        })
+    }
+
+    /// Allocates an array of type `ty` with nested arrays, for `multianewarray`
+    fn new_multi_array<R: ClassResolver<Self>>(&self, resolver: &R, frame: &Frame, ty: ArrayType, lengths: &[i32]) -> Value {
+        let array = allocate_array(resolver, self, ty, lengths[0] as usize);
+        if let (Some(ReferenceType::Array(component)), true) = (ty.component(), lengths.len() > 1) {
+            // Allocating the inner arrays can collect garbage, so the outer one is kept on the operand stack meanwhile
+            frame.push(Value::Reference(array as *mut ObjectHeader));
+            for index in 0..lengths[0] {
+                let inner = self.new_multi_array(resolver, frame, component, &lengths[1..]);
+                // Safe because the index lies inside of the array, whose elements are references
+                unsafe { *(array.add(ARRAY_HEADER_SIZE + index as usize * ty.element_size()) as *mut *mut ObjectHeader) = inner.reference() };
+            }
+            frame.pop();
+        }
+        Value::Reference(array as *mut ObjectHeader)
+    }
+}
+
+/// The frame of a running method, inside of the value stack of the interpreter
+struct Frame<'a> {
+    values: &'a RefCell<Vec<Value>>,
+    /// Where the local variables start
+    locals: usize,
+    /// Where the operand stack starts
+    stack: usize,
+}
+
+impl Frame<'_> {
+    fn push(&self, value: Value) {
+        self.values.borrow_mut().push(value);
+    }
+
+    fn push_all(&self, values: &[Value]) {
+        self.values.borrow_mut().extend_from_slice(values);
+    }
+
+    fn pop(&self) -> Value {
+        let mut values = self.values.borrow_mut();
+        assert!(values.len() > self.stack, "popped from an empty operand stack");
+        values.pop().unwrap()
+    }
+
+    fn peek(&self) -> Value {
+        self.peek_at(0)
+    }
+
+    /// The value `depth` values below the top of the operand stack
+    fn peek_at(&self, depth: usize) -> Value {
+        let values = self.values.borrow();
+        values[values.len() - 1 - depth]
+    }
+
+    /// Copies the top `count` values of the operand stack, the deepest first
+    fn top(&self, count: usize) -> Vec<Value> {
+        let values = self.values.borrow();
+        values[values.len() - count..].to_vec()
+    }
+
+    fn drop_top(&self, count: usize) {
+        let mut values = self.values.borrow_mut();
+        let length = values.len() - count;
+        values.truncate(length);
+    }
+
+    fn clear_stack(&self) {
+        self.values.borrow_mut().truncate(self.stack);
+    }
+
+    fn local(&self, index: u8) -> Value {
+        self.values.borrow()[self.locals + index as usize]
+    }
+
+    fn set_local(&self, index: u8, value: Value) {
+        self.values.borrow_mut()[self.locals + index as usize] = value;
+    }
+}
+
+/// The address of an element of an array, or the exception that accessing it throws
+fn element(array: *mut ObjectHeader, index: i32, ty: &FieldDescriptor) -> Result<*mut u8, RuntimeException> {
+    if array.is_null() {
+        return Err(RuntimeException::NullPointer);
+    }
+    // Safe because arrays start with their length
+    let length = unsafe { *(array as *const usize) };
+    match usize::try_from(index) {
+        // Safe because the index lies inside of the array
+        Ok(index) if index < length => Ok(unsafe { (array as *mut u8).add(ARRAY_HEADER_SIZE + index * field_size(ty)) }),
+        _ => Err(RuntimeException::ArrayIndexOutOfBounds),
+    }
+}
+
+/// Reads a field or array element of type `ty`
+///
+/// # Safety
+/// `address` has to point to a value of type `ty`
+unsafe fn read(address: *const u8, ty: &FieldDescriptor) -> Value {
+    match ty {
+        FieldDescriptor::Boolean => Value::Int(*address as i32),
+        FieldDescriptor::Byte => Value::Int(*(address as *const i8) as i32),
+        FieldDescriptor::Char => Value::Int(*(address as *const u16) as i32),
+        FieldDescriptor::Short => Value::Int(*(address as *const i16) as i32),
+        FieldDescriptor::Int => Value::Int(*(address as *const i32)),
+        FieldDescriptor::Long => Value::Long(*(address as *const i64)),
+        FieldDescriptor::Float => Value::Float(*(address as *const f32)),
+        FieldDescriptor::Double => Value::Double(*(address as *const f64)),
+        FieldDescriptor::Object(_) | FieldDescriptor::Array(_) => Value::Reference(*(address as *const *mut ObjectHeader)),
+    }
+}
+
+/// Writes a field or array element of type `ty`, ints are truncated to the size of the type
+///
+/// # Safety
+/// `address` has to point to a value of type `ty`
+unsafe fn write(address: *mut u8, ty: &FieldDescriptor, value: Value) {
+    match ty {
+        FieldDescriptor::Boolean | FieldDescriptor::Byte => *address = value.int() as u8,
+        FieldDescriptor::Char | FieldDescriptor::Short => *(address as *mut u16) = value.int() as u16,
+        FieldDescriptor::Int => *(address as *mut i32) = value.int(),
+        FieldDescriptor::Long => *(address as *mut i64) = value.long(),
+        FieldDescriptor::Float => *(address as *mut f32) = value.float(),
+        FieldDescriptor::Double => *(address as *mut f64) = value.double(),
+        FieldDescriptor::Object(_) | FieldDescriptor::Array(_) => *(address as *mut *mut ObjectHeader) = value.reference(),
+    }
+}
+
+/// The result of `lcmp`, `fcmpl` and friends. `nan` is the result when either value is NaN.
+fn compare<T: PartialOrd>(a: T, b: T, nan: i32) -> i32 {
+    match a.partial_cmp(&b) {
+        Some(Ordering::Less) => -1,
+        Some(Ordering::Equal) => 0,
+        Some(Ordering::Greater) => 1,
+        None => nan,
+    }
+}
+
+fn method_name<R: ClassResolver<Interpreter>>(resolver: &R, method: LoadedMethodRef) -> String {
+    let class = resolver.retrieve(method.class_ref);
+    let data = class.retrieve_method(method);
+    format!("{}.{}{}", class.name(), data.name, data.descriptor)
+}
+
+#[cfg(test)]
+mod tests {
+    use classfile_parser::attributes::ExceptionTableEntry;
+    use classfile_parser::builder::ClassBuilder;
+    use classfile_parser::bytecode::{Instruction, TableSwitch};
+    use classfile_parser::class_file::{FieldAccessFlags, MethodAccessFlags};
+    use vm_core::class_loaders::{BootstrapClassLoader, ChainedClassLoader, SimpleClassLoader};
+    use vm_core::interop::{JavaBoolean, JavaDouble, JavaFloat, JavaInt, JavaLong, JavaObject, JavaValue};
+    use vm_core::{ClassLoader, VirtualMachine, VmError};
+
+    use crate::Interpreter;
+
+    const STATIC: MethodAccessFlags = MethodAccessFlags::STATIC;
+
+    fn vm(classes: Vec<ClassBuilder>) -> VirtualMachine<ChainedClassLoader, Interpreter> {
+        let mut loaders: Vec<Box<dyn ClassLoader>> = vec![Box::new(BootstrapClassLoader::new())];
+        loaders.extend(classes.into_iter().map(|class| Box::new(SimpleClassLoader::new(class.build().unwrap())) as Box<dyn ClassLoader>));
+        VirtualMachine::new(ChainedClassLoader::new(loaders), Interpreter::default())
+    }
+
+    fn run(vm: &mut VirtualMachine<ChainedClassLoader, Interpreter>) -> JavaInt {
+        let run = vm.get_fn_pointer::<extern "C" fn() -> JavaInt>("Main", "run").unwrap();
+        run()
+    }
+
+    #[test]
+    fn loops() {
+        let mut main = ClassBuilder::new("Main", Some("java/lang/Object"));
+        main.method(STATIC, "run", "()I", vec![
+            Instruction::IConst(0),
+            Instruction::IStore(0),
+            Instruction::IConst(1),
+            Instruction::IStore(1),
+            Instruction::ILoad(0), // 4
+            Instruction::ILoad(1),
+            Instruction::IAdd,
+            Instruction::IStore(0),
+            Instruction::IInc(1, 1),
+            Instruction::ILoad(1),
+            Instruction::IConst(10),
+            Instruction::IfICmpLe(4 - 14), // 14
+            Instruction::ILoad(0),
+            Instruction::IReturn,
+        ]);
+        assert_eq!(run(&mut vm(vec![main])), 55);
+    }
+
+    #[test]
+    fn arguments() {
+        let mut main = ClassBuilder::new("Main", Some("java/lang/Object"));
+        main.method(STATIC, "add", "(IJFD)D", vec![
+            Instruction::ILoad(0),
+            Instruction::I2D,
+            Instruction::LLoad(1),
+            Instruction::L2D,
+            Instruction::DAdd,
+            Instruction::FLoad(3),
+            Instruction::F2D,
+            Instruction::DAdd,
+            Instruction::DLoad(4),
+            Instruction::DAdd,
+            Instruction::DReturn,
+        ]);
+        let mut vm = vm(vec![main]);
+        let add = vm.get_fn_pointer::<extern "C" fn(JavaInt, JavaLong, JavaFloat, JavaDouble) -> JavaDouble>("Main", "add").unwrap();
+        assert_eq!(add(1, 2, 0.5, 0.25), 3.75);
+        assert_eq!(add(-1, 1 << 40, 0.0, 0.0), (1i64 << 40) as f64 - 1.0);
+    }
+
+    /// A `Point` with two int fields that `sum()` adds, and a subclass `Scaled` whose `sum()` multiplies that by ten
+    fn points() -> Vec<ClassBuilder> {
+        let mut point = ClassBuilder::new("Point", Some("java/lang/Object"));
+        let object_init = point.method_ref("java/lang/Object", "<init>", "()V");
+        let (x, y) = (point.field_ref("Point", "x", "I"), point.field_ref("Point", "y", "I"));
+        point.field(FieldAccessFlags::empty(), "x", "I");
+        point.field(FieldAccessFlags::empty(), "y", "I");
+        point.method(MethodAccessFlags::PUBLIC, "<init>", "(II)V", vec![
+            Instruction::ALoad(0),
+            Instruction::InvokeSpecial(object_init),
+            Instruction::ALoad(0),
+            Instruction::ILoad(1),
+            Instruction::Putfield(x),
+            Instruction::ALoad(0),
+            Instruction::ILoad(2),
+            Instruction::Putfield(y),
+            Instruction::Return,
+        ]);
+        point.method(MethodAccessFlags::PUBLIC, "sum", "()I", vec![
+            Instruction::ALoad(0),
+            Instruction::GetField(x),
+            Instruction::ALoad(0),
+            Instruction::GetField(y),
+            Instruction::IAdd,
+            Instruction::IReturn,
+        ]);
+
+        let mut scaled = ClassBuilder::new("Scaled", Some("Point"));
+        let (point_init, point_sum) = (scaled.method_ref("Point", "<init>", "(II)V"), scaled.method_ref("Point", "sum", "()I"));
+        scaled.method(MethodAccessFlags::PUBLIC, "<init>", "(II)V", vec![
+            Instruction::ALoad(0),
+            Instruction::ILoad(1),
+            Instruction::ILoad(2),
+            Instruction::InvokeSpecial(point_init),
+            Instruction::Return,
+        ]);
+        scaled.method(MethodAccessFlags::PUBLIC, "sum", "()I", vec![
+            Instruction::ALoad(0),
+            Instruction::InvokeSpecial(point_sum),
+            Instruction::IConst(10),
+            Instruction::IMul,
+            Instruction::IReturn,
+        ]);
+
+        vec![point, scaled]
+    }
+
+    #[test]
+    fn switches() {
+        let mut main = ClassBuilder::new("Main", Some("java/lang/Object"));
+        main.method(STATIC, "table", "(I)I", vec![
+            Instruction::ILoad(0),
+            Instruction::TableSwitch(TableSwitch { default: 30 - 1, low: 3, high: 4, offsets: vec![24 - 1, 27 - 1] }), // 1
+            Instruction::BIPush(30), // 24
+            Instruction::IReturn,
+            Instruction::BIPush(40), // 27
+            Instruction::IReturn,
+            Instruction::IConst(0), // 30
+            Instruction::IReturn,
+        ]);
+        let mut vm = vm(vec![main]);
+        let table = vm.get_fn_pointer::<extern "C" fn(JavaInt) -> JavaInt>("Main", "table").unwrap();
+        assert_eq!(table(3), 30);
+        assert_eq!(table(4), 40);
+        assert_eq!(table(5), 0);
+        assert_eq!(table(2), 0);
+        assert_eq!(table(JavaInt::MIN), 0);
+    }
+
+    #[test]
+    fn objects() {
+        let mut main = ClassBuilder::new("Main", Some("java/lang/Object"));
+        let (scaled_class, scaled_init, sum) = (main.class("Scaled"), main.method_ref("Scaled", "<init>", "(II)V"), main.method_ref("Point", "sum", "()I"));
+        main.method(STATIC, "run", "()I", vec![
+            Instruction::New(scaled_class),
+            Instruction::Dup,
+            Instruction::IConst(2),
+            Instruction::IConst(3),
+            Instruction::InvokeSpecial(scaled_init),
+            Instruction::InvokeVirtual(sum),
+            Instruction::IReturn,
+        ]);
+        let mut classes = points();
+        classes.push(main);
+        assert_eq!(run(&mut vm(classes)), 50);
+    }
+
+    #[test]
+    fn embedding() {
+        let mut vm = vm(points());
+        let scaled = vm.new_object("Scaled", "(II)V", &[JavaValue::Int(2), JavaValue::Int(3)]).unwrap();
+        assert_eq!(vm.call_method(&scaled, "sum", "()I", &[]).unwrap(), JavaValue::Int(50));
+        vm.set_field(&scaled, "x", "I", JavaValue::Int(5)).unwrap();
+        assert_eq!(vm.call_method(&scaled, "sum", "()I", &[]).unwrap(), JavaValue::Int(80));
+        assert!(vm.is_instance_of(&scaled, "Point").unwrap());
+        assert!(matches!(vm.call_method(&scaled, "sum", "(I)I", &[JavaValue::Int(1)]), Err(VmError::NoSuchMethod(_))));
+        assert!(matches!(vm.call_method(&scaled, "sum", "()I", &[JavaValue::Int(1)]), Err(VmError::ArgumentMismatch(_))));
+
+        let length = vm.local_scope(|vm| {
+            let builder = vm.new_object("java/lang/StringBuilder", "()V", &[]).unwrap();
+            vm.call_method(&builder, "append", "(I)Ljava/lang/StringBuilder;", &[JavaValue::Int(-42)]).unwrap();
+            let string = match vm.call_method(&builder, "toString", "()Ljava/lang/String;", &[]).unwrap() {
+                JavaValue::Object(string) => vm.new_ref(string).unwrap(),
+                value => panic!("{:?}", value),
+            };
+            vm.collect_garbage();
+            vm.call_method(&string, "length", "()I", &[]).unwrap()
+        });
+        assert_eq!(length, JavaValue::Int(3));
+    }
+
+    #[test]
+    fn arrays_from_rust() {
+        let mut main = ClassBuilder::new("Main", Some("java/lang/Object"));
+        main.method(STATIC, "sum", "([I)I", vec![
+            Instruction::IConst(0),
+            Instruction::IStore(1),
+            Instruction::IConst(0),
+            Instruction::IStore(2),
+            Instruction::ILoad(2), // 4
+            Instruction::ALoad(0),
+            Instruction::ArrayLength,
+            Instruction::IfICmpGe(22 - 7), // 7
+            Instruction::ILoad(1),
+            Instruction::ALoad(0),
+            Instruction::ILoad(2),
+            Instruction::IALoad,
+            Instruction::IAdd,
+            Instruction::IStore(1),
+            Instruction::IInc(2, 1),
+            Instruction::Goto(4 - 19), // 19
+            Instruction::ILoad(1), // 22
+            Instruction::IReturn,
+        ]);
+        main.method(STATIC, "scale", "([DD)V", vec![
+            Instruction::IConst(0),
+            Instruction::IStore(3),
+            Instruction::ILoad(3), // 2
+            Instruction::ALoad(0),
+            Instruction::ArrayLength,
+            Instruction::IfICmpGe(22 - 5), // 5
+            Instruction::ALoad(0),
+            Instruction::ILoad(3),
+            Instruction::ALoad(0),
+            Instruction::ILoad(3),
+            Instruction::DALoad,
+            Instruction::DLoad(1),
+            Instruction::DMul,
+            Instruction::DAStore,
+            Instruction::IInc(3, 1),
+            Instruction::Goto(2 - 19), // 19
+            Instruction::Return, // 22
+        ]);
+        let mut vm = vm(vec![main]);
+
+        let numbers: Vec<JavaInt> = (1..=10_000).collect();
+        let ints = vm.new_array(&numbers);
+        let sum = |vm: &mut VirtualMachine<_, _>| vm.invoke("Main", "sum", "([I)I", &[JavaValue::Object(vm.get_ref(&ints))]);
+        assert_eq!(sum(&mut vm).unwrap(), JavaValue::Int(50_005_000));
+        vm.array_elements_mut::<JavaInt>(&ints).unwrap().fill(3);
+        assert_eq!(sum(&mut vm).unwrap(), JavaValue::Int(30_000));
+        // A JavaObject fits any reference type
+        let sum = vm.get_fn_pointer_with::<extern "C" fn(JavaObject) -> JavaInt>("Main", "sum", "([I)I").unwrap();
+        assert_eq!(sum(vm.get_ref(&ints)), 30_000);
+        assert!(matches!(vm.get_fn_pointer_with::<extern "C" fn(JavaInt) -> JavaInt>("Main", "sum", "([I)I"), Err(VmError::ArgumentMismatch(_))));
+        // Arrays have the methods of Object
+        assert!(matches!(vm.call_method(&ints, "hashCode", "()I", &[]), Ok(JavaValue::Int(_))));
+
+        let doubles = vm.new_array(&[1.0, 2.5, -4.0]);
+        vm.invoke("Main", "scale", "([DD)V", &[JavaValue::Object(vm.get_ref(&doubles)), JavaValue::Double(2.0)]).unwrap();
+        assert_eq!(vm.array_elements::<f64>(&doubles).unwrap(), &[2.0, 5.0, -8.0]);
+    }
+
+    #[test]
+    fn array_types() {
+        let mut main = ClassBuilder::new("Main", Some("java/lang/Object"));
+        let (ints, floats, object, strings, objects) = (main.class("[I"), main.class("[F"), main.class("java/lang/Object"), main.class("java/lang/String"), main.class("[Ljava/lang/Object;"));
+        let hash_code = main.method_ref("[I", "hashCode", "()I");
+        main.method(STATIC, "run", "()I", vec![
+            Instruction::IConst(1),
+            Instruction::NewArray(10),
+            Instruction::Checkcast(ints),
+            Instruction::Dup,
+            Instruction::InvokeVirtual(hash_code),
+            Instruction::Pop,
+            Instruction::Dup,
+            Instruction::InstanceOf(object),
+            Instruction::Swap,
+            Instruction::InstanceOf(floats),
+            Instruction::IConst(2),
+            Instruction::IMul,
+            Instruction::IAdd,
+            Instruction::IConst(1),
+            Instruction::ANewArray(strings),
+            Instruction::InstanceOf(objects),
+            Instruction::IConst(4),
+            Instruction::IMul,
+            Instruction::IAdd,
+            Instruction::IReturn,
+        ]);
+        main.method(STATIC, "store", "()V", vec![
+            Instruction::IConst(1),
+            Instruction::ANewArray(strings),
+            Instruction::AStore(0),
+            Instruction::ALoad(0),
+            Instruction::IConst(0),
+            Instruction::ALoad(0),
+            Instruction::AAStore,
+            Instruction::Return,
+        ]);
+        let mut vm = vm(vec![main]);
+        assert_eq!(run(&mut vm), 5);
+        vm.check_exception().unwrap();
+
+        // A String[] can't hold itself
+        assert!(matches!(vm.invoke("Main", "store", "()V", &[]), Err(VmError::Exception(e)) if e.class == "java/lang/ArrayStoreException"));
+    }
+
+    #[test]
+    fn exceptions() {
+        let mut main = ClassBuilder::new("Main", Some("java/lang/Object"));
+        let arithmetic = main.class("java/lang/ArithmeticException");
+        main.method_with_handlers(STATIC, "run", "()I", vec![
+            Instruction::IConst(1),
+            Instruction::IConst(0),
+            Instruction::IDiv,
+            Instruction::IReturn,
+            Instruction::Pop, // 4
+            Instruction::IConst(42),
+            Instruction::IReturn,
+        ], vec![ExceptionTableEntry { start_pc: 0, end_pc: 4, handler_pc: 4, catch_type: arithmetic }]);
+        main.method(STATIC, "uncaught", "()I", vec![
+            Instruction::AConstNull,
+            Instruction::ArrayLength,
+            Instruction::IReturn,
+        ]);
+        let mut vm = vm(vec![main]);
+        assert_eq!(run(&mut vm), 42);
+        vm.check_exception().unwrap();
+
+        let uncaught = vm.get_fn_pointer::<extern "C" fn() -> JavaInt>("Main", "uncaught").unwrap();
+        assert_eq!(uncaught(), 0);
+        assert!(matches!(vm.check_exception(), Err(VmError::Exception(e)) if e.class == "java/lang/NullPointerException"));
+    }
+
+    #[test]
+    fn linkage_errors() {
+        let mut failing = ClassBuilder::new("Failing", Some("java/lang/Object"));
+        failing.field(FieldAccessFlags::PUBLIC | FieldAccessFlags::STATIC, "x", "I");
+        let x = failing.field_ref("Failing", "x", "I");
+        failing.method(STATIC, "<clinit>", "()V", vec![
+            Instruction::IConst(1),
+            Instruction::IConst(0),
+            Instruction::IDiv,
+            Instruction::PutStatic(x),
+            Instruction::Return,
+        ]);
+
+        let mut main = ClassBuilder::new("Main", Some("java/lang/Object"));
+        let x = main.field_ref("Failing", "x", "I");
+        let in_initializer = main.class("java/lang/ExceptionInInitializerError");
+        let no_class = main.class("java/lang/NoClassDefFoundError");
+        let arithmetic = main.class("java/lang/ArithmeticException");
+        let get_cause = main.method_ref("java/lang/Throwable", "getCause", "()Ljava/lang/Throwable;");
+        let handler = |start_pc, end_pc, handler_pc, catch_type| ExceptionTableEntry { start_pc, end_pc, handler_pc, catch_type };
+        main.method_with_handlers(STATIC, "run", "()I", vec![
+            Instruction::GetStatic(x),
+            Instruction::IReturn,
+            // The exception of the initializer is the cause
+            Instruction::InvokeVirtual(get_cause), // 4
+            Instruction::InstanceOf(arithmetic),
+            Instruction::IfEq(21 - 10),
+            Instruction::GetStatic(x), // 13
+            Instruction::IReturn,
+            Instruction::Pop, // 17
+            Instruction::BIPush(42),
+            Instruction::IReturn,
+            Instruction::IConst(0), // 21
+            Instruction::IReturn,
+        ], vec![handler(0, 4, 4, in_initializer), handler(13, 17, 17, no_class)]);
+        let missing = main.field_ref("Main", "missing", "I");
+        main.method(STATIC, "missing", "()I", vec![
+            Instruction::AConstNull,
+            Instruction::GetField(missing),
+            Instruction::IReturn,
+        ]);
+        let absent = main.class("Absent");
+        main.method(STATIC, "absent", "(Z)I", vec![
+            Instruction::ILoad(0),
+            Instruction::IfEq(8 - 1),
+            Instruction::New(absent),
+            Instruction::Pop,
+            Instruction::IConst(1), // 8
+            Instruction::IReturn,
+        ]);
+        // Errors aren't wrapped
+        let mut asserting = ClassBuilder::new("Asserting", Some("java/lang/Object"));
+        let error = asserting.class("java/lang/AssertionError");
+        let error_init = asserting.method_ref("java/lang/AssertionError", "<init>", "()V");
+        asserting.method(STATIC, "<clinit>", "()V", vec![
+            Instruction::New(error),
+            Instruction::Dup,
+            Instruction::InvokeSpecial(error_init),
+            Instruction::AThrow,
+        ]);
+        asserting.method(STATIC, "get", "()I", vec![Instruction::IConst(1), Instruction::IReturn]);
+        let mut vm = vm(vec![main, failing, asserting]);
+        assert_eq!(run(&mut vm), 42);
+        vm.check_exception().unwrap();
+        assert!(matches!(vm.invoke("Asserting", "get", "()I", &[]), Err(VmError::Exception(e)) if e.class == "java/lang/AssertionError"));
+
+        // Missing classes only fail the code that uses them
+        let absent = vm.get_fn_pointer::<extern "C" fn(JavaBoolean) -> JavaInt>("Main", "absent").unwrap();
+        assert_eq!(absent(false), 1);
+        vm.check_exception().unwrap();
+        absent(true);
+        assert!(matches!(vm.check_exception(), Err(VmError::Exception(e)) if e.class == "java/lang/NoClassDefFoundError"));
+
+        let missing = vm.get_fn_pointer::<extern "C" fn() -> JavaInt>("Main", "missing").unwrap();
+        assert_eq!(missing(), 0);
+        assert!(matches!(vm.check_exception(), Err(VmError::Exception(e)) if e.class == "java/lang/NoSuchFieldError"));
+    }
+
+    #[test]
+    fn dynamic_invocation() {
+        let mut main = ClassBuilder::new("Main", Some("java/lang/Object"));
+        main.method(STATIC, "pick", "(ZCJ)J", vec![
+            Instruction::ILoad(0),
+            Instruction::IfEq(9 - 1),
+            Instruction::ILoad(1),
+            Instruction::I2L,
+            Instruction::LLoad(2),
+            Instruction::LAdd,
+            Instruction::LReturn,
+            Instruction::LLoad(2), // 9
+            Instruction::LReturn,
+        ]);
+        main.method(STATIC, "divide", "(II)I", vec![
+            Instruction::ILoad(0),
+            Instruction::ILoad(1),
+            Instruction::IDiv,
+            Instruction::IReturn,
+        ]);
+        main.method(STATIC, "nothing", "()V", vec![Instruction::Return]);
+        let mut vm = vm(vec![main]);
+
+        let pick = |vm: &mut VirtualMachine<_, _>, condition: bool| vm.invoke("Main", "pick", "(ZCJ)J", &[condition.into(), JavaValue::Char(0x20AC), JavaValue::Long(1 << 40)]);
+        assert_eq!(pick(&mut vm, true).unwrap(), JavaValue::Long((1 << 40) + 0x20AC));
+        assert_eq!(pick(&mut vm, false).unwrap(), JavaValue::Long(1 << 40));
+        assert_eq!(vm.invoke("Main", "divide", "(II)I", &[JavaValue::Int(7), JavaValue::Int(2)]).unwrap(), JavaValue::Int(3));
+        assert!(matches!(vm.invoke("Main", "divide", "(II)I", &[JavaValue::Int(7), JavaValue::Int(0)]), Err(VmError::Exception(e)) if e.class == "java/lang/ArithmeticException"));
+        assert_eq!(vm.invoke("Main", "nothing", "()V", &[]).unwrap(), JavaValue::Void);
+
+        assert!(matches!(vm.invoke("Main", "divide", "(II)I", &[JavaValue::Int(7)]), Err(VmError::ArgumentMismatch(_))));
+        assert!(matches!(vm.invoke("Main", "divide", "(II)I", &[JavaValue::Int(7), JavaValue::Long(2)]), Err(VmError::ArgumentMismatch(_))));
+        assert!(matches!(vm.invoke("Main", "divide", "(II", &[]), Err(VmError::InvalidDescriptor(_))));
+        assert!(matches!(vm.invoke("Main", "divide", "(JJ)J", &[JavaValue::Long(7), JavaValue::Long(2)]), Err(VmError::NoSuchMethod(_))));
+    }
+
+    #[test]
+    fn natives() {
+        let mut main = ClassBuilder::new("Main", Some("java/lang/Object"));
+        let builder = main.class("java/lang/StringBuilder");
+        let init = main.method_ref("java/lang/StringBuilder", "<init>", "()V");
+        let append_string = main.method_ref("java/lang/StringBuilder", "append", "(Ljava/lang/String;)Ljava/lang/StringBuilder;");
+        let append_int = main.method_ref("java/lang/StringBuilder", "append", "(I)Ljava/lang/StringBuilder;");
+        let to_string = main.method_ref("java/lang/StringBuilder", "toString", "()Ljava/lang/String;");
+        let length = main.method_ref("java/lang/String", "length", "()I");
+        let abc = main.string("abc");
+        main.method(STATIC, "run", "()I", vec![
+            Instruction::New(builder),
+            Instruction::Dup,
+            Instruction::InvokeSpecial(init),
+            Instruction::LdC_w(abc),
+            Instruction::InvokeVirtual(append_string),
+            Instruction::IConst(-17),
+            Instruction::InvokeVirtual(append_int),
+            Instruction::InvokeVirtual(to_string),
+            Instruction::InvokeVirtual(length),
+            Instruction::IReturn,
+        ]);
+        main.method_without_code(STATIC | MethodAccessFlags::NATIVE, "unlinked", "()I");
+        let mut vm = vm(vec![main]);
+        assert_eq!(run(&mut vm), 6);
+        assert!(matches!(vm.invoke("Main", "unlinked", "()I", &[]), Err(VmError::Exception(e)) if e.class == "java/lang/UnsatisfiedLinkError"));
+    }
+
+    #[test]
+    fn string_concatenation() {
+        let mut main = ClassBuilder::new("Main", Some("java/lang/Object"));
+        let object_init = main.method_ref("java/lang/Object", "<init>", "()V");
+        main.method(MethodAccessFlags::PUBLIC, "<init>", "()V", vec![
+            Instruction::ALoad(0),
+            Instruction::InvokeSpecial(object_init),
+            Instruction::Return,
+        ]);
+        let p = main.string("p");
+        main.method(MethodAccessFlags::PUBLIC, "toString", "()Ljava/lang/String;", vec![
+            Instruction::LdC_w(p),
+            Instruction::AReturn,
+        ]);
+
+        let class = main.class("Main");
+        let init = main.method_ref("Main", "<init>", "()V");
+        let recipe = main.string("x=\u{1}, \u{1}\u{2}");
+        let seven = main.long(7);
+        let bootstrap = main.bootstrap_method("java/lang/invoke/StringConcatFactory", "makeConcatWithConstants",
+            "(Ljava/lang/invoke/MethodHandles$Lookup;Ljava/lang/String;Ljava/lang/invoke/MethodType;Ljava/lang/String;[Ljava/lang/Object;)Ljava/lang/invoke/CallSite;",
+            vec![recipe, seven]);
+        let make_concat = main.invoke_dynamic(bootstrap, "makeConcatWithConstants", "(ILjava/lang/Object;)Ljava/lang/String;");
+        let expected = main.string("x=42, p7");
+        let equals = main.method_ref("java/lang/String", "equals", "(Ljava/lang/Object;)Z");
+        main.method(STATIC, "run", "()I", vec![
+            Instruction::BIPush(42),
+            Instruction::New(class),
+            Instruction::Dup,
+            Instruction::InvokeSpecial(init),
+            Instruction::InvokeDynamic(make_concat, 0),
+            Instruction::LdC_w(expected),
+            Instruction::InvokeVirtual(equals),
+            Instruction::IReturn,
+        ]);
+        assert_eq!(run(&mut vm(vec![main])), 1);
+    }
+
+    #[test]
+    fn garbage_collection() {
+        let mut main = ClassBuilder::new("Main", Some("java/lang/Object"));
+        // Allocates far more than the heap holds before it collects, while keeping the first array alive
+        main.method(STATIC, "run", "()I", vec![
+            Instruction::IConst(100),
+            Instruction::NewArray(10),
+            Instruction::AStore(0),
+            Instruction::ALoad(0),
+            Instruction::IConst(0),
+            Instruction::IConst(42),
+            Instruction::IAstore,
+            Instruction::IConst(0),
+            Instruction::IStore(1),
+            Instruction::IConst(1000), // 12
+            Instruction::NewArray(10),
+            Instruction::Pop,
+            Instruction::IInc(1, 1),
+            Instruction::ILoad(1),
+            Instruction::IConst(1000),
+            Instruction::IfICmpLt(12 - 25), // 25
+            Instruction::ALoad(0),
+            Instruction::IConst(0),
+            Instruction::IALoad,
+            Instruction::IReturn,
+        ]);
+        assert_eq!(run(&mut vm(vec![main])), 42);
+    }
+}
@@ -1,6 +1,8 @@
 mod type_translation;
 
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
 use std::mem;
 
 use inkwell::basic_block::BasicBlock;
@@ -9,18 +11,30 @@ use inkwell::builder::Builder;
 use inkwell::passes::{PassBuilderOptions, PassManagerSubType};
 use inkwell::targets::{CodeModel, InitializationConfig, RelocMode, Target, TargetData, TargetMachine};
 use inkwell::types::{BasicMetadataTypeEnum, BasicType, BasicTypeEnum, FloatType, FunctionType, IntType, VoidType};
-use inkwell::values::{AnyValue, BasicValueEnum, IntValue, PointerValue};
-use type_translation::{IntoBasicType, LlvmReturnType};
+use inkwell::values::{AnyValue, BasicMetadataValueEnum, BasicValueEnum, FunctionValue, IntValue, PointerValue};
+use type_translation::{storage_type, IntoBasicType, LlvmReturnType};
 use vm_core::types::{IsReturnAddress, LvtEntryType, PrimitiveTypes};
-use vm_core::{ClassResolver, ClassShell, JitCompiler};
-use vm_core::class_store::{DescriptorEntry, LoadedMethodRef, MethodData};
+use vm_core::{ClassResolver, ClassShell, JitCompiler, JitError};
+use vm_core::class_store::{ClassStoreIsh, LoadedMethodRef, MethodData};
+use vm_core::statics::{resolve_static_field, StaticFieldRef};
+use vm_core::object::{allocate_array, allocate_object, class_of, dispatch_class, is_instance, resolve_instance_field, resolve_new, ArrayType, BaseType, ObjectHeader, ReferenceType, ARRAY_HEADER_SIZE};
+use vm_core::class_store::LoadedClassRef;
+use vm_core::dispatch::{select_interface, select_special, select_virtual, vtable_slot, DispatchTables};
+use vm_core::resolution::{resolve_method, resolve_type};
+use vm_core::strings::intern;
+use vm_core::exceptions::{find_handler, throw_runtime_exception, RuntimeException};
+use vm_core::natives::{find_native, NativeEnv};
+use vm_core::initialization::InitError;
+use classfile_parser::attributes::ExceptionTableEntry;
+use classfile_parser::descriptor::{FieldDescriptor, MethodDescriptor};
 use vm_core::classfile_util::{split_code_into_basic_blocks, ConstantPoolExtensions};
 use classfile_parser::constant_pool::{ConstantPool, types, ConstantPoolEntry};
 use classfile_parser::bytecode::Instruction;
+use classfile_parser::analysis::compute_limits;
 use inkwell::context::Context;
 use inkwell::execution_engine::{ExecutionEngine, JitFunction};
 use inkwell::module::Module;
-use inkwell::{IntPredicate, OptimizationLevel};
+use inkwell::{AddressSpace, IntPredicate, OptimizationLevel};
 use classfile_parser::class_file::{ClassFile, MethodInfo};
 
 use crate::type_translation::{CtxJavaTypeExtension, IntoType};
@@ -30,6 +44,13 @@ pub struct LlvmJitCompiler {
     module: Module<'static>,
     builder: Builder<'static>,
     execution_engine: ExecutionEngine<'static>,
+    /// Addresses of the methods that were compiled before
+    compiled: RefCell<HashMap<LoadedMethodRef, usize>>,
+}
+
+pub struct LlvmClassData {
+    /// Compiled code for each slot of the vtable, or 0 if the slot wasn't called on this class yet
+    vtable: Box<[Cell<usize>]>,
 }
 
 impl Default for LlvmJitCompiler {
@@ -38,12 +59,15 @@ impl Default for LlvmJitCompiler {
         let ctx = Box::leak(Box::new(Context::create()));
         let m = ctx.create_module("main");
         let e = m.create_jit_execution_engine(OptimizationLevel::Aggressive).unwrap();
-        Self {
+        let compiler = Self {
             context: ctx,
             module: m,
             builder: ctx.create_builder(),
             execution_engine: e,
-        }
+            compiled: Default::default(),
+        };
+        compiler.declare_root_chain();
+        compiler
     }
 }
 
@@ -68,7 +92,7 @@ fn run_passes_on(module: &Module, machine: &TargetData) {
 }
 
 impl JitCompiler for LlvmJitCompiler {
-    type ClassData = ();
+    type ClassData = LlvmClassData;
     // type ClassId = ClassId;
     // type MethodId = MethodId;
     // type ClassShell = LlvmClass;
@@ -86,30 +110,42 @@ impl JitCompiler for LlvmJitCompiler {
     // }
 
     fn get_fn_pointer(&self, method: LoadedMethodRef, resolver: &impl ClassResolver<Self>) -> usize {
+        if let Some(&address) = self.compiled.borrow().get(&method) {
+            return address;
+        }
+
         // Retrieve some variables
+        let method_ref = method;
         let class = resolver.retrieve(method.class_ref);
         let method = class.retrieve_method(method);
-        let desc = method.parse_descriptors();
+        let desc = method.parse_descriptor().unwrap();
+        let function_name = format!("{}.{}{}", class.name(), method.name, method.descriptor);
         
         // Setup some LLVM stuff
         let usize_type = self.context.ptr_sized_int_type(self.execution_engine.get_target_data(), None);
-        let return_types: Vec<_> = desc.0.iter().map(|t| t.to_type(self.context).to_meta()).collect();
-        let ty = desc.1.to_type(self.context).fn_type(&return_types, false);
-        let function = self.module.add_function(&format!("{}-{}", class.name(), method.name), ty, None);
+        let function = self.module.add_function(&function_name, self.method_type(&desc, method.is_static()), None);
+        if method.is_native() {
+            self.build_native_stub(resolver, method_ref, function);
+            return self.finish(method_ref, function, &function_name);
+        }
+        let code = method.code.unwrap_or_else(|| panic!("{} has no code", function_name));
+        // References in locals and spill slots are registered on the shadow stack, see [JitCompiler::visit_frame_roots]
+        function.set_gc("shadow-stack");
 
         // Split into basic blocks
         let entry_block = self.context.append_basic_block(function, "entry-init");
-        let basic_blocks = split_code_into_basic_blocks(&method.code.code).into_iter()
+        let basic_blocks = split_code_into_basic_blocks(code).into_iter()
             .map(|block_range| {
                 (block_range.start, (block_range, self.context.append_basic_block(function, "")))
             })
             .collect::<HashMap<_,_>>();
 
-        let local_variables: [LocalVariableEntry; 50] = [LocalVariableEntry::default(); 50];
-        let stack: Vec<BasicValueEnum<'static>> = Vec::new();
-        let mut cctx = CompilingContext { entry_block, context: self.context, builder: &self.builder, local_variables, stack };
+        let limits = compute_limits(code, method.descriptor, method.is_static(), &class.java_class.constant_pool).unwrap();
+        let local_variables = vec![LocalVariableEntry::default(); limits.max_locals as usize];
+        let stack: Vec<BasicValueEnum<'static>> = Vec::with_capacity(limits.max_stack as usize);
+        let mut cctx = CompilingContext { entry_block, context: self.context, builder: &self.builder, local_variables, stack, spill_slots: Vec::new() };
 
-        let arr_header_size = usize_type.const_int(mem::size_of::<usize>() as u64, false);
+        let arr_header_size = usize_type.const_int(ARRAY_HEADER_SIZE as u64, false);
         let indexed_ptr = |array: PointerValue<'static>, index: IntValue<'static>, ty: LlvmReturnType<'static>| {
             let offset = self.builder.build_int_add(arr_header_size, self.builder.build_int_mul(ty.size_of().unwrap(), index, "ptrcalc interm"), "index offset");
             unsafe {
@@ -122,6 +158,24 @@ impl JitCompiler for LlvmJitCompiler {
         //     self.builder.build_store(*ptr, stack.pop().unwrap());
         // };
 
+        // Parameters start out in the first local variables, after the object the method was called on
+        self.builder.position_at_end(entry_block);
+        let this = (!method.is_static()).then(|| FieldDescriptor::Object(class.name().to_owned()));
+        // Longs and doubles take up two local variables
+        let mut local = 0;
+        for (value, ty) in function.get_param_iter().zip(this.iter().chain(&desc.parameters)) {
+            let lvt_type = match ty {
+                FieldDescriptor::Float => LvtEntryType::Float,
+                FieldDescriptor::Object(_) | FieldDescriptor::Array(_) => LvtEntryType::Reference,
+                FieldDescriptor::Long => LvtEntryType::Long,
+                FieldDescriptor::Double => LvtEntryType::Double,
+                _ => LvtEntryType::Int,
+            };
+            let ptr = *cctx.local_variables[local].get(entry_block, self, lvt_type);
+            self.builder.build_store(ptr, self.widen(value, ty));
+            local += if matches!(lvt_type, LvtEntryType::Long | LvtEntryType::Double) { 2 } else { 1 };
+        }
+
         fn lvt_store<'ctx, 'cctx>(cctx: &mut CompilingContext<'ctx, 'cctx>, ctx: &LlvmJitCompiler, index: u8, ty: LvtEntryType) {
                 let ptr = cctx.local_variables[index as usize].get(cctx.entry_block, ctx, ty).clone();
                 ctx.builder.build_store(ptr, cctx.stack.pop().unwrap());
@@ -132,15 +186,60 @@ impl JitCompiler for LlvmJitCompiler {
             cctx.stack.push(ctx.builder.build_load(ty.to_basic_type(ctx.context), ptr, ""));
         }
 
+        let frame = Frame { function, method: method_ref, exception_table: &code.exception_table, blocks: &basic_blocks };
+        let handlers: HashSet<usize> = frame.exception_table.iter().map(|entry| entry.handler_pc as usize).collect();
+        let pending_exception = self.pending_exception(resolver);
+        let reference_type = PrimitiveTypes::Reference.to_basic_type(self.context).into_pointer_type();
+
         for (block_bytes, block) in basic_blocks.values() {
             self.builder.position_at_end(*block);
+            if handlers.contains(&block_bytes.start) {
+                // Handlers start with only the caught exception on the operand stack
+                cctx.stack.clear();
+                cctx.stack.push(self.builder.build_load(reference_type, pending_exception, "caught"));
+                self.builder.build_store(pending_exception, reference_type.const_null());
+                // What the spill slots held was thrown away with the operand stack
+                self.spill_references(&mut cctx);
+            }
             let mut ended_with_branch = false;
-            for (byte, instr) in method.code.code.iter(block_bytes.clone()) {
+            for (byte, instr) in code.code.iter(block_bytes.clone()) {
                 ended_with_branch = false;
+                // An instruction that can't be linked throws, so the rest of its block and its operand stack are never reached
+                macro_rules! linked {
+                    ($result:expr) => {
+                        match $result {
+                            Ok(value) => value,
+                            Err(error) => {
+                                self.build_throw(resolver, &frame, byte, RuntimeException::from(&error));
+                                cctx.stack.clear();
+                                ended_with_branch = true;
+                                break;
+                            }
+                        }
+                    };
+                }
+                // A static initializer that throws leaves its error pending
+                macro_rules! initialized {
+                    ($class:expr) => {
+                        match resolver.initialize($class, self) {
+                            Ok(()) => {}
+                            Err(InitError::ExceptionInInitializerError(_)) => {
+                                let exception = self.builder.build_load(reference_type, pending_exception, "pending").into_pointer_value();
+                                self.build_dispatch(resolver, &frame, byte, exception);
+                                cctx.stack.clear();
+                                ended_with_branch = true;
+                                break;
+                            }
+                            Err(error) => linked!(Err(error)),
+                        }
+                    };
+                }
                 match instr {
                     Instruction::IConst(x) => {
                         cctx.stack.push(self.context.i32_type().const_int(x as u64, false).into());
                     }
+                    Instruction::LdC(index) => cctx.stack.push(self.constant(resolver, method_ref, index as u16)),
+                    Instruction::LdC_w(index) => cctx.stack.push(self.constant(resolver, method_ref, index)),
                     Instruction::SIPush(short) => {
                         cctx.stack.push(self.context.java_int().const_int(short as u64, true).into());
                     }
@@ -149,6 +248,12 @@ impl JitCompiler for LlvmJitCompiler {
                         let b = cctx.stack.pop().unwrap().into_int_value();
                         cctx.stack.push(self.builder.build_int_add(a, b, "result").into());
                     },
+                    Instruction::IDiv | Instruction::IRem => {
+                        let divisor = cctx.stack.pop().unwrap().into_int_value();
+                        let dividend = cctx.stack.pop().unwrap().into_int_value();
+                        let result = self.build_int_division(resolver, &frame, byte, dividend, divisor, matches!(instr, Instruction::IRem));
+                        cctx.stack.push(result.into());
+                    }
                     Instruction::IInc(local_variable, constant) => {
                         let aa = &mut cctx;
                         let ptr = aa.local_variables[local_variable as usize].get(entry_block, &self, LvtEntryType::Int);
@@ -163,38 +268,119 @@ impl JitCompiler for LlvmJitCompiler {
                     Instruction::ALoad(i) => lvt_load(&mut cctx, &self, i, LvtEntryType::Reference),
                     Instruction::FLoad(i) => lvt_load(&mut cctx, &self, i, LvtEntryType::Float),
                     Instruction::ILoad(i) => lvt_load(&mut cctx, &self, i, LvtEntryType::Int),
+                    Instruction::LStore(i) => lvt_store(&mut cctx, &self, i, LvtEntryType::Long),
+                    Instruction::DStore(i) => lvt_store(&mut cctx, &self, i, LvtEntryType::Double),
+                    Instruction::LLoad(i) => lvt_load(&mut cctx, &self, i, LvtEntryType::Long),
+                    Instruction::DLoad(i) => lvt_load(&mut cctx, &self, i, LvtEntryType::Double),
                     Instruction::NewArray(atype) => {
                         let ty = match atype {
-                            4 => DescriptorEntry::Boolean,
-                            5 => DescriptorEntry::Char,
-                            6 => DescriptorEntry::Float,
-                            7 => DescriptorEntry::Double,
-                            8 => DescriptorEntry::Byte,
-                            9 => DescriptorEntry::Short,
-                            10 => DescriptorEntry::Int,
-                            11 => DescriptorEntry::Long,
+                            4 => BaseType::Boolean,
+                            5 => BaseType::Char,
+                            6 => BaseType::Float,
+                            7 => BaseType::Double,
+                            8 => BaseType::Byte,
+                            9 => BaseType::Short,
+                            10 => BaseType::Int,
+                            11 => BaseType::Long,
                             _ => panic!()
                         };
-    
-                        // malloc(sizeof(ty) * i + ARR_HEADER_SIZE);
-                        let malloc_size = self.builder.build_int_add(self.builder.build_int_mul(cctx.stack.pop().unwrap().into_int_value(), ty.to_type(self.context).size_of().unwrap(), "amalloc intermediate"), arr_header_size, "amalloc size");
-                        cctx.stack.push(self.builder.build_array_malloc(self.context.i8_type(), malloc_size, "arrayptr").unwrap().into());
+
+                        let length = cctx.stack.pop().unwrap().into_int_value();
+                        let negative = self.builder.build_int_compare(IntPredicate::SLT, length, self.context.java_int().const_zero(), "negative");
+                        self.build_throw_if(resolver, &frame, byte, negative, RuntimeException::NegativeArraySize);
+                        self.spill_references(&mut cctx);
+                        cctx.stack.push(self.build_new_array(resolver, ArrayType::of(ty), length));
                     }
                     Instruction::IAstore => {
-                        let ty = DescriptorEntry::Int.to_type(self.context);
+                        let ty = FieldDescriptor::Int.to_type(self.context);
                         let value: IntValue<'static> = cctx.stack.pop().unwrap().into_int_value();
                         let index: IntValue<'static> = cctx.stack.pop().unwrap().into_int_value();
                         let array: PointerValue<'static> = cctx.stack.pop().unwrap().into_pointer_value();
+                        self.build_array_check(resolver, &frame, byte, array, index);
                         self.builder.build_store(indexed_ptr(array, index, ty), value);
                     }
                     Instruction::IALoad => {
-                        let ty = DescriptorEntry::Int.to_type(self.context);
+                        let ty = FieldDescriptor::Int.to_type(self.context);
                         let index: IntValue<'static> = cctx.stack.pop().unwrap().into_int_value();
                         let array: PointerValue<'static> = cctx.stack.pop().unwrap().into_pointer_value();
+                        self.build_array_check(resolver, &frame, byte, array, index);
                         cctx.stack.push(self.builder.build_load(ty.to_basic().unwrap(), indexed_ptr(array, index, ty), "iaload result"));
                     }
-                    Instruction::IReturn => {
-                        self.builder.build_return(Some(&cctx.stack.pop().unwrap()));
+                    Instruction::GetStatic(index) => {
+                        let field = linked!(resolve_static_field(resolver, method_ref.class_ref, index));
+                        initialized!(field.class);
+                        let (ptr, descriptor) = self.static_field(resolver, field);
+                        let value = self.builder.build_load(storage_type(&descriptor, self.context), ptr, "getstatic");
+                        cctx.stack.push(self.widen(value, &descriptor));
+                    }
+                    Instruction::PutStatic(index) => {
+                        let field = linked!(resolve_static_field(resolver, method_ref.class_ref, index));
+                        initialized!(field.class);
+                        let (ptr, descriptor) = self.static_field(resolver, field);
+                        let value = self.narrow(cctx.stack.pop().unwrap(), &descriptor);
+                        self.builder.build_store(ptr, value);
+                    }
+                    Instruction::New(index) => {
+                        let class = linked!(resolve_new(resolver, method_ref.class_ref, index));
+                        initialized!(class);
+                        self.spill_references(&mut cctx);
+                        cctx.stack.push(self.build_new(resolver, class));
+                    }
+                    Instruction::GetField(index) => {
+                        let field = linked!(resolve_instance_field(resolver, method_ref.class_ref, index));
+                        let object = cctx.stack.pop().unwrap().into_pointer_value();
+                        self.build_null_check(resolver, &frame, byte, object);
+                        let ptr = self.field_ptr(object, field.offset);
+                        let value = self.builder.build_load(storage_type(&field.descriptor, self.context), ptr, "getfield");
+                        cctx.stack.push(self.widen(value, &field.descriptor));
+                    }
+                    Instruction::Putfield(index) => {
+                        let field = linked!(resolve_instance_field(resolver, method_ref.class_ref, index));
+                        let value = self.narrow(cctx.stack.pop().unwrap(), &field.descriptor);
+                        let object = cctx.stack.pop().unwrap().into_pointer_value();
+                        self.build_null_check(resolver, &frame, byte, object);
+                        self.builder.build_store(self.field_ptr(object, field.offset), value);
+                    }
+                    Instruction::InvokeVirtual(index) | Instruction::InvokeSpecial(index) | Instruction::InvokeInterface(index, _) => {
+                        let resolved = linked!(resolve_method(resolver, method_ref.class_ref, index));
+                        let kind = match instr {
+                            Instruction::InvokeSpecial(_) => CallKind::Direct(linked!(select_special(resolver, method_ref.class_ref, resolved))),
+                            Instruction::InvokeVirtual(_) => match vtable_slot(resolver, resolved) {
+                                Some(slot) => CallKind::Virtual(resolved, slot),
+                                None => CallKind::Interface(resolved),
+                            },
+                            _ => CallKind::Interface(resolved),
+                        };
+                        // The callee might allocate, or initialize a class that does
+                        self.spill_references(&mut cctx);
+                        self.build_invoke(&mut cctx.stack, resolver, &frame, byte, resolved, kind);
+                        self.build_exception_check(resolver, &frame, byte);
+                    }
+                    Instruction::Checkcast(index) => {
+                        let ty = linked!(resolve_type(resolver, method_ref.class_ref, index));
+                        let object = cctx.stack.last().unwrap().into_pointer_value();
+                        self.build_check_cast(resolver, ty, object);
+                        self.build_exception_check(resolver, &frame, byte);
+                    }
+                    Instruction::AThrow => {
+                        let exception = cctx.stack.pop().unwrap().into_pointer_value();
+                        self.build_null_check(resolver, &frame, byte, exception);
+                        self.builder.build_store(pending_exception, exception);
+                        self.build_dispatch(resolver, &frame, byte, exception);
+                        ended_with_branch = true;
+                    }
+                    Instruction::Dup => {
+                        let value = *cctx.stack.last().unwrap();
+                        cctx.stack.push(value);
+                    }
+                    Instruction::IReturn | Instruction::AReturn | Instruction::LReturn | Instruction::DReturn => {
+                        let value = cctx.stack.pop().unwrap();
+                        let value = desc.return_type.as_ref().map_or(value, |ty| self.narrow(value, ty));
+                        self.builder.build_return(Some(&value));
+                        ended_with_branch = true;
+                    }
+                    Instruction::Return => {
+                        self.builder.build_return(None);
                         ended_with_branch = true;
                     }
                     Instruction::IfEq(o) => {
@@ -220,23 +406,556 @@ impl JitCompiler for LlvmJitCompiler {
         self.builder.position_at_end(entry_block);
         self.builder.build_unconditional_branch(basic_blocks[&0].1);
 
+        self.finish(method_ref, function, &function_name)
+    }
+    
+    fn load(&mut self, class: &ClassFile, dispatch: &DispatchTables) -> Result<Self::ClassData, JitError> {
+        Ok(LlvmClassData { vtable: vec![Cell::new(0); dispatch.vtable.len()].into_boxed_slice() })
+    }
+
+    /// Walks the shadow stack that LLVM maintains for functions using the `shadow-stack` gc strategy.
+    /// Every compiled method registers its reference locals and spill slots there.
+    fn visit_frame_roots(&self, visitor: &mut dyn FnMut(*mut u8)) {
+        let address = self.execution_engine.get_function_address("rave_gc_root_chain").unwrap();
+        // Safe because the function was compiled by [Self::declare_root_chain] with this signature
+        let root_chain: extern "C" fn() -> *const StackEntry = unsafe { mem::transmute(address) };
+        let mut entry = root_chain();
+        while !entry.is_null() {
+            // Safe because LLVM only links complete entries into the chain, with the roots directly after the entry
+            unsafe {
+                let roots = entry.add(1) as *const *mut u8;
+                for index in 0..(*(*entry).map).num_roots as usize {
+                    visitor(*roots.add(index));
+                }
+                entry = (*entry).next;
+            }
+        }
+    }
+}
+
+impl LlvmJitCompiler {
+    /// Optimizes a compiled method and returns its address
+    fn finish(&self, method: LoadedMethodRef, function: FunctionValue<'static>, function_name: &str) -> usize {
         run_passes_on(&self.module, self.execution_engine.get_target_data());
         println!("Running {}", function.print_to_string());
-        
-        self.execution_engine.get_function_address(&format!("{}-{}", class.name(), method.name)).unwrap()
+
+        let address = self.execution_engine.get_function_address(function_name).unwrap();
+        self.compiled.borrow_mut().insert(method, address);
+        address
     }
-    
-    fn load(&mut self, class: &ClassFile) -> Result<Self::ClassData,()> {
-        Ok(())
+
+    /// Compiles a native method into a call to the rust function that implements it, with a [NativeEnv] in front of the arguments
+    fn build_native_stub<R: ClassResolver<Self>>(&self, resolver: &R, method: LoadedMethodRef, function: FunctionValue<'static>) {
+        self.builder.position_at_end(self.context.append_basic_block(function, "entry"));
+        let native = match find_native(resolver, method) {
+            Ok(native) => native,
+            Err(_) => {
+                // Calling it throws an `UnsatisfiedLinkError`, which the caller checks for
+                let frame = Frame { function, method, exception_table: &[], blocks: &HashMap::new() };
+                self.build_throw(resolver, &frame, 0, RuntimeException::UnsatisfiedLink);
+                return;
+            }
+        };
+        // Never freed, just like the code that uses it
+        let env: &NativeEnv = Box::leak(Box::new(unsafe { NativeEnv::new(resolver, self) }));
+
+        let reference_type = PrimitiveTypes::Reference.to_basic_type(self.context);
+        let function_type = function.get_type();
+        let parameters: Vec<BasicMetadataTypeEnum> = std::iter::once(reference_type).chain(function_type.get_param_types()).map(Into::into).collect();
+        let native_type = match function_type.get_return_type() {
+            Some(ty) => ty.fn_type(&parameters, false),
+            None => self.context.void_type().fn_type(&parameters, false),
+        };
+
+        let usize_type = self.context.ptr_sized_int_type(self.execution_engine.get_target_data(), None);
+        let target = self.builder.build_int_to_ptr(usize_type.const_int(native.address as u64, false), native_type.ptr_type(AddressSpace::default()), "native");
+        let arguments: Vec<BasicMetadataValueEnum> = std::iter::once(self.const_ptr(env).into()).chain(function.get_param_iter().map(Into::into)).collect();
+        let result = self.builder.build_indirect_call(native_type, target, &arguments, "");
+        match result.try_as_basic_value().left() {
+            Some(value) => self.builder.build_return(Some(&value)),
+            None => self.builder.build_return(None),
+        };
+    }
+
+    /// A global that's mapped onto the storage of a resolved static field, whose class has been initialized
+    fn static_field(&self, resolver: &impl ClassResolver<Self>, field: StaticFieldRef) -> (PointerValue<'static>, FieldDescriptor) {
+        let class = resolver.retrieve(field.class);
+        let descriptor = class.statics.fields()[field.slot].descriptor.clone();
+        let name = format!("{}.{}", class.name(), class.statics.fields()[field.slot].name);
+        let global = self.module.get_global(&name).unwrap_or_else(|| {
+            let global = self.module.add_global(storage_type(&descriptor, self.context), None, &name);
+            self.execution_engine.add_global_mapping(&global, class.statics.address(field.slot) as usize);
+            global
+        });
+        (global.as_pointer_value(), descriptor)
+    }
+
+    /// A constant from the pool of the method's class, for `ldc`. Strings are interned while compiling,
+    /// which is fine because interned strings are never freed or moved.
+    fn constant(&self, resolver: &impl ClassResolver<Self>, method: LoadedMethodRef, index: u16) -> BasicValueEnum<'static> {
+        let pool = &resolver.retrieve(method.class_ref).java_class.constant_pool;
+        match pool.get_entry(index) {
+            Some(ConstantPoolEntry::IntegerInfo(value)) => self.context.java_int().const_int(value.inner as u64, true).into(),
+            Some(ConstantPoolEntry::FloatInfo(value)) => self.context.f32_type().const_float(value.inner as f64).into(),
+            Some(ConstantPoolEntry::StringInfo(string)) => {
+                let value = pool.get_as::<types::Utf8Info>(string.string_index).unwrap().to_utf16();
+                let object = intern(resolver, self, &value).unwrap();
+                self.const_ptr(object).into()
+            }
+            entry => panic!("No LLVM implementation for ldc of {:?}", entry),
+        }
+    }
+
+    /// A global that's mapped onto the pending exception of the vm
+    fn pending_exception(&self, resolver: &impl ClassResolver<Self>) -> PointerValue<'static> {
+        let global = self.module.get_global("rave_pending_exception").unwrap_or_else(|| {
+            let global = self.module.add_global(PrimitiveTypes::Reference.to_basic_type(self.context), None, "rave_pending_exception");
+            self.execution_engine.add_global_mapping(&global, resolver.exception().address() as usize);
+            global
+        });
+        global.as_pointer_value()
+    }
+
+    /// Checks if the instruction at `pc` threw, and if so, goes to its handler or returns to the caller
+    fn build_exception_check<R: ClassResolver<Self>>(&self, resolver: &R, frame: &Frame, pc: usize) {
+        let reference_type = PrimitiveTypes::Reference.to_basic_type(self.context);
+        let exception = self.builder.build_load(reference_type, self.pending_exception(resolver), "pending").into_pointer_value();
+        let thrown = self.builder.build_is_not_null(exception, "thrown");
+        let dispatch = self.context.append_basic_block(frame.function, "dispatch");
+        let next = self.context.append_basic_block(frame.function, "");
+        self.builder.build_conditional_branch(thrown, dispatch, next);
+
+        self.builder.position_at_end(dispatch);
+        self.build_dispatch(resolver, frame, pc, exception);
+        self.builder.position_at_end(next);
+    }
+
+    /// Throws a runtime exception from the instruction at `pc` if `condition` is true
+    fn build_throw_if<R: ClassResolver<Self>>(&self, resolver: &R, frame: &Frame, pc: usize, condition: IntValue<'static>, exception: RuntimeException) {
+        let throw = self.context.append_basic_block(frame.function, "throw");
+        let next = self.context.append_basic_block(frame.function, "");
+        self.builder.build_conditional_branch(condition, throw, next);
+
+        self.builder.position_at_end(throw);
+        self.build_throw(resolver, frame, pc, exception);
+        self.builder.position_at_end(next);
+    }
+
+    /// Throws a runtime exception from the instruction at `pc`
+    fn build_throw<R: ClassResolver<Self>>(&self, resolver: &R, frame: &Frame, pc: usize, exception: RuntimeException) {
+        // Nothing on the operand stack has to be spilled, a handler starts with an empty one anyway
+        let reference_type = PrimitiveTypes::Reference.to_basic_type(self.context);
+        let function = self.runtime_fn("rave_throw", reference_type.fn_type(&[reference_type.into()], false), throw_exception::<R> as usize);
+        let site: &ThrowSite<R> = Box::leak(Box::new(ThrowSite { runtime: self.runtime(resolver), exception }));
+        let thrown = self.builder.build_call(function, &[self.const_ptr(site).into()], "thrown");
+        self.build_dispatch(resolver, frame, pc, thrown.try_as_basic_value().left().unwrap().into_pointer_value());
+    }
+
+    /// Throws a `NullPointerException` if `object` is null
+    fn build_null_check<R: ClassResolver<Self>>(&self, resolver: &R, frame: &Frame, pc: usize, object: PointerValue<'static>) {
+        let is_null = self.builder.build_is_null(object, "is null");
+        self.build_throw_if(resolver, frame, pc, is_null, RuntimeException::NullPointer);
+    }
+
+    /// Checks that `array` isn't null, and that `index` lies within it
+    fn build_array_check<R: ClassResolver<Self>>(&self, resolver: &R, frame: &Frame, pc: usize, array: PointerValue<'static>, index: IntValue<'static>) {
+        self.build_null_check(resolver, frame, pc, array);
+        let usize_type = self.context.ptr_sized_int_type(self.execution_engine.get_target_data(), None);
+        let length = self.builder.build_load(usize_type, array, "length").into_int_value();
+        // Negative indices become larger than any length
+        let index = self.builder.build_int_z_extend(index, usize_type, "index");
+        let out_of_bounds = self.builder.build_int_compare(IntPredicate::UGE, index, length, "out of bounds");
+        self.build_throw_if(resolver, frame, pc, out_of_bounds, RuntimeException::ArrayIndexOutOfBounds);
+    }
+
+    /// Divides or takes the remainder of ints, throwing an `ArithmeticException` when dividing by zero.
+    /// Dividing the smallest int by -1 overflows, which LLVM leaves undefined but java defines as wrapping around.
+    fn build_int_division<R: ClassResolver<Self>>(&self, resolver: &R, frame: &Frame, pc: usize, dividend: IntValue<'static>, divisor: IntValue<'static>, remainder: bool) -> IntValue<'static> {
+        let int_type = self.context.java_int();
+        let by_zero = self.builder.build_int_compare(IntPredicate::EQ, divisor, int_type.const_zero(), "by zero");
+        self.build_throw_if(resolver, frame, pc, by_zero, RuntimeException::Arithmetic);
+
+        let minus_one = int_type.const_all_ones();
+        let by_minus_one = self.builder.build_int_compare(IntPredicate::EQ, divisor, minus_one, "by minus one");
+        let safe_divisor = self.builder.build_select(by_minus_one, int_type.const_int(1, false), divisor, "divisor").into_int_value();
+        if remainder {
+            let result = self.builder.build_int_signed_rem(dividend, safe_divisor, "rem");
+            self.builder.build_select(by_minus_one, int_type.const_zero(), result, "rem").into_int_value()
+        } else {
+            let result = self.builder.build_int_signed_div(dividend, safe_divisor, "div");
+            let negated = self.builder.build_int_sub(int_type.const_zero(), dividend, "negated");
+            self.builder.build_select(by_minus_one, negated, result, "div").into_int_value()
+        }
+    }
+
+    /// Jumps to the handler for the pending exception, which was thrown at `pc`.
+    /// If no handler catches it, the method returns and the exception stays pending for the caller.
+    fn build_dispatch<R: ClassResolver<Self>>(&self, resolver: &R, frame: &Frame, pc: usize, exception: PointerValue<'static>) {
+        let mut handlers: Vec<usize> = frame.exception_table.iter()
+            .filter(|entry| (entry.start_pc as usize..entry.end_pc as usize).contains(&pc))
+            .map(|entry| entry.handler_pc as usize)
+            .collect();
+        handlers.sort_unstable();
+        handlers.dedup();
+
+        let unwind = self.context.append_basic_block(frame.function, "unwind");
+        if handlers.is_empty() {
+            self.builder.build_unconditional_branch(unwind);
+        } else {
+            // Which handler catches it depends on the class of the exception, so that's decided at runtime
+            let reference_type = PrimitiveTypes::Reference.to_basic_type(self.context);
+            let ty = self.context.java_int().fn_type(&[reference_type.into(), reference_type.into()], false);
+            let find = self.runtime_fn("rave_find_handler", ty, catching_handler::<R> as usize);
+            let site: &HandlerSite<R> = Box::leak(Box::new(HandlerSite { runtime: self.runtime(resolver), method: frame.method, pc }));
+            let handler = self.builder.build_call(find, &[self.const_ptr(site).into(), exception.into()], "handler");
+            let handler = handler.try_as_basic_value().left().unwrap().into_int_value();
+            let cases: Vec<_> = handlers.iter().map(|&handler| (self.context.java_int().const_int(handler as u64, false), frame.blocks[&handler].1)).collect();
+            self.builder.build_switch(handler, unwind, &cases);
+        }
+
+        self.builder.position_at_end(unwind);
+        match frame.function.get_type().get_return_type() {
+            Some(ty) => self.builder.build_return(Some(&ty.const_zero())),
+            None => self.builder.build_return(None),
+        };
+    }
+
+    /// The type of a compiled method. Instance methods get the object they were called on as the first parameter.
+    fn method_type(&self, descriptor: &MethodDescriptor, is_static: bool) -> FunctionType<'static> {
+        let this = (!is_static).then(|| PrimitiveTypes::Reference.to_basic_type(self.context).into());
+        let parameters: Vec<BasicMetadataTypeEnum> = this.into_iter().chain(descriptor.parameters.iter().map(|ty| storage_type(ty, self.context).into())).collect();
+        match &descriptor.return_type {
+            Some(ty) => storage_type(ty, self.context).fn_type(&parameters, false),
+            None => self.context.void_type().fn_type(&parameters, false),
+        }
+    }
+
+    /// Calls an instance method. The target is looked up by [call_target] when the call runs.
+    fn build_invoke<R: ClassResolver<Self>>(&self, stack: &mut Vec<BasicValueEnum<'static>>, resolver: &R, frame: &Frame, pc: usize, resolved: LoadedMethodRef, kind: CallKind) {
+        let class = resolver.retrieve(resolved.class_ref);
+        let descriptor = class.java_class.constant_pool.get_as_string(class.method_info(resolved).descriptor).unwrap();
+        let descriptor: MethodDescriptor = descriptor.parse().unwrap();
+        let function_type = self.method_type(&descriptor, false);
+
+        // The receiver, followed by the arguments
+        let mut arguments = stack.split_off(stack.len() - descriptor.parameters.len() - 1);
+        for (argument, ty) in arguments[1..].iter_mut().zip(&descriptor.parameters) {
+            *argument = self.narrow(*argument, ty);
+        }
+        self.build_null_check(resolver, frame, pc, arguments[0].into_pointer_value());
+
+        // The call site is never freed, just like the code that uses it
+        let site: &CallSite<R> = Box::leak(Box::new(CallSite { runtime: self.runtime(resolver), kind }));
+        let target = self.builder.build_call(self.call_target_fn::<R>(), &[self.const_ptr(site).into(), arguments[0].into()], "target");
+        let target = target.try_as_basic_value().left().unwrap().into_int_value();
+        // Selecting the target throws if the method can't be linked
+        self.build_exception_check(resolver, frame, pc);
+        let target = self.builder.build_int_to_ptr(target, function_type.ptr_type(AddressSpace::default()), "");
+
+        let arguments: Vec<BasicMetadataValueEnum> = arguments.into_iter().map(Into::into).collect();
+        let result = self.builder.build_indirect_call(function_type, target, &arguments, "");
+        if let (Some(value), Some(ty)) = (result.try_as_basic_value().left(), &descriptor.return_type) {
+            stack.push(self.widen(value, ty));
+        }
+    }
+
+    /// Declares the function that compiled code calls to find the target of a call
+    fn call_target_fn<R: ClassResolver<Self>>(&self) -> FunctionValue<'static> {
+        let usize_type = self.context.ptr_sized_int_type(self.execution_engine.get_target_data(), None);
+        let reference_type = PrimitiveTypes::Reference.to_basic_type(self.context);
+        self.runtime_fn("rave_call_target", usize_type.fn_type(&[reference_type.into(), reference_type.into()], false), call_target::<R> as usize)
+    }
+
+    /// Throws a `ClassCastException` if `object` isn't null and isn't an instance of `class`
+    fn build_check_cast<R: ClassResolver<Self>>(&self, resolver: &R, ty: ReferenceType, object: PointerValue<'static>) {
+        let reference_type = PrimitiveTypes::Reference.to_basic_type(self.context);
+        let ty = self.context.void_type().fn_type(&[reference_type.into(), reference_type.into()], false);
+        let function = self.runtime_fn("rave_check_cast", ty, check_cast::<R> as usize);
+        let site: &CastSite<R> = Box::leak(Box::new(CastSite { runtime: self.runtime(resolver), ty }));
+        self.builder.build_call(function, &[self.const_ptr(site).into(), object.into()], "");
+    }
+
+    /// Allocates an instance of `class`, which has to be initialized already
+    fn build_new<R: ClassResolver<Self>>(&self, resolver: &R, class: LoadedClassRef) -> BasicValueEnum<'static> {
+        let reference_type = PrimitiveTypes::Reference.to_basic_type(self.context);
+        let function = self.runtime_fn("rave_new_object", reference_type.fn_type(&[reference_type.into()], false), new_object::<R> as usize);
+        // Never freed, just like the code that uses it
+        let site: &NewSite<R> = Box::leak(Box::new(NewSite { runtime: self.runtime(resolver), class }));
+        let object = self.builder.build_call(function, &[self.const_ptr(site).into()], "new");
+        object.try_as_basic_value().left().unwrap()
+    }
+
+    /// Allocates an array of type `ty` with `length` elements
+    fn build_new_array<R: ClassResolver<Self>>(&self, resolver: &R, ty: ArrayType, length: IntValue<'static>) -> BasicValueEnum<'static> {
+        let reference_type = PrimitiveTypes::Reference.to_basic_type(self.context);
+        let ty = reference_type.fn_type(&[reference_type.into(), self.context.java_int().into()], false);
+        let function = self.runtime_fn("rave_new_array", ty, new_array::<R> as usize);
+        let site: &NewArraySite<R> = Box::leak(Box::new(NewArraySite { runtime: self.runtime(resolver), ty }));
+        let array = self.builder.build_call(function, &[self.const_ptr(site).into(), length.into()], "newarray");
+        array.try_as_basic_value().left().unwrap()
+    }
+
+    /// Declares a function of the vm that compiled code calls
+    fn runtime_fn(&self, name: &str, ty: FunctionType<'static>, address: usize) -> FunctionValue<'static> {
+        self.module.get_function(name).unwrap_or_else(|| {
+            let function = self.module.add_function(name, ty, None);
+            self.execution_engine.add_global_mapping(&function, address);
+            function
+        })
+    }
+
+    fn runtime<R>(&self, resolver: &R) -> Runtime<R> {
+        Runtime { compiler: self, resolver }
+    }
+
+    /// A pointer to data that outlives the compiled code, like a call site or an interned string
+    fn const_ptr<T>(&self, data: *const T) -> PointerValue<'static> {
+        let reference_type = PrimitiveTypes::Reference.to_basic_type(self.context).into_pointer_type();
+        let usize_type = self.context.ptr_sized_int_type(self.execution_engine.get_target_data(), None);
+        usize_type.const_int(data as u64, false).const_to_pointer(reference_type)
+    }
+
+    /// Defines the head of the shadow stack, and a function that reads it.
+    /// LLVM's shadow stack lowering uses the existing `llvm_gc_root_chain` global instead of creating its own.
+    fn declare_root_chain(&self) {
+        let reference_type = PrimitiveTypes::Reference.to_basic_type(self.context).into_pointer_type();
+        let head = self.module.add_global(reference_type, None, "llvm_gc_root_chain");
+        head.set_initializer(&reference_type.const_null());
+        let function = self.module.add_function("rave_gc_root_chain", reference_type.fn_type(&[], false), None);
+        self.builder.position_at_end(self.context.append_basic_block(function, "entry"));
+        let entry = self.builder.build_load(reference_type, head.as_pointer_value(), "head");
+        self.builder.build_return(Some(&entry));
+    }
+
+    /// An entry-block stack slot that the garbage collector treats as a root. It starts out null.
+    fn gc_root(&self, entry_block: BasicBlock<'static>) -> PointerValue<'static> {
+        let reference_type = PrimitiveTypes::Reference.to_basic_type(self.context).into_pointer_type();
+        let gcroot = self.module.get_function("llvm.gcroot").unwrap_or_else(|| {
+            let ty = self.context.void_type().fn_type(&[reference_type.into(), reference_type.into()], false);
+            self.module.add_function("llvm.gcroot", ty, None)
+        });
+        let prev_block = self.builder.get_insert_block();
+        self.builder.position_at_end(entry_block);
+        let slot = self.builder.build_alloca(reference_type, "root");
+        self.builder.build_call(gcroot, &[slot.into(), reference_type.const_null().into()], "");
+        self.builder.build_store(slot, reference_type.const_null());
+        self.builder.position_at_end(prev_block.unwrap());
+        slot
+    }
+
+    /// Stores the references on the operand stack in spill slots, before a call that might collect garbage.
+    /// The values themselves stay in registers, so the slots only keep the objects alive. Slots of values that
+    /// left the operand stack since the last call are cleared, so they don't keep garbage alive.
+    fn spill_references(&self, cctx: &mut CompilingContext<'static, '_>) {
+        while cctx.spill_slots.len() < cctx.stack.len() {
+            cctx.spill_slots.push(self.gc_root(cctx.entry_block));
+        }
+        let null = PrimitiveTypes::Reference.to_basic_type(self.context).into_pointer_type().const_null();
+        for (index, &slot) in cctx.spill_slots.iter().enumerate() {
+            match cctx.stack.get(index) {
+                Some(BasicValueEnum::PointerValue(pointer)) => self.builder.build_store(slot, *pointer),
+                _ => self.builder.build_store(slot, null),
+            };
+        }
+    }
+
+    fn field_ptr(&self, object: PointerValue<'static>, offset: usize) -> PointerValue<'static> {
+        let offset = self.context.i64_type().const_int(offset as u64, false);
+        unsafe { self.builder.build_gep(self.context.i8_type(), object, &[offset], "field ptr") }
+    }
+
+    /// Widens a value loaded from a field to the type it has on the operand stack
+    fn widen(&self, value: BasicValueEnum<'static>, descriptor: &FieldDescriptor) -> BasicValueEnum<'static> {
+        match descriptor {
+            FieldDescriptor::Boolean | FieldDescriptor::Char => self.builder.build_int_z_extend(value.into_int_value(), self.context.java_int(), "").into(),
+            FieldDescriptor::Byte | FieldDescriptor::Short => self.builder.build_int_s_extend(value.into_int_value(), self.context.java_int(), "").into(),
+            _ => value,
+        }
+    }
+
+    /// Truncates a value from the operand stack to the type it's stored as
+    fn narrow(&self, value: BasicValueEnum<'static>, descriptor: &FieldDescriptor) -> BasicValueEnum<'static> {
+        match descriptor {
+            FieldDescriptor::Boolean | FieldDescriptor::Byte | FieldDescriptor::Char | FieldDescriptor::Short => {
+                let ty = storage_type(descriptor, self.context).into_int_type();
+                self.builder.build_int_truncate(value.into_int_value(), ty, "").into()
+            }
+            _ => value,
+        }
+    }
+}
+
+/// Lets functions that compiled code calls get back to the vm.
+/// The vm that compiled the code can't move or be dropped while compiled code runs.
+struct Runtime<R> {
+    compiler: *const LlvmJitCompiler,
+    resolver: *const R,
+}
+
+impl<R> Runtime<R> {
+    /// Safe as long as the vm is still in the same place
+    unsafe fn get(&self) -> (&LlvmJitCompiler, &R) {
+        (&*self.compiler, &*self.resolver)
     }
 }
 
+/// A call whose target is selected when it runs. Compiled code passes it to [call_target].
+struct CallSite<R> {
+    runtime: Runtime<R>,
+    kind: CallKind,
+}
+
+/// A `new` instruction, which compiled code passes to [new_object]
+struct NewSite<R> {
+    runtime: Runtime<R>,
+    class: LoadedClassRef,
+}
+
+/// A `newarray` instruction, which compiled code passes to [new_array]
+struct NewArraySite<R> {
+    runtime: Runtime<R>,
+    ty: ArrayType,
+}
+
+/// An instruction that can throw, in a method with exception handlers. Compiled code passes it to [catching_handler].
+struct HandlerSite<R> {
+    runtime: Runtime<R>,
+    method: LoadedMethodRef,
+    pc: usize,
+}
+
+/// An instruction that throws a runtime exception, which compiled code passes to [throw_exception]
+struct ThrowSite<R> {
+    runtime: Runtime<R>,
+    exception: RuntimeException,
+}
+
+/// A `checkcast` instruction, which compiled code passes to [check_cast]
+struct CastSite<R> {
+    runtime: Runtime<R>,
+    ty: ReferenceType,
+}
+
+/// The start of a frame on the shadow stack, the roots of the frame follow directly after it.
+/// See: https://llvm.org/docs/GarbageCollection.html#the-shadow-stack-gc
+#[repr(C)]
+struct StackEntry {
+    next: *const StackEntry,
+    map: *const FrameMap,
+}
+
+#[repr(C)]
+struct FrameMap {
+    num_roots: i32,
+    num_meta: i32,
+}
+
+#[derive(Clone, Copy)]
+enum CallKind {
+    /// `invokevirtual`, the resolved method and its vtable slot
+    Virtual(LoadedMethodRef, usize),
+    /// `invokeinterface`, or `invokevirtual` of a method without a vtable slot
+    Interface(LoadedMethodRef),
+    /// A method that was already selected during compilation, like with `invokespecial`
+    Direct(LoadedMethodRef),
+}
+
+/// Selects the method that a call site calls on `receiver`, and returns its compiled code.
+/// If no method can be selected, it throws the linkage error and returns 0.
+extern "C" fn call_target<R: ClassResolver<LlvmJitCompiler>>(site: &CallSite<R>, receiver: *const ObjectHeader) -> usize {
+    let (compiler, resolver) = unsafe { site.runtime.get() };
+    let selected = match site.kind {
+        CallKind::Virtual(resolved, slot) => {
+            let class = dispatch_class(resolver, receiver as *mut ObjectHeader, resolved.class_ref);
+            let cached = &resolver.retrieve(class).jit_data.vtable[slot];
+            if cached.get() == 0 {
+                match select_virtual(resolver, class, resolved) {
+                    Ok(method) => cached.set(compiler.get_fn_pointer(method, resolver)),
+                    Err(error) => {
+                        throw_runtime_exception(resolver, compiler, RuntimeException::from(&error));
+                        return 0;
+                    }
+                }
+            }
+            return cached.get();
+        }
+        CallKind::Interface(resolved) => {
+            let class = dispatch_class(resolver, receiver as *mut ObjectHeader, resolved.class_ref);
+            select_interface(resolver, class, resolved)
+        }
+        CallKind::Direct(method) => Ok(method),
+    };
+    match selected {
+        Ok(method) => compiler.get_fn_pointer(method, resolver),
+        Err(error) => {
+            throw_runtime_exception(resolver, compiler, RuntimeException::from(&error));
+            0
+        }
+    }
+}
+
+/// Allocates the object of a `new` instruction
+extern "C" fn new_object<R: ClassResolver<LlvmJitCompiler>>(site: &NewSite<R>) -> *mut ObjectHeader {
+    let (compiler, resolver) = unsafe { site.runtime.get() };
+    allocate_object(resolver, compiler, site.class)
+}
+
+/// Allocates the array of a `newarray` instruction
+extern "C" fn new_array<R: ClassResolver<LlvmJitCompiler>>(site: &NewArraySite<R>, length: i32) -> *mut u8 {
+    let (compiler, resolver) = unsafe { site.runtime.get() };
+    // Compiled code throws before getting here with a negative length
+    let length = usize::try_from(length).unwrap_or_else(|_| panic!("negative array size {}", length));
+    allocate_array(resolver, compiler, site.ty, length)
+}
+
+/// Throws the runtime exception of a [ThrowSite], and returns it
+extern "C" fn throw_exception<R: ClassResolver<LlvmJitCompiler>>(site: &ThrowSite<R>) -> *mut ObjectHeader {
+    let (compiler, resolver) = unsafe { site.runtime.get() };
+    throw_runtime_exception(resolver, compiler, site.exception)
+}
+
+/// Throws a `ClassCastException` if `object` can't be cast to the type of a [CastSite]
+extern "C" fn check_cast<R: ClassResolver<LlvmJitCompiler>>(site: &CastSite<R>, object: *const ObjectHeader) {
+    if object.is_null() {
+        return;
+    }
+    let (compiler, resolver) = unsafe { site.runtime.get() };
+    if !is_instance(resolver, object as *mut ObjectHeader, site.ty) {
+        throw_runtime_exception(resolver, compiler, RuntimeException::ClassCast);
+    }
+}
+
+/// Finds the handler that catches an exception thrown at a [HandlerSite], or returns -1 if there is none.
+/// A catch type that can't be resolved replaces the exception with the linkage error, which isn't caught.
+extern "C" fn catching_handler<R: ClassResolver<LlvmJitCompiler>>(site: &HandlerSite<R>, exception: *const ObjectHeader) -> i32 {
+    let (compiler, resolver) = unsafe { site.runtime.get() };
+    let class = class_of(resolver, exception as *mut ObjectHeader).expect("exceptions are instances of a class");
+    match find_handler(resolver, site.method, site.pc, class) {
+        Ok(Some(handler)) => handler as i32,
+        Ok(None) => -1,
+        Err(error) => {
+            throw_runtime_exception(resolver, compiler, RuntimeException::from(&error));
+            -1
+        }
+    }
+}
+
+/// The method that's being compiled, as far as exception handling needs it
+struct Frame<'a> {
+    function: FunctionValue<'static>,
+    method: LoadedMethodRef,
+    exception_table: &'a [ExceptionTableEntry],
+    blocks: &'a HashMap<usize, (Range<usize>, BasicBlock<'static>)>,
+}
+
 pub struct CompilingContext<'ctx, 'cctx> {
     entry_block: BasicBlock<'ctx>,
     context: &'cctx Context,
     builder: &'cctx Builder<'ctx>,
     stack: Vec<BasicValueEnum<'ctx>>,
-    local_variables: [LocalVariableEntry<'ctx>; 50],
+    local_variables: Vec<LocalVariableEntry<'ctx>>,
+    /// Slots that hold the references on the operand stack during calls, by stack index
+    spill_slots: Vec<PointerValue<'ctx>>,
 }
 
 #[derive(Default, Clone, Copy)]
@@ -247,6 +966,9 @@ pub struct LocalVariableEntry<'ctx> {
 impl<'ctx> LocalVariableEntry<'ctx> {
     pub fn get(&mut self, entry_block: BasicBlock<'ctx>, compiler: &LlvmJitCompiler, ty: LvtEntryType) -> &PointerValue<'ctx> {
         self.allocs[ty].get_or_insert_with(move || {
+            if ty == LvtEntryType::Reference {
+                return compiler.gc_root(entry_block);
+            }
             let prev_block = compiler.builder.get_insert_block();
             compiler.builder.position_at_end(entry_block);
 
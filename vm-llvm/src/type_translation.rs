@@ -1,7 +1,8 @@
 // Translates java types to llvm ones
 
 use inkwell::{context::Context, types::{BasicMetadataTypeEnum, BasicType, BasicTypeEnum, FloatType, FunctionType, IntType, VoidType}, values::IntValue, AddressSpace};
-use vm_core::{class_store::DescriptorEntry, types::{LvtEntryType, PrimitiveTypes}};
+use classfile_parser::descriptor::FieldDescriptor;
+use vm_core::types::{LvtEntryType, PrimitiveTypes};
 
 use crate::LocalVariableEntry;
 
@@ -22,7 +23,7 @@ impl<'ctx> CtxJavaTypeExtension<'ctx> for Context {
     }
 
     fn java_char(&'ctx self) -> IntType<'ctx> {
-        self.i8_type()
+        self.i16_type()
     }
 
     fn java_double(&'ctx self) -> FloatType<'ctx> {
@@ -58,10 +59,25 @@ pub trait IntoBasicType {
     fn to_basic_type<'ctx>(&self, ctx: &'ctx Context) -> BasicTypeEnum<'ctx>;
 }
 
-impl IntoType for DescriptorEntry {
+impl IntoType for FieldDescriptor {
+    fn to_type<'ctx>(&self, ctx: &'ctx Context) -> LlvmReturnType<'ctx> {
+        PrimitiveTypes::from(self).to_basic_type(ctx).into()
+    }
+}
+
+/// A return type, where `None` is void
+impl IntoType for Option<FieldDescriptor> {
     fn to_type<'ctx>(&self, ctx: &'ctx Context) -> LlvmReturnType<'ctx> {
-        let primative: Result<PrimitiveTypes, _> = self.try_into();
-        primative.map_or(ctx.void_type().into(), |p| p.to_basic_type(ctx).into())
+        self.as_ref().map_or(ctx.void_type().into(), |ty| ty.to_type(ctx))
+    }
+}
+
+/// The type a field is stored as. Unlike on the operand stack, booleans, bytes, chars and shorts keep their own size.
+pub fn storage_type<'ctx>(descriptor: &FieldDescriptor, ctx: &'ctx Context) -> BasicTypeEnum<'ctx> {
+    match descriptor {
+        FieldDescriptor::Boolean | FieldDescriptor::Byte => ctx.i8_type().into(),
+        FieldDescriptor::Char | FieldDescriptor::Short => ctx.i16_type().into(),
+        other => PrimitiveTypes::from(other).to_basic_type(ctx),
     }
 }
 